@@ -0,0 +1,486 @@
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+use embassy_time::Duration;
+use rmk::event::KeyEvent;
+
+use crate::alert::toggle_alerts_muted;
+use crate::brightness::cycle_brightness;
+use crate::clock::current_time;
+use crate::config_reset::{press_config_reset, release_config_reset};
+use crate::debounce::toggle_rapid_trigger;
+use crate::demo::toggle_demo;
+use crate::event::{rmk_events_sent, wait_rmk_taken, HookContext, KeyEventHook};
+use crate::feature_flags::{toggle_feature, Feature};
+use crate::info::BuildInfo;
+use crate::heatmap::toggle_heatmap_shown;
+use crate::jiggler::toggle_jiggler;
+use crate::key_lock::toggle_keyboard_lock;
+use crate::key_stream::toggle_key_stream;
+use crate::layer_state::active_layer;
+use crate::layout::cycle_host_layout;
+use crate::log::{toggle_matrix_debug_log, LogModule};
+use crate::log_info;
+use crate::output::{hold_modifiers, release_modifiers, try_hold_modifiers};
+use crate::physical::set_swap_hands;
+use crate::pointer_settings::{cycle_pointer_accel, step_pointer_cpi};
+use crate::pointing::{set_scroll_emulation, toggle_caret_scroll, toggle_scroll_momentum, ScrollEmulation};
+use crate::presenter::toggle_presenter;
+use crate::queues::CUSTOM_ACTION_QUEUE_DEPTH;
+use crate::reboot::{request_bootloader, request_system_reset};
+use crate::recorder::dump_flight_recorder;
+use crate::scheduler::{cancel_scheduled, schedule_actions, schedule_turbo, wait_scheduler_idle, ActionStep};
+use crate::snippets::type_snippet;
+use crate::socd::toggle_socd;
+use crate::soft_off::request_soft_off;
+use crate::timer::{start_timer, stop_timer, POMODORO_DURATION};
+#[cfg(feature = "totp")]
+use crate::totp::type_totp;
+use crate::training::toggle_training;
+use crate::typing::{press_char, release_keys, type_char, type_text};
+use crate::typing_game::toggle_typing_game;
+#[cfg(feature = "secret_vault")]
+use crate::vault::{lock_vault, start_vault_unlock, type_secret};
+
+
+/// Firmware-side actions which aren't part of rmk's keymap
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum CustomAction {
+    /// Type the firmware version string
+    Version,
+    /// Type a summary of the current settings
+    Settings,
+    /// Enable or disable SOCD cleaning
+    ToggleSocd,
+    /// Enable or disable rapid trigger emulation
+    ToggleRapidTrigger,
+    /// Switch the matrix log between info and debug
+    ToggleMatrixLog,
+    /// Start or restart a 25 minutes timer
+    StartPomodoro,
+    /// Stop the running timer
+    StopTimer,
+    /// Type the current date and time
+    Timestamp,
+    /// Step the brightness of the LEDs and the display
+    CycleBrightness,
+    /// Type the char on the host layout, e.g. `é`
+    Char(char),
+    /// Switch the host layout used for typing
+    CycleHostLayout,
+    /// Hold the symbol with the modifiers it takes on the host layout, e.g. `@` is shift+2 on US and AltGr+Q on DE
+    Symbol(char),
+    /// Print the flight recorder by defmt
+    DumpFlightRecorder,
+    /// Enable or disable the feature, persisted
+    ToggleFeature(Feature),
+    /// Turn the keyboard off until the wake key is pressed
+    SoftOff,
+    /// Lock the keyboard, [`KeyLockHook`](crate::key_lock::KeyLockHook) must be before the custom action hook
+    LockKeyboard,
+    /// Start or stop the key stream polled by host apps, needs [`KeyStreamHook`](crate::key_stream::KeyStreamHook)
+    ToggleKeyStream,
+    /// Start or stop the demo script
+    ToggleDemo,
+    /// Run the script in the action scheduler, needs the scheduler task
+    Macro(&'static [ActionStep]),
+    /// Tap the char repeatedly while held, needs the scheduler task
+    Turbo(char),
+    /// Release the keys, flush the storage and reset the MCU, needs the system reset task
+    SystemReset,
+    /// Like [`SystemReset`](Self::SystemReset) but into the bootloader, leaving the config handoff flag
+    Bootloader,
+    /// Wipe the stored keymap and settings when held for 3 seconds, needs the config reset task
+    ResetConfig,
+    /// Start or stop the presence mode keeping the host awake
+    ToggleJiggler,
+    /// Start or quit the typing game on the OLED, needs [`TypingGameHook`](crate::typing_game::TypingGameHook)
+    ToggleTypingGame,
+    /// Type the text snippet of the index on the host layout
+    Snippet(u8),
+    /// Raise the CPI of the pointing device of the index, persisted
+    PointerCpiUp(u8),
+    /// Lower the CPI of the pointing device of the index, persisted
+    PointerCpiDown(u8),
+    /// Step the acceleration of the pointing device of the index, persisted
+    CyclePointerAccel(u8),
+    /// Scroll by the pointing devices while held
+    DragScroll,
+    /// Mirror the keys across the layout while held, needs [`SwapHandsHook`](crate::physical::SwapHandsHook)
+    SwapHands,
+    /// Move the caret by the pointing devices, or go back to the layer's mode
+    ToggleCaretScroll,
+    /// Enable or disable the scrolling coasting on after the motion stops
+    ToggleScrollMomentum,
+    /// Start or stop presenting, needs [`PresenterHook`](crate::presenter::PresenterHook) and the presenter task
+    TogglePresenter,
+    /// Start or stop the training mode, needs [`TrainingHook`](crate::training::TrainingHook)
+    ToggleTraining,
+    /// Ignore the alerts raised by the host until toggled again
+    MuteAlerts,
+    /// Show or hide the press counts on the lighting, needs [`HeatmapEffect`](crate::heatmap::HeatmapEffect)
+    ToggleHeatmap,
+    /// Hold the modifier bits while the action runs and the key is held, e.g. shift on a macro or ctrl on
+    /// a mouse key of rmk by [`Key`](Self::Key). Chainable, and held actions inside are released along with it,
+    /// a macro's once it's typed. Needs [`HeldModifiersDriver`](crate::output::HeldModifiersDriver) over USB.
+    WithMods(u8, &'static CustomAction),
+    /// rmk's action of the key's position, passed on to rmk, e.g. a macro or a mouse key inside
+    /// [`WithMods`](Self::WithMods)
+    Key,
+    /// Take the next key presses as the vault's unlock combo, needs [`VaultHook`](crate::vault::VaultHook)
+    #[cfg(feature = "secret_vault")]
+    UnlockVault,
+    /// Wipe the vault key from RAM
+    #[cfg(feature = "secret_vault")]
+    LockVault,
+    /// Type the secret of the index from the unlocked vault, nothing while locked
+    #[cfg(feature = "secret_vault")]
+    Secret(u8),
+    /// Type the current TOTP code of the vault's secret of the index, needs the clock set
+    #[cfg(feature = "totp")]
+    Totp(u8),
+}
+
+impl CustomAction {
+    /// Whether the action also needs the release, instead of firing once on press
+    pub fn is_held(&self) -> bool {
+        matches!(
+            self,
+            Self::Symbol(_)
+                | Self::Turbo(_)
+                | Self::ResetConfig
+                | Self::DragScroll
+                | Self::SwapHands
+                | Self::WithMods(..)
+        )
+    }
+
+    /// The action inside the modifier wrappers and their modifiers combined
+    pub fn unwrap_mods(&self) -> (CustomAction, u8) {
+        let (mut action, mut modifier) = (*self, 0);
+        while let Self::WithMods(mods, inner) = action {
+            modifier |= mods;
+            action = *inner;
+        }
+        (action, modifier)
+    }
+}
+
+/// Press or release of a custom key
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct CustomActionEvent {
+    pub action: CustomAction,
+    pub pressed: bool,
+    /// Number of the key's event to rmk by [`rmk_events_sent`], for a [`CustomAction::Key`] passed on
+    pub rmk_event: u32,
+}
+
+/// Matrix position bound to a custom action, on every layer or on one of them
+#[derive(Clone, Copy, Debug, defmt::Format)]
+pub struct CustomKey {
+    pub row: u8,
+    pub col: u8,
+    /// The layer the key is bound on, every layer if `None`
+    pub layer: Option<u8>,
+    pub action: CustomAction,
+}
+
+impl CustomKey {
+    /// Bound on every layer, in place of the keymap's action
+    pub const fn new(row: u8, col: u8, action: CustomAction) -> Self {
+        Self {
+            row,
+            col,
+            layer: None,
+            action,
+        }
+    }
+
+    /// Bound while the layer is the [active one](active_layer), the keymap's action elsewhere
+    pub const fn on_layer(layer: u8, row: u8, col: u8, action: CustomAction) -> Self {
+        Self {
+            row,
+            col,
+            layer: Some(layer),
+            action,
+        }
+    }
+}
+
+/// The keys of the parts in one array, to keep the keys of optional features out of the main list, e.g.
+/// `const KEYS: [CustomKey; BASE.len() + EXTRA.len()] = join_custom_keys(&[&BASE, &EXTRA]);`
+pub const fn join_custom_keys<const N: usize>(parts: &[&[CustomKey]]) -> [CustomKey; N] {
+    let mut keys = [CustomKey::new(0, 0, CustomAction::Version); N];
+    let mut n = 0;
+    let mut part = 0;
+    while part < parts.len() {
+        let mut i = 0;
+        while i < parts[part].len() {
+            keys[n] = parts[part][i];
+            n += 1;
+            i += 1;
+        }
+        part += 1;
+    }
+    assert!(n == N, "the parts don't have N keys");
+    keys
+}
+
+/// Longest wait for rmk to take the release of a [`CustomAction::Key`] before its modifiers are released
+const RMK_KEY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Triggered custom actions, consumed by the custom action task.
+/// Releases are only sent for held actions.
+pub static CUSTOM_ACTION_CHANNEL: Channel<CriticalSectionRawMutex, CustomActionEvent, CUSTOM_ACTION_QUEUE_DEPTH> = Channel::new();
+
+
+/// Hook which takes the events of custom keys out of the rmk pipeline.
+/// A key bound on the active layer goes before one bound on every layer, and a release goes the way
+/// of its press, even after the layer changed. Put it before
+/// [`LayerTrackerHook`](crate::layer_state::LayerTrackerHook).
+pub struct CustomActionHook<const N: usize> {
+    keys: [CustomKey; N],
+    /// Keys whose press was taken, by index
+    pressed: [bool; N],
+}
+
+impl<const N: usize> CustomActionHook<N> {
+    pub fn new(keys: [CustomKey; N]) -> Self {
+        Self {
+            keys,
+            pressed: [false; N],
+        }
+    }
+
+    /// The key taking the event, if any
+    fn key_index(&self, event: &KeyEvent) -> Option<usize> {
+        let at = |key: &CustomKey| key.row == event.row && key.col == event.col;
+        if !event.pressed {
+            return (0..N).find(|&i| self.pressed[i] && at(&self.keys[i]));
+        }
+        let layer = active_layer();
+        self.keys
+            .iter()
+            .position(|key| at(key) && key.layer == Some(layer))
+            .or_else(|| self.keys.iter().position(|key| at(key) && key.layer.is_none()))
+    }
+}
+
+impl<const N: usize> KeyEventHook for CustomActionHook<N> {
+    async fn process(&mut self, event: KeyEvent, _ctx: &mut HookContext) -> Option<KeyEvent> {
+        let Some(index) = self.key_index(&event) else {
+            return Some(event);
+        };
+        self.pressed[index] = event.pressed;
+        let key = self.keys[index];
+        let (action, modifier) = key.action.unwrap_mods();
+        if action == CustomAction::Key {
+            // Held before rmk sees the press, released by the task once rmk is done with the release
+            if event.pressed {
+                try_hold_modifiers(modifier);
+            } else if modifier != 0 {
+                let action_event = CustomActionEvent {
+                    action: key.action,
+                    pressed: false,
+                    rmk_event: rmk_events_sent().wrapping_add(1),
+                };
+                if CUSTOM_ACTION_CHANNEL.try_send(action_event).is_err() {
+                    defmt::warn!("Custom action {} dropped", action_event);
+                }
+            }
+            return Some(event);
+        }
+        if event.pressed || key.action.is_held() {
+            let action_event = CustomActionEvent {
+                action: key.action,
+                pressed: event.pressed,
+                rmk_event: 0,
+            };
+            // Never block the scan loop, drop the action if the task is busy
+            if CUSTOM_ACTION_CHANNEL.try_send(action_event).is_err() {
+                defmt::warn!("Custom action {} dropped", action_event);
+            }
+        }
+        None
+    }
+}
+
+/// Run the custom action task. This function should never return.
+pub async fn run_custom_actions(build_info: &BuildInfo) -> ! {
+    loop {
+        let CustomActionEvent {
+            action,
+            pressed,
+            rmk_event,
+        } = CUSTOM_ACTION_CHANNEL.receive().await;
+        let (action, modifier) = action.unwrap_mods();
+        if !pressed {
+            match action {
+                CustomAction::Symbol(_) => release_keys().await,
+                CustomAction::Turbo(_) => {
+                    cancel_scheduled();
+                    if modifier != 0 {
+                        wait_scheduler_idle().await;
+                    }
+                }
+                // Still typing after a quick tap
+                CustomAction::Macro(_) if modifier != 0 => wait_scheduler_idle().await,
+                CustomAction::Key => {
+                    if !wait_rmk_taken(rmk_event, RMK_KEY_TIMEOUT).await {
+                        defmt::warn!("rmk didn't take the key's release, releasing the modifiers");
+                    }
+                }
+                CustomAction::ResetConfig => release_config_reset(),
+                CustomAction::DragScroll => set_scroll_emulation(ScrollEmulation::Off),
+                CustomAction::SwapHands => set_swap_hands(false),
+                _ => {}
+            }
+            if modifier != 0 {
+                release_modifiers(modifier).await;
+            }
+            continue;
+        }
+        log_info!(LogModule::Action, "Custom action: {}", action);
+        if modifier != 0 {
+            hold_modifiers(modifier).await;
+        }
+        match action {
+            CustomAction::Version => type_text(&build_info.version_string()).await,
+            CustomAction::Settings => type_text(&build_info.settings_summary()).await,
+            CustomAction::ToggleSocd => toggle_socd(),
+            CustomAction::ToggleRapidTrigger => toggle_rapid_trigger(),
+            CustomAction::ToggleMatrixLog => toggle_matrix_debug_log(),
+            CustomAction::StartPomodoro => start_timer(POMODORO_DURATION),
+            CustomAction::StopTimer => stop_timer(),
+            CustomAction::Timestamp => match current_time() {
+                Some(time) => type_text(&time.timestamp_string()).await,
+                None => defmt::warn!("Clock isn't set"),
+            },
+            CustomAction::CycleBrightness => cycle_brightness(),
+            CustomAction::Char(c) => {
+                type_char(c).await;
+            }
+            CustomAction::CycleHostLayout => cycle_host_layout(),
+            CustomAction::Symbol(c) => {
+                press_char(c).await;
+            }
+            CustomAction::DumpFlightRecorder => dump_flight_recorder(),
+            CustomAction::ToggleFeature(feature) => toggle_feature(feature),
+            CustomAction::SoftOff => request_soft_off(),
+            CustomAction::LockKeyboard => toggle_keyboard_lock(),
+            CustomAction::ToggleKeyStream => toggle_key_stream(),
+            CustomAction::ToggleDemo => toggle_demo(),
+            CustomAction::Macro(script) => {
+                if !schedule_actions(script) {
+                    defmt::warn!("Macro dropped");
+                }
+            }
+            CustomAction::Turbo(c) => {
+                if !schedule_turbo(c) {
+                    defmt::warn!("Turbo dropped");
+                }
+            }
+            CustomAction::SystemReset => request_system_reset(),
+            CustomAction::Bootloader => request_bootloader(),
+            CustomAction::ResetConfig => press_config_reset(),
+            CustomAction::ToggleJiggler => toggle_jiggler(),
+            CustomAction::ToggleTypingGame => toggle_typing_game(),
+            CustomAction::Snippet(index) => type_snippet(index).await,
+            CustomAction::PointerCpiUp(device) => step_pointer_cpi(device, true),
+            CustomAction::PointerCpiDown(device) => step_pointer_cpi(device, false),
+            CustomAction::CyclePointerAccel(device) => cycle_pointer_accel(device),
+            CustomAction::DragScroll => set_scroll_emulation(ScrollEmulation::Drag),
+            CustomAction::SwapHands => set_swap_hands(true),
+            CustomAction::ToggleCaretScroll => toggle_caret_scroll(),
+            CustomAction::ToggleScrollMomentum => toggle_scroll_momentum(),
+            CustomAction::TogglePresenter => toggle_presenter(),
+            CustomAction::ToggleTraining => toggle_training(),
+            CustomAction::MuteAlerts => toggle_alerts_muted(),
+            CustomAction::ToggleHeatmap => toggle_heatmap_shown(),
+            // Unwrapped above, and rmk's keys never come here pressed
+            CustomAction::WithMods(..) | CustomAction::Key => {}
+            #[cfg(feature = "secret_vault")]
+            CustomAction::UnlockVault => start_vault_unlock(),
+            #[cfg(feature = "secret_vault")]
+            CustomAction::LockVault => lock_vault(),
+            #[cfg(feature = "secret_vault")]
+            CustomAction::Secret(index) => type_secret(index).await,
+            #[cfg(feature = "totp")]
+            CustomAction::Totp(index) => type_totp(index).await,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layer_state::set_active_layer;
+    use crate::test_support::serial;
+
+    const KEYS: [CustomKey; 3] = [
+        CustomKey::new(0, 0, CustomAction::Version),
+        CustomKey::on_layer(2, 0, 0, CustomAction::ResetConfig),
+        CustomKey::on_layer(1, 0, 1, CustomAction::ResetConfig),
+    ];
+
+    fn key(hook: &mut CustomActionHook<3>, col: u8, pressed: bool) -> Option<KeyEvent> {
+        let event = KeyEvent { row: 0, col, pressed };
+        embassy_futures::block_on(hook.process(event, &mut HookContext::new()))
+    }
+
+    fn taken() -> Option<(CustomAction, bool)> {
+        CUSTOM_ACTION_CHANNEL.try_receive().ok().map(|e| (e.action, e.pressed))
+    }
+
+    fn hook() -> CustomActionHook<3> {
+        while CUSTOM_ACTION_CHANNEL.try_receive().is_ok() {}
+        set_active_layer(0);
+        CustomActionHook::new(KEYS)
+    }
+
+    #[test]
+    fn layer_key_only_on_its_layer() {
+        let _serial = serial();
+        let mut hook = hook();
+        assert!(key(&mut hook, 1, true).is_some());
+        assert!(key(&mut hook, 1, false).is_some());
+        assert_eq!(taken(), None);
+        set_active_layer(1);
+        assert_eq!(key(&mut hook, 1, true), None);
+        assert_eq!(taken(), Some((CustomAction::ResetConfig, true)));
+    }
+
+    #[test]
+    fn layer_key_before_every_layer_key() {
+        let _serial = serial();
+        let mut hook = hook();
+        assert_eq!(key(&mut hook, 0, true), None);
+        assert_eq!(key(&mut hook, 0, false), None);
+        assert_eq!(taken(), Some((CustomAction::Version, true)));
+        set_active_layer(2);
+        assert_eq!(key(&mut hook, 0, true), None);
+        assert_eq!(taken(), Some((CustomAction::ResetConfig, true)));
+    }
+
+    #[test]
+    fn release_follows_the_press_across_layers() {
+        let _serial = serial();
+        let mut hook = hook();
+        set_active_layer(1);
+        assert_eq!(key(&mut hook, 1, true), None);
+        set_active_layer(0);
+        assert_eq!(key(&mut hook, 1, false), None);
+        assert_eq!(taken(), Some((CustomAction::ResetConfig, true)));
+        assert_eq!(taken(), Some((CustomAction::ResetConfig, false)));
+        // Pressed on the base layer, rmk has the release
+        assert!(key(&mut hook, 1, true).is_some());
+        set_active_layer(1);
+        assert!(key(&mut hook, 1, false).is_some());
+        assert_eq!(taken(), None);
+    }
+
+    #[test]
+    fn joined_keys_keep_their_order() {
+        const JOINED: [CustomKey; 3] = join_custom_keys(&[&[KEYS[0]], &[], &[KEYS[1], KEYS[2]]]);
+        assert_eq!(JOINED.map(|k| (k.layer, k.col)), KEYS.map(|k| (k.layer, k.col)));
+    }
+}
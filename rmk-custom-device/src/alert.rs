@@ -0,0 +1,228 @@
+//! Alerts raised by host scripts over raw HID, e.g. a failed CI run or a meeting starting.
+//! The lighting flashes the pattern and a buzzer beeps along, until the repeats are done or any key is pressed.
+
+use core::cell::Cell;
+use core::sync::atomic::{AtomicBool, Ordering};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Timer};
+use embedded_hal::digital::OutputPin;
+
+use crate::event::idle_time;
+use crate::log::LogModule;
+use crate::log_info;
+use crate::rgb::{RgbEffect, RGB8};
+
+
+/// Raw HID command id raising an alert, `[ALERT_COMMAND, pattern, r, g, b, repeats, tone_hz (u16 LE)]`.
+/// Pattern 0 clears the alert, 0 repeats last until a key is pressed and 0 Hz is silent.
+/// Responds `[ALERT_COMMAND, status]`, status is 0 on success, 1 for an unknown pattern and 2 while muted.
+pub const ALERT_COMMAND: u8 = 0xEE;
+
+const STATUS_OK: u8 = 0;
+const STATUS_UNKNOWN_PATTERN: u8 = 1;
+const STATUS_MUTED: u8 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum AlertPattern {
+    /// 250ms on and off
+    Blink = 1,
+    /// 1s triangle wave
+    Pulse = 2,
+    /// Lit for a second per repeat
+    Solid = 3,
+}
+
+impl AlertPattern {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(Self::Blink),
+            2 => Some(Self::Pulse),
+            3 => Some(Self::Solid),
+            _ => None,
+        }
+    }
+
+    fn period(self) -> Duration {
+        match self {
+            Self::Blink => Duration::from_millis(500),
+            Self::Pulse | Self::Solid => Duration::from_secs(1),
+        }
+    }
+
+    /// Brightness 0-255 at the time into the period
+    fn level(self, phase: Duration) -> u16 {
+        let position = (phase.as_millis() * 512 / self.period().as_millis()) as u16;
+        match self {
+            Self::Blink if position < 256 => 255,
+            Self::Blink => 0,
+            Self::Pulse if position < 256 => position,
+            Self::Pulse => 511 - position.min(511),
+            Self::Solid => 255,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Alert {
+    pub pattern: AlertPattern,
+    pub color: RGB8,
+    /// Periods of the pattern, 0 until a key is pressed
+    pub repeats: u8,
+    /// Buzzer frequency, 0 for silent
+    pub tone_hz: u16,
+}
+
+static ALERT: Mutex<CriticalSectionRawMutex, Cell<Option<(Alert, Instant)>>> = Mutex::new(Cell::new(None));
+static ALERT_RAISED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+static ALERTS_MUTED: AtomicBool = AtomicBool::new(false);
+
+pub fn raise_alert(alert: Alert) {
+    log_info!(LogModule::Device, "Alert: {} x{}", alert.pattern, alert.repeats);
+    ALERT.lock(|a| a.set(Some((alert, Instant::now()))));
+    ALERT_RAISED.signal(());
+}
+
+pub fn clear_alert() {
+    ALERT.lock(|a| a.set(None));
+}
+
+pub fn alerts_muted() -> bool {
+    ALERTS_MUTED.load(Ordering::Relaxed)
+}
+
+/// Ignore the host's alerts until toggled again, e.g. through a meeting, clearing the running one
+pub fn toggle_alerts_muted() {
+    let muted = !alerts_muted();
+    ALERTS_MUTED.store(muted, Ordering::Relaxed);
+    log_info!(LogModule::Device, "Alerts {}", if muted { "muted" } else { "unmuted" });
+    if muted {
+        clear_alert();
+    }
+}
+
+/// The running alert and its brightness 0-255 now, clearing it once done or dismissed by a key press
+fn alert_level(now: Instant) -> Option<(Alert, u16)> {
+    let (alert, since) = ALERT.lock(|a| a.get())?;
+    let elapsed = now.duration_since(since);
+    let period = alert.pattern.period();
+    let done = alert.repeats != 0 && elapsed >= period * alert.repeats as u32;
+    if done || idle_time() < elapsed {
+        clear_alert();
+        return None;
+    }
+    let phase = Duration::from_ticks(elapsed.as_ticks() % period.as_ticks());
+    Some((alert, alert.pattern.level(phase)))
+}
+
+/// Answer an alert command in place.
+/// Returns false if the report isn't an alert command.
+pub fn handle_alert_command(report: &mut [u8]) -> bool {
+    if report.len() < 8 || report[0] != ALERT_COMMAND {
+        return false;
+    }
+    let status = match (report[1], AlertPattern::from_u8(report[1])) {
+        (0, _) => {
+            clear_alert();
+            STATUS_OK
+        }
+        (_, Some(_)) if alerts_muted() => STATUS_MUTED,
+        (_, Some(pattern)) => {
+            raise_alert(Alert {
+                pattern,
+                color: RGB8::new(report[2], report[3], report[4]),
+                repeats: report[5],
+                tone_hz: u16::from_le_bytes([report[6], report[7]]),
+            });
+            STATUS_OK
+        }
+        (_, None) => STATUS_UNKNOWN_PATTERN,
+    };
+    report[1..].fill(0);
+    report[1] = status;
+    true
+}
+
+
+/// Effect filling the pixels with the alert pattern while one runs, drawing nothing otherwise.
+/// Put it on top of the regular effect.
+pub struct AlertEffect;
+
+impl<const N: usize> RgbEffect<N> for AlertEffect {
+    fn render(&mut self, frame: &mut [RGB8; N], now: Instant) {
+        let Some((alert, level)) = alert_level(now) else {
+            return;
+        };
+        let scale = |c: u8| (c as u16 * level / 255) as u8;
+        frame.fill(RGB8::new(scale(alert.color.r), scale(alert.color.g), scale(alert.color.b)));
+    }
+}
+
+/// Beep a piezo buzzer on the pin while the alert pattern is on, a square wave at the tone.
+/// This function should never return.
+pub async fn run_alert_buzzer<Out: OutputPin>(mut buzzer: Out) -> ! {
+    loop {
+        ALERT_RAISED.wait().await;
+        while let Some((alert, level)) = alert_level(Instant::now()) {
+            if alert.tone_hz == 0 || level < 128 {
+                buzzer.set_low().ok();
+                Timer::after_millis(10).await;
+                continue;
+            }
+            let half_period = Duration::from_micros(500_000 / alert.tone_hz as u64);
+            buzzer.set_high().ok();
+            Timer::after(half_period).await;
+            buzzer.set_low().ok();
+            Timer::after(half_period).await;
+        }
+        buzzer.set_low().ok();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::serial;
+
+    fn alert_report(pattern: u8) -> [u8; 32] {
+        let mut report = [0u8; 32];
+        report[..8].copy_from_slice(&[ALERT_COMMAND, pattern, 255, 0, 0, 3, 0, 0]);
+        report
+    }
+
+    #[test]
+    fn muted_alerts_are_refused() {
+        let _serial = serial();
+        clear_alert();
+        toggle_alerts_muted();
+        let mut report = alert_report(AlertPattern::Blink as u8);
+        assert!(handle_alert_command(&mut report));
+        assert_eq!(report[1], STATUS_MUTED);
+        assert!(ALERT.lock(|a| a.get()).is_none());
+
+        toggle_alerts_muted();
+        let mut report = alert_report(AlertPattern::Blink as u8);
+        assert!(handle_alert_command(&mut report));
+        assert_eq!(report[1], STATUS_OK);
+        assert!(ALERT.lock(|a| a.get()).is_some());
+        clear_alert();
+    }
+
+    #[test]
+    fn muting_clears_the_running_alert() {
+        let _serial = serial();
+        let mut report = alert_report(AlertPattern::Solid as u8);
+        assert!(handle_alert_command(&mut report));
+        toggle_alerts_muted();
+        assert!(ALERT.lock(|a| a.get()).is_none());
+        toggle_alerts_muted();
+    }
+
+    #[test]
+    fn unknown_pattern_is_reported() {
+        let mut report = alert_report(9);
+        assert!(handle_alert_command(&mut report));
+        assert_eq!(report[1], STATUS_UNKNOWN_PATTERN);
+    }
+}
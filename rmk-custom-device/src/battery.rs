@@ -0,0 +1,126 @@
+//! Battery levels of both halves on battery builds, each half's from its own VSYS telemetry.
+//! The peripheral relays its level to the central over the [split transport](crate::split_transport),
+//! which reports both to the host by the [`Battery`](crate::info::InfoField::Battery) info field
+//! and blinks the LED while either half runs low.
+
+use embassy_time::{Duration, Timer};
+use embedded_hal::digital::OutputPin;
+use portable_atomic::{AtomicU8, Ordering};
+
+use crate::log::LogModule;
+use crate::log_warn;
+use crate::split_transport::{send_split_message, SplitMessage};
+use crate::telemetry::latest_telemetry;
+
+
+/// VSYS of an empty cell, at the charger's cut-off
+pub const EMPTY_MV: u16 = 3300;
+/// VSYS of a full cell
+pub const FULL_MV: u16 = 4200;
+/// Level of an unknown battery in [`battery_bytes`]
+pub const UNKNOWN_LEVEL: u8 = 0xFF;
+
+static PERIPHERAL_LEVEL: AtomicU8 = AtomicU8::new(UNKNOWN_LEVEL);
+
+/// Battery level in percent of the VSYS, linear between [`EMPTY_MV`] and [`FULL_MV`]
+pub const fn battery_percent(vsys_mv: u16) -> u8 {
+    if vsys_mv <= EMPTY_MV {
+        0
+    } else if vsys_mv >= FULL_MV {
+        100
+    } else {
+        ((vsys_mv - EMPTY_MV) as u32 * 100 / (FULL_MV - EMPTY_MV) as u32) as u8
+    }
+}
+
+/// This half's level, `None` before the first telemetry or without the VSYS sense
+pub fn local_battery() -> Option<u8> {
+    latest_telemetry()?.vsys_mv.map(battery_percent)
+}
+
+/// Peripheral's level as last relayed, `None` until the first one came
+pub fn peripheral_battery() -> Option<u8> {
+    match PERIPHERAL_LEVEL.load(Ordering::Relaxed) {
+        UNKNOWN_LEVEL => None,
+        level => Some(level),
+    }
+}
+
+/// Take the level relayed by the peripheral, on the central
+pub(crate) fn set_peripheral_battery(level: u8) {
+    PERIPHERAL_LEVEL.store(level.min(100), Ordering::Relaxed);
+}
+
+/// `[central, peripheral]` in percent, [`UNKNOWN_LEVEL`] if unknown
+pub fn battery_bytes() -> [u8; 2] {
+    [
+        local_battery().unwrap_or(UNKNOWN_LEVEL),
+        peripheral_battery().unwrap_or(UNKNOWN_LEVEL),
+    ]
+}
+
+/// Send the peripheral's level to the central, also when unchanged so that a rebooted central learns it
+/// within `interval`. Needs the telemetry task reading VSYS. This function should never return.
+pub async fn run_battery_relay(interval: Duration) -> ! {
+    loop {
+        match local_battery() {
+            Some(level) => send_split_message(SplitMessage::Battery(level)).await,
+            None => log_warn!(LogModule::Device, "No battery level to relay"),
+        }
+        Timer::after(interval).await;
+    }
+}
+
+/// Blink the LED twice every few seconds while the level of either half is below `threshold` percent.
+/// This function should never return.
+pub async fn run_low_battery_indicator<Out: OutputPin>(mut led: Out, threshold: u8) -> ! {
+    const BLINK_COUNT: usize = 2;
+    const BLINK_INTERVAL: Duration = Duration::from_millis(150);
+    const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+    loop {
+        let low = [local_battery(), peripheral_battery()]
+            .into_iter()
+            .flatten()
+            .any(|level| level < threshold);
+        if low {
+            for _ in 0..BLINK_COUNT {
+                led.set_high().ok();
+                Timer::after(BLINK_INTERVAL).await;
+                led.set_low().ok();
+                Timer::after(BLINK_INTERVAL).await;
+            }
+        }
+        Timer::after(CHECK_INTERVAL).await;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::serial;
+
+    #[test]
+    fn percent_follows_the_cell_voltage() {
+        assert_eq!(battery_percent(3000), 0);
+        assert_eq!(battery_percent(EMPTY_MV), 0);
+        assert_eq!(battery_percent(3750), 50);
+        assert_eq!(battery_percent(FULL_MV), 100);
+        assert_eq!(battery_percent(5000), 100);
+    }
+
+    #[test]
+    fn relayed_level_is_reported() {
+        let _serial = serial();
+        PERIPHERAL_LEVEL.store(UNKNOWN_LEVEL, Ordering::Relaxed);
+        assert_eq!(peripheral_battery(), None);
+        assert_eq!(battery_bytes()[1], UNKNOWN_LEVEL);
+        set_peripheral_battery(42);
+        assert_eq!(peripheral_battery(), Some(42));
+        assert_eq!(battery_bytes()[1], 42);
+        // A broken peripheral doesn't report the unknown level by accident
+        set_peripheral_battery(UNKNOWN_LEVEL);
+        assert_eq!(peripheral_battery(), Some(100));
+        PERIPHERAL_LEVEL.store(UNKNOWN_LEVEL, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,125 @@
+//! Self-benchmark of the firmware on its own hardware, reported by the `bench` shell command.
+//! The matrix scan and the flash writes are timed as they run, the debouncer and the report building
+//! are run in a loop by the command, so that regressions across releases show up on the user's board.
+
+use core::fmt::Write as _;
+use core::hint::black_box;
+use core::marker::PhantomData;
+use embassy_time::{Duration, Instant};
+use portable_atomic::{AtomicU32, Ordering};
+use rmk::{debounce::DebouncerTrait, matrix::KeyState};
+use usbd_hid::descriptor::KeyboardReport;
+
+use crate::shell::{ShellCommand, ShellReply};
+
+
+/// Loops of the benchmarks run by the command, long enough for the 1us timer
+const BENCH_ITERATIONS: u32 = 1000;
+
+/// Last and longest duration of a timed operation, in microseconds
+pub struct BenchTiming {
+    last: AtomicU32,
+    max: AtomicU32,
+}
+
+impl BenchTiming {
+    pub const fn new() -> Self {
+        Self {
+            last: AtomicU32::new(0),
+            max: AtomicU32::new(0),
+        }
+    }
+
+    pub fn record(&self, duration: Duration) {
+        let us = duration.as_micros().min(u32::MAX as u64) as u32;
+        self.last.store(us, Ordering::Relaxed);
+        self.max.fetch_max(us, Ordering::Relaxed);
+    }
+
+    fn write(&self, name: &str, reply: &mut ShellReply) {
+        match self.last.load(Ordering::Relaxed) {
+            0 => writeln!(reply, "{} not run yet", name),
+            last => writeln!(reply, "{} {}us max {}us", name, last, self.max.load(Ordering::Relaxed)),
+        }
+        .ok();
+    }
+}
+
+/// Full scans of the [`SequentialMatrix`](crate::matrix::SequentialMatrix), the idle wait excluded
+pub static SCAN_TIMING: BenchTiming = BenchTiming::new();
+
+/// Erase and write of a [reserved sector](crate::reserved)
+pub static FLASH_WRITE_TIMING: BenchTiming = BenchTiming::new();
+
+/// Nanoseconds per loop of `f`
+fn time_per_iteration(mut f: impl FnMut(u32)) -> u64 {
+    let start = Instant::now();
+    for i in 0..BENCH_ITERATIONS {
+        f(i);
+    }
+    start.elapsed().as_micros() * 1000 / BENCH_ITERATIONS as u64
+}
+
+/// Debouncing of a key toggling on every other read
+fn bench_debounce<D: DebouncerTrait, const ROW: usize, const COL: usize>() -> u64 {
+    let mut debouncer = D::new();
+    let key_state = KeyState::new();
+    time_per_iteration(|i| {
+        let (row, col) = (i as usize / COL % ROW, i as usize % COL);
+        black_box(debouncer.detect_change_with_debounce(row, col, i % 2 == 0, black_box(&key_state)));
+    })
+}
+
+/// Building a keyboard report of 6 keys and modifiers from the pressed keycodes
+fn bench_report() -> u64 {
+    time_per_iteration(|i| {
+        let keys = black_box([0x04 + (i % 8) as u8, 0x05, 0x06, 0x07, 0x08, 0x09, 0xE1]);
+        let mut report = KeyboardReport::default();
+        let mut slots = report.keycodes.iter_mut();
+        for key in keys {
+            match key {
+                0xE0..=0xE7 => report.modifier |= 1 << (key - 0xE0),
+                key => {
+                    if let Some(slot) = slots.next() {
+                        *slot = key;
+                    }
+                }
+            }
+        }
+        black_box(report);
+    })
+}
+
+
+/// Shell command running the benchmarks, with the debouncer the firmware is built with
+pub struct BenchShell<D: DebouncerTrait, const ROW: usize, const COL: usize> {
+    _debouncer: PhantomData<D>,
+}
+
+impl<D: DebouncerTrait, const ROW: usize, const COL: usize> BenchShell<D, ROW, COL> {
+    pub const fn new() -> Self {
+        Self { _debouncer: PhantomData }
+    }
+}
+
+impl<D: DebouncerTrait, const ROW: usize, const COL: usize> ShellCommand for BenchShell<D, ROW, COL> {
+    fn name(&self) -> &'static str {
+        "bench"
+    }
+
+    fn usage(&self) -> &'static str {
+        "bench"
+    }
+
+    /// Blocks the executor for a few milliseconds while the loops run
+    fn run(&self, args: &[&str], reply: &mut ShellReply) -> Result<(), &'static str> {
+        if !args.is_empty() {
+            return Err(self.usage());
+        }
+        SCAN_TIMING.write("scan", reply);
+        let _ = writeln!(reply, "debounce {}ns/key", bench_debounce::<D, ROW, COL>());
+        let _ = writeln!(reply, "report {}ns", bench_report());
+        FLASH_WRITE_TIMING.write("flash write", reply);
+        Ok(())
+    }
+}
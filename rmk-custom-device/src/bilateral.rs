@@ -0,0 +1,167 @@
+//! Bilateral combinations for home row mods: a mod-tap key is tapped instead of held
+//! when the next key is pressed by the same hand, so rolls on one hand don't fire modifiers.
+
+use embassy_time::{Duration, Instant};
+use rmk::{
+    action::{Action, KeyAction},
+    event::KeyEvent,
+};
+
+use crate::event::{HookContext, KeyEventHook};
+use crate::layer_state::active_layer;
+
+
+/// Hand pressing a key
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum Hand {
+    Left,
+    Right,
+    /// Thumb keys and the like, never cancel a hold
+    Either,
+}
+
+/// Hand of each matrix position
+#[derive(Clone, Copy, Debug)]
+pub struct HandMap<const ROW: usize, const COL: usize> {
+    pub hands: [[Hand; COL]; ROW],
+}
+
+impl<const ROW: usize, const COL: usize> HandMap<ROW, COL> {
+    pub const fn new(hands: [[Hand; COL]; ROW]) -> Self {
+        Self { hands }
+    }
+
+    pub fn hand(&self, row: u8, col: u8) -> Hand {
+        self.hands
+            .get(row as usize)
+            .and_then(|cols| cols.get(col as usize))
+            .copied()
+            .unwrap_or(Hand::Either)
+    }
+}
+
+/// Hook tapping a held mod-tap key when the next press within `window` is on the same hand.
+/// `window` should be below rmk's hold timeout, the hold is already decided after it.
+/// The release is emitted ahead of the next press.
+pub struct BilateralHook<const ROW: usize, const COL: usize, const NUM_LAYER: usize> {
+    hands: HandMap<ROW, COL>,
+    /// Whether each key is a mod-tap on each layer
+    mod_tap: [[[bool; COL]; ROW]; NUM_LAYER],
+    window: Duration,
+    /// Mod-tap key held since
+    held: Option<(u8, u8, Instant)>,
+    /// Mod-tap key already released to rmk, its physical release is consumed
+    tapped: Option<(u8, u8)>,
+}
+
+impl<const ROW: usize, const COL: usize, const NUM_LAYER: usize> BilateralHook<ROW, COL, NUM_LAYER> {
+    /// Built from the default keymap, so mod-taps added by Vial at runtime are not enforced
+    pub fn new(keymap: &[[[KeyAction; COL]; ROW]; NUM_LAYER], hands: HandMap<ROW, COL>, window: Duration) -> Self {
+        let mut mod_tap = [[[false; COL]; ROW]; NUM_LAYER];
+        for (layer, rows) in keymap.iter().enumerate() {
+            for (row, cols) in rows.iter().enumerate() {
+                for (col, action) in cols.iter().enumerate() {
+                    mod_tap[layer][row][col] = matches!(
+                        action,
+                        KeyAction::ModifierTapHold(_, _) | KeyAction::TapHold(_, Action::Modifier(_))
+                    );
+                }
+            }
+        }
+        Self {
+            hands,
+            mod_tap,
+            window,
+            held: None,
+            tapped: None,
+        }
+    }
+
+    fn is_mod_tap(&self, row: u8, col: u8) -> bool {
+        self.mod_tap
+            .get(active_layer() as usize)
+            .and_then(|rows| rows.get(row as usize))
+            .and_then(|cols| cols.get(col as usize))
+            .copied()
+            .unwrap_or(false)
+    }
+}
+
+impl<const ROW: usize, const COL: usize, const NUM_LAYER: usize> KeyEventHook for BilateralHook<ROW, COL, NUM_LAYER> {
+    async fn process(&mut self, event: KeyEvent, ctx: &mut HookContext) -> Option<KeyEvent> {
+        let position = (event.row, event.col);
+        if !event.pressed {
+            if self.tapped == Some(position) {
+                self.tapped = None;
+                return None;
+            }
+            if matches!(self.held, Some((row, col, _)) if (row, col) == position) {
+                self.held = None;
+            }
+            return Some(event);
+        }
+        if let Some((row, col, since)) = self.held.take() {
+            let hand = self.hands.hand(row, col);
+            if since.elapsed() < self.window && hand != Hand::Either && hand == self.hands.hand(event.row, event.col) {
+                // Released before rmk's hold timeout, so rmk resolves it as a tap
+                ctx.emit(KeyEvent { row, col, pressed: false });
+                self.tapped = Some((row, col));
+            }
+        }
+        if self.is_mod_tap(event.row, event.col) {
+            self.held = Some((event.row, event.col, Instant::now()));
+        }
+        Some(event)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+
+    use super::*;
+    use crate::event::{process_key_event, ChannelSink};
+    use crate::layer_state::set_active_layer;
+    use crate::test_support::serial;
+
+    /// A mod-tap and a key on the left, a key on the right
+    const KEYMAP: [[[KeyAction; 3]; 1]; 1] = [[[crate::keymap_key!(MT(A, LCtrl)), crate::keymap_key!(B), crate::keymap_key!(C)]]];
+    const HANDS: HandMap<1, 3> = HandMap::new([[Hand::Left, Hand::Left, Hand::Right]]);
+
+    fn hook() -> BilateralHook<1, 3, 1> {
+        BilateralHook::new(&KEYMAP, HANDS, Duration::from_secs(60))
+    }
+
+    /// (row, col, pressed) of the events reaching the sink for the event
+    fn run(hook: &mut BilateralHook<1, 3, 1>, col: u8, pressed: bool) -> std::vec::Vec<(u8, u8, bool)> {
+        let channel: Channel<CriticalSectionRawMutex, KeyEvent, 8> = Channel::new();
+        let event = KeyEvent { row: 0, col, pressed };
+        embassy_futures::block_on(process_key_event(hook, &mut ChannelSink::new(&channel), event));
+        core::iter::from_fn(|| channel.try_receive().ok())
+            .map(|e| (e.row, e.col, e.pressed))
+            .collect()
+    }
+
+    #[test]
+    fn same_hand_press_taps_the_mod_tap() {
+        let _serial = serial();
+        set_active_layer(0);
+        let mut hook = hook();
+        assert_eq!(run(&mut hook, 0, true), [(0, 0, true)]);
+        assert_eq!(run(&mut hook, 1, true), [(0, 0, false), (0, 1, true)]);
+        // The physical release was already sent
+        assert!(run(&mut hook, 0, false).is_empty());
+        assert_eq!(run(&mut hook, 1, false), [(0, 1, false)]);
+    }
+
+    #[test]
+    fn other_hand_press_keeps_the_hold() {
+        let _serial = serial();
+        set_active_layer(0);
+        let mut hook = hook();
+        assert_eq!(run(&mut hook, 0, true), [(0, 0, true)]);
+        assert_eq!(run(&mut hook, 2, true), [(0, 2, true)]);
+        assert_eq!(run(&mut hook, 0, false), [(0, 0, false)]);
+    }
+}
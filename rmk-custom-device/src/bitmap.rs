@@ -0,0 +1,185 @@
+//! Custom OLED bitmaps uploaded over raw HID into reserved flash sectors, e.g. a boot logo or layer icons.
+//! Each slot is a sector holding a few frames, read in place through XIP.
+
+use core::cell::RefCell;
+use embassy_rp::flash::ERASE_SIZE;
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_sync::signal::Signal;
+use embassy_time::Instant;
+
+use crate::layer_state::active_layer;
+use crate::log::LogModule;
+use crate::oled::{OledAnimation, OledFrame};
+use crate::quiesce::quiesce_flash;
+use crate::reserved::write_reserved_sector;
+use crate::{log_info, log_warn};
+
+
+/// Raw HID command id of the upload, `[BITMAP_COMMAND, subcommand, ...]`
+pub const BITMAP_COMMAND: u8 = 0xEA;
+
+/// `[BITMAP_COMMAND, BITMAP_BEGIN, slot]`
+pub const BITMAP_BEGIN: u8 = 0x00;
+/// `[BITMAP_COMMAND, BITMAP_DATA, offset (u16 LE), len, data...]`, offset into the frames
+pub const BITMAP_DATA: u8 = 0x01;
+/// `[BITMAP_COMMAND, BITMAP_COMMIT, frames]`, write the slot
+pub const BITMAP_COMMIT: u8 = 0x02;
+/// `[BITMAP_COMMAND, BITMAP_STATUS]`, responds `[BITMAP_COMMAND, status, slot]`
+pub const BITMAP_STATUS: u8 = 0x03;
+
+/// Data bytes per report
+pub const BITMAP_CHUNK_SIZE: usize = 24;
+
+const BITMAP_MAGIC: u32 = 0x5041_4D42; // "BMAP"
+const HEADER_SIZE: usize = 8;
+const FRAME_SIZE: usize = core::mem::size_of::<OledFrame>();
+
+/// Frames fitting in a slot after the header
+pub const BITMAP_MAX_FRAMES: usize = (ERASE_SIZE - HEADER_SIZE) / FRAME_SIZE;
+
+/// Flash is mapped for reads from here
+const XIP_BASE: usize = 0x1000_0000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+#[repr(u8)]
+pub enum BitmapStatus {
+    Idle = 0,
+    Receiving = 1,
+    Writing = 2,
+    Done = 3,
+    InvalidSlot = 4,
+    FlashError = 5,
+}
+
+struct BitmapUpload {
+    status: BitmapStatus,
+    slot: u8,
+    frames: u8,
+    /// The sector as it's written, header included
+    sector: [u8; ERASE_SIZE],
+}
+
+/// RAM taken by the upload buffer, for the [RAM budget](crate::ram_budget)
+pub(crate) const BITMAP_UPLOAD_RAM: usize = core::mem::size_of::<BitmapUpload>();
+
+static BITMAP_UPLOAD: Mutex<CriticalSectionRawMutex, RefCell<BitmapUpload>> = Mutex::new(RefCell::new(BitmapUpload {
+    status: BitmapStatus::Idle,
+    slot: 0,
+    frames: 0,
+    sector: [0xFF; ERASE_SIZE],
+}));
+
+static BITMAP_COMMIT_REQUEST: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Answer a bitmap command in place. Returns false if the report isn't one.
+pub fn handle_bitmap_command(report: &mut [u8]) -> bool {
+    if report.len() < 2 || report[0] != BITMAP_COMMAND {
+        return false;
+    }
+    let (status, slot) = BITMAP_UPLOAD.lock(|u| {
+        let mut u = u.borrow_mut();
+        match report[1] {
+            BITMAP_BEGIN if report.len() >= 3 && u.status != BitmapStatus::Writing => {
+                u.slot = report[2];
+                u.sector.fill(0xFF);
+                u.status = BitmapStatus::Receiving;
+            }
+            BITMAP_DATA if report.len() >= 5 && u.status == BitmapStatus::Receiving => {
+                let offset = HEADER_SIZE + u16::from_le_bytes([report[2], report[3]]) as usize;
+                let len = (report[4] as usize).min(BITMAP_CHUNK_SIZE).min(report.len() - 5);
+                if offset + len <= HEADER_SIZE + BITMAP_MAX_FRAMES * FRAME_SIZE {
+                    u.sector[offset..offset + len].copy_from_slice(&report[5..5 + len]);
+                }
+            }
+            BITMAP_COMMIT if report.len() >= 3 && u.status == BitmapStatus::Receiving => {
+                u.frames = report[2].min(BITMAP_MAX_FRAMES as u8);
+                u.status = BitmapStatus::Writing;
+                BITMAP_COMMIT_REQUEST.signal(());
+            }
+            _ => {}
+        }
+        (u.status, u.slot)
+    });
+    report[1..].fill(0);
+    report[1] = status as u8;
+    report[2] = slot;
+    true
+}
+
+
+/// Slots of the reserved flash region, a sector each
+#[derive(Clone, Copy, Debug, defmt::Format)]
+pub struct BitmapSlots {
+    /// Offset of the first slot, erase size aligned and outside of rmk's storage
+    pub offset: u32,
+    pub count: u8,
+}
+
+impl BitmapSlots {
+    pub const fn new(offset: u32, count: u8) -> Self {
+        Self { offset, count }
+    }
+
+    /// Frames of the slot, `None` if it's empty or out of the region
+    pub fn frames(&self, slot: u8) -> Option<&'static [u8]> {
+        if slot >= self.count {
+            return None;
+        }
+        let address = XIP_BASE + self.offset as usize + slot as usize * ERASE_SIZE;
+        // SAFETY: the region is reserved for the slots, and reads through XIP are always valid
+        let sector = unsafe { core::slice::from_raw_parts(address as *const u8, ERASE_SIZE) };
+        if sector[0..4] != BITMAP_MAGIC.to_le_bytes() {
+            return None;
+        }
+        let frames = (sector[4] as usize).min(BITMAP_MAX_FRAMES);
+        (frames > 0).then(|| &sector[HEADER_SIZE..HEADER_SIZE + frames * FRAME_SIZE])
+    }
+}
+
+/// Write the uploaded slots. This function should never return.
+pub async fn run_bitmap_upload<const FLASH_SIZE: usize>(slots: BitmapSlots) -> ! {
+    loop {
+        BITMAP_COMMIT_REQUEST.wait().await;
+        let (slot, sector) = BITMAP_UPLOAD.lock(|u| {
+            let mut u = u.borrow_mut();
+            let frames = u.frames;
+            u.sector[0..4].copy_from_slice(&BITMAP_MAGIC.to_le_bytes());
+            u.sector[4..8].copy_from_slice(&(frames as u32).to_le_bytes());
+            (u.slot, u.sector)
+        });
+        let status = if slot >= slots.count {
+            BitmapStatus::InvalidSlot
+        } else {
+            let offset = slots.offset + slot as u32 * ERASE_SIZE as u32;
+            match quiesce_flash(async { write_reserved_sector::<FLASH_SIZE>(offset, &sector) }).await {
+                Ok(()) => BitmapStatus::Done,
+                Err(e) => {
+                    log_warn!(LogModule::Device, "Failed to write bitmap slot {}: {}", slot, e);
+                    BitmapStatus::FlashError
+                }
+            }
+        };
+        log_info!(LogModule::Device, "Bitmap slot {}: {}", slot, status);
+        BITMAP_UPLOAD.lock(|u| u.borrow_mut().status = status);
+    }
+}
+
+
+/// Icon of the active layer, the first frame of the slot numbered as the layer
+pub struct LayerIconPage {
+    slots: BitmapSlots,
+}
+
+impl LayerIconPage {
+    pub const fn new(slots: BitmapSlots) -> Self {
+        Self { slots }
+    }
+}
+
+impl OledAnimation for LayerIconPage {
+    fn draw(&mut self, frame: &mut OledFrame, _now: Instant) {
+        if let Some(frames) = self.slots.frames(active_layer()) {
+            frame.copy_from_slice(&frames[..FRAME_SIZE]);
+        }
+    }
+}
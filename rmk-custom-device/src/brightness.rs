@@ -0,0 +1,43 @@
+//! Global LED and display brightness, stepped by a key or an encoder.
+
+use portable_atomic::{AtomicU8, Ordering};
+
+use crate::bus::{publish_device_event, DeviceEvent};
+use crate::log::LogModule;
+use crate::log_info;
+
+
+/// Brightness of each level stepped through by [`cycle_brightness`]
+const LEVELS: [u8; 5] = [16, 48, 96, 176, 255];
+
+static BRIGHTNESS: AtomicU8 = AtomicU8::new(255);
+
+/// Brightness to apply to LEDs, display contrast etc., 0-255
+pub fn brightness() -> u8 {
+    BRIGHTNESS.load(Ordering::Relaxed)
+}
+
+fn set_brightness(value: u8) {
+    if BRIGHTNESS.swap(value, Ordering::Relaxed) != value {
+        log_info!(LogModule::Device, "Brightness: {}", value);
+        publish_device_event(DeviceEvent::Brightness(value));
+    }
+}
+
+/// Scale a channel by the brightness
+pub const fn scale_brightness(value: u8, brightness: u8) -> u8 {
+    ((value as u16 * (brightness as u16 + 1)) >> 8) as u8
+}
+
+/// Step to the next level, back to the dimmest after the brightest
+pub fn cycle_brightness() {
+    let current = brightness();
+    let next = LEVELS.iter().copied().find(|level| *level > current).unwrap_or(LEVELS[0]);
+    set_brightness(next);
+}
+
+/// Change by a step, e.g. an encoder detent
+pub fn step_brightness(step: i16) {
+    let current = brightness() as i16;
+    set_brightness((current + step).clamp(0, 255) as u8);
+}
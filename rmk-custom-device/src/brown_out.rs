@@ -0,0 +1,61 @@
+//! Brown-out handling for weak USB ports sagging under the lighting load.
+//!
+//! RP2040's brown-out detector only resets the chip once the core voltage is already too low, so the supply
+//! is also watched through the VSYS reading of the [telemetry](crate::telemetry). Below the threshold the keys
+//! are released, the lighting blanks to shed the load, and flash operations wait for the supply to recover
+//! instead of risking a torn write.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use embassy_time::{Duration, Timer};
+
+use crate::log::LogModule;
+use crate::telemetry::latest_telemetry;
+use crate::typing::release_keys;
+use crate::{log_info, log_warn};
+
+
+const SUPPLY_POLL: Duration = Duration::from_millis(10);
+
+static LOW_VOLTAGE: AtomicBool = AtomicBool::new(false);
+
+/// Whether the supply is below the guard's threshold, the lighting stays dark and flash operations wait while set
+pub fn is_low_voltage() -> bool {
+    LOW_VOLTAGE.load(Ordering::Relaxed)
+}
+
+/// Wait until the supply is back above the guard's recovery level, called before flash operations
+pub async fn wait_for_supply() {
+    while is_low_voltage() {
+        Timer::after(SUPPLY_POLL).await;
+    }
+}
+
+/// Enable RP2040's brown-out reset at the core voltage threshold, 473-1118mV in steps of about 43mV.
+/// The reset default is 860mV.
+#[cfg(feature = "rp2040")]
+pub fn configure_brown_out(threshold_mv: u16) {
+    let vsel = (threshold_mv.saturating_sub(473) / 43).min(15) as u8;
+    embassy_rp::pac::VREG_AND_CHIP_RESET.bod().write(|w| {
+        w.set_en(true);
+        w.set_vsel(vsel);
+    });
+}
+
+/// Watch VSYS, entering the low voltage state below `threshold_mv` and leaving it above `recover_mv`.
+/// Needs the telemetry task reading VSYS. This function should never return.
+pub async fn run_low_voltage_guard(threshold_mv: u16, recover_mv: u16, interval: Duration) -> ! {
+    loop {
+        Timer::after(interval).await;
+        let Some(vsys_mv) = latest_telemetry().and_then(|t| t.vsys_mv) else {
+            continue;
+        };
+        if !is_low_voltage() && vsys_mv < threshold_mv {
+            log_warn!(LogModule::Device, "Low voltage: {} mV", vsys_mv);
+            LOW_VOLTAGE.store(true, Ordering::Relaxed);
+            release_keys().await;
+        } else if is_low_voltage() && vsys_mv >= recover_mv {
+            log_info!(LogModule::Device, "Voltage recovered: {} mV", vsys_mv);
+            LOW_VOLTAGE.store(false, Ordering::Relaxed);
+        }
+    }
+}
@@ -0,0 +1,40 @@
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, pubsub::PubSubChannel};
+
+use crate::charger::ChargeState;
+use crate::usb_power::UsbPowerState;
+
+
+/// Events published by the firmware-side devices
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum DeviceEvent {
+    ChargeState(ChargeState),
+    /// Global brightness changed, 0-255
+    Brightness(u8),
+    /// USB VBUS appeared or disappeared
+    UsbConnection(bool),
+    /// USB power state changed, e.g. a charger turned out not to be a host
+    UsbPower(UsbPowerState),
+    /// A key was force-released by the stuck key watchdog, (row, col)
+    StuckKey(u8, u8),
+    /// Play the waveform, for the haptics driver
+    Haptic(u8),
+    /// The stored config is about to be wiped, followed by a reboot
+    ConfigReset,
+    /// A typing game finished, the average reaction time in ms
+    TypingGameFinished(u16),
+    /// Host lock LEDs shown changed, the bits of the LED output report
+    LockLeds(u8),
+    /// The host went to sleep or woke up, and the sleep profile was applied or restored
+    HostSleep(bool),
+    /// Highest active layer changed, as [`LayerTrackerHook`](crate::layer_state::LayerTrackerHook) follows it
+    LayerChange(u8),
+}
+
+/// Event bus of [`DeviceEvent`], subscribe to react on device state changes
+pub static DEVICE_EVENT_BUS: PubSubChannel<CriticalSectionRawMutex, DeviceEvent, 4, 4, 2> =
+    PubSubChannel::new();
+
+/// Publish without waiting, the oldest event is dropped for lagging subscribers
+pub fn publish_device_event(event: DeviceEvent) {
+    DEVICE_EVENT_BUS.immediate_publisher().publish_immediate(event);
+}
@@ -0,0 +1,153 @@
+use embassy_time::{Duration, Timer};
+use embedded_hal::digital::{InputPin, OutputPin};
+use embedded_hal::i2c::I2c;
+
+use crate::bus::{publish_device_event, DeviceEvent, DEVICE_EVENT_BUS};
+use crate::log::LogModule;
+use crate::log_info;
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum ChargeState {
+    Discharging,
+    Charging,
+    Full,
+}
+
+/// Source of the charge state, either status pins or a fuel gauge
+#[allow(async_fn_in_trait)]
+pub trait ChargeStateSource {
+    /// Returns `None` if the state couldn't be read
+    async fn charge_state(&mut self) -> Option<ChargeState>;
+}
+
+
+/// Open-drain status outputs of linear chargers like TP4056, active low.
+/// `standby_not` is optional since some chargers (e.g. MCP73831) only have one status pin.
+pub struct ChargerStatusPins<In: InputPin> {
+    charging_not: In,
+    standby_not: Option<In>,
+}
+
+impl<In: InputPin> ChargerStatusPins<In> {
+    pub fn new(charging_not: In, standby_not: Option<In>) -> Self {
+        Self {
+            charging_not,
+            standby_not,
+        }
+    }
+}
+
+impl<In: InputPin> ChargeStateSource for ChargerStatusPins<In> {
+    async fn charge_state(&mut self) -> Option<ChargeState> {
+        if self.charging_not.is_low().ok()? {
+            return Some(ChargeState::Charging);
+        }
+        match self.standby_not.as_mut() {
+            Some(standby_not) if standby_not.is_low().ok()? => Some(ChargeState::Full),
+            _ => Some(ChargeState::Discharging),
+        }
+    }
+}
+
+
+/// MAX17048 fuel gauge over I2C
+pub struct Max17048<I: I2c> {
+    i2c: I,
+}
+
+impl<I: I2c> Max17048<I> {
+    const ADDRESS: u8 = 0x36;
+    const REG_VCELL: u8 = 0x02;
+    const REG_SOC: u8 = 0x04;
+    const REG_CRATE: u8 = 0x16;
+
+    pub fn new(i2c: I) -> Self {
+        Self { i2c }
+    }
+
+    fn read_register(&mut self, register: u8) -> Result<u16, I::Error> {
+        let mut buf = [0u8; 2];
+        self.i2c.write_read(Self::ADDRESS, &[register], &mut buf)?;
+        Ok(u16::from_be_bytes(buf))
+    }
+
+    /// Cell voltage in mV, the register is 78.125uV per LSB
+    pub fn voltage_mv(&mut self) -> Result<u16, I::Error> {
+        Ok((self.read_register(Self::REG_VCELL)? as u32 * 5 / 64) as u16)
+    }
+
+    /// State of charge in percent
+    pub fn state_of_charge(&mut self) -> Result<u8, I::Error> {
+        Ok((self.read_register(Self::REG_SOC)? >> 8).min(100) as u8)
+    }
+
+    /// Charge rate in 0.208%/hr, positive while charging
+    pub fn charge_rate(&mut self) -> Result<i16, I::Error> {
+        Ok(self.read_register(Self::REG_CRATE)? as i16)
+    }
+}
+
+impl<I: I2c> ChargeStateSource for Max17048<I> {
+    async fn charge_state(&mut self) -> Option<ChargeState> {
+        let soc = self.state_of_charge().ok()?;
+        let rate = self.charge_rate().ok()?;
+        if rate > 0 {
+            Some(ChargeState::Charging)
+        } else if soc >= 100 {
+            Some(ChargeState::Full)
+        } else {
+            Some(ChargeState::Discharging)
+        }
+    }
+}
+
+
+/// Poll the charge state and publish changes to the device event bus. This function should never return.
+pub async fn run_charger_monitor<S: ChargeStateSource>(mut source: S, interval: Duration) -> ! {
+    let mut last_state = None;
+    loop {
+        let state = source.charge_state().await;
+        if state.is_some() && state != last_state {
+            log_info!(LogModule::Device, "Charge state: {}", state);
+            last_state = state;
+            if let Some(state) = state {
+                publish_device_event(DeviceEvent::ChargeState(state));
+            }
+        }
+        Timer::after(interval).await;
+    }
+}
+
+/// Drive an indicator LED by the charge state: blinking while charging, lit when full.
+/// This function should never return.
+pub async fn run_charge_indicator<Out: OutputPin>(mut led: Out) -> ! {
+    const BLINK_INTERVAL: Duration = Duration::from_millis(500);
+    let Ok(mut subscriber) = DEVICE_EVENT_BUS.subscriber() else {
+        defmt::panic!("No subscriber slot left on the device event bus");
+    };
+    let mut state = ChargeState::Discharging;
+    loop {
+        let event = match state {
+            ChargeState::Charging => {
+                led.set_high().ok();
+                Timer::after(BLINK_INTERVAL).await;
+                led.set_low().ok();
+                Timer::after(BLINK_INTERVAL).await;
+                // Pick up the new state without stopping the blink
+                subscriber.try_next_message_pure()
+            }
+            ChargeState::Full => {
+                led.set_high().ok();
+                Some(subscriber.next_message_pure().await)
+            }
+            ChargeState::Discharging => {
+                led.set_low().ok();
+                Some(subscriber.next_message_pure().await)
+            }
+        };
+        if let Some(DeviceEvent::ChargeState(new_state)) = event {
+            state = new_state;
+        }
+    }
+}
@@ -0,0 +1,325 @@
+//! Wall clock kept by the RP2040 RTC or an external DS3231, set from the host over raw HID.
+
+use core::cell::Cell;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicI16, Ordering};
+#[cfg(feature = "rp2040")]
+use embassy_rp::rtc::{DayOfWeek, Instance, Rtc};
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    signal::Signal,
+};
+use embassy_time::{Duration, Timer};
+use embedded_hal::i2c::I2c;
+use heapless::String;
+
+use crate::log::LogModule;
+use crate::{log_info, log_warn};
+
+
+/// Raw HID command id setting the clock,
+/// `[CLOCK_COMMAND, year (u16 LE), month, day, hour, minute, second, utc_offset_minutes (i16 LE)]`.
+/// The time is local, the offset is only needed for [`current_unix_time`] and 0 if the host leaves it out.
+pub const CLOCK_COMMAND: u8 = 0xE5;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct DateTime {
+    pub year: u16,
+    /// 1-12
+    pub month: u8,
+    /// 1 to the length of the month
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    fn is_leap_year(year: u16) -> bool {
+        year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+    }
+
+    /// Days of the month, 0 for an invalid month
+    fn days_in_month(year: u16, month: u8) -> u8 {
+        match month {
+            1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+            4 | 6 | 9 | 11 => 30,
+            2 if Self::is_leap_year(year) => 29,
+            2 => 28,
+            _ => 0,
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        (2000..2100).contains(&self.year)
+            && (1..=Self::days_in_month(self.year, self.month)).contains(&self.day)
+            && self.hour < 24
+            && self.minute < 60
+            && self.second < 60
+    }
+
+    /// 0 for Sunday
+    pub fn day_of_week(&self) -> u8 {
+        // Sakamoto's method
+        const T: [u16; 12] = [0, 3, 2, 5, 0, 3, 5, 1, 4, 6, 2, 4];
+        let y = if self.month < 3 { self.year - 1 } else { self.year };
+        ((y + y / 4 - y / 100 + y / 400 + T[self.month as usize - 1] + self.day as u16) % 7) as u8
+    }
+
+    /// Seconds since 1970-01-01 00:00:00, taking the time as UTC
+    pub fn unix_time(&self) -> u64 {
+        // Days from civil, with the years starting in March
+        let (year, month) = if self.month < 3 {
+            (self.year as u64 - 1, self.month as u64 + 9)
+        } else {
+            (self.year as u64, self.month as u64 - 3)
+        };
+        let days_of_year = (153 * month + 2) / 5 + self.day as u64 - 1;
+        let days_of_era = year * 365 + year / 4 - year / 100 + year / 400 + days_of_year;
+        // 719468 days from 0000-03-01 to 1970-01-01
+        let days = days_of_era - 719_468;
+        days * 86_400 + self.hour as u64 * 3_600 + self.minute as u64 * 60 + self.second as u64
+    }
+
+    /// e.g. `2024-11-03 12:34:56`
+    pub fn timestamp_string(&self) -> String<20> {
+        let mut s = String::new();
+        let _ = write!(
+            s,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        );
+        s
+    }
+
+    /// e.g. `12:34`, for small displays
+    pub fn time_string(&self) -> String<8> {
+        let mut s = String::new();
+        let _ = write!(s, "{:02}:{:02}", self.hour, self.minute);
+        s
+    }
+}
+
+
+/// Clock hardware
+pub trait RtcSource {
+    /// Returns `None` if the clock isn't set or couldn't be read
+    fn now(&mut self) -> Option<DateTime>;
+    /// Returns false on failure
+    fn set(&mut self, time: DateTime) -> bool;
+}
+
+/// DS3231 over I2C. The century bit is ignored, years are 2000-2099.
+pub struct Ds3231<I: I2c> {
+    i2c: I,
+}
+
+impl<I: I2c> Ds3231<I> {
+    const ADDRESS: u8 = 0x68;
+    const REG_SECONDS: u8 = 0x00;
+
+    pub fn new(i2c: I) -> Self {
+        Self { i2c }
+    }
+
+    const fn from_bcd(value: u8) -> u8 {
+        (value >> 4) * 10 + (value & 0x0F)
+    }
+
+    const fn to_bcd(value: u8) -> u8 {
+        ((value / 10) << 4) | (value % 10)
+    }
+}
+
+impl<I: I2c> RtcSource for Ds3231<I> {
+    fn now(&mut self) -> Option<DateTime> {
+        let mut regs = [0u8; 7];
+        self.i2c
+            .write_read(Self::ADDRESS, &[Self::REG_SECONDS], &mut regs)
+            .ok()?;
+        let time = DateTime {
+            year: 2000 + Self::from_bcd(regs[6]) as u16,
+            month: Self::from_bcd(regs[5] & 0x1F),
+            day: Self::from_bcd(regs[4] & 0x3F),
+            // 24 hour mode
+            hour: Self::from_bcd(regs[2] & 0x3F),
+            minute: Self::from_bcd(regs[1] & 0x7F),
+            second: Self::from_bcd(regs[0] & 0x7F),
+        };
+        time.is_valid().then_some(time)
+    }
+
+    fn set(&mut self, time: DateTime) -> bool {
+        let regs = [
+            Self::REG_SECONDS,
+            Self::to_bcd(time.second),
+            Self::to_bcd(time.minute),
+            Self::to_bcd(time.hour),
+            time.day_of_week() + 1,
+            Self::to_bcd(time.day),
+            Self::to_bcd(time.month),
+            Self::to_bcd((time.year - 2000) as u8),
+        ];
+        self.i2c.write(Self::ADDRESS, &regs).is_ok()
+    }
+}
+
+/// RP2040 internal RTC, lost on power cycle
+#[cfg(feature = "rp2040")]
+pub struct Rp2040Rtc<'d, T: Instance> {
+    rtc: Rtc<'d, T>,
+}
+
+#[cfg(feature = "rp2040")]
+impl<'d, T: Instance> Rp2040Rtc<'d, T> {
+    pub fn new(rtc: Rtc<'d, T>) -> Self {
+        Self { rtc }
+    }
+}
+
+#[cfg(feature = "rp2040")]
+impl<T: Instance> RtcSource for Rp2040Rtc<'_, T> {
+    fn now(&mut self) -> Option<DateTime> {
+        let now = self.rtc.now().ok()?;
+        Some(DateTime {
+            year: now.year,
+            month: now.month,
+            day: now.day,
+            hour: now.hour,
+            minute: now.minute,
+            second: now.second,
+        })
+    }
+
+    fn set(&mut self, time: DateTime) -> bool {
+        let day_of_week = match time.day_of_week() {
+            0 => DayOfWeek::Sunday,
+            1 => DayOfWeek::Monday,
+            2 => DayOfWeek::Tuesday,
+            3 => DayOfWeek::Wednesday,
+            4 => DayOfWeek::Thursday,
+            5 => DayOfWeek::Friday,
+            _ => DayOfWeek::Saturday,
+        };
+        self.rtc
+            .set_datetime(embassy_rp::rtc::DateTime {
+                year: time.year,
+                month: time.month,
+                day: time.day,
+                day_of_week,
+                hour: time.hour,
+                minute: time.minute,
+                second: time.second,
+            })
+            .is_ok()
+    }
+}
+
+
+static CURRENT_TIME: Mutex<CriticalSectionRawMutex, Cell<Option<DateTime>>> = Mutex::new(Cell::new(None));
+
+static CLOCK_SET: Signal<CriticalSectionRawMutex, DateTime> = Signal::new();
+/// Minutes the local time is ahead of UTC, as of the last clock command
+static UTC_OFFSET_MINUTES: AtomicI16 = AtomicI16::new(0);
+
+/// Time as of the last clock poll, `None` until set
+pub fn current_time() -> Option<DateTime> {
+    CURRENT_TIME.lock(|t| t.get())
+}
+
+/// UTC seconds since 1970, `None` until set
+pub fn current_unix_time() -> Option<u64> {
+    let local = current_time()?.unix_time() as i64;
+    let offset = UTC_OFFSET_MINUTES.load(Ordering::Relaxed) as i64 * 60;
+    u64::try_from(local - offset).ok()
+}
+
+/// Answer a clock command in place.
+/// Response: `[CLOCK_COMMAND, status]`, status is 0 on success and 1 if the time is invalid.
+/// Returns false if the report isn't a clock command.
+pub fn handle_clock_command(report: &mut [u8]) -> bool {
+    if report.len() < 8 || report[0] != CLOCK_COMMAND {
+        return false;
+    }
+    let time = DateTime {
+        year: u16::from_le_bytes([report[1], report[2]]),
+        month: report[3],
+        day: report[4],
+        hour: report[5],
+        minute: report[6],
+        second: report[7],
+    };
+    let valid = time.is_valid();
+    if valid {
+        let utc_offset = report.get(8..10).map_or(0, |b| i16::from_le_bytes([b[0], b[1]]));
+        UTC_OFFSET_MINUTES.store(utc_offset, Ordering::Relaxed);
+        CLOCK_SET.signal(time);
+    }
+    report[1..].fill(0);
+    report[1] = if valid { 0 } else { 1 };
+    true
+}
+
+/// Poll the clock every second and apply the time set by the host. This function should never return.
+pub async fn run_clock<R: RtcSource>(mut rtc: R) -> ! {
+    loop {
+        if let Some(time) = CLOCK_SET.try_take() {
+            log_info!(LogModule::Device, "Clock set: {}", time);
+            if !rtc.set(time) {
+                log_warn!(LogModule::Device, "Failed to set the clock");
+            }
+        }
+        let now = rtc.now();
+        CURRENT_TIME.lock(|t| t.set(now));
+        Timer::after(Duration::from_secs(1)).await;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(year: u16, month: u8, day: u8) -> DateTime {
+        DateTime {
+            year,
+            month,
+            day,
+            hour: 0,
+            minute: 0,
+            second: 0,
+        }
+    }
+
+    #[test]
+    fn leap_days() {
+        assert!(date(2024, 2, 29).is_valid());
+        assert!(date(2000, 2, 29).is_valid());
+        assert!(!date(2023, 2, 29).is_valid());
+        assert!(date(2023, 2, 28).is_valid());
+    }
+
+    #[test]
+    fn rejects_out_of_range() {
+        assert!(!date(2024, 4, 31).is_valid());
+        assert!(!date(2024, 1, 0).is_valid());
+        assert!(!date(2024, 0, 1).is_valid());
+        assert!(!date(2024, 13, 1).is_valid());
+        assert!(!date(2100, 2, 28).is_valid());
+        assert!(!DateTime { hour: 24, ..date(2024, 1, 1) }.is_valid());
+    }
+
+    #[test]
+    fn unix_time_and_day_of_week() {
+        let time = DateTime {
+            hour: 12,
+            minute: 34,
+            second: 56,
+            ..date(2024, 11, 3)
+        };
+        assert_eq!(time.unix_time(), 1_730_637_296);
+        assert_eq!(time.day_of_week(), 0);
+        assert_eq!(date(2000, 1, 1).unix_time(), 946_684_800);
+        assert_eq!(time.timestamp_string().as_str(), "2024-11-03 12:34:56");
+    }
+}
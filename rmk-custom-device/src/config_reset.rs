@@ -0,0 +1,69 @@
+//! Config reset from the keyboard itself, for recovering from a broken Vial layout.
+//! Holding the key for [`CONFIG_RESET_HOLD`] wipes the stored keymap and settings, then reboots into the compiled defaults.
+
+use embassy_futures::select::{select, Either};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Duration, Timer};
+use embedded_hal::digital::OutputPin;
+
+use crate::bus::{publish_device_event, DeviceEvent, DEVICE_EVENT_BUS};
+use crate::log::LogModule;
+use crate::log_info;
+use crate::quiesce::quiesce_flash;
+use crate::reboot::request_system_reset;
+
+
+/// Hold time of the reset key, long enough to never fire by accident
+pub const CONFIG_RESET_HOLD: Duration = Duration::from_secs(3);
+/// Time for the confirmation to be seen before the wipe and the reboot
+const CONFIRM_DURATION: Duration = Duration::from_secs(1);
+const BLINK_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Whether the reset key is held
+static CONFIG_RESET_KEY: Signal<CriticalSectionRawMutex, bool> = Signal::new();
+
+pub fn press_config_reset() {
+    CONFIG_RESET_KEY.signal(true);
+}
+
+pub fn release_config_reset() {
+    CONFIG_RESET_KEY.signal(false);
+}
+
+async fn wait_key(pressed: bool) {
+    while CONFIG_RESET_KEY.wait().await != pressed {}
+}
+
+/// Wait for the reset key to be held, then publish [`DeviceEvent::ConfigReset`], call `wipe` with the matrix parked
+/// and reboot by the system reset task. `wipe` erases rmk's storage and the firmware-side settings, e.g. by
+/// [`erase_sectors`](crate::reserved::erase_sectors). This function should never return.
+pub async fn run_config_reset<F: FnMut()>(mut wipe: F) -> ! {
+    loop {
+        wait_key(true).await;
+        if let Either::Second(_) = select(Timer::after(CONFIG_RESET_HOLD), wait_key(false)).await {
+            continue;
+        }
+        log_info!(LogModule::Device, "Config reset, rebooting into the defaults");
+        publish_device_event(DeviceEvent::ConfigReset);
+        Timer::after(CONFIRM_DURATION).await;
+        quiesce_flash(async { wipe() }).await;
+        request_system_reset();
+    }
+}
+
+/// Blink the LED rapidly on config reset, until the reboot. This function should never return.
+pub async fn run_config_reset_indicator<Out: OutputPin>(mut led: Out) -> ! {
+    let Ok(mut subscriber) = DEVICE_EVENT_BUS.subscriber() else {
+        defmt::panic!("No subscriber slot left on the device event bus");
+    };
+    loop {
+        if let DeviceEvent::ConfigReset = subscriber.next_message_pure().await {
+            loop {
+                led.set_high().ok();
+                Timer::after(BLINK_INTERVAL).await;
+                led.set_low().ok();
+                Timer::after(BLINK_INTERVAL).await;
+            }
+        }
+    }
+}
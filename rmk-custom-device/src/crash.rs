@@ -0,0 +1,267 @@
+//! Crash log persisted to a reserved flash page by the panic and HardFault handlers,
+//! and read back after reboot for the raw HID crash command.
+//!
+//! A HardFault records the faulting PC and LR from its exception frame. A panic has no faulting instruction,
+//! its location is in the message and the return addresses of its callers in the stack snapshot.
+
+use core::cell::Cell;
+use core::fmt::Write;
+use embassy_rp::flash::{Flash, Instance, Mode};
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    signal::Signal,
+};
+
+use crate::quiesce::quiesce_flash;
+use crate::reserved::erase_sectors;
+
+
+/// Raw HID command id of the crash log, `[CRASH_COMMAND, op, ...]`
+pub const CRASH_COMMAND: u8 = 0xE3;
+/// `[CRASH_COMMAND, CRASH_READ, offset]`
+pub const CRASH_READ: u8 = 0x00;
+/// `[CRASH_COMMAND, CRASH_CLEAR]`, erase the record so that the next crash isn't mistaken for an old one
+pub const CRASH_CLEAR: u8 = 0x01;
+
+/// Size of the crash record, a flash page
+pub const CRASH_RECORD_SIZE: usize = 256;
+
+const CRASH_MAGIC: u32 = 0x4853_5243; // "CRSH"
+const STACK_SNAPSHOT_WORDS: usize = 16;
+const STACK_START: usize = 20;
+const HEADER_SIZE: usize = STACK_START + STACK_SNAPSHOT_WORDS * 4;
+
+/// What crashed, in the record
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+#[repr(u16)]
+pub enum CrashKind {
+    Panic = 0,
+    HardFault = 1,
+}
+
+/// Crash record layout, little endian:
+/// `[magic, pc, lr, sp, message_len (u16), kind (u16), stack (16 words), message...]`.
+/// `pc` and `lr` are 0 for a panic.
+#[derive(Clone, Copy)]
+pub struct CrashRecord {
+    bytes: [u8; CRASH_RECORD_SIZE],
+}
+
+impl CrashRecord {
+    pub fn new(
+        kind: CrashKind,
+        message: &dyn core::fmt::Display,
+        pc: u32,
+        lr: u32,
+        sp: u32,
+        stack: &[u32; STACK_SNAPSHOT_WORDS],
+    ) -> Self {
+        let mut bytes = [0xFF; CRASH_RECORD_SIZE];
+        let mut writer = SliceWriter {
+            buf: &mut bytes[HEADER_SIZE..],
+            len: 0,
+        };
+        let _ = write!(writer, "{}", message);
+        let message_len = writer.len as u16;
+        bytes[0..4].copy_from_slice(&CRASH_MAGIC.to_le_bytes());
+        bytes[4..8].copy_from_slice(&pc.to_le_bytes());
+        bytes[8..12].copy_from_slice(&lr.to_le_bytes());
+        bytes[12..16].copy_from_slice(&sp.to_le_bytes());
+        bytes[16..18].copy_from_slice(&message_len.to_le_bytes());
+        bytes[18..20].copy_from_slice(&(kind as u16).to_le_bytes());
+        for (i, word) in stack.iter().enumerate() {
+            bytes[STACK_START + i * 4..STACK_START + 4 + i * 4].copy_from_slice(&word.to_le_bytes());
+        }
+        Self { bytes }
+    }
+
+    /// Returns `None` if the page doesn't hold a crash record, e.g. erased
+    pub fn from_bytes(bytes: [u8; CRASH_RECORD_SIZE]) -> Option<Self> {
+        let magic = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        (magic == CRASH_MAGIC).then_some(Self { bytes })
+    }
+
+    pub fn as_bytes(&self) -> &[u8; CRASH_RECORD_SIZE] {
+        &self.bytes
+    }
+
+    pub fn kind(&self) -> CrashKind {
+        match u16::from_le_bytes([self.bytes[18], self.bytes[19]]) {
+            1 => CrashKind::HardFault,
+            _ => CrashKind::Panic,
+        }
+    }
+
+    /// Faulting instruction of a HardFault, 0 for a panic
+    pub fn pc(&self) -> u32 {
+        u32::from_le_bytes([self.bytes[4], self.bytes[5], self.bytes[6], self.bytes[7]])
+    }
+
+    pub fn message(&self) -> &str {
+        let len = u16::from_le_bytes([self.bytes[16], self.bytes[17]]) as usize;
+        let end = (HEADER_SIZE + len).min(CRASH_RECORD_SIZE);
+        core::str::from_utf8(&self.bytes[HEADER_SIZE..end]).unwrap_or("<invalid utf8>")
+    }
+}
+
+/// `fmt::Write` into a fixed buffer, truncating the overflow
+struct SliceWriter<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl Write for SliceWriter<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let n = s.len().min(self.buf.len() - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+
+static LAST_CRASH: Mutex<CriticalSectionRawMutex, Cell<Option<CrashRecord>>> = Mutex::new(Cell::new(None));
+/// Set by [`CRASH_CLEAR`], taken by [`run_crash_log`]
+static CLEAR_REQUEST: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Read the crash record left by the previous boot. Call it before handing the flash to rmk.
+pub fn load_crash_record<T: Instance, M: Mode, const FLASH_SIZE: usize>(
+    flash: &mut Flash<'_, T, M, FLASH_SIZE>,
+    offset: u32,
+) -> Option<CrashRecord> {
+    let mut bytes = [0u8; CRASH_RECORD_SIZE];
+    flash.blocking_read(offset, &mut bytes).ok()?;
+    let record = CrashRecord::from_bytes(bytes)?;
+    defmt::warn!("Previous boot crashed, {} at {=u32:#010x}: {=str}", record.kind(), record.pc(), record.message());
+    LAST_CRASH.lock(|c| c.set(Some(record)));
+    Some(record)
+}
+
+/// Answer a crash command in place.
+/// Response to a read: `[CRASH_COMMAND, valid, data...]`, record bytes from `offset`.
+/// Response to a clear: `[CRASH_COMMAND, 0]`, the record is erased by [`run_crash_log`].
+/// Returns false if the report isn't a crash command.
+pub fn handle_crash_command(report: &mut [u8]) -> bool {
+    if report.len() < 3 || report[0] != CRASH_COMMAND {
+        return false;
+    }
+    let (op, offset) = (report[1], report[2] as usize);
+    report[1..].fill(0);
+    if op == CRASH_CLEAR {
+        LAST_CRASH.lock(|c| c.set(None));
+        CLEAR_REQUEST.signal(());
+        return true;
+    }
+    let record = LAST_CRASH.lock(|c| c.get());
+    if let Some(record) = record {
+        report[1] = 1;
+        let page = &record.as_bytes()[offset..];
+        let len = page.len().min(report.len() - 2);
+        report[2..2 + len].copy_from_slice(&page[..len]);
+    }
+    true
+}
+
+/// Erase the crash record on [`CRASH_CLEAR`]. `offset` is the page of the handlers. This function should never return.
+pub async fn run_crash_log<const FLASH_SIZE: usize>(offset: u32) -> ! {
+    loop {
+        CLEAR_REQUEST.wait().await;
+        if let Err(e) = quiesce_flash(async { erase_sectors::<FLASH_SIZE>(offset, 1) }).await {
+            defmt::warn!("Failed to clear the crash log: {}", e);
+        }
+    }
+}
+
+/// Write the record of a panic and reset. Only for the panic handler.
+#[doc(hidden)]
+pub fn persist_crash_and_reset<const FLASH_SIZE: usize>(info: &core::panic::PanicInfo, offset: u32) -> ! {
+    cortex_m::interrupt::disable();
+    let sp: u32;
+    // SAFETY: reads the register only
+    unsafe {
+        core::arch::asm!("mov {}, sp", out(reg) sp);
+    }
+    persist_and_reset::<FLASH_SIZE>(CrashKind::Panic, info, 0, 0, sp, offset)
+}
+
+/// Write the record of a HardFault from its exception frame and reset. Only for the HardFault handler.
+/// `sp` is the stack pointer at the fault, right above the frame.
+#[doc(hidden)]
+pub fn persist_fault_and_reset<const FLASH_SIZE: usize>(pc: u32, lr: u32, sp: u32, offset: u32) -> ! {
+    cortex_m::interrupt::disable();
+    persist_and_reset::<FLASH_SIZE>(CrashKind::HardFault, &"HardFault", pc, lr, sp, offset)
+}
+
+fn persist_and_reset<const FLASH_SIZE: usize>(
+    kind: CrashKind,
+    message: &dyn core::fmt::Display,
+    pc: u32,
+    lr: u32,
+    sp: u32,
+    offset: u32,
+) -> ! {
+    use embassy_rp::flash::{Blocking, ERASE_SIZE};
+
+    let mut stack = [0u32; STACK_SNAPSHOT_WORDS];
+    for (i, word) in stack.iter_mut().enumerate() {
+        // SAFETY: above sp is the used stack, or .data/.bss with flip-link
+        *word = unsafe { core::ptr::read_volatile((sp as *const u32).add(i)) };
+    }
+    let record = CrashRecord::new(kind, message, pc, lr, sp, &stack);
+
+    // SAFETY: nothing else runs anymore with the interrupts disabled
+    let flash_peripheral = unsafe { embassy_rp::peripherals::FLASH::steal() };
+    let mut flash = Flash::<_, Blocking, FLASH_SIZE>::new_blocking(flash_peripheral);
+    let _ = flash.blocking_erase(offset, offset + ERASE_SIZE as u32);
+    let _ = flash.blocking_write(offset, record.as_bytes());
+
+    cortex_m::peripheral::SCB::sys_reset();
+}
+
+/// Define the panic and HardFault handlers persisting the crash log at `offset`, which must be erase size aligned
+/// and outside of rmk's storage. Replaces `panic_probe`, the firmware needs `cortex-m-rt`.
+#[macro_export]
+macro_rules! crash_log_panic_handler {
+    (flash_size: $flash_size:expr, offset: $offset:expr $(,)?) => {
+        #[panic_handler]
+        fn panic(info: &core::panic::PanicInfo) -> ! {
+            $crate::crash::persist_crash_and_reset::<{ $flash_size }>(info, $offset)
+        }
+
+        #[cortex_m_rt::exception]
+        unsafe fn HardFault(frame: &cortex_m_rt::ExceptionFrame) -> ! {
+            // The 8 words of the frame were pushed below the faulting code's stack
+            let sp = frame as *const cortex_m_rt::ExceptionFrame as u32 + 32;
+            $crate::crash::persist_fault_and_reset::<{ $flash_size }>(frame.pc(), frame.lr(), sp, $offset)
+        }
+    };
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_roundtrip() {
+        let stack = [0x2000_1000; STACK_SNAPSHOT_WORDS];
+        let record = CrashRecord::new(CrashKind::HardFault, &"bus fault", 0x1000_0abc, 0x1000_0def, 0x2003_ff00, &stack);
+        let read = CrashRecord::from_bytes(*record.as_bytes()).expect("a crash record");
+        assert!(read.kind() == CrashKind::HardFault);
+        assert_eq!(read.pc(), 0x1000_0abc);
+        assert_eq!(read.message(), "bus fault");
+    }
+
+    #[test]
+    fn erased_page_is_no_record() {
+        assert!(CrashRecord::from_bytes([0xFF; CRASH_RECORD_SIZE]).is_none());
+    }
+
+    #[test]
+    fn long_message_is_truncated() {
+        let message = "x".repeat(CRASH_RECORD_SIZE);
+        let record = CrashRecord::new(CrashKind::Panic, &message, 0, 0, 0, &[0; STACK_SNAPSHOT_WORDS]);
+        assert_eq!(record.message().len(), CRASH_RECORD_SIZE - HEADER_SIZE);
+        assert!(record.kind() == CrashKind::Panic);
+    }
+}
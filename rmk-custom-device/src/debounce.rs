@@ -0,0 +1,204 @@
+use core::fmt::Write as _;
+use core::ops::Range;
+use core::sync::atomic::{AtomicBool, Ordering};
+use embassy_time::{Duration, Instant};
+use rmk::{
+    debounce::{DebounceState, DebouncerTrait},
+    matrix::KeyState,
+};
+
+use crate::shell::{parse_switch, ShellCommand, ShellReply};
+
+
+static RAPID_TRIGGER_ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub fn set_rapid_trigger_enabled(enabled: bool) {
+    RAPID_TRIGGER_ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn is_rapid_trigger_enabled() -> bool {
+    RAPID_TRIGGER_ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn toggle_rapid_trigger() {
+    set_rapid_trigger_enabled(!is_rapid_trigger_enabled());
+}
+
+/// Shell commands of the debouncing, not persisted
+pub struct DebounceShell;
+
+impl ShellCommand for DebounceShell {
+    fn name(&self) -> &'static str {
+        "debounce"
+    }
+
+    fn usage(&self) -> &'static str {
+        "debounce show | debounce rapid <on|off>"
+    }
+
+    fn run(&self, args: &[&str], reply: &mut ShellReply) -> Result<(), &'static str> {
+        match args.first().copied() {
+            Some("show") => {
+                let _ = writeln!(reply, "rapid trigger {}", if is_rapid_trigger_enabled() { "on" } else { "off" });
+            }
+            Some("rapid") => set_rapid_trigger_enabled(parse_switch(args.get(1))?),
+            _ => return Err(self.usage()),
+        }
+        Ok(())
+    }
+}
+
+
+/// Debouncer wrapper emulating rapid trigger.
+/// A key re-pressed within `WINDOW_MS` after its debounced release is registered
+/// on the first high reading, skipping the inner debouncer.
+/// Releases are still debounced by the inner one, so release chatter is already settled by then.
+pub struct RapidTrigger<D: DebouncerTrait, const ROW: usize, const COL: usize, const WINDOW_MS: u64> {
+    inner: D,
+    released_at: [[Option<Instant>; COL]; ROW],
+}
+
+impl<D: DebouncerTrait, const ROW: usize, const COL: usize, const WINDOW_MS: u64>
+    RapidTrigger<D, ROW, COL, WINDOW_MS>
+{
+    pub fn wrap(inner: D) -> Self {
+        Self {
+            inner,
+            released_at: [[None; COL]; ROW],
+        }
+    }
+}
+
+impl<D: DebouncerTrait, const ROW: usize, const COL: usize, const WINDOW_MS: u64> DebouncerTrait
+    for RapidTrigger<D, ROW, COL, WINDOW_MS>
+{
+    fn new() -> Self {
+        Self::wrap(D::new())
+    }
+
+    /// `in_idx` and `out_idx` are row and col, as the sequential matrix calls it
+    fn detect_change_with_debounce(
+        &mut self,
+        in_idx: usize,
+        out_idx: usize,
+        pin_state: bool,
+        key_state: &KeyState,
+    ) -> DebounceState {
+        let released_at = &mut self.released_at[in_idx][out_idx];
+        if RAPID_TRIGGER_ENABLED.load(Ordering::Relaxed) && pin_state && !key_state.pressed {
+            if let Some(at) = released_at.take() {
+                if at.elapsed() <= Duration::from_millis(WINDOW_MS) {
+                    return DebounceState::Debounced;
+                }
+            }
+        }
+
+        let state = self
+            .inner
+            .detect_change_with_debounce(in_idx, out_idx, pin_state, key_state);
+        if matches!(state, DebounceState::Debounced) && key_state.pressed {
+            *released_at = Some(Instant::now());
+        }
+        state
+    }
+}
+
+
+/// Debouncer accepting a change once the reading held it for `MS`, e.g. longer for bouncy low-profile switches
+pub struct TimedDebouncer<const ROW: usize, const COL: usize, const MS: u64> {
+    changed_at: [[Option<Instant>; COL]; ROW],
+}
+
+impl<const ROW: usize, const COL: usize, const MS: u64> DebouncerTrait for TimedDebouncer<ROW, COL, MS> {
+    fn new() -> Self {
+        Self {
+            changed_at: [[None; COL]; ROW],
+        }
+    }
+
+    fn detect_change_with_debounce(
+        &mut self,
+        in_idx: usize,
+        out_idx: usize,
+        pin_state: bool,
+        key_state: &KeyState,
+    ) -> DebounceState {
+        let changed_at = &mut self.changed_at[in_idx][out_idx];
+        if pin_state == key_state.pressed {
+            *changed_at = None;
+            return DebounceState::Ignored;
+        }
+        match changed_at {
+            None => {
+                *changed_at = Some(Instant::now());
+                DebounceState::InProgress
+            }
+            Some(at) if at.elapsed() >= Duration::from_millis(MS) => {
+                *changed_at = None;
+                DebounceState::Debounced
+            }
+            Some(_) => DebounceState::InProgress,
+        }
+    }
+}
+
+
+/// Rectangle of matrix positions
+#[derive(Clone, Debug)]
+pub struct MatrixRegion {
+    pub rows: Range<u8>,
+    pub cols: Range<u8>,
+}
+
+impl MatrixRegion {
+    pub const fn new(rows: Range<u8>, cols: Range<u8>) -> Self {
+        Self { rows, cols }
+    }
+
+    fn contains(&self, row: usize, col: usize) -> bool {
+        self.rows.contains(&(row as u8)) && self.cols.contains(&(col as u8))
+    }
+}
+
+/// Debouncer using `B` for the keys in the regions and `A` for the others, e.g. the thumb cluster and the alphas.
+/// Nest it in `B` for more debouncers.
+pub struct RegionDebouncer<A: DebouncerTrait, B: DebouncerTrait, const ROW: usize, const COL: usize> {
+    a: A,
+    b: B,
+    in_regions: [[bool; COL]; ROW],
+}
+
+impl<A: DebouncerTrait, B: DebouncerTrait, const ROW: usize, const COL: usize> RegionDebouncer<A, B, ROW, COL> {
+    pub fn split(a: A, b: B, regions: &[MatrixRegion]) -> Self {
+        let mut in_regions = [[false; COL]; ROW];
+        for (row, cols) in in_regions.iter_mut().enumerate() {
+            for (col, in_region) in cols.iter_mut().enumerate() {
+                *in_region = regions.iter().any(|region| region.contains(row, col));
+            }
+        }
+        Self { a, b, in_regions }
+    }
+}
+
+impl<A: DebouncerTrait, B: DebouncerTrait, const ROW: usize, const COL: usize> DebouncerTrait
+    for RegionDebouncer<A, B, ROW, COL>
+{
+    /// Without regions, everything goes to `A`
+    fn new() -> Self {
+        Self::split(A::new(), B::new(), &[])
+    }
+
+    fn detect_change_with_debounce(
+        &mut self,
+        in_idx: usize,
+        out_idx: usize,
+        pin_state: bool,
+        key_state: &KeyState,
+    ) -> DebounceState {
+        if self.in_regions[in_idx][out_idx] {
+            self.b.detect_change_with_debounce(in_idx, out_idx, pin_state, key_state)
+        } else {
+            self.a.detect_change_with_debounce(in_idx, out_idx, pin_state, key_state)
+        }
+    }
+}
@@ -0,0 +1,152 @@
+//! De-duplication of keys reachable from more than one scan source, e.g. a key wired both to
+//! the matrix and to a direct pin while a hardware revision is being migrated.
+//! Every source is mapped to the key's logical position, which is reported pressed while any of its sources is.
+
+use portable_atomic::{AtomicU32, Ordering};
+use rmk::event::KeyEvent;
+
+use crate::event::{HookContext, KeyEventHook};
+
+
+static DUPLICATES: AtomicU32 = AtomicU32::new(0);
+
+/// Events dropped since boot because another source of the key had already reported it
+pub fn duplicate_count() -> u32 {
+    DUPLICATES.load(Ordering::Relaxed)
+}
+
+/// Redundant source of a key, the source's events are reported at the logical position
+#[derive(Clone, Copy, Debug, defmt::Format)]
+pub struct KeyAlias {
+    /// (row, col) the redundant source scans at
+    pub source: (u8, u8),
+    /// (row, col) rmk knows the key by, scanned by the key's primary source
+    pub logical: (u8, u8),
+}
+
+impl KeyAlias {
+    pub const fn new(source: (u8, u8), logical: (u8, u8)) -> Self {
+        Self { source, logical }
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct AliasState {
+    /// Physical state of the redundant source
+    source: bool,
+    /// Physical state of the primary source, kept in every alias of the key
+    primary: bool,
+    /// State reported to rmk, kept in every alias of the key
+    reported: bool,
+}
+
+
+/// Hook merging the events of the aliased sources into their logical positions.
+/// Put it first in the hook chain, so that the other hooks only see the logical positions.
+pub struct DedupHook<const N: usize> {
+    aliases: [KeyAlias; N],
+    states: [AliasState; N],
+}
+
+impl<const N: usize> DedupHook<N> {
+    pub fn new(aliases: [KeyAlias; N]) -> Self {
+        Self {
+            aliases,
+            states: [AliasState::default(); N],
+        }
+    }
+}
+
+impl<const N: usize> KeyEventHook for DedupHook<N> {
+    async fn process(&mut self, event: KeyEvent, _ctx: &mut HookContext) -> Option<KeyEvent> {
+        let position = (event.row, event.col);
+        let logical = if let Some(i) = self.aliases.iter().position(|a| a.source == position) {
+            self.states[i].source = event.pressed;
+            self.aliases[i].logical
+        } else if self.aliases.iter().any(|a| a.logical == position) {
+            for (alias, state) in self.aliases.iter().zip(self.states.iter_mut()) {
+                if alias.logical == position {
+                    state.primary = event.pressed;
+                }
+            }
+            position
+        } else {
+            return Some(event);
+        };
+
+        // The key is held while any of its sources is
+        let sources = self.aliases.iter().zip(self.states.iter()).filter(|(a, _)| a.logical == logical);
+        let (mut pressed, mut reported) = (false, false);
+        for (_, state) in sources {
+            pressed |= state.source || state.primary;
+            reported = state.reported;
+        }
+        if pressed == reported {
+            DUPLICATES.add(1, Ordering::Relaxed);
+            return None;
+        }
+        for (alias, state) in self.aliases.iter().zip(self.states.iter_mut()) {
+            if alias.logical == logical {
+                state.reported = pressed;
+            }
+        }
+        Some(KeyEvent {
+            row: logical.0,
+            col: logical.1,
+            pressed,
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+
+    use super::*;
+    use crate::event::{process_key_event, ChannelSink};
+    use crate::test_support::serial;
+
+    const PRIMARY: (u8, u8) = (0, 0);
+    const SOURCE: (u8, u8) = (1, 0);
+
+    /// (row, col, pressed) of the events reaching the sink for the event
+    fn run(hook: &mut DedupHook<1>, (row, col): (u8, u8), pressed: bool) -> std::vec::Vec<(u8, u8, bool)> {
+        let channel: Channel<CriticalSectionRawMutex, KeyEvent, 8> = Channel::new();
+        let event = KeyEvent { row, col, pressed };
+        embassy_futures::block_on(process_key_event(hook, &mut ChannelSink::new(&channel), event));
+        core::iter::from_fn(|| channel.try_receive().ok())
+            .map(|e| (e.row, e.col, e.pressed))
+            .collect()
+    }
+
+    #[test]
+    fn duplicated_press_is_dropped() {
+        let _serial = serial();
+        let mut hook = DedupHook::new([KeyAlias::new(SOURCE, PRIMARY)]);
+        let duplicates = duplicate_count();
+        assert_eq!(run(&mut hook, PRIMARY, true), [(0, 0, true)]);
+        assert!(run(&mut hook, SOURCE, true).is_empty());
+        assert_eq!(duplicate_count(), duplicates + 1);
+        // Held while either source is
+        assert!(run(&mut hook, PRIMARY, false).is_empty());
+        assert_eq!(run(&mut hook, SOURCE, false), [(0, 0, false)]);
+    }
+
+    #[test]
+    fn duplicated_release_is_dropped() {
+        let _serial = serial();
+        let mut hook = DedupHook::new([KeyAlias::new(SOURCE, PRIMARY)]);
+        let duplicates = duplicate_count();
+        assert_eq!(run(&mut hook, SOURCE, true), [(0, 0, true)]);
+        assert_eq!(run(&mut hook, SOURCE, false), [(0, 0, false)]);
+        assert!(run(&mut hook, PRIMARY, false).is_empty());
+        assert_eq!(duplicate_count(), duplicates + 1);
+    }
+
+    #[test]
+    fn other_keys_pass() {
+        let mut hook = DedupHook::new([KeyAlias::new(SOURCE, PRIMARY)]);
+        assert_eq!(run(&mut hook, (0, 1), true), [(0, 1, true)]);
+    }
+}
@@ -0,0 +1,83 @@
+//! Demo mode for show floors, stepping through a script of lighting modes, OLED pages and haptic waveforms.
+//! The OLED shows the screensaver animation of the page, haptic steps are published to the device event bus.
+//! The lighting before the demo is restored when it stops.
+
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use embassy_futures::select::{select, Either};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Duration, Timer};
+
+use crate::bus::{publish_device_event, DeviceEvent};
+use crate::lighting::{lighting_settings, set_lighting_settings, LightingSettings};
+use crate::log::LogModule;
+use crate::log_info;
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum DemoAction {
+    Lighting(LightingSettings),
+    /// Index of the screensaver animation shown by the OLED
+    OledPage(u8),
+    /// Waveform id for the haptics driver, e.g. a DRV2605L library effect
+    Haptic(u8),
+}
+
+/// Step of the demo script, the next one starts after `duration`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct DemoStep {
+    pub action: DemoAction,
+    pub duration: Duration,
+}
+
+impl DemoStep {
+    pub const fn new(action: DemoAction, duration: Duration) -> Self {
+        Self { action, duration }
+    }
+}
+
+static DEMO_RUNNING: AtomicBool = AtomicBool::new(false);
+static DEMO_OLED_PAGE: AtomicU8 = AtomicU8::new(0);
+static DEMO_TOGGLE: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+pub fn is_demo_running() -> bool {
+    DEMO_RUNNING.load(Ordering::Relaxed)
+}
+
+/// Page the OLED shows while the demo runs
+pub fn demo_oled_page() -> Option<u8> {
+    is_demo_running().then(|| DEMO_OLED_PAGE.load(Ordering::Relaxed))
+}
+
+/// Start or stop the demo, run by [`run_demo_mode`]
+pub fn toggle_demo() {
+    DEMO_TOGGLE.signal(());
+}
+
+/// Loop the script while the demo runs, until toggled again. This function should never return.
+pub async fn run_demo_mode(script: &'static [DemoStep]) -> ! {
+    loop {
+        DEMO_TOGGLE.wait().await;
+        if script.is_empty() {
+            continue;
+        }
+        log_info!(LogModule::Device, "Demo started");
+        DEMO_RUNNING.store(true, Ordering::Relaxed);
+        let saved_lighting = lighting_settings();
+        DEMO_OLED_PAGE.store(0, Ordering::Relaxed);
+        'demo: loop {
+            for step in script {
+                match step.action {
+                    DemoAction::Lighting(settings) => set_lighting_settings(settings),
+                    DemoAction::OledPage(page) => DEMO_OLED_PAGE.store(page, Ordering::Relaxed),
+                    DemoAction::Haptic(waveform) => publish_device_event(DeviceEvent::Haptic(waveform)),
+                }
+                if let Either::Second(_) = select(Timer::after(step.duration), DEMO_TOGGLE.wait()).await {
+                    break 'demo;
+                }
+            }
+        }
+        set_lighting_settings(saved_lighting);
+        DEMO_RUNNING.store(false, Ordering::Relaxed);
+        log_info!(LogModule::Device, "Demo stopped");
+    }
+}
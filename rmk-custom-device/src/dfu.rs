@@ -0,0 +1,215 @@
+//! Application level firmware update over raw HID, for boards running embassy-boot's bootloader.
+//!
+//! The image is written to the DFU partition while the keyboard keeps working, verified by its ed25519
+//! signature and swapped in by the bootloader on reboot. The new image must confirm itself by
+//! [`run_dfu`] staying up for a while, otherwise the bootloader rolls back on the next reset.
+
+use core::cell::RefCell;
+use embassy_boot_rp::{AlignedBuffer, BlockingFirmwareUpdater, FirmwareUpdaterConfig, State};
+use embassy_rp::flash::{Blocking, Flash, ERASE_SIZE, WRITE_SIZE};
+use embassy_rp::peripherals::FLASH;
+use embassy_sync::blocking_mutex::{
+    raw::{CriticalSectionRawMutex, NoopRawMutex},
+    Mutex,
+};
+use embassy_sync::channel::Channel;
+use embassy_time::{Duration, Timer};
+
+use crate::log::LogModule;
+use crate::quiesce::quiesce_flash;
+use crate::{log_info, log_warn};
+
+
+/// Raw HID command id of the firmware update, `[DFU_COMMAND, subcommand, ...]`
+pub const DFU_COMMAND: u8 = 0xE7;
+
+/// `[DFU_COMMAND, DFU_BEGIN, size (u32 LE)]`
+pub const DFU_BEGIN: u8 = 0x00;
+/// `[DFU_COMMAND, DFU_DATA, offset (u32 LE), len, data...]`, in order
+pub const DFU_DATA: u8 = 0x01;
+/// `[DFU_COMMAND, DFU_SIGNATURE, part, 16 bytes]`, 4 parts of the 64 bytes signature
+pub const DFU_SIGNATURE: u8 = 0x02;
+/// `[DFU_COMMAND, DFU_FINISH]`, verify and reboot into the new image
+pub const DFU_FINISH: u8 = 0x03;
+/// `[DFU_COMMAND, DFU_STATUS]`, responds `[DFU_COMMAND, status, received (u32 LE)]`
+pub const DFU_STATUS: u8 = 0x04;
+
+/// Data bytes per report
+pub const DFU_CHUNK_SIZE: usize = 24;
+
+/// Uptime after which the running image confirms itself
+const CONFIRM_AFTER: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+#[repr(u8)]
+pub enum DfuStatus {
+    Idle = 0,
+    Receiving = 1,
+    /// A chunk came out of order, begin again
+    OutOfOrder = 2,
+    VerifyFailed = 3,
+    FlashError = 4,
+    /// The request wasn't queued, retry it
+    Busy = 5,
+}
+
+#[derive(Clone, Copy)]
+enum DfuRequest {
+    Begin(u32),
+    Data {
+        offset: u32,
+        len: u8,
+        data: [u8; DFU_CHUNK_SIZE],
+    },
+    Signature {
+        part: u8,
+        bytes: [u8; 16],
+    },
+    Finish,
+}
+
+static DFU_REQUESTS: Channel<CriticalSectionRawMutex, DfuRequest, 4> = Channel::new();
+
+struct DfuProgress {
+    status: DfuStatus,
+    received: u32,
+}
+
+static DFU_PROGRESS: Mutex<CriticalSectionRawMutex, RefCell<DfuProgress>> = Mutex::new(RefCell::new(DfuProgress {
+    status: DfuStatus::Idle,
+    received: 0,
+}));
+
+fn set_progress(status: DfuStatus, received: u32) {
+    DFU_PROGRESS.lock(|p| *p.borrow_mut() = DfuProgress { status, received });
+}
+
+/// Answer a firmware update command in place. Requests are queued for [`run_dfu`],
+/// the host polls `DFU_STATUS` for the result. Returns false if the report isn't a DFU command.
+pub fn handle_dfu_command(report: &mut [u8]) -> bool {
+    if report.len() < 2 || report[0] != DFU_COMMAND {
+        return false;
+    }
+    let request = match report[1] {
+        DFU_BEGIN if report.len() >= 6 => Some(DfuRequest::Begin(u32::from_le_bytes([
+            report[2], report[3], report[4], report[5],
+        ]))),
+        DFU_DATA if report.len() >= 7 => {
+            let len = (report[6] as usize).min(DFU_CHUNK_SIZE).min(report.len() - 7);
+            let mut data = [0xFF; DFU_CHUNK_SIZE];
+            data[..len].copy_from_slice(&report[7..7 + len]);
+            Some(DfuRequest::Data {
+                offset: u32::from_le_bytes([report[2], report[3], report[4], report[5]]),
+                len: len as u8,
+                data,
+            })
+        }
+        DFU_SIGNATURE if report.len() >= 19 => {
+            let mut bytes = [0u8; 16];
+            bytes.copy_from_slice(&report[3..19]);
+            Some(DfuRequest::Signature { part: report[2], bytes })
+        }
+        DFU_FINISH => Some(DfuRequest::Finish),
+        _ => None,
+    };
+    let (mut status, received) = DFU_PROGRESS.lock(|p| {
+        let p = p.borrow();
+        (p.status, p.received)
+    });
+    if let Some(request) = request {
+        if DFU_REQUESTS.try_send(request).is_err() {
+            status = DfuStatus::Busy;
+        }
+    }
+    report[1..].fill(0);
+    report[1] = status as u8;
+    report[2..6].copy_from_slice(&received.to_le_bytes());
+    true
+}
+
+
+/// Run the firmware update task, verifying images by `public_key`. This function should never return.
+///
+/// The flash is owned by rmk, so this borrows it through the peripheral with blocking operations
+/// on the bootloader's partitions, which are outside of rmk's storage.
+pub async fn run_dfu<const FLASH_SIZE: usize>(public_key: &'static [u8; 32]) -> ! {
+    // SAFETY: the updater only touches the bootloader's partitions, and its erases and writes run in
+    // `quiesce_flash`, excluding rmk's DMA reads the interrupts being off doesn't stop
+    let flash_peripheral = unsafe { FLASH::steal() };
+    let flash: Mutex<NoopRawMutex, RefCell<Flash<'static, FLASH, Blocking, FLASH_SIZE>>> =
+        Mutex::new(RefCell::new(Flash::new_blocking(flash_peripheral)));
+    let config = FirmwareUpdaterConfig::from_linkerfile_blocking(&flash, &flash);
+    let mut aligned = AlignedBuffer([0u8; WRITE_SIZE]);
+    let mut updater = BlockingFirmwareUpdater::new(config, &mut aligned.0);
+
+    Timer::after(CONFIRM_AFTER).await;
+    if let Ok(State::Swap) = updater.get_state() {
+        log_info!(LogModule::Device, "Updated firmware confirmed");
+        if quiesce_flash(async { updater.mark_booted() }).await.is_err() {
+            log_warn!(LogModule::Device, "Failed to confirm the updated firmware");
+        }
+    }
+
+    // Image is written a sector at a time, as the updater erases what it writes
+    let mut sector = [0xFFu8; ERASE_SIZE];
+    let mut signature = [0u8; 64];
+    let mut size = 0u32;
+    let mut received = 0u32;
+    let mut status = DfuStatus::Idle;
+    loop {
+        match DFU_REQUESTS.receive().await {
+            DfuRequest::Begin(image_size) => {
+                log_info!(LogModule::Device, "Firmware update: {} bytes", image_size);
+                sector.fill(0xFF);
+                size = image_size;
+                received = 0;
+                status = DfuStatus::Receiving;
+            }
+            DfuRequest::Data { offset, len, data } if status == DfuStatus::Receiving => {
+                if offset != received {
+                    status = DfuStatus::OutOfOrder;
+                } else {
+                    for &byte in &data[..len as usize] {
+                        sector[received as usize % ERASE_SIZE] = byte;
+                        received += 1;
+                        if received as usize % ERASE_SIZE == 0 {
+                            let sector_offset = received - ERASE_SIZE as u32;
+                            if quiesce_flash(async { updater.write_firmware(sector_offset as usize, &sector) })
+                                .await
+                                .is_err()
+                            {
+                                status = DfuStatus::FlashError;
+                            }
+                            sector.fill(0xFF);
+                        }
+                    }
+                }
+            }
+            DfuRequest::Signature { part, bytes } if (part as usize) < 4 => {
+                signature[part as usize * 16..(part as usize + 1) * 16].copy_from_slice(&bytes);
+            }
+            DfuRequest::Finish if status == DfuStatus::Receiving && received == size => {
+                let tail = received as usize % ERASE_SIZE;
+                let flushed = tail == 0
+                    || quiesce_flash(async { updater.write_firmware(received as usize - tail, &sector) })
+                        .await
+                        .is_ok();
+                let verified = flushed
+                    && quiesce_flash(async { updater.verify_and_mark_updated(public_key, &signature, size) })
+                        .await
+                        .is_ok();
+                if verified {
+                    log_info!(LogModule::Device, "Firmware verified, rebooting into it");
+                    set_progress(DfuStatus::Idle, received);
+                    // Let the host read the status before the reset
+                    Timer::after_millis(100).await;
+                    cortex_m::peripheral::SCB::sys_reset();
+                }
+                log_warn!(LogModule::Device, "Firmware verification failed");
+                status = if flushed { DfuStatus::VerifyFailed } else { DfuStatus::FlashError };
+            }
+            _ => {}
+        }
+        set_progress(status, received);
+    }
+}
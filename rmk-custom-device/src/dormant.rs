@@ -0,0 +1,177 @@
+//! RP2040 dormant mode, all clocks stopped until a GPIO level wakes the XOSC.
+//!
+//! Idle sleep enters it without USB, waking on a key press through the sequential matrix:
+//! it waits for keys with `any_not` low, so its input goes high for any key.
+//! A split central also wakes on the peripheral's attention line, the UART can't wake it.
+//! The PLLs come back with their settings on wake, so the USB stack resumes where it was.
+//! Time stands still while dormant, including embassy-time and the RTC.
+//!
+//! A suspended host resumes the bus by signalling on D+/D-, which can't wake the XOSC, so with
+//! the host asleep it only [sleeps](enter_usb_sleep) with the USB clock running instead.
+
+use embassy_rp::pac;
+use embassy_rp::pac::clocks::vals::{ClkRefCtrlSrc, ClkSysCtrlSrc};
+#[cfg(feature = "async_matrix")]
+use embassy_time::{Duration, Timer};
+
+#[cfg(feature = "async_matrix")]
+use crate::event::idle_time;
+#[cfg(feature = "async_matrix")]
+use crate::log::LogModule;
+#[cfg(feature = "async_matrix")]
+use crate::log_info;
+#[cfg(feature = "async_matrix")]
+use crate::matrix::is_matrix_waiting;
+#[cfg(feature = "async_matrix")]
+use crate::soft_off::is_soft_off;
+#[cfg(feature = "async_matrix")]
+use crate::usb_power::{is_host_suspended, usb_power_state, UsbPowerState};
+
+
+/// How often the idle time is checked
+#[cfg(feature = "async_matrix")]
+const IDLE_POLL: Duration = Duration::from_secs(1);
+/// Time for the logs to drain before the clocks stop
+#[cfg(feature = "async_matrix")]
+const DORMANT_SETTLE: Duration = Duration::from_millis(50);
+/// Awake time after a wake from the USB sleep, for the woken tasks to run before sleeping again
+#[cfg(feature = "async_matrix")]
+const USB_SLEEP_AWAKE: Duration = Duration::from_millis(2);
+
+fn power_down_pll(pll: pac::pll::Pll) {
+    pll.pwr().modify(|w| {
+        w.set_pd(true);
+        w.set_vcopd(true);
+        w.set_postdivpd(true);
+    });
+}
+
+/// Same order as embassy-rp's init, the post dividers start once locked
+fn power_up_pll(pll: pac::pll::Pll) {
+    pll.pwr().modify(|w| {
+        w.set_pd(false);
+        w.set_vcopd(false);
+    });
+    while !pll.cs().read().lock() {}
+    pll.pwr().modify(|w| w.set_postdivpd(false));
+}
+
+/// Stop the clocks and wait for the level on any of the GPIOs, `high` or low, then return running from the XOSC.
+/// Call it in a critical section, the peripherals stay unclocked until [`restore_clocks`].
+///
+/// # Safety
+/// Nothing but the XOSC runs on return, e.g. a flash operation or a DMA transfer in flight breaks.
+pub unsafe fn enter_dormant(pins: &[usize], high: bool) {
+    for &pin in pins {
+        pac::IO_BANK0.dormant_wake_inte(pin / 8).modify(|w| {
+            if high {
+                w.set_level_high(pin % 8, true)
+            } else {
+                w.set_level_low(pin % 8, true)
+            }
+        });
+    }
+    // Run from the XOSC, PLLs and the peripherals' clocks stop along with it
+    pac::CLOCKS.clk_ref_ctrl().modify(|w| w.set_src(ClkRefCtrlSrc::XOSC_CLKSRC));
+    while pac::CLOCKS.clk_ref_selected().read() != 1 << ClkRefCtrlSrc::XOSC_CLKSRC as u32 {}
+    pac::CLOCKS.clk_sys_ctrl().modify(|w| w.set_src(ClkSysCtrlSrc::CLK_REF));
+    while pac::CLOCKS.clk_sys_selected().read() != 1 << ClkSysCtrlSrc::CLK_REF as u32 {}
+    power_down_pll(pac::PLL_SYS);
+    power_down_pll(pac::PLL_USB);
+    // "coma", halts the XOSC until the wake level
+    pac::XOSC.dormant().write_value(0x636f_6d61);
+    while !pac::XOSC.status().read().stable() {}
+    for &pin in pins {
+        pac::IO_BANK0.dormant_wake_inte(pin / 8).modify(|w| {
+            w.set_level_high(pin % 8, false);
+            w.set_level_low(pin % 8, false);
+        });
+    }
+}
+
+/// Run from the XOSC with the system PLL stopped and wait for any interrupt, e.g. the host resuming USB,
+/// a timer, or the level going high on one of the GPIOs. Call it in a critical section, the pending
+/// interrupt runs once it ends, after [`restore_clocks`].
+///
+/// The USB PLL keeps the controller clocked, so it still sees the resume, and the timer keeps its tick
+/// from the XOSC reference. The peripherals clocked from the system clock run slow until the restore.
+///
+/// # Safety
+/// A flash operation or a DMA transfer in flight breaks, like for [`enter_dormant`].
+pub unsafe fn enter_usb_sleep(pins: &[usize]) {
+    // The GPIO interrupts are enabled only for the sleep, the ones waited on already stay
+    let enabled: [u32; 4] = core::array::from_fn(|bank| pac::IO_BANK0.int_proc(0).inte(bank).read().0);
+    for &pin in pins {
+        pac::IO_BANK0.int_proc(0).inte(pin / 8).modify(|w| w.set_level_high(pin % 8, true));
+    }
+    pac::CLOCKS.clk_ref_ctrl().modify(|w| w.set_src(ClkRefCtrlSrc::XOSC_CLKSRC));
+    while pac::CLOCKS.clk_ref_selected().read() != 1 << ClkRefCtrlSrc::XOSC_CLKSRC as u32 {}
+    pac::CLOCKS.clk_sys_ctrl().modify(|w| w.set_src(ClkSysCtrlSrc::CLK_REF));
+    while pac::CLOCKS.clk_sys_selected().read() != 1 << ClkSysCtrlSrc::CLK_REF as u32 {}
+    power_down_pll(pac::PLL_SYS);
+    // Masked by the critical section, a pending interrupt still ends the wait
+    cortex_m::asm::wfi();
+    for (bank, &bits) in enabled.iter().enumerate() {
+        pac::IO_BANK0.int_proc(0).inte(bank).write_value(pac::io::regs::Int(bits));
+    }
+}
+
+/// Bring the PLLs back with the dividers they kept and run the system clock from them again
+///
+/// # Safety
+/// Only after [`enter_dormant`] or [`enter_usb_sleep`], with the clock setup of embassy-rp's init.
+pub unsafe fn restore_clocks() {
+    power_up_pll(pac::PLL_SYS);
+    power_up_pll(pac::PLL_USB);
+    pac::CLOCKS.clk_sys_ctrl().modify(|w| w.set_src(ClkSysCtrlSrc::CLKSRC_CLK_SYS_AUX));
+    while pac::CLOCKS.clk_sys_selected().read() != 1 << ClkSysCtrlSrc::CLKSRC_CLK_SYS_AUX as u32 {}
+}
+
+/// Sleep after `idle` without key events, dormant while USB is unpowered and with the USB clock running
+/// while the host sleeps, waking on a key press or the host's resume. `pins` are the GPIO numbers of the
+/// matrix input and, on a split central, the [attention line](crate::split_link::AttentionLink) waking it
+/// for the peripheral's keys. This function should never return.
+#[cfg(feature = "async_matrix")]
+pub async fn run_dormant_sleep(pins: &[usize], idle: Duration) -> ! {
+    loop {
+        Timer::after(IDLE_POLL).await;
+        let unpowered = usb_power_state() == UsbPowerState::Unpowered;
+        if idle_time() < idle || !(unpowered || is_host_suspended()) || is_soft_off() {
+            continue;
+        }
+        if unpowered {
+            log_info!(LogModule::Device, "Dormant");
+            Timer::after(DORMANT_SETTLE).await;
+            let slept = critical_section::with(|_| {
+                // Only the waiting matrix holds `any_not` low
+                if !is_matrix_waiting() {
+                    return false;
+                }
+                // SAFETY: in a critical section, nothing else runs until the clocks are back
+                unsafe {
+                    enter_dormant(pins, true);
+                    restore_clocks();
+                }
+                true
+            });
+            if slept {
+                log_info!(LogModule::Device, "Woke up from dormant");
+            }
+            continue;
+        }
+        log_info!(LogModule::Device, "Sleeping until the host resumes");
+        Timer::after(DORMANT_SETTLE).await;
+        // Every interrupt wakes it, so it sleeps again for as long as the host and the keys stay idle
+        while is_host_suspended() && idle_time() >= idle && !is_soft_off() {
+            critical_section::with(|_| {
+                // SAFETY: in a critical section, nothing else runs until the clocks are back
+                unsafe {
+                    enter_usb_sleep(pins);
+                    restore_clocks();
+                }
+            });
+            Timer::after(USB_SLEEP_AWAKE).await;
+        }
+        log_info!(LogModule::Device, "Woke up from USB sleep");
+    }
+}
@@ -0,0 +1,103 @@
+//! Rotary encoders with per-layer behavior, e.g. scrolling on the base layer and zooming on a design layer
+//! by tapping positions bound to ctrl + wheel.
+//! The layer is the tracked [`active_layer`].
+
+use embassy_time::{Duration, Timer};
+use embedded_hal::digital::InputPin;
+
+use crate::brightness::step_brightness;
+use crate::event::tap_key;
+use crate::layer_state::active_layer;
+use crate::pointing::send_mouse_report;
+
+
+/// Brightness change per detent
+const BRIGHTNESS_STEP: i16 = 16;
+
+
+/// Quadrature encoder on two pins, read by polling
+pub struct QuadratureEncoder<A: InputPin, B: InputPin> {
+    a: A,
+    b: B,
+    state: u8,
+    pulses: i8,
+    pulses_per_detent: i8,
+}
+
+impl<A: InputPin, B: InputPin> QuadratureEncoder<A, B> {
+    /// Most encoders have 4 pulses per detent
+    pub fn new(a: A, b: B, pulses_per_detent: i8) -> Self {
+        let mut encoder = Self {
+            a,
+            b,
+            state: 0,
+            pulses: 0,
+            pulses_per_detent: pulses_per_detent.max(1),
+        };
+        encoder.state = encoder.read();
+        encoder
+    }
+
+    fn read(&mut self) -> u8 {
+        (self.a.is_high().unwrap_or(false) as u8) << 1 | self.b.is_high().unwrap_or(false) as u8
+    }
+
+    /// Detents turned since the last poll, clockwise positive
+    pub fn poll(&mut self) -> i8 {
+        // Pulse by the previous and current states, invalid transitions are bounces
+        const TRANSITIONS: [i8; 16] = [0, -1, 1, 0, 1, 0, 0, -1, -1, 0, 0, 1, 0, 1, -1, 0];
+        let state = self.read();
+        self.pulses += TRANSITIONS[(self.state << 2 | state) as usize];
+        self.state = state;
+        let detents = self.pulses / self.pulses_per_detent;
+        self.pulses -= detents * self.pulses_per_detent;
+        detents
+    }
+}
+
+/// What a detent does on a layer
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum EncoderAction {
+    /// Tap the position, so the keymap decides the keys
+    Keys { cw: (u8, u8), ccw: (u8, u8) },
+    /// Vertical wheel, clockwise scrolls down
+    Wheel,
+    /// Horizontal wheel, clockwise scrolls right
+    Pan,
+    /// Manual brightness, clockwise brightens
+    Brightness,
+    Disabled,
+}
+
+async fn run_action(action: EncoderAction, detents: i8) {
+    match action {
+        EncoderAction::Keys { cw, ccw } => {
+            let (row, col) = if detents > 0 { cw } else { ccw };
+            for _ in 0..detents.unsigned_abs() {
+                tap_key(row, col).await;
+            }
+        }
+        EncoderAction::Wheel => send_mouse_report(0, 0, 0, -detents, 0).await,
+        EncoderAction::Pan => send_mouse_report(0, 0, 0, 0, detents).await,
+        EncoderAction::Brightness => step_brightness(detents as i16 * BRIGHTNESS_STEP),
+        EncoderAction::Disabled => {}
+    }
+}
+
+/// Poll the encoder and act by the action of the active layer, layers beyond the table are disabled.
+/// This function should never return.
+pub async fn run_encoder<A: InputPin, B: InputPin, const NUM_LAYER: usize>(
+    mut encoder: QuadratureEncoder<A, B>,
+    actions: [EncoderAction; NUM_LAYER],
+    interval: Duration,
+) -> ! {
+    loop {
+        Timer::after(interval).await;
+        let detents = encoder.poll();
+        if detents == 0 {
+            continue;
+        }
+        let action = actions.get(active_layer() as usize).copied().unwrap_or(EncoderAction::Disabled);
+        run_action(action, detents).await;
+    }
+}
@@ -0,0 +1,333 @@
+use embassy_sync::{
+    blocking_mutex::raw::CriticalSectionRawMutex,
+    channel::{Channel, TrySendError},
+};
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use heapless::Vec;
+use portable_atomic::{AtomicU32, AtomicU64, Ordering};
+use rmk::{event::KeyEvent, keyboard::KEY_EVENT_CHANNEL};
+
+use crate::metrics::KEY_EVENT_METRICS;
+use crate::queues::INPUT_EVENT_QUEUE_DEPTH;
+
+
+static LAST_ACTIVITY: AtomicU64 = AtomicU64::new(0);
+/// Events queued to rmk since boot, wrapping
+static RMK_EVENTS_SENT: AtomicU32 = AtomicU32::new(0);
+const RMK_TAKEN_POLL: Duration = Duration::from_millis(1);
+
+/// Time since the last key event sent to rmk, of either half
+pub fn idle_time() -> Duration {
+    Instant::now().duration_since(Instant::from_ticks(LAST_ACTIVITY.load(Ordering::Relaxed)))
+}
+
+fn record_activity() {
+    LAST_ACTIVITY.store(Instant::now().as_ticks(), Ordering::Relaxed);
+}
+
+
+/// Events the hooks emit besides the ones they return, at most a few per event
+const MAX_EMITTED_EVENTS: usize = 8;
+
+/// State of one run of an event through a hook chain, passed down to every hook it reaches.
+/// Each run has its own, so that chains on different matrices or tasks never see each other's events.
+pub struct HookContext {
+    emitted: Vec<KeyEvent, MAX_EMITTED_EVENTS>,
+}
+
+impl HookContext {
+    pub const fn new() -> Self {
+        Self { emitted: Vec::new() }
+    }
+
+    /// Emit an event besides the one the hook returns, e.g. the release of the opposite key of a SOCD pair.
+    /// It goes through the hooks after the emitting one and reaches rmk ahead of the returned event.
+    pub fn emit(&mut self, event: KeyEvent) {
+        if self.emitted.push(event).is_err() {
+            KEY_EVENT_METRICS.record_dropped();
+        }
+    }
+
+    fn take_emitted(&mut self) -> Vec<KeyEvent, MAX_EMITTED_EVENTS> {
+        core::mem::take(&mut self.emitted)
+    }
+}
+
+
+/// Hook that sees every debounced key event before it reaches rmk.
+///
+/// Hooks never send to rmk themselves. An event is forwarded by returning it, and any extra events
+/// are [emitted](HookContext::emit), so the order in the chain only decides which hooks see which events.
+/// The matrix running the chain is the only writer of its sink, see [`process_key_event`].
+#[allow(async_fn_in_trait)]
+pub trait KeyEventHook {
+    /// Returns the event to forward to rmk, or `None` to consume it.
+    async fn process(&mut self, event: KeyEvent, ctx: &mut HookContext) -> Option<KeyEvent>;
+}
+
+/// No-op hook, forwards every event as is
+impl KeyEventHook for () {
+    async fn process(&mut self, event: KeyEvent, _ctx: &mut HookContext) -> Option<KeyEvent> {
+        Some(event)
+    }
+}
+
+/// Chain two hooks, the first one sees the event first.
+/// The events the first one emits go through the second one too, ahead of the returned event.
+impl<A: KeyEventHook, B: KeyEventHook> KeyEventHook for (A, B) {
+    async fn process(&mut self, event: KeyEvent, ctx: &mut HookContext) -> Option<KeyEvent> {
+        let mut first = HookContext::new();
+        let returned = self.0.process(event, &mut first).await;
+        for emitted in first.take_emitted() {
+            if let Some(emitted) = self.1.process(emitted, ctx).await {
+                ctx.emit(emitted);
+            }
+        }
+        match returned {
+            Some(event) => self.1.process(event, ctx).await,
+            None => None,
+        }
+    }
+}
+
+/// Run the event through the hook and send the outcome to the sink, the emitted events ahead of the returned one
+pub async fn process_key_event<H: KeyEventHook, S: KeyEventSink>(hook: &mut H, sink: &mut S, event: KeyEvent) {
+    let mut ctx = HookContext::new();
+    let returned = hook.process(event, &mut ctx).await;
+    for emitted in ctx.take_emitted() {
+        sink.send(emitted).await;
+    }
+    if let Some(event) = returned {
+        sink.send(event).await;
+    }
+}
+
+/// Send the event to rmk, waiting if the queue is full. Only the sinks and the resets of
+/// the reported keys call it, hooks [emit](HookContext::emit) instead.
+/// Stalls are counted in [`KEY_EVENT_METRICS`] so that a stuck consumer shows up in the info command.
+pub async fn send_key_event(event: KeyEvent) {
+    record_activity();
+    if let Err(TrySendError::Full(event)) = KEY_EVENT_CHANNEL.try_send(event) {
+        KEY_EVENT_METRICS.record_stalled();
+        KEY_EVENT_CHANNEL.send(event).await;
+    }
+    RMK_EVENTS_SENT.fetch_add(1, Ordering::Relaxed);
+    KEY_EVENT_METRICS.record_sent(KEY_EVENT_CHANNEL.len());
+}
+
+/// Send the event to rmk without waiting, returns false and counts a drop if the queue is full
+pub fn try_send_key_event(event: KeyEvent) -> bool {
+    record_activity();
+    match KEY_EVENT_CHANNEL.try_send(event) {
+        Ok(_) => {
+            RMK_EVENTS_SENT.fetch_add(1, Ordering::Relaxed);
+            KEY_EVENT_METRICS.record_sent(KEY_EVENT_CHANNEL.len());
+            true
+        }
+        Err(_) => {
+            KEY_EVENT_METRICS.record_dropped();
+            false
+        }
+    }
+}
+
+/// Destination of the key events a matrix emits, after its hook
+#[allow(async_fn_in_trait)]
+pub trait KeyEventSink {
+    async fn send(&mut self, event: KeyEvent);
+}
+
+/// Events sent to rmk so far, wrapping, see [`wait_rmk_taken`]
+pub fn rmk_events_sent() -> u32 {
+    RMK_EVENTS_SENT.load(Ordering::Relaxed)
+}
+
+/// Wait until rmk took the event numbered `count` by [`rmk_events_sent`] from its channel, false on the timeout.
+/// rmk handles its events one after another, so it's done with the earlier ones by then, e.g. a macro it typed.
+pub async fn wait_rmk_taken(count: u32, timeout: Duration) -> bool {
+    with_timeout(timeout, async {
+        loop {
+            // The sent count first, so that an event queued in between isn't taken for a taken one
+            let sent = rmk_events_sent();
+            let taken = sent.wrapping_sub(KEY_EVENT_CHANNEL.len() as u32);
+            if taken.wrapping_sub(count) < 1 << 31 {
+                return;
+            }
+            Timer::after(RMK_TAKEN_POLL).await;
+        }
+    })
+    .await
+    .is_ok()
+}
+
+/// Sink into rmk's key event channel, see [`send_key_event`]
+pub struct RmkSink;
+
+impl KeyEventSink for RmkSink {
+    async fn send(&mut self, event: KeyEvent) {
+        send_key_event(event).await
+    }
+}
+
+/// Sink into a channel, e.g. to merge matrices or to capture the events in a test
+pub struct ChannelSink<'a, const N: usize> {
+    channel: &'a Channel<CriticalSectionRawMutex, KeyEvent, N>,
+}
+
+impl<'a, const N: usize> ChannelSink<'a, N> {
+    pub const fn new(channel: &'a Channel<CriticalSectionRawMutex, KeyEvent, N>) -> Self {
+        Self { channel }
+    }
+}
+
+impl<const N: usize> KeyEventSink for ChannelSink<'_, N> {
+    async fn send(&mut self, event: KeyEvent) {
+        self.channel.send(event).await
+    }
+}
+
+/// Sink shifting the positions by the offsets, for a matrix which is a part of a larger keymap
+pub struct OffsetSink<S: KeyEventSink, const ROW_OFFSET: u8, const COL_OFFSET: u8> {
+    inner: S,
+}
+
+impl<S: KeyEventSink, const ROW_OFFSET: u8, const COL_OFFSET: u8> OffsetSink<S, ROW_OFFSET, COL_OFFSET> {
+    pub const fn new(inner: S) -> Self {
+        Self { inner }
+    }
+}
+
+impl<S: KeyEventSink, const ROW_OFFSET: u8, const COL_OFFSET: u8> KeyEventSink for OffsetSink<S, ROW_OFFSET, COL_OFFSET> {
+    async fn send(&mut self, event: KeyEvent) {
+        self.inner
+            .send(KeyEvent {
+                row: event.row + ROW_OFFSET,
+                col: event.col + COL_OFFSET,
+                pressed: event.pressed,
+            })
+            .await
+    }
+}
+
+/// Events of inputs other than the matrix like encoders or injected ones, picked up by the matrix scan.
+/// They skip the debouncer but go through the matrix's hooks, unlike [`send_key_event`].
+pub static INPUT_EVENT_CHANNEL: Channel<CriticalSectionRawMutex, KeyEvent, INPUT_EVENT_QUEUE_DEPTH> = Channel::new();
+
+/// Send the event of a non-matrix input through the hooks, waiting if the queue is full
+pub async fn send_input_event(event: KeyEvent) {
+    INPUT_EVENT_CHANNEL.send(event).await;
+}
+
+/// Send the event of a non-matrix input through the hooks without waiting, returns false if the queue is full
+pub fn try_send_input_event(event: KeyEvent) -> bool {
+    INPUT_EVENT_CHANNEL.try_send(event).is_ok()
+}
+
+/// Press and release a position through the hooks, e.g. a virtual one bound in the keymap
+pub async fn tap_key(row: u8, col: u8) {
+    send_input_event(KeyEvent { row, col, pressed: true }).await;
+    send_input_event(KeyEvent { row, col, pressed: false }).await;
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+
+    fn key(row: u8, col: u8, pressed: bool) -> KeyEvent {
+        KeyEvent { row, col, pressed }
+    }
+
+    fn tuple(event: KeyEvent) -> (u8, u8, bool) {
+        (event.row, event.col, event.pressed)
+    }
+
+    /// Emits the release of (0, 1) ahead of each press
+    struct Emitting;
+
+    impl KeyEventHook for Emitting {
+        async fn process(&mut self, event: KeyEvent, ctx: &mut HookContext) -> Option<KeyEvent> {
+            if event.pressed {
+                ctx.emit(key(0, 1, false));
+            }
+            Some(event)
+        }
+    }
+
+    /// Records the events it sees
+    #[derive(Default)]
+    struct Recording(Vec<(u8, u8, bool)>);
+
+    impl KeyEventHook for Recording {
+        async fn process(&mut self, event: KeyEvent, _ctx: &mut HookContext) -> Option<KeyEvent> {
+            self.0.push(tuple(event));
+            Some(event)
+        }
+    }
+
+    /// Consumes every event
+    struct Consuming;
+
+    impl KeyEventHook for Consuming {
+        async fn process(&mut self, _event: KeyEvent, _ctx: &mut HookContext) -> Option<KeyEvent> {
+            None
+        }
+    }
+
+    /// Emits the release of the event's row at column 9, yielding before returning the event
+    struct Yielding;
+
+    impl KeyEventHook for Yielding {
+        async fn process(&mut self, event: KeyEvent, ctx: &mut HookContext) -> Option<KeyEvent> {
+            ctx.emit(key(event.row, 9, false));
+            embassy_futures::yield_now().await;
+            Some(event)
+        }
+    }
+
+    fn run<H: KeyEventHook>(hook: &mut H, event: KeyEvent) -> Vec<(u8, u8, bool)> {
+        let channel: Channel<CriticalSectionRawMutex, KeyEvent, 8> = Channel::new();
+        embassy_futures::block_on(process_key_event(hook, &mut ChannelSink::new(&channel), event));
+        core::iter::from_fn(|| channel.try_receive().ok()).map(tuple).collect()
+    }
+
+    #[test]
+    fn emitted_events_go_ahead() {
+        let mut hook = (Emitting, Recording::default());
+        assert_eq!(run(&mut hook, key(2, 3, true)), [(0, 1, false), (2, 3, true)]);
+        assert_eq!(hook.1 .0, [(0, 1, false), (2, 3, true)]);
+        assert_eq!(run(&mut hook, key(2, 3, false)), [(2, 3, false)]);
+    }
+
+    #[test]
+    fn emitted_events_go_through_later_hooks() {
+        let mut hook = (Emitting, Consuming);
+        assert!(run(&mut hook, key(2, 3, true)).is_empty());
+        let mut hook = (Consuming, Emitting);
+        assert!(run(&mut hook, key(2, 3, true)).is_empty());
+    }
+
+    #[test]
+    fn emitting_last_in_chain() {
+        let mut hook = ((), Emitting);
+        assert_eq!(run(&mut hook, key(2, 3, true)), [(0, 1, false), (2, 3, true)]);
+    }
+
+    #[test]
+    fn concurrent_chains_keep_their_events() {
+        let first: Channel<CriticalSectionRawMutex, KeyEvent, 8> = Channel::new();
+        let second: Channel<CriticalSectionRawMutex, KeyEvent, 8> = Channel::new();
+        let (mut first_hook, mut second_hook) = ((Yielding, ()), (Yielding, ()));
+        embassy_futures::block_on(embassy_futures::join::join(
+            process_key_event(&mut first_hook, &mut ChannelSink::new(&first), key(1, 0, true)),
+            process_key_event(&mut second_hook, &mut ChannelSink::new(&second), key(2, 0, true)),
+        ));
+        let received = |channel: &Channel<CriticalSectionRawMutex, KeyEvent, 8>| {
+            core::iter::from_fn(|| channel.try_receive().ok()).map(tuple).collect::<Vec<_>>()
+        };
+        assert_eq!(received(&first), [(1, 9, false), (1, 0, true)]);
+        assert_eq!(received(&second), [(2, 9, false), (2, 0, true)]);
+    }
+}
@@ -0,0 +1,110 @@
+//! Features switchable at runtime and persisted as a bitfield, so a misbehaving one can be disabled
+//! in the field without reflashing. Each feature's code checks [`feature_enabled`].
+
+#[cfg(feature = "rp2040")]
+use embassy_rp::flash::{Flash, Instance, Mode};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use portable_atomic::{AtomicU8, Ordering};
+
+use crate::log::LogModule;
+use crate::log_info;
+use crate::quiesce::quiesce_flash;
+#[cfg(feature = "rp2040")]
+use crate::reserved::write_reserved_sector;
+
+
+/// Raw HID command id querying the flags, responds `[FEATURE_COMMAND, flags, known flags]`
+pub const FEATURE_COMMAND: u8 = 0xEB;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum Feature {
+    /// N-key rollover report instead of the 6KRO boot report
+    Nkro,
+    /// Mouse reports of the pointing devices and the encoders
+    MouseKeys,
+    Combos,
+    Haptics,
+}
+
+impl Feature {
+    pub const fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+/// Every known feature enabled
+const DEFAULT_FLAGS: u8 = 0x0F;
+#[cfg(feature = "rp2040")]
+const FLAGS_MAGIC: u32 = 0x5441_4546; // "FEAT"
+
+static FEATURE_FLAGS: AtomicU8 = AtomicU8::new(DEFAULT_FLAGS);
+static FEATURE_FLAGS_CHANGED: Signal<CriticalSectionRawMutex, u8> = Signal::new();
+
+pub fn feature_flags() -> u8 {
+    FEATURE_FLAGS.load(Ordering::Relaxed)
+}
+
+pub fn feature_enabled(feature: Feature) -> bool {
+    feature_flags() & feature.bit() != 0
+}
+
+/// Enable or disable the feature, the change is passed to [`run_feature_flags_save`]
+pub fn set_feature_enabled(feature: Feature, enabled: bool) {
+    let flags = if enabled {
+        FEATURE_FLAGS.fetch_or(feature.bit(), Ordering::Relaxed) | feature.bit()
+    } else {
+        FEATURE_FLAGS.fetch_and(!feature.bit(), Ordering::Relaxed) & !feature.bit()
+    };
+    log_info!(LogModule::Device, "{} {}", feature, if enabled { "enabled" } else { "disabled" });
+    FEATURE_FLAGS_CHANGED.signal(flags);
+}
+
+pub fn toggle_feature(feature: Feature) {
+    set_feature_enabled(feature, !feature_enabled(feature));
+}
+
+/// Read the flags saved by the previous boot. Call it before handing the flash to rmk.
+#[cfg(feature = "rp2040")]
+pub fn load_feature_flags<T: Instance, M: Mode, const FLASH_SIZE: usize>(
+    flash: &mut Flash<'_, T, M, FLASH_SIZE>,
+    offset: u32,
+) -> bool {
+    let mut bytes = [0u8; 8];
+    if flash.blocking_read(offset, &mut bytes).is_err() || bytes[0..4] != FLAGS_MAGIC.to_le_bytes() {
+        return false;
+    }
+    FEATURE_FLAGS.store(bytes[4] & DEFAULT_FLAGS, Ordering::Relaxed);
+    true
+}
+
+/// Save the flags to the reserved sector at `offset`, as `[magic, flags]` LE
+#[cfg(feature = "rp2040")]
+pub fn save_feature_flags<const FLASH_SIZE: usize>(offset: u32, flags: &u8) {
+    let mut bytes = [0u8; 8];
+    bytes[0..4].copy_from_slice(&FLAGS_MAGIC.to_le_bytes());
+    bytes[4] = *flags;
+    if let Err(e) = write_reserved_sector::<FLASH_SIZE>(offset, &bytes) {
+        defmt::warn!("Failed to save feature flags: {}", e);
+    }
+}
+
+/// Answer a feature query in place.
+/// Returns false if the report isn't a feature command.
+pub fn handle_feature_command(report: &mut [u8]) -> bool {
+    if report.len() < 3 || report[0] != FEATURE_COMMAND {
+        return false;
+    }
+    report[1..].fill(0);
+    report[1] = feature_flags();
+    report[2] = DEFAULT_FLAGS;
+    true
+}
+
+/// Wait for changes of the flags and pass them to `on_change`, e.g. [`save_feature_flags`].
+/// This function should never return.
+pub async fn run_feature_flags_save<F: FnMut(&u8)>(mut on_change: F) -> ! {
+    loop {
+        let flags = FEATURE_FLAGS_CHANGED.wait().await;
+        quiesce_flash(async { on_change(&flags) }).await;
+    }
+}
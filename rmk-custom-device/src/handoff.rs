@@ -0,0 +1,91 @@
+//! Handoff flag left for the next firmware when rebooting into the bootloader for an update.
+//! RP2040's watchdog scratch registers survive the reboots of the bootrom, so the firmware booting after
+//! the flash knows it came from an update and which layout of the stored settings it left behind,
+//! and can keep or migrate them instead of silently falling back to the defaults.
+//! The bootrom itself uses scratch0 and scratch1 for its reboot parameters, the flag is in scratch2 and scratch3.
+
+use embassy_rp::pac::WATCHDOG;
+
+use crate::log::LogModule;
+use crate::reserved::erase_sectors;
+use crate::{log_info, log_warn};
+
+
+/// Version of the layouts of the settings stored by this crate, bump it when one of them changes
+pub const CONFIG_SCHEMA: u16 = 1;
+
+const HANDOFF_MAGIC: u32 = 0x4646_4F48; // "HOFF"
+
+/// Settings migration from a schema to the next one, rewriting the stored settings in place.
+/// Returns false when they can't be carried over, to start them over instead.
+pub type Migration = fn() -> bool;
+
+/// `MIGRATIONS[s]` takes the settings of schema `s` to `s + 1`, add one with each bump of [`CONFIG_SCHEMA`]
+const MIGRATIONS: [Migration; CONFIG_SCHEMA as usize] = [keep_pre_handoff_layouts];
+
+/// Schema 0 is the settings stored before the handoff existed, whose layouts schema 1 didn't change
+fn keep_pre_handoff_layouts() -> bool {
+    true
+}
+
+/// What the stored settings went through since the last boot
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum ConfigHandoff {
+    /// Not booted from the bootloader handoff, e.g. a power cycle, keep the settings
+    None,
+    /// Flashed with a firmware of the same schema, keep the settings
+    Kept,
+    /// Upgraded from the older schema, migrate the settings
+    Upgraded(u16),
+    /// Downgraded from the newer schema, the settings may not parse
+    Downgraded(u16),
+}
+
+/// Leave the flag with the running firmware's schema, right before the bootloader takes over
+pub fn record_handoff() {
+    WATCHDOG.scratch2().write_value(HANDOFF_MAGIC);
+    WATCHDOG.scratch3().write_value(CONFIG_SCHEMA as u32);
+}
+
+/// Read and clear the flag, call it once at boot before loading the settings
+pub fn take_config_handoff() -> ConfigHandoff {
+    let magic = WATCHDOG.scratch2().read();
+    let schema = WATCHDOG.scratch3().read() as u16;
+    WATCHDOG.scratch2().write_value(0);
+    if magic != HANDOFF_MAGIC {
+        return ConfigHandoff::None;
+    }
+    match schema {
+        s if s == CONFIG_SCHEMA => ConfigHandoff::Kept,
+        s if s < CONFIG_SCHEMA => ConfigHandoff::Upgraded(s),
+        s => ConfigHandoff::Downgraded(s),
+    }
+}
+
+/// Keep, migrate or erase the stored settings by the handoff, call it at boot before loading them.
+/// `settings` are the flash regions of every stored setting as `(offset, sectors)`, rmk's storage included,
+/// erased when they can't be carried over, i.e. after a downgrade or a failed migration.
+pub fn apply_config_handoff<const FLASH_SIZE: usize>(handoff: ConfigHandoff, settings: &[(u32, u32)]) {
+    let carried = match handoff {
+        ConfigHandoff::None | ConfigHandoff::Kept => true,
+        ConfigHandoff::Upgraded(from) => MIGRATIONS[from as usize..].iter().all(|migrate| migrate()),
+        ConfigHandoff::Downgraded(_) => false,
+    };
+    if carried {
+        log_info!(LogModule::Device, "Config handoff {}, settings kept", handoff);
+        return;
+    }
+    log_warn!(LogModule::Device, "Config handoff {}, erasing the settings", handoff);
+    for &(offset, count) in settings {
+        if let Err(e) = erase_sectors::<FLASH_SIZE>(offset, count) {
+            log_warn!(LogModule::Device, "Failed to erase the settings at {}: {}", offset, e);
+        }
+    }
+}
+
+/// Leave the flag and reboot into the bootrom's USB mass storage for a UF2 update
+pub fn reboot_to_bootloader() -> ! {
+    record_handoff();
+    embassy_rp::rom_data::reset_to_usb_boot(0, 0);
+    loop {}
+}
@@ -0,0 +1,312 @@
+//! Per-key press counts, checkpointed to flash, rendered as a heatmap and exported via raw HID
+//! for layout optimization tools.
+//!
+//! Checkpoints are appended to the reserved sector one after the other, the last complete one wins,
+//! so the sector is only erased once it's full instead of for every checkpoint.
+
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicBool, Ordering};
+#[cfg(feature = "rp2040")]
+use embassy_rp::flash::{Flash, Instance, Mode, ERASE_SIZE};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::Instant;
+#[cfg(feature = "rp2040")]
+use embassy_time::{Duration, Timer};
+use rmk::event::KeyEvent;
+
+#[cfg(feature = "rp2040")]
+use crate::{
+    quiesce::quiesce_flash,
+    reserved::{append_reserved, write_reserved_sector},
+};
+use crate::{
+    event::{HookContext, KeyEventHook},
+    rgb::{LedMap, RgbEffect, RGB8},
+};
+
+
+/// Raw HID command id reading the counts, `[HEATMAP_COMMAND, first_key]`
+pub const HEATMAP_COMMAND: u8 = 0xE4;
+
+/// Keys tracked, indexed by `row * COL + col`
+pub const HEATMAP_MAX_KEYS: usize = 64;
+
+const HEATMAP_MAGIC: u32 = 0x5441_4548; // "HEAT"
+
+/// Size of the checkpoint, the counts followed by the magic
+pub const HEATMAP_RECORD_SIZE: usize = HEATMAP_MAX_KEYS * 4 + 4;
+/// Checkpoints fitting in the reserved sector
+#[cfg(feature = "rp2040")]
+const HEATMAP_SLOTS: usize = ERASE_SIZE / HEATMAP_RECORD_SIZE;
+
+struct HeatmapState {
+    counts: [u32; HEATMAP_MAX_KEYS],
+    /// Keys actually used by the keyboard
+    keys: usize,
+    /// Whether counts changed since the last checkpoint
+    dirty: bool,
+    /// Slot of the next checkpoint in the sector, a full sector is erased first
+    next_slot: usize,
+}
+
+/// RAM taken by the counts, for the [RAM budget](crate::ram_budget)
+pub(crate) const HEATMAP_RAM: usize = core::mem::size_of::<HeatmapState>();
+
+static HEATMAP: Mutex<CriticalSectionRawMutex, RefCell<HeatmapState>> = Mutex::new(RefCell::new(HeatmapState {
+    counts: [0; HEATMAP_MAX_KEYS],
+    keys: 0,
+    dirty: false,
+    next_slot: usize::MAX,
+}));
+
+/// Whether the heatmap is drawn over the lighting, not persisted
+static HEATMAP_SHOWN: AtomicBool = AtomicBool::new(false);
+
+/// Show or hide the heatmap on the lighting, the counting goes on either way
+pub fn toggle_heatmap_shown() {
+    HEATMAP_SHOWN.store(!heatmap_shown(), Ordering::Relaxed);
+}
+
+pub fn heatmap_shown() -> bool {
+    HEATMAP_SHOWN.load(Ordering::Relaxed)
+}
+
+pub fn key_count(index: usize) -> u32 {
+    HEATMAP.lock(|h| h.borrow().counts.get(index).copied().unwrap_or(0))
+}
+
+pub fn clear_heatmap() {
+    HEATMAP.lock(|h| {
+        let mut h = h.borrow_mut();
+        h.counts.fill(0);
+        h.dirty = true;
+    });
+}
+
+/// `[counts..., magic]`, u32 LE each. The magic goes last, so a checkpoint cut short by a reset has none
+pub fn heatmap_record() -> [u8; HEATMAP_RECORD_SIZE] {
+    let mut bytes = [0u8; HEATMAP_RECORD_SIZE];
+    HEATMAP.lock(|h| {
+        for (i, count) in h.borrow().counts.iter().enumerate() {
+            bytes[i * 4..i * 4 + 4].copy_from_slice(&count.to_le_bytes());
+        }
+    });
+    bytes[HEATMAP_RECORD_SIZE - 4..].copy_from_slice(&HEATMAP_MAGIC.to_le_bytes());
+    bytes
+}
+
+/// Restore the counts from a checkpoint, returns false if it isn't one, e.g. erased
+pub fn restore_heatmap(bytes: &[u8; HEATMAP_RECORD_SIZE]) -> bool {
+    let magic = &bytes[HEATMAP_RECORD_SIZE - 4..];
+    if magic != HEATMAP_MAGIC.to_le_bytes() {
+        return false;
+    }
+    HEATMAP.lock(|h| {
+        for (i, count) in h.borrow_mut().counts.iter_mut().enumerate() {
+            *count = u32::from_le_bytes([bytes[i * 4], bytes[i * 4 + 1], bytes[i * 4 + 2], bytes[i * 4 + 3]]);
+        }
+    });
+    true
+}
+
+/// Answer a heatmap command in place.
+/// Response: `[HEATMAP_COMMAND, keys, first_key, counts...]`, u32 LE each from `first_key`.
+/// Returns false if the report isn't a heatmap command.
+pub fn handle_heatmap_command(report: &mut [u8]) -> bool {
+    if report.len() < 2 || report[0] != HEATMAP_COMMAND {
+        return false;
+    }
+    let first_key = report[1] as usize;
+    report[1..].fill(0);
+    HEATMAP.lock(|h| {
+        let h = h.borrow();
+        report[1] = h.keys as u8;
+        report[2] = first_key as u8;
+        let counts = h.counts.get(first_key..h.keys).unwrap_or(&[]);
+        for (chunk, count) in report[3..].chunks_exact_mut(4).zip(counts.iter()) {
+            chunk.copy_from_slice(&count.to_le_bytes());
+        }
+    });
+    true
+}
+
+
+/// Hook counting presses per key, forwards every event.
+pub struct HeatmapHook<const ROW: usize, const COL: usize>;
+
+impl<const ROW: usize, const COL: usize> HeatmapHook<ROW, COL> {
+    pub fn new() -> Self {
+        HEATMAP.lock(|h| h.borrow_mut().keys = (ROW * COL).min(HEATMAP_MAX_KEYS));
+        Self
+    }
+}
+
+impl<const ROW: usize, const COL: usize> Default for HeatmapHook<ROW, COL> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const ROW: usize, const COL: usize> KeyEventHook for HeatmapHook<ROW, COL> {
+    async fn process(&mut self, event: KeyEvent, _ctx: &mut HookContext) -> Option<KeyEvent> {
+        let index = event.row as usize * COL + event.col as usize;
+        if event.pressed && index < HEATMAP_MAX_KEYS {
+            HEATMAP.lock(|h| {
+                let mut h = h.borrow_mut();
+                h.counts[index] = h.counts[index].saturating_add(1);
+                h.dirty = true;
+            });
+        }
+        Some(event)
+    }
+}
+
+
+/// Effect coloring each key from blue to red by its share of the most pressed key's count while
+/// [shown](toggle_heatmap_shown), leaving the frame to the effects below otherwise
+pub struct HeatmapEffect<const N: usize, const COL: usize> {
+    leds: LedMap<N>,
+}
+
+impl<const N: usize, const COL: usize> HeatmapEffect<N, COL> {
+    pub fn new(leds: LedMap<N>) -> Self {
+        Self { leds }
+    }
+}
+
+impl<const N: usize, const COL: usize> RgbEffect<N> for HeatmapEffect<N, COL> {
+    fn render(&mut self, frame: &mut [RGB8; N], _now: Instant) {
+        if !heatmap_shown() {
+            return;
+        }
+        let counts = HEATMAP.lock(|h| h.borrow().counts);
+        let max = counts.iter().copied().max().unwrap_or(0).max(1) as u64;
+        for (pixel, position) in frame.iter_mut().zip(self.leds.positions.iter()) {
+            let Some((row, col)) = position else {
+                continue;
+            };
+            let count = counts.get(*row as usize * COL + *col as usize).copied().unwrap_or(0) as u64;
+            let heat = (count * 255 / max) as u8;
+            *pixel = RGB8::new(heat, 0, 255 - heat);
+        }
+    }
+}
+
+
+/// Restore the counts saved by the previous boot from the sector at `offset`. Call it before handing the flash to rmk.
+#[cfg(feature = "rp2040")]
+pub fn load_heatmap<T: Instance, M: Mode, const FLASH_SIZE: usize>(
+    flash: &mut Flash<'_, T, M, FLASH_SIZE>,
+    offset: u32,
+) -> bool {
+    let mut restored = false;
+    let mut next_slot = HEATMAP_SLOTS;
+    let mut bytes = [0u8; HEATMAP_RECORD_SIZE];
+    for slot in 0..HEATMAP_SLOTS {
+        if flash.blocking_read(offset + (slot * HEATMAP_RECORD_SIZE) as u32, &mut bytes).is_err() {
+            break;
+        }
+        // Written slots can't be written again, even a checkpoint cut short
+        if bytes.iter().all(|&b| b == 0xFF) {
+            next_slot = slot;
+            break;
+        }
+        restored |= restore_heatmap(&bytes);
+    }
+    HEATMAP.lock(|h| h.borrow_mut().next_slot = next_slot);
+    restored
+}
+
+/// Append the counts to the reserved sector at `offset` every interval, if they changed.
+/// This function should never return.
+#[cfg(feature = "rp2040")]
+pub async fn run_heatmap_checkpoint<const FLASH_SIZE: usize>(offset: u32, interval: Duration) -> ! {
+    loop {
+        // Also skips the boot, when rmk reads its storage
+        Timer::after(interval).await;
+        let (dirty, slot) = HEATMAP.lock(|h| {
+            let mut h = h.borrow_mut();
+            (core::mem::replace(&mut h.dirty, false), h.next_slot)
+        });
+        if !dirty {
+            continue;
+        }
+        let record = heatmap_record();
+        // Not loaded, or full: start the sector over
+        let slot = if slot < HEATMAP_SLOTS { slot } else { 0 };
+        let result = quiesce_flash(async {
+            if slot == 0 {
+                write_reserved_sector::<FLASH_SIZE>(offset, &record)
+            } else {
+                append_reserved::<FLASH_SIZE>(offset + (slot * HEATMAP_RECORD_SIZE) as u32, &record)
+            }
+        })
+        .await;
+        match result {
+            Ok(()) => HEATMAP.lock(|h| h.borrow_mut().next_slot = slot + 1),
+            Err(e) => {
+                defmt::warn!("Failed to checkpoint heatmap: {}", e);
+                // Written partly or not at all, the next one starts the sector over
+                HEATMAP.lock(|h| h.borrow_mut().next_slot = HEATMAP_SLOTS);
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+
+    use super::*;
+    use crate::event::{process_key_event, ChannelSink};
+    use crate::test_support::serial;
+
+    fn run(hook: &mut HeatmapHook<2, 3>, (row, col): (u8, u8), pressed: bool) -> std::vec::Vec<(u8, u8, bool)> {
+        let channel: Channel<CriticalSectionRawMutex, KeyEvent, 8> = Channel::new();
+        let event = KeyEvent { row, col, pressed };
+        embassy_futures::block_on(process_key_event(hook, &mut ChannelSink::new(&channel), event));
+        core::iter::from_fn(|| channel.try_receive().ok())
+            .map(|e| (e.row, e.col, e.pressed))
+            .collect()
+    }
+
+    #[test]
+    fn presses_are_counted_and_forwarded() {
+        let _serial = serial();
+        clear_heatmap();
+        let mut hook = HeatmapHook::<2, 3>::new();
+        assert_eq!(run(&mut hook, (1, 2), true), [(1, 2, true)]);
+        assert_eq!(run(&mut hook, (1, 2), false), [(1, 2, false)]);
+        run(&mut hook, (1, 2), true);
+        assert_eq!(key_count(5), 2);
+        assert_eq!(key_count(0), 0);
+    }
+
+    #[test]
+    fn command_exports_the_counts() {
+        let _serial = serial();
+        clear_heatmap();
+        let mut hook = HeatmapHook::<2, 3>::new();
+        run(&mut hook, (0, 1), true);
+        let mut report = [0u8; 32];
+        report[..2].copy_from_slice(&[HEATMAP_COMMAND, 1]);
+        assert!(handle_heatmap_command(&mut report));
+        assert_eq!(report[..3], [HEATMAP_COMMAND, 6, 1]);
+        assert_eq!(report[3..7], 1u32.to_le_bytes());
+        assert_eq!(report[7..11], 0u32.to_le_bytes());
+    }
+
+    #[test]
+    fn record_round_trips() {
+        let _serial = serial();
+        clear_heatmap();
+        let mut hook = HeatmapHook::<2, 3>::new();
+        run(&mut hook, (0, 0), true);
+        let record = heatmap_record();
+        clear_heatmap();
+        assert!(restore_heatmap(&record));
+        assert_eq!(key_count(0), 1);
+        assert!(!restore_heatmap(&[0xFF; HEATMAP_RECORD_SIZE]));
+    }
+}
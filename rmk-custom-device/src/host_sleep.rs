@@ -0,0 +1,134 @@
+//! Behavior profile applied while the host sleeps, i.e. suspended the USB bus or dropped the BLE connection.
+//! The profile declares what changes, the lighting, the display and the scan rate read the state themselves,
+//! and everything is restored once the host is back.
+//! rmk runs the BLE stack, so a BLE firmware reports the connection by [`set_ble_connected`].
+
+use embassy_time::{Duration, Instant, Timer};
+use portable_atomic::{AtomicBool, Ordering};
+
+use crate::bus::{publish_device_event, DeviceEvent};
+use crate::event::tap_key;
+use crate::log::LogModule;
+use crate::log_info;
+use crate::matrix::set_sleep_scan_interval;
+use crate::usb_power::{is_host_suspended, usb_power_state, UsbPowerState};
+
+
+/// What the keyboard does while the host sleeps
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct SleepProfile {
+    /// Time the host must stay asleep first, so that short suspends don't blink the lighting
+    pub delay: Duration,
+    pub rgb_off: bool,
+    pub oled_off: bool,
+    /// Interval between the matrix scans, `None` keeps the regular rate
+    pub scan_interval: Option<Duration>,
+    /// Position tapped when the BLE host drops, bound to the profile of a secondary host in the keymap
+    pub ble_profile_key: Option<(u8, u8)>,
+}
+
+impl SleepProfile {
+    /// Nothing changes until enabled by the `with_*` below
+    pub const fn new(delay: Duration) -> Self {
+        Self {
+            delay,
+            rgb_off: false,
+            oled_off: false,
+            scan_interval: None,
+            ble_profile_key: None,
+        }
+    }
+
+    pub const fn with_rgb_off(mut self) -> Self {
+        self.rgb_off = true;
+        self
+    }
+
+    pub const fn with_oled_off(mut self) -> Self {
+        self.oled_off = true;
+        self
+    }
+
+    pub const fn with_scan_interval(mut self, interval: Duration) -> Self {
+        self.scan_interval = Some(interval);
+        self
+    }
+
+    pub const fn with_ble_profile_key(mut self, row: u8, col: u8) -> Self {
+        self.ble_profile_key = Some((row, col));
+        self
+    }
+}
+
+/// Why the host is considered asleep
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum HostSleepCause {
+    UsbSuspend,
+    BleDisconnect,
+}
+
+static BLE_DISCONNECTED: AtomicBool = AtomicBool::new(false);
+static RGB_ASLEEP: AtomicBool = AtomicBool::new(false);
+static OLED_ASLEEP: AtomicBool = AtomicBool::new(false);
+
+/// Record the BLE connection, a drop counts as the host sleeping while USB has no host
+pub fn set_ble_connected(connected: bool) {
+    BLE_DISCONNECTED.store(!connected, Ordering::Relaxed);
+}
+
+/// Whether the LEDs stay dark for the sleeping host
+pub fn is_rgb_asleep() -> bool {
+    RGB_ASLEEP.load(Ordering::Relaxed)
+}
+
+/// Whether the display stays off for the sleeping host
+pub fn is_oled_asleep() -> bool {
+    OLED_ASLEEP.load(Ordering::Relaxed)
+}
+
+fn host_sleep_cause() -> Option<HostSleepCause> {
+    if is_host_suspended() {
+        Some(HostSleepCause::UsbSuspend)
+    } else if BLE_DISCONNECTED.load(Ordering::Relaxed) && usb_power_state() != UsbPowerState::Connected {
+        Some(HostSleepCause::BleDisconnect)
+    } else {
+        None
+    }
+}
+
+fn apply_profile(profile: Option<&SleepProfile>) {
+    RGB_ASLEEP.store(profile.is_some_and(|p| p.rgb_off), Ordering::Relaxed);
+    OLED_ASLEEP.store(profile.is_some_and(|p| p.oled_off), Ordering::Relaxed);
+    set_sleep_scan_interval(profile.and_then(|p| p.scan_interval));
+    publish_device_event(DeviceEvent::HostSleep(profile.is_some()));
+}
+
+
+/// Watch the host and apply the profile while it sleeps, restoring the regular behavior when it wakes.
+/// This function should never return.
+pub async fn run_host_sleep(profile: SleepProfile, interval: Duration) -> ! {
+    let mut asleep_since = None;
+    let mut applied = false;
+    loop {
+        Timer::after(interval).await;
+        let Some(cause) = host_sleep_cause() else {
+            asleep_since = None;
+            if applied {
+                applied = false;
+                log_info!(LogModule::Device, "Host woke up");
+                apply_profile(None);
+            }
+            continue;
+        };
+        let since = *asleep_since.get_or_insert(Instant::now());
+        if applied || since.elapsed() < profile.delay {
+            continue;
+        }
+        applied = true;
+        log_info!(LogModule::Device, "Host asleep by {}, applying {}", cause, profile);
+        apply_profile(Some(&profile));
+        if let (HostSleepCause::BleDisconnect, Some((row, col))) = (cause, profile.ble_profile_key) {
+            tap_key(row, col).await;
+        }
+    }
+}
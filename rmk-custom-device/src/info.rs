@@ -0,0 +1,159 @@
+use core::fmt::Write;
+use heapless::String;
+
+use crate::battery::battery_bytes;
+use crate::layer_state::active_layer;
+use crate::metrics::{KEY_EVENT_METRICS, REPORT_METRICS};
+use crate::split_link::SPLIT_LINK_STATS;
+use crate::telemetry::latest_telemetry;
+
+
+/// Raw HID command id of the info query, placed in the vendor range above Vial's ids
+pub const INFO_COMMAND: u8 = 0xE0;
+
+/// Queryable fields of the info command
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+#[repr(u8)]
+pub enum InfoField {
+    /// `[rows, cols, layers]`
+    Dimensions = 0,
+    Version = 1,
+    GitHash = 2,
+    BuildDate = 3,
+    Features = 4,
+    /// `[temperature_centi (i16 LE), vsys_mv (u16 LE)]`, empty before the first reading
+    Telemetry = 5,
+    /// Key event queue metrics, `[sent, stalled, dropped, coalesced (u32 LE each), max_depth]`
+    KeyEventQueue = 6,
+    /// Report queue metrics of the firmware side, same layout as `KeyEventQueue`
+    ReportQueue = 7,
+    /// Split link counters, `[rx_bytes, tx_bytes, read_errors, write_errors, silence_ms, retransmits, crc_errors, rtt_us (u32 LE each)]`,
+    /// over two pages
+    SplitLink = 8,
+    /// Battery levels in percent, `[central, peripheral]`, 0xFF if unknown, see [`battery`](crate::battery)
+    Battery = 9,
+}
+
+impl InfoField {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Self::Dimensions),
+            1 => Some(Self::Version),
+            2 => Some(Self::GitHash),
+            3 => Some(Self::BuildDate),
+            4 => Some(Self::Features),
+            5 => Some(Self::Telemetry),
+            6 => Some(Self::KeyEventQueue),
+            7 => Some(Self::ReportQueue),
+            8 => Some(Self::SplitLink),
+            9 => Some(Self::Battery),
+            _ => None,
+        }
+    }
+}
+
+/// Firmware build information, embedded at compile time by [`build_info!`]
+#[derive(Clone, Copy, Debug, defmt::Format)]
+pub struct BuildInfo {
+    pub name: &'static str,
+    pub version: &'static str,
+    pub git_hash: &'static str,
+    pub build_date: &'static str,
+    /// Comma separated cargo features of the firmware
+    pub features: &'static str,
+    /// Debouncer in use, `default` or `rapid`
+    pub debounce: &'static str,
+    /// Host connection, `usb` or `ble`
+    pub connection: &'static str,
+    /// Hold time of rmk's tap-hold keys
+    pub tapping_term_ms: u16,
+    pub rows: u8,
+    pub cols: u8,
+    pub layers: u8,
+}
+
+impl BuildInfo {
+    /// Human readable version string, e.g. `rmk-dflipdaisy 0.2.0 (1a2b3c4 2024-11-03)`
+    pub fn version_string(&self) -> String<64> {
+        let mut s = String::new();
+        // Truncated silently if the name is too long
+        let _ = write!(s, "{} {} ({} {})", self.name, self.version, self.git_hash, self.build_date);
+        s
+    }
+
+    /// Summary of the current settings as `key=value` pairs on one line, e.g.
+    /// `layer=0 debounce=default tapping_term=250ms connection=usb matrix=4x3 layers=2`.
+    /// Without newlines, so that typing it into a form doesn't submit it.
+    pub fn settings_summary(&self) -> String<128> {
+        let mut s = String::new();
+        let _ = write!(
+            s,
+            "layer={} debounce={} tapping_term={}ms connection={}",
+            active_layer(),
+            self.debounce,
+            self.tapping_term_ms,
+            self.connection
+        );
+        let _ = write!(s, " matrix={}x{} layers={}", self.rows, self.cols, self.layers);
+        s
+    }
+
+    /// Answer an info command in place.
+    ///
+    /// Request: `[INFO_COMMAND, field, offset]`.
+    /// Response: `[INFO_COMMAND, field, total_len, data...]`, where string fields are paged by `offset`.
+    /// Returns false if the report isn't an info command.
+    pub fn handle_info_command(&self, report: &mut [u8]) -> bool {
+        if report.len() < 3 || report[0] != INFO_COMMAND {
+            return false;
+        }
+        let offset = report[2] as usize;
+        let dimensions = [self.rows, self.cols, self.layers];
+        let telemetry = latest_telemetry().map(|t| t.to_bytes());
+        let key_event_queue = KEY_EVENT_METRICS.to_bytes();
+        let report_queue = REPORT_METRICS.to_bytes();
+        let split_link = SPLIT_LINK_STATS.to_bytes();
+        let battery = battery_bytes();
+        let data: &[u8] = match InfoField::from_u8(report[1]) {
+            Some(InfoField::Dimensions) => &dimensions,
+            Some(InfoField::Version) => self.version.as_bytes(),
+            Some(InfoField::GitHash) => self.git_hash.as_bytes(),
+            Some(InfoField::BuildDate) => self.build_date.as_bytes(),
+            Some(InfoField::Features) => self.features.as_bytes(),
+            Some(InfoField::Telemetry) => telemetry.as_ref().map(|t| &t[..]).unwrap_or_default(),
+            Some(InfoField::KeyEventQueue) => &key_event_queue,
+            Some(InfoField::ReportQueue) => &report_queue,
+            Some(InfoField::SplitLink) => &split_link,
+            Some(InfoField::Battery) => &battery,
+            None => &[],
+        };
+        let page = data.get(offset..).unwrap_or_default();
+        let body = &mut report[3..];
+        body.fill(0);
+        let len = page.len().min(body.len());
+        body[..len].copy_from_slice(&page[..len]);
+        report[2] = data.len().min(u8::MAX as usize) as u8;
+        true
+    }
+}
+
+/// Build [`BuildInfo`] of the calling crate.
+/// The crate's build script must set `DFLIPDAISY_GIT_HASH`, `DFLIPDAISY_BUILD_DATE` and `DFLIPDAISY_FEATURES`.
+#[macro_export]
+macro_rules! build_info {
+    (rows: $rows:expr, cols: $cols:expr, layers: $layers:expr, tapping_term: $tapping_term:expr $(,)?) => {
+        $crate::info::BuildInfo {
+            name: env!("CARGO_PKG_NAME"),
+            version: env!("CARGO_PKG_VERSION"),
+            git_hash: env!("DFLIPDAISY_GIT_HASH"),
+            build_date: env!("DFLIPDAISY_BUILD_DATE"),
+            features: env!("DFLIPDAISY_FEATURES"),
+            debounce: if cfg!(feature = "rapid_debouncer") { "rapid" } else { "default" },
+            connection: if cfg!(feature = "_ble") { "ble" } else { "usb" },
+            tapping_term_ms: $tapping_term.as_millis() as u16,
+            rows: $rows as u8,
+            cols: $cols as u8,
+            layers: $layers as u8,
+        }
+    };
+}
@@ -0,0 +1,102 @@
+//! Presence mode keeping the host awake during long reads, by tiny mouse moves or F15 taps.
+//! It turns itself off after a while, so that it isn't left on by accident.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use embassy_futures::select::{select, Either};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Duration, Instant, Timer};
+use usbd_hid::descriptor::MouseReport;
+
+use crate::log::LogModule;
+use crate::log_info;
+use crate::output::{send_output_report, OutputReport};
+use crate::rgb::{RgbEffect, RGB8};
+use crate::typing::tap_usage;
+
+
+/// Usage id of F15, unbound on most hosts
+const USAGE_F15: u8 = 0x6A;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum JigglerMode {
+    /// Move the pointer by a pixel and back
+    Mouse,
+    /// Tap F15, for hosts locking on keyboard idle only
+    F15,
+}
+
+static JIGGLER_ACTIVE: AtomicBool = AtomicBool::new(false);
+static JIGGLER_TOGGLE: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+pub fn is_jiggler_active() -> bool {
+    JIGGLER_ACTIVE.load(Ordering::Relaxed)
+}
+
+pub fn toggle_jiggler() {
+    JIGGLER_TOGGLE.signal(());
+}
+
+async fn jiggle(mode: JigglerMode, x: i8) {
+    match mode {
+        JigglerMode::Mouse => {
+            let report = MouseReport {
+                buttons: 0,
+                x,
+                y: 0,
+                wheel: 0,
+                pan: 0,
+            };
+            send_output_report(OutputReport::Mouse(report)).await;
+        }
+        JigglerMode::F15 => tap_usage(USAGE_F15).await,
+    }
+}
+
+/// Jiggle every `interval` while toggled on, turning off after `auto_off`. This function should never return.
+pub async fn run_jiggler(mode: JigglerMode, interval: Duration, auto_off: Duration) -> ! {
+    loop {
+        JIGGLER_TOGGLE.wait().await;
+        log_info!(LogModule::Action, "Jiggler on: {}", mode);
+        JIGGLER_ACTIVE.store(true, Ordering::Relaxed);
+        let deadline = Instant::now() + auto_off;
+        // Alternate the direction, so the pointer stays in place
+        let mut x = 1;
+        loop {
+            if let Either::Second(_) = select(Timer::after(interval), JIGGLER_TOGGLE.wait()).await {
+                break;
+            }
+            if Instant::now() >= deadline {
+                log_info!(LogModule::Action, "Jiggler timed out");
+                break;
+            }
+            jiggle(mode, x).await;
+            x = -x;
+        }
+        JIGGLER_ACTIVE.store(false, Ordering::Relaxed);
+        log_info!(LogModule::Action, "Jiggler off");
+    }
+}
+
+
+/// Effect lighting the pixel in the color while the jiggler is on. Put it on top of the regular effect.
+pub struct JigglerEffect {
+    index: usize,
+    color: RGB8,
+}
+
+impl JigglerEffect {
+    pub const fn new(index: usize, color: RGB8) -> Self {
+        Self { index, color }
+    }
+}
+
+impl<const N: usize> RgbEffect<N> for JigglerEffect {
+    fn render(&mut self, frame: &mut [RGB8; N], _now: Instant) {
+        if !is_jiggler_active() {
+            return;
+        }
+        if let Some(pixel) = frame.get_mut(self.index) {
+            *pixel = self.color;
+        }
+    }
+}
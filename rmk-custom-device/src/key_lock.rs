@@ -0,0 +1,171 @@
+//! Keyboard lock ignoring every key but the unlock combo, e.g. while cleaning the board.
+//! The lock isn't persisted, a reboot unlocks.
+
+use portable_atomic::{AtomicBool, Ordering};
+use embassy_time::Instant;
+use heapless::Vec;
+use rmk::event::KeyEvent;
+
+use crate::event::{HookContext, KeyEventHook};
+use crate::log::LogModule;
+use crate::log_info;
+use crate::rgb::{RgbEffect, RGB8};
+
+
+/// Keys pressed while locked which are tracked, so their releases are consumed too
+const MAX_LOCKED_KEYS: usize = 16;
+
+static LOCKED: AtomicBool = AtomicBool::new(false);
+
+pub fn is_keyboard_locked() -> bool {
+    LOCKED.load(Ordering::Relaxed)
+}
+
+pub fn set_keyboard_locked(locked: bool) {
+    if LOCKED.swap(locked, Ordering::Relaxed) != locked {
+        log_info!(LogModule::Action, "Keyboard {}", if locked { "locked" } else { "unlocked" });
+    }
+}
+
+pub fn toggle_keyboard_lock() {
+    set_keyboard_locked(!is_keyboard_locked());
+}
+
+
+/// Hook consuming every key event while locked. Holding all keys of the combo toggles the lock.
+/// Keys held when locking are released normally. Put it before the hooks acting on the keys, e.g. custom actions.
+pub struct KeyLockHook<const N: usize> {
+    combo: [(u8, u8); N],
+    /// Keys whose presses were consumed, waiting for their releases
+    consumed: Vec<(u8, u8), MAX_LOCKED_KEYS>,
+    combo_held: [bool; N],
+}
+
+impl<const N: usize> KeyLockHook<N> {
+    pub fn new(combo: [(u8, u8); N]) -> Self {
+        Self {
+            combo,
+            consumed: Vec::new(),
+            combo_held: [false; N],
+        }
+    }
+
+    fn update_combo(&mut self, event: &KeyEvent) -> bool {
+        let Some(i) = self.combo.iter().position(|p| *p == (event.row, event.col)) else {
+            return false;
+        };
+        let completed = event.pressed && !self.combo_held[i] && self.combo_held.iter().filter(|h| !**h).count() == 1;
+        self.combo_held[i] = event.pressed;
+        completed
+    }
+}
+
+impl<const N: usize> KeyEventHook for KeyLockHook<N> {
+    async fn process(&mut self, event: KeyEvent, _ctx: &mut HookContext) -> Option<KeyEvent> {
+        let position = (event.row, event.col);
+        // The key completing the combo is consumed either way
+        let completed = N > 0 && self.update_combo(&event);
+        if completed {
+            toggle_keyboard_lock();
+        }
+        if event.pressed {
+            if !completed && !is_keyboard_locked() {
+                return Some(event);
+            }
+            if !self.consumed.contains(&position) {
+                let _ = self.consumed.push(position);
+            }
+            None
+        } else {
+            match self.consumed.iter().position(|p| *p == position) {
+                Some(i) => {
+                    self.consumed.swap_remove(i);
+                    None
+                }
+                None => Some(event),
+            }
+        }
+    }
+}
+
+
+/// Effect pulsing the pixels in the color while locked, drawing nothing otherwise. Put it on top of the regular effect.
+pub struct KeyLockEffect {
+    color: RGB8,
+}
+
+impl KeyLockEffect {
+    pub const fn new(color: RGB8) -> Self {
+        Self { color }
+    }
+}
+
+impl<const N: usize> RgbEffect<N> for KeyLockEffect {
+    fn render(&mut self, frame: &mut [RGB8; N], now: Instant) {
+        if !is_keyboard_locked() {
+            return;
+        }
+        // 2s triangle wave
+        let phase = (now.as_millis() % 2000 * 512 / 2000) as u16;
+        let level = if phase < 256 { phase } else { 511 - phase };
+        let scale = |c: u8| (c as u16 * level / 255) as u8;
+        frame.fill(RGB8::new(scale(self.color.r), scale(self.color.g), scale(self.color.b)));
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+
+    use super::*;
+    use crate::event::{process_key_event, ChannelSink};
+    use crate::test_support::serial;
+
+    const COMBO: [(u8, u8); 2] = [(0, 0), (1, 1)];
+
+    /// (row, col, pressed) of the events reaching the sink for the event
+    fn run(hook: &mut KeyLockHook<2>, (row, col): (u8, u8), pressed: bool) -> std::vec::Vec<(u8, u8, bool)> {
+        let channel: Channel<CriticalSectionRawMutex, KeyEvent, 8> = Channel::new();
+        let event = KeyEvent { row, col, pressed };
+        embassy_futures::block_on(process_key_event(hook, &mut ChannelSink::new(&channel), event));
+        core::iter::from_fn(|| channel.try_receive().ok())
+            .map(|e| (e.row, e.col, e.pressed))
+            .collect()
+    }
+
+    #[test]
+    fn locked_presses_are_swallowed() {
+        let _serial = serial();
+        set_keyboard_locked(true);
+        let mut hook = KeyLockHook::new(COMBO);
+        assert!(run(&mut hook, (0, 1), true).is_empty());
+        assert!(run(&mut hook, (0, 1), false).is_empty());
+        assert!(is_keyboard_locked());
+    }
+
+    #[test]
+    fn unlock_combo_gets_through() {
+        let _serial = serial();
+        set_keyboard_locked(true);
+        let mut hook = KeyLockHook::new(COMBO);
+        assert!(run(&mut hook, (0, 0), true).is_empty());
+        assert!(run(&mut hook, (1, 1), true).is_empty());
+        assert!(!is_keyboard_locked());
+        // The combo's releases are swallowed like their presses
+        assert!(run(&mut hook, (1, 1), false).is_empty());
+        assert!(run(&mut hook, (0, 0), false).is_empty());
+        assert_eq!(run(&mut hook, (0, 1), true), [(0, 1, true)]);
+    }
+
+    #[test]
+    fn keys_held_when_locking_are_released() {
+        let _serial = serial();
+        set_keyboard_locked(false);
+        let mut hook = KeyLockHook::new(COMBO);
+        assert_eq!(run(&mut hook, (0, 1), true), [(0, 1, true)]);
+        set_keyboard_locked(true);
+        assert_eq!(run(&mut hook, (0, 1), false), [(0, 1, false)]);
+        set_keyboard_locked(false);
+    }
+}
@@ -0,0 +1,229 @@
+//! Stream of key presses and releases polled over raw HID, for host apps playing typing sounds
+//! or drawing visualizations. It carries matrix positions only, never keycodes, and is off until enabled.
+
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicBool, Ordering};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Instant};
+use heapless::Deque;
+use rmk::event::KeyEvent;
+
+use crate::event::{HookContext, KeyEventHook};
+use crate::log::LogModule;
+use crate::log_info;
+
+
+/// Raw HID command id of the key stream, `[KEY_STREAM_COMMAND, subcommand, ...]`
+pub const KEY_STREAM_COMMAND: u8 = 0xEC;
+/// `[KEY_STREAM_COMMAND, KEY_STREAM_READ]`, responds `[KEY_STREAM_COMMAND, events, dropped, records...]`
+/// and removes the events read, see [`KeyStreamRecord`]
+pub const KEY_STREAM_READ: u8 = 0;
+/// `[KEY_STREAM_COMMAND, KEY_STREAM_ANONYMOUS, anonymous]`, hide the positions as `0xFF`
+pub const KEY_STREAM_ANONYMOUS: u8 = 1;
+
+/// Events queued between polls, older ones are dropped when full
+const QUEUE_SIZE: usize = 32;
+/// Bytes of an event in the raw HID response
+const RECORD_SIZE: usize = 5;
+
+/// Events per window, more are dropped to bound the host's work and the stream's timing detail
+const RATE_LIMIT: u8 = 20;
+const RATE_WINDOW: Duration = Duration::from_millis(250);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct KeyStreamRecord {
+    /// Milliseconds since boot, wrapping
+    pub time_ms: u16,
+    pub row: u8,
+    pub col: u8,
+    pub pressed: bool,
+}
+
+impl KeyStreamRecord {
+    /// `[time_ms (u16 LE), row, col, pressed]`
+    fn to_bytes(self) -> [u8; RECORD_SIZE] {
+        let time = self.time_ms.to_le_bytes();
+        [time[0], time[1], self.row, self.col, self.pressed as u8]
+    }
+}
+
+struct KeyStream {
+    queue: Deque<KeyStreamRecord, QUEUE_SIZE>,
+    /// Events dropped since the last read, saturating
+    dropped: u8,
+    window_start: Instant,
+    window_events: u8,
+}
+
+/// RAM taken by the queue, for the [RAM budget](crate::ram_budget)
+pub(crate) const KEY_STREAM_RAM: usize = core::mem::size_of::<KeyStream>();
+
+static KEY_STREAM: Mutex<CriticalSectionRawMutex, RefCell<KeyStream>> = Mutex::new(RefCell::new(KeyStream {
+    queue: Deque::new(),
+    dropped: 0,
+    window_start: Instant::from_ticks(0),
+    window_events: 0,
+}));
+static KEY_STREAM_ENABLED: AtomicBool = AtomicBool::new(false);
+static ANONYMOUS: AtomicBool = AtomicBool::new(false);
+
+pub fn is_key_stream_enabled() -> bool {
+    KEY_STREAM_ENABLED.load(Ordering::Relaxed)
+}
+
+/// Start or stop the stream, queued events are discarded when stopping
+pub fn toggle_key_stream() {
+    let enabled = !KEY_STREAM_ENABLED.load(Ordering::Relaxed);
+    log_info!(LogModule::Action, "Key stream: {}", enabled);
+    KEY_STREAM_ENABLED.store(enabled, Ordering::Relaxed);
+    if !enabled {
+        KEY_STREAM.lock(|s| {
+            let mut s = s.borrow_mut();
+            s.queue.clear();
+            s.dropped = 0;
+        });
+    }
+}
+
+fn push_event(event: &KeyEvent) {
+    let now = Instant::now();
+    let anonymous = ANONYMOUS.load(Ordering::Relaxed);
+    let record = KeyStreamRecord {
+        time_ms: now.as_millis() as u16,
+        row: if anonymous { 0xFF } else { event.row },
+        col: if anonymous { 0xFF } else { event.col },
+        pressed: event.pressed,
+    };
+    KEY_STREAM.lock(|s| {
+        let mut s = s.borrow_mut();
+        if now.duration_since(s.window_start) >= RATE_WINDOW {
+            s.window_start = now;
+            s.window_events = 0;
+        }
+        if s.window_events >= RATE_LIMIT {
+            s.dropped = s.dropped.saturating_add(1);
+            return;
+        }
+        s.window_events += 1;
+        if s.queue.is_full() {
+            s.queue.pop_front();
+            s.dropped = s.dropped.saturating_add(1);
+        }
+        let _ = s.queue.push_back(record);
+    });
+}
+
+/// Hook queueing the events while the stream is enabled, forwards every event
+pub struct KeyStreamHook;
+
+impl KeyEventHook for KeyStreamHook {
+    async fn process(&mut self, event: KeyEvent, _ctx: &mut HookContext) -> Option<KeyEvent> {
+        if is_key_stream_enabled() {
+            push_event(&event);
+        }
+        Some(event)
+    }
+}
+
+/// Answer a key stream command in place, a disabled stream reads no events.
+/// Returns false if the report isn't a key stream command.
+pub fn handle_key_stream_command(report: &mut [u8]) -> bool {
+    if report.len() < 3 || report[0] != KEY_STREAM_COMMAND {
+        return false;
+    }
+    match report[1] {
+        KEY_STREAM_READ => {
+            report[1..].fill(0);
+            KEY_STREAM.lock(|s| {
+                let mut s = s.borrow_mut();
+                let mut count = 0;
+                for chunk in report[3..].chunks_exact_mut(RECORD_SIZE) {
+                    let Some(record) = s.queue.pop_front() else {
+                        break;
+                    };
+                    chunk.copy_from_slice(&record.to_bytes());
+                    count += 1;
+                }
+                report[1] = count;
+                report[2] = s.dropped;
+                s.dropped = 0;
+            });
+        }
+        KEY_STREAM_ANONYMOUS => {
+            ANONYMOUS.store(report[2] != 0, Ordering::Relaxed);
+            report[1..].fill(0);
+        }
+        _ => return false,
+    }
+    true
+}
+
+
+#[cfg(test)]
+mod tests {
+    use embassy_sync::channel::Channel;
+
+    use super::*;
+    use crate::event::{process_key_event, ChannelSink};
+    use crate::test_support::serial;
+
+    fn run(row: u8, col: u8, pressed: bool) -> std::vec::Vec<(u8, u8, bool)> {
+        let channel: Channel<CriticalSectionRawMutex, KeyEvent, 8> = Channel::new();
+        let event = KeyEvent { row, col, pressed };
+        embassy_futures::block_on(process_key_event(&mut KeyStreamHook, &mut ChannelSink::new(&channel), event));
+        core::iter::from_fn(|| channel.try_receive().ok())
+            .map(|e| (e.row, e.col, e.pressed))
+            .collect()
+    }
+
+    fn read() -> [u8; 32] {
+        let mut report = [0u8; 32];
+        report[..3].copy_from_slice(&[KEY_STREAM_COMMAND, KEY_STREAM_READ, 0]);
+        assert!(handle_key_stream_command(&mut report));
+        report
+    }
+
+    /// Start the stream over from empty
+    fn enable() {
+        if is_key_stream_enabled() {
+            toggle_key_stream();
+        }
+        toggle_key_stream();
+        ANONYMOUS.store(false, Ordering::Relaxed);
+    }
+
+    #[test]
+    fn disabled_stream_forwards_without_queueing() {
+        let _serial = serial();
+        enable();
+        toggle_key_stream();
+        assert_eq!(run(1, 2, true), [(1, 2, true)]);
+        assert_eq!(read()[1], 0);
+    }
+
+    #[test]
+    fn enabled_stream_queues_the_positions() {
+        let _serial = serial();
+        enable();
+        assert_eq!(run(1, 2, true), [(1, 2, true)]);
+        run(1, 2, false);
+        let report = read();
+        assert_eq!(report[1..3], [2, 0]);
+        assert_eq!(report[5..8], [1, 2, 1]);
+        assert_eq!(report[10..13], [1, 2, 0]);
+        // Read events are removed
+        assert_eq!(read()[1], 0);
+    }
+
+    #[test]
+    fn anonymous_stream_hides_the_positions() {
+        let _serial = serial();
+        enable();
+        let mut report = [KEY_STREAM_COMMAND, KEY_STREAM_ANONYMOUS, 1];
+        assert!(handle_key_stream_command(&mut report));
+        run(0, 1, true);
+        let report = read();
+        assert_eq!(report[5..8], [0xFF, 0xFF, 1]);
+        ANONYMOUS.store(false, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,168 @@
+//! Checks of the keymap usable in const assertions, so mistakes fail the build instead of misbehaving,
+//! e.g. `const _: () = assert!(layers_in_range(&KEYMAP));`.
+
+use rmk::action::{Action, KeyAction};
+
+use crate::action::CustomKey;
+use crate::debounce::MatrixRegion;
+
+
+const fn action_layer(action: &Action) -> Option<u8> {
+    match action {
+        Action::LayerOn(layer) | Action::LayerOff(layer) | Action::LayerToggle(layer) => Some(*layer),
+        _ => None,
+    }
+}
+
+/// Layers the key action switches to, up to two
+const fn key_action_layers(action: &KeyAction) -> [Option<u8>; 2] {
+    match action {
+        KeyAction::Single(a) | KeyAction::Tap(a) | KeyAction::OneShot(a) => [action_layer(a), None],
+        KeyAction::WithModifier(a, _) => [action_layer(a), None],
+        KeyAction::TapHold(tap, hold) => [action_layer(tap), action_layer(hold)],
+        KeyAction::LayerTapHold(tap, layer) => [action_layer(tap), Some(*layer)],
+        _ => [None, None],
+    }
+}
+
+/// Whether every layer referenced by `MO`, `TG`, `LT` etc. exists
+pub const fn layers_in_range<const ROW: usize, const COL: usize, const NUM_LAYER: usize>(
+    keymap: &[[[KeyAction; COL]; ROW]; NUM_LAYER],
+) -> bool {
+    let mut layer = 0;
+    while layer < NUM_LAYER {
+        let mut row = 0;
+        while row < ROW {
+            let mut col = 0;
+            while col < COL {
+                let layers = key_action_layers(&keymap[layer][row][col]);
+                let mut i = 0;
+                while i < layers.len() {
+                    if let Some(target) = layers[i] {
+                        if target as usize >= NUM_LAYER {
+                            return false;
+                        }
+                    }
+                    i += 1;
+                }
+                col += 1;
+            }
+            row += 1;
+        }
+        layer += 1;
+    }
+    true
+}
+
+/// Whether every custom key is inside the matrix and on an existing layer, and no two of them are bound
+/// at the same position on the same layer, which would leave one out of reach
+pub const fn custom_keys_in_range(keys: &[CustomKey], rows: usize, cols: usize, layers: usize) -> bool {
+    let mut i = 0;
+    while i < keys.len() {
+        let key = &keys[i];
+        if key.row as usize >= rows || key.col as usize >= cols {
+            return false;
+        }
+        if let Some(layer) = key.layer {
+            if layer as usize >= layers {
+                return false;
+            }
+        }
+        let mut j = 0;
+        while j < i {
+            let other = &keys[j];
+            let same_layer = match (key.layer, other.layer) {
+                (Some(a), Some(b)) => a == b,
+                (None, None) => true,
+                _ => false,
+            };
+            if same_layer && other.row == key.row && other.col == key.col {
+                return false;
+            }
+            j += 1;
+        }
+        i += 1;
+    }
+    true
+}
+
+/// Whether the matrices of the halves cover every position of the `rows`x`cols` keymap once,
+/// so that no key of the keymap is out of reach and no two physical keys share a position
+pub const fn matrices_tile(matrices: &[MatrixRegion], rows: usize, cols: usize) -> bool {
+    let mut row = 0;
+    while row < rows {
+        let mut col = 0;
+        while col < cols {
+            let mut covered = 0;
+            let mut i = 0;
+            while i < matrices.len() {
+                let m = &matrices[i];
+                if (m.rows.start as usize) <= row
+                    && row < m.rows.end as usize
+                    && (m.cols.start as usize) <= col
+                    && col < m.cols.end as usize
+                {
+                    covered += 1;
+                }
+                i += 1;
+            }
+            if covered != 1 {
+                return false;
+            }
+            col += 1;
+        }
+        row += 1;
+    }
+    // Nothing beyond the keymap either
+    let mut i = 0;
+    while i < matrices.len() {
+        if matrices[i].rows.end as usize > rows || matrices[i].cols.end as usize > cols {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn halves_side_by_side_tile() {
+        let halves = [MatrixRegion::new(0..2, 0..2), MatrixRegion::new(0..2, 2..4)];
+        assert!(matrices_tile(&halves, 2, 4));
+    }
+
+    #[test]
+    fn gap_or_overlap_doesnt_tile() {
+        let gap = [MatrixRegion::new(0..2, 0..2), MatrixRegion::new(2..4, 1..3)];
+        assert!(!matrices_tile(&gap, 4, 3));
+        let overlap = [MatrixRegion::new(0..2, 0..2), MatrixRegion::new(0..2, 1..3)];
+        assert!(!matrices_tile(&overlap, 2, 3));
+    }
+
+    #[test]
+    fn matrix_beyond_the_keymap_doesnt_tile() {
+        let halves = [MatrixRegion::new(0..2, 0..2), MatrixRegion::new(0..2, 2..5)];
+        assert!(!matrices_tile(&halves, 2, 4));
+    }
+
+    #[test]
+    fn custom_keys_checked_per_layer() {
+        use crate::action::CustomAction;
+        let keys = [
+            CustomKey::new(0, 0, CustomAction::Version),
+            CustomKey::on_layer(1, 0, 0, CustomAction::ResetConfig),
+            CustomKey::on_layer(2, 0, 0, CustomAction::Bootloader),
+        ];
+        assert!(custom_keys_in_range(&keys, 1, 1, 3));
+        assert!(!custom_keys_in_range(&keys, 1, 1, 2));
+        let shadowed = [
+            CustomKey::on_layer(1, 0, 0, CustomAction::ResetConfig),
+            CustomKey::on_layer(1, 0, 0, CustomAction::Bootloader),
+        ];
+        assert!(!custom_keys_in_range(&shadowed, 1, 1, 2));
+    }
+}
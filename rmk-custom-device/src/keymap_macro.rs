@@ -0,0 +1,213 @@
+//! Compact keymap syntax, so the keymap reads like the layout instead of nested `KeyAction` constructors.
+//!
+//! ```ignore
+//! layer_names!(pub(crate) BASE, FN);
+//! key_aliases! {
+//!     const HRM_A = MT(A, LGui);
+//! }
+//! const KEYMAP: [[[KeyAction; COL]; ROW]; NUM_LAYER] = keymap! {
+//!     BASE: [
+//!         [@HRM_A  B       C]
+//!         [MO(FN)  LT(FN, Space)  XX]
+//!     ],
+//!     FN: [
+//!         [_  {k!(Kp8)}  TG(FN)]
+//!         [_  _          _]
+//!     ],
+//! };
+//! ```
+//!
+//! Keys of a row are separated by spaces:
+//! - `A`, `Kp1`, `LShift`: the `KeyCode`
+//! - `Ro`, `Kana`, `Yen`, `Henkan`, `Muhenkan`, `Hangul`, `Hanja`: JIS and Korean keys by their legends,
+//!   the `International` and `Language` keycodes otherwise
+//! - `_`: transparent, `XX`: no action
+//! - `MO(l)`, `TG(l)`, `OSL(l)`, `LT(l, key)`: layer keys, `l` is a layer name or index
+//! - `MT(key, mods)`, `WM(key, mods)`, `OSM(mods)`: modifiers joined by `|`, e.g. `LShift | LGui`
+//! - `@ALIAS`: an alias of `key_aliases!`
+//! - `{expr}`: any `KeyAction` expression
+
+use rmk::keycode::ModifierCombination;
+
+#[doc(hidden)]
+pub use rmk::action::{Action, KeyAction};
+#[doc(hidden)]
+pub use rmk::keycode::KeyCode;
+
+
+/// Combine modifier keycodes, the right ones make the whole combination right-handed
+pub const fn modifiers(keys: &[KeyCode]) -> ModifierCombination {
+    let (mut right, mut gui, mut alt, mut shift, mut ctrl) = (false, false, false, false, false);
+    let mut i = 0;
+    while i < keys.len() {
+        match keys[i] {
+            KeyCode::LGui => gui = true,
+            KeyCode::LAlt => alt = true,
+            KeyCode::LShift => shift = true,
+            KeyCode::LCtrl => ctrl = true,
+            KeyCode::RGui => (right, gui) = (true, true),
+            KeyCode::RAlt => (right, alt) = (true, true),
+            KeyCode::RShift => (right, shift) = (true, true),
+            KeyCode::RCtrl => (right, ctrl) = (true, true),
+            _ => panic!("not a modifier keycode"),
+        }
+        i += 1;
+    }
+    ModifierCombination::new_from(right, gui, alt, shift, ctrl)
+}
+
+/// Whether the layer names are listed in the order of their indices
+pub const fn layers_in_order(layers: &[u8]) -> bool {
+    let mut i = 0;
+    while i < layers.len() {
+        if layers[i] as usize != i {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+// The international keys are basic keycodes, which Vial assigns by their HID usage ids
+const _: () = assert!(
+    KeyCode::International1 as u16 == 0x87 && KeyCode::International9 as u16 == 0x8F,
+    "International1-9 aren't at their HID usage ids"
+);
+const _: () = assert!(
+    KeyCode::Language1 as u16 == 0x90 && KeyCode::Language9 as u16 == 0x98,
+    "Language1-9 aren't at their HID usage ids"
+);
+const _: () = assert!(
+    KeyCode::F13 as u16 == 0x68 && KeyCode::F24 as u16 == 0x73,
+    "F13-F24 aren't at their HID usage ids"
+);
+
+
+/// Declare the layer indices by name, in order, e.g. `layer_names!(pub(crate) BASE, LOWER, RAISE);`
+#[macro_export]
+macro_rules! layer_names {
+    ($vis:vis $($name:ident),+ $(,)?) => {
+        $crate::layer_names!(@next $vis 0u8; $($name),+);
+    };
+    (@next $vis:vis $index:expr; $name:ident $(, $rest:ident)*) => {
+        $vis const $name: u8 = $index;
+        $crate::layer_names!(@next $vis $index + 1; $($rest),*);
+    };
+    (@next $vis:vis $index:expr;) => {};
+}
+
+/// Declare named keys, usable as `@NAME` in `keymap!`, e.g. `const HRM_A = MT(A, LGui);`
+#[macro_export]
+macro_rules! key_aliases {
+    ($($vis:vis const $name:ident = $key:tt $(($($args:tt)*))?;)+) => {
+        $(
+            $vis const $name: $crate::keymap_macro::KeyAction = $crate::keymap_key!($key $(($($args)*))?);
+        )+
+    };
+}
+
+/// A `KeyAction` of the compact syntax
+#[macro_export]
+macro_rules! keymap_key {
+    (_) => {
+        $crate::keymap_macro::KeyAction::Transparent
+    };
+    (XX) => {
+        $crate::keymap_macro::KeyAction::No
+    };
+    (@ $alias:ident) => {
+        $alias
+    };
+    ({ $action:expr }) => {
+        $action
+    };
+    (MO($layer:expr)) => {
+        $crate::keymap_macro::KeyAction::Single($crate::keymap_macro::Action::LayerOn($layer))
+    };
+    (TG($layer:expr)) => {
+        $crate::keymap_macro::KeyAction::Single($crate::keymap_macro::Action::LayerToggle($layer))
+    };
+    (OSL($layer:expr)) => {
+        $crate::keymap_macro::KeyAction::OneShot($crate::keymap_macro::Action::LayerOn($layer))
+    };
+    (LT($layer:expr, $key:ident)) => {
+        $crate::keymap_macro::KeyAction::LayerTapHold(
+            $crate::keymap_macro::Action::Key($crate::keymap_macro::KeyCode::$key),
+            $layer,
+        )
+    };
+    (MT($key:ident, $($modifier:ident)|+)) => {
+        $crate::keymap_macro::KeyAction::ModifierTapHold(
+            $crate::keymap_macro::Action::Key($crate::keymap_macro::KeyCode::$key),
+            $crate::keymap_macro::modifiers(&[$($crate::keymap_macro::KeyCode::$modifier),+]),
+        )
+    };
+    (WM($key:ident, $($modifier:ident)|+)) => {
+        $crate::keymap_macro::KeyAction::WithModifier(
+            $crate::keymap_macro::Action::Key($crate::keymap_macro::KeyCode::$key),
+            $crate::keymap_macro::modifiers(&[$($crate::keymap_macro::KeyCode::$modifier),+]),
+        )
+    };
+    (OSM($($modifier:ident)|+)) => {
+        $crate::keymap_macro::KeyAction::OneShot($crate::keymap_macro::Action::Modifier(
+            $crate::keymap_macro::modifiers(&[$($crate::keymap_macro::KeyCode::$modifier),+]),
+        ))
+    };
+    (Ro) => {
+        $crate::keymap_key!(International1)
+    };
+    (Kana) => {
+        $crate::keymap_key!(International2)
+    };
+    (Yen) => {
+        $crate::keymap_key!(International3)
+    };
+    (Henkan) => {
+        $crate::keymap_key!(International4)
+    };
+    (Muhenkan) => {
+        $crate::keymap_key!(International5)
+    };
+    (Hangul) => {
+        $crate::keymap_key!(Language1)
+    };
+    (Hanja) => {
+        $crate::keymap_key!(Language2)
+    };
+    ($key:ident) => {
+        $crate::keymap_macro::KeyAction::Single($crate::keymap_macro::Action::Key(
+            $crate::keymap_macro::KeyCode::$key,
+        ))
+    };
+}
+
+/// A row of keys of the compact syntax, munched one key at a time
+#[doc(hidden)]
+#[macro_export]
+macro_rules! keymap_row {
+    ([$($done:expr,)*]) => {
+        [$($done),*]
+    };
+    ([$($done:expr,)*] @ $alias:ident $($rest:tt)*) => {
+        $crate::keymap_row!([$($done,)* $crate::keymap_key!(@ $alias),] $($rest)*)
+    };
+    ([$($done:expr,)*] $name:ident ($($args:tt)*) $($rest:tt)*) => {
+        $crate::keymap_row!([$($done,)* $crate::keymap_key!($name($($args)*)),] $($rest)*)
+    };
+    ([$($done:expr,)*] $key:tt $($rest:tt)*) => {
+        $crate::keymap_row!([$($done,)* $crate::keymap_key!($key),] $($rest)*)
+    };
+}
+
+/// The keymap array of layers in the compact syntax, see the module doc.
+/// Layer names must be given in the order of their indices.
+#[macro_export]
+macro_rules! keymap {
+    ($($layer:ident: [$([$($key:tt)*])*]),+ $(,)?) => {{
+        const _: () = assert!(
+            $crate::keymap_macro::layers_in_order(&[$($layer),+]),
+            "layers of keymap! are out of the order of their names"
+        );
+        [$([$($crate::keymap_row!([] $($key)*)),*]),+]
+    }};
+}
@@ -0,0 +1,162 @@
+//! Names and stable ids of key actions, shared by the displays, the logs and raw HID,
+//! and a snapshot of the keymap to look them up by position.
+//!
+//! Ids follow QMK's keycode numbering as Vial reports it, so host tools can map them with their own tables.
+//! Actions without a QMK counterpart here are [`UNKNOWN_ACTION_ID`].
+
+use core::cell::RefCell;
+use core::fmt::Write;
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use heapless::String;
+use rmk::action::{Action, KeyAction};
+
+
+/// Raw HID command id naming a key of the keymap, `[KEYMAP_NAME_COMMAND, layer, row, col]`.
+/// Responds `[KEYMAP_NAME_COMMAND, status, id (u16 LE), len, name...]`, status is 0 on success
+/// and 1 if the position is out of the snapshot.
+pub const KEYMAP_NAME_COMMAND: u8 = 0xF2;
+
+const STATUS_OK: u8 = 0;
+const STATUS_OUT_OF_RANGE: u8 = 1;
+
+/// Room for the firmwares' 7 layers
+pub const MAX_NAMED_LAYERS: usize = 8;
+pub const MAX_NAMED_ROWS: usize = 8;
+pub const MAX_NAMED_COLS: usize = 16;
+
+/// Id of the actions without a QMK keycode
+pub const UNKNOWN_ACTION_ID: u16 = 0xFFFF;
+
+/// Short name of an action, e.g. `A`, `MO 1`, `ESCAPE/L2`
+pub type ActionName = String<16>;
+
+type Snapshot = [[[KeyAction; MAX_NAMED_COLS]; MAX_NAMED_ROWS]; MAX_NAMED_LAYERS];
+
+/// RAM taken by the snapshot, for the [RAM budget](crate::ram_budget)
+pub(crate) const KEYMAP_SNAPSHOT_RAM: usize = core::mem::size_of::<Snapshot>();
+
+static KEYMAP: Mutex<CriticalSectionRawMutex, RefCell<Snapshot>> =
+    Mutex::new(RefCell::new([[[KeyAction::No; MAX_NAMED_COLS]; MAX_NAMED_ROWS]; MAX_NAMED_LAYERS]));
+
+fn write_action(name: &mut ActionName, action: &Action) {
+    let _ = match action {
+        Action::No => write!(name, "XX"),
+        Action::Transparent => write!(name, "_"),
+        Action::Key(key) => write!(name, "{:?}", key),
+        Action::LayerOn(layer) => write!(name, "MO {}", layer),
+        Action::LayerToggle(layer) => write!(name, "TG {}", layer),
+        _ => write!(name, "?"),
+    };
+}
+
+/// Short name of the action, `?` for the parts it doesn't know
+pub fn key_action_name(action: &KeyAction) -> ActionName {
+    let mut name = String::new();
+    match action {
+        KeyAction::No => write_action(&mut name, &Action::No),
+        KeyAction::Transparent => write_action(&mut name, &Action::Transparent),
+        KeyAction::Single(a) | KeyAction::Tap(a) | KeyAction::WithModifier(a, _) => write_action(&mut name, a),
+        KeyAction::OneShot(a) => {
+            let _ = write!(name, "OS ");
+            write_action(&mut name, a);
+        }
+        KeyAction::TapHold(tap, hold) => {
+            write_action(&mut name, tap);
+            let _ = write!(name, "/");
+            write_action(&mut name, hold);
+        }
+        KeyAction::LayerTapHold(tap, layer) => {
+            write_action(&mut name, tap);
+            let _ = write!(name, "/L{}", layer);
+        }
+        _ => {
+            let _ = write!(name, "?");
+        }
+    }
+    name
+}
+
+/// Basic keycode of the action, QMK has them at 0x04-0xFF
+fn basic_keycode(action: &Action) -> Option<u16> {
+    match action {
+        Action::Key(key) if (*key as u16) <= 0xFF => Some(*key as u16),
+        _ => None,
+    }
+}
+
+/// Stable id of the action in QMK's numbering, [`UNKNOWN_ACTION_ID`] without one.
+/// Modifiers of `WM` and `MT` aren't encoded yet.
+pub fn key_action_id(action: &KeyAction) -> u16 {
+    let id = match action {
+        KeyAction::No => Some(0x0000),
+        KeyAction::Transparent => Some(0x0001),
+        KeyAction::Single(a) | KeyAction::Tap(a) => match a {
+            Action::No => Some(0x0000),
+            Action::Transparent => Some(0x0001),
+            Action::LayerOn(layer) if *layer < 32 => Some(0x5220 | *layer as u16),
+            Action::LayerToggle(layer) if *layer < 32 => Some(0x5260 | *layer as u16),
+            a => basic_keycode(a),
+        },
+        KeyAction::OneShot(Action::LayerOn(layer)) if *layer < 32 => Some(0x5280 | *layer as u16),
+        KeyAction::LayerTapHold(tap, layer) if *layer < 16 => {
+            basic_keycode(tap).map(|key| 0x4000 | (*layer as u16) << 8 | key)
+        }
+        _ => None,
+    };
+    id.unwrap_or(UNKNOWN_ACTION_ID)
+}
+
+/// Take a snapshot of the keymap for the lookups by position, the part beyond the snapshot's size is dropped.
+/// Built from the default keymap, so changes made by Vial at runtime are not reflected.
+pub fn register_keymap<const ROW: usize, const COL: usize, const NUM_LAYER: usize>(
+    keymap: &[[[KeyAction; COL]; ROW]; NUM_LAYER],
+) {
+    KEYMAP.lock(|k| {
+        let mut snapshot = k.borrow_mut();
+        for (layer, rows) in snapshot.iter_mut().zip(keymap.iter()) {
+            for (row, actions) in layer.iter_mut().zip(rows.iter()) {
+                for (slot, action) in row.iter_mut().zip(actions.iter()) {
+                    *slot = *action;
+                }
+            }
+        }
+    });
+}
+
+/// Action of the position on the layer, falling through the transparent keys.
+/// `None` if the position is out of the snapshot.
+pub fn resolve_key_action(layer: u8, row: u8, col: u8) -> Option<KeyAction> {
+    let (row, col) = (row as usize, col as usize);
+    if layer as usize >= MAX_NAMED_LAYERS || row >= MAX_NAMED_ROWS || col >= MAX_NAMED_COLS {
+        return None;
+    }
+    KEYMAP.lock(|k| {
+        let snapshot = k.borrow();
+        let action = (0..=layer as usize)
+            .rev()
+            .map(|l| snapshot[l][row][col])
+            .find(|action| !matches!(action, KeyAction::Transparent));
+        Some(action.unwrap_or(KeyAction::No))
+    })
+}
+
+/// Answer a keymap name command in place.
+/// Returns false if the report isn't a keymap name command.
+pub fn handle_keymap_name_command(report: &mut [u8]) -> bool {
+    if report.len() < 5 || report[0] != KEYMAP_NAME_COMMAND {
+        return false;
+    }
+    let action = resolve_key_action(report[1], report[2], report[3]);
+    report[1..].fill(0);
+    let Some(action) = action else {
+        report[1] = STATUS_OUT_OF_RANGE;
+        return true;
+    };
+    let name = key_action_name(&action);
+    let len = name.len().min(report.len() - 5);
+    report[1] = STATUS_OK;
+    report[2..4].copy_from_slice(&key_action_id(&action).to_le_bytes());
+    report[4] = len as u8;
+    report[5..5 + len].copy_from_slice(&name.as_bytes()[..len]);
+    true
+}
@@ -0,0 +1,222 @@
+//! Per-layer key colors edited over raw HID and persisted, e.g. navigation keys blue on the function layer.
+//! Keys without a color show the effect below, see [`LayerColorEffect`].
+
+use core::cell::RefCell;
+#[cfg(feature = "rp2040")]
+use embassy_rp::flash::{Flash, Instance, Mode};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_sync::signal::Signal;
+use embassy_time::Instant;
+
+use crate::layer_state::active_layer;
+use crate::log::LogModule;
+use crate::log_info;
+use crate::quiesce::quiesce_flash;
+#[cfg(feature = "rp2040")]
+use crate::reserved::write_reserved_sector;
+use crate::rgb::{LedMap, RgbEffect, RGB8};
+
+
+/// Raw HID command id of the layer colors, `[LAYER_COLOR_COMMAND, subcommand, ...]`
+pub const LAYER_COLOR_COMMAND: u8 = 0xED;
+/// `[LAYER_COLOR_COMMAND, LAYER_COLOR_GET, layer, row, col]`, responds `[.., status, set, r, g, b]`
+pub const LAYER_COLOR_GET: u8 = 0;
+/// `[LAYER_COLOR_COMMAND, LAYER_COLOR_SET, layer, row, col, set, r, g, b]`, unset keys show the effect below
+pub const LAYER_COLOR_SET: u8 = 1;
+/// `[LAYER_COLOR_COMMAND, LAYER_COLOR_CLEAR, layer]`, unset every key of the layer
+pub const LAYER_COLOR_CLEAR: u8 = 2;
+/// `[LAYER_COLOR_COMMAND, LAYER_COLOR_SAVE]`, persist the colors
+pub const LAYER_COLOR_SAVE: u8 = 3;
+
+/// Status of the responses, `[LAYER_COLOR_COMMAND, subcommand, status, ...]`
+const STATUS_OK: u8 = 0;
+const STATUS_OUT_OF_RANGE: u8 = 1;
+
+pub const MAX_COLOR_LAYERS: usize = 4;
+pub const MAX_COLOR_ROWS: usize = 8;
+pub const MAX_COLOR_COLS: usize = 16;
+const KEYS_PER_LAYER: usize = MAX_COLOR_ROWS * MAX_COLOR_COLS;
+
+/// Bytes of the persisted table, `[magic, (set, r, g, b) per key row by row, layer by layer]` LE
+const TABLE_SIZE: usize = 4 + MAX_COLOR_LAYERS * KEYS_PER_LAYER * 4;
+
+/// Colors of the keys on each layer, `None` for keys without one
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LayerColors {
+    colors: [[Option<RGB8>; KEYS_PER_LAYER]; MAX_COLOR_LAYERS],
+}
+
+impl LayerColors {
+    const MAGIC: u32 = 0x524C_4F43; // "COLR"
+
+    pub const fn new() -> Self {
+        Self {
+            colors: [[None; KEYS_PER_LAYER]; MAX_COLOR_LAYERS],
+        }
+    }
+
+    fn index(row: u8, col: u8) -> Option<usize> {
+        let (row, col) = (row as usize, col as usize);
+        (row < MAX_COLOR_ROWS && col < MAX_COLOR_COLS).then_some(row * MAX_COLOR_COLS + col)
+    }
+
+    pub fn color(&self, layer: u8, row: u8, col: u8) -> Option<RGB8> {
+        self.colors.get(layer as usize)?[Self::index(row, col)?]
+    }
+
+    /// Returns false if the position is out of the table
+    pub fn set_color(&mut self, layer: u8, row: u8, col: u8, color: Option<RGB8>) -> bool {
+        match (self.colors.get_mut(layer as usize), Self::index(row, col)) {
+            (Some(layer), Some(i)) => {
+                layer[i] = color;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns false if the layer is out of the table
+    pub fn clear_layer(&mut self, layer: u8) -> bool {
+        match self.colors.get_mut(layer as usize) {
+            Some(layer) => {
+                layer.fill(None);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn to_bytes(&self) -> [u8; TABLE_SIZE] {
+        let mut bytes = [0u8; TABLE_SIZE];
+        bytes[0..4].copy_from_slice(&Self::MAGIC.to_le_bytes());
+        for (chunk, color) in bytes[4..].chunks_exact_mut(4).zip(self.colors.iter().flatten()) {
+            if let Some(c) = color {
+                chunk.copy_from_slice(&[1, c.r, c.g, c.b]);
+            }
+        }
+        bytes
+    }
+
+    /// Returns `None` if the bytes aren't colors, e.g. erased
+    fn from_bytes(bytes: &[u8; TABLE_SIZE]) -> Option<Self> {
+        if bytes[0..4] != Self::MAGIC.to_le_bytes() {
+            return None;
+        }
+        let mut colors = Self::new();
+        for (color, chunk) in colors.colors.iter_mut().flatten().zip(bytes[4..].chunks_exact(4)) {
+            *color = (chunk[0] == 1).then(|| RGB8::new(chunk[1], chunk[2], chunk[3]));
+        }
+        Some(colors)
+    }
+}
+
+impl Default for LayerColors {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+static LAYER_COLORS: Mutex<CriticalSectionRawMutex, RefCell<LayerColors>> = Mutex::new(RefCell::new(LayerColors::new()));
+static LAYER_COLORS_SAVE_REQUEST: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+pub fn layer_color(layer: u8, row: u8, col: u8) -> Option<RGB8> {
+    LAYER_COLORS.lock(|c| c.borrow().color(layer, row, col))
+}
+
+/// Read the colors saved by the previous boot. Call it before handing the flash to rmk.
+#[cfg(feature = "rp2040")]
+pub fn load_layer_colors<T: Instance, M: Mode, const FLASH_SIZE: usize>(
+    flash: &mut Flash<'_, T, M, FLASH_SIZE>,
+    offset: u32,
+) -> bool {
+    let mut bytes = [0u8; TABLE_SIZE];
+    if flash.blocking_read(offset, &mut bytes).is_err() {
+        return false;
+    }
+    match LayerColors::from_bytes(&bytes) {
+        Some(colors) => {
+            LAYER_COLORS.lock(|c| *c.borrow_mut() = colors);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Save the colors to the reserved sector at `offset`
+#[cfg(feature = "rp2040")]
+pub fn save_layer_colors<const FLASH_SIZE: usize>(offset: u32, colors: &LayerColors) {
+    if let Err(e) = write_reserved_sector::<FLASH_SIZE>(offset, &colors.to_bytes()) {
+        defmt::warn!("Failed to save layer colors: {}", e);
+    }
+}
+
+/// Answer a layer color command in place.
+/// Returns false if the report isn't a layer color command.
+pub fn handle_layer_color_command(report: &mut [u8]) -> bool {
+    if report.len() < 9 || report[0] != LAYER_COLOR_COMMAND {
+        return false;
+    }
+    let (layer, row, col) = (report[2], report[3], report[4]);
+    let in_range = (layer as usize) < MAX_COLOR_LAYERS && LayerColors::index(row, col).is_some();
+    let status_of = |ok: bool| if ok { STATUS_OK } else { STATUS_OUT_OF_RANGE };
+    let status = match report[1] {
+        LAYER_COLOR_GET => {
+            let color = layer_color(layer, row, col);
+            let c = color.unwrap_or_default();
+            report[2..].fill(0);
+            report[2] = status_of(in_range);
+            report[3..7].copy_from_slice(&[color.is_some() as u8, c.r, c.g, c.b]);
+            return true;
+        }
+        LAYER_COLOR_SET => {
+            let color = (report[5] != 0).then(|| RGB8::new(report[6], report[7], report[8]));
+            status_of(LAYER_COLORS.lock(|c| c.borrow_mut().set_color(layer, row, col, color)))
+        }
+        LAYER_COLOR_CLEAR => status_of(LAYER_COLORS.lock(|c| c.borrow_mut().clear_layer(layer))),
+        LAYER_COLOR_SAVE => {
+            LAYER_COLORS_SAVE_REQUEST.signal(());
+            STATUS_OK
+        }
+        _ => return false,
+    };
+    report[2..].fill(0);
+    report[2] = status;
+    true
+}
+
+/// Wait for the save command and pass the colors to `on_save`, e.g. [`save_layer_colors`].
+/// This function should never return.
+pub async fn run_layer_colors_save<F: FnMut(&LayerColors)>(mut on_save: F) -> ! {
+    loop {
+        LAYER_COLORS_SAVE_REQUEST.wait().await;
+        log_info!(LogModule::Device, "Layer colors saved");
+        let colors = LAYER_COLORS.lock(|c| *c.borrow());
+        quiesce_flash(async { on_save(&colors) }).await;
+    }
+}
+
+
+/// Effect drawing the colors of the active layer over the keys which have one. Put it on top of the regular effect.
+pub struct LayerColorEffect<const N: usize> {
+    map: LedMap<N>,
+}
+
+impl<const N: usize> LayerColorEffect<N> {
+    pub const fn new(map: LedMap<N>) -> Self {
+        Self { map }
+    }
+}
+
+impl<const N: usize> RgbEffect<N> for LayerColorEffect<N> {
+    fn render(&mut self, frame: &mut [RGB8; N], _now: Instant) {
+        let layer = active_layer();
+        LAYER_COLORS.lock(|c| {
+            let colors = c.borrow();
+            for (pixel, position) in frame.iter_mut().zip(self.map.positions.iter()) {
+                if let Some(color) = position.and_then(|(row, col)| colors.color(layer, row, col)) {
+                    *pixel = color;
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,162 @@
+//! Highlight the keys bound on a momentary layer while its layer key is held.
+
+use core::fmt::Write as _;
+use core::sync::atomic::{AtomicU8, Ordering};
+use embassy_time::Instant;
+use rmk::{
+    action::{Action, KeyAction},
+    event::KeyEvent,
+};
+
+use crate::{
+    event::{HookContext, KeyEventHook},
+    layer_state::active_layer,
+    rgb::{LedMap, RgbEffect, RGB8},
+    shell::{ShellCommand, ShellReply},
+};
+
+
+const NO_PREVIEW: u8 = u8::MAX;
+
+static PREVIEW_LAYER: AtomicU8 = AtomicU8::new(NO_PREVIEW);
+
+/// Layer whose layer key is currently held
+pub fn previewed_layer() -> Option<u8> {
+    match PREVIEW_LAYER.load(Ordering::Relaxed) {
+        NO_PREVIEW => None,
+        layer => Some(layer),
+    }
+}
+
+/// Shell command showing the active layer
+pub struct LayerShell;
+
+impl ShellCommand for LayerShell {
+    fn name(&self) -> &'static str {
+        "layer"
+    }
+
+    fn usage(&self) -> &'static str {
+        "layer show"
+    }
+
+    fn run(&self, args: &[&str], reply: &mut ShellReply) -> Result<(), &'static str> {
+        match args {
+            ["show"] => {
+                let _ = writeln!(reply, "active layer {}", active_layer());
+                Ok(())
+            }
+            _ => Err(self.usage()),
+        }
+    }
+}
+
+/// What the renderer needs to know from the keymap.
+/// Built from the default keymap, so changes made by Vial at runtime are not reflected.
+#[derive(Clone, Copy)]
+pub struct KeymapSummary<const ROW: usize, const COL: usize, const NUM_LAYER: usize> {
+    /// Whether each key has a non-transparent binding on each layer
+    bound: [[[bool; COL]; ROW]; NUM_LAYER],
+    /// Momentary layer of each key, from the lowest layer binding one
+    momentary: [[Option<u8>; COL]; ROW],
+}
+
+impl<const ROW: usize, const COL: usize, const NUM_LAYER: usize> KeymapSummary<ROW, COL, NUM_LAYER> {
+    pub fn from_keymap(keymap: &[[[KeyAction; COL]; ROW]; NUM_LAYER]) -> Self {
+        let mut bound = [[[false; COL]; ROW]; NUM_LAYER];
+        let mut momentary = [[None; COL]; ROW];
+        for (layer, rows) in keymap.iter().enumerate() {
+            for (row, actions) in rows.iter().enumerate() {
+                for (col, action) in actions.iter().enumerate() {
+                    bound[layer][row][col] = !matches!(action, KeyAction::Transparent);
+                    if let KeyAction::Single(Action::LayerOn(target)) = action {
+                        momentary[row][col].get_or_insert(*target);
+                    }
+                }
+            }
+        }
+        Self { bound, momentary }
+    }
+
+    pub fn is_bound(&self, layer: u8, row: u8, col: u8) -> bool {
+        self.bound
+            .get(layer as usize)
+            .and_then(|rows| rows.get(row as usize))
+            .and_then(|cols| cols.get(col as usize))
+            .copied()
+            .unwrap_or(false)
+    }
+
+    pub fn momentary_layer(&self, row: u8, col: u8) -> Option<u8> {
+        self.momentary
+            .get(row as usize)
+            .and_then(|cols| cols.get(col as usize))
+            .copied()
+            .flatten()
+    }
+}
+
+
+/// Hook tracking the held momentary layer key, forwards every event.
+pub struct LayerPreviewHook<const ROW: usize, const COL: usize, const NUM_LAYER: usize> {
+    summary: KeymapSummary<ROW, COL, NUM_LAYER>,
+    /// Position of the layer key being previewed
+    held: Option<(u8, u8)>,
+}
+
+impl<const ROW: usize, const COL: usize, const NUM_LAYER: usize> LayerPreviewHook<ROW, COL, NUM_LAYER> {
+    pub fn new(summary: KeymapSummary<ROW, COL, NUM_LAYER>) -> Self {
+        Self { summary, held: None }
+    }
+}
+
+impl<const ROW: usize, const COL: usize, const NUM_LAYER: usize> KeyEventHook
+    for LayerPreviewHook<ROW, COL, NUM_LAYER>
+{
+    async fn process(&mut self, event: KeyEvent, _ctx: &mut HookContext) -> Option<KeyEvent> {
+        let position = (event.row, event.col);
+        if event.pressed {
+            if let Some(layer) = self.summary.momentary_layer(event.row, event.col) {
+                self.held = Some(position);
+                PREVIEW_LAYER.store(layer, Ordering::Relaxed);
+            }
+        } else if self.held == Some(position) {
+            self.held = None;
+            PREVIEW_LAYER.store(NO_PREVIEW, Ordering::Relaxed);
+        }
+        Some(event)
+    }
+}
+
+
+/// Effect drawing the keys bound on the previewed layer in the color and the others black.
+/// Draws nothing when no layer key is held, so put it on top of the regular effect.
+pub struct LayerPreviewEffect<const N: usize, const ROW: usize, const COL: usize, const NUM_LAYER: usize> {
+    summary: KeymapSummary<ROW, COL, NUM_LAYER>,
+    leds: LedMap<N>,
+    color: RGB8,
+}
+
+impl<const N: usize, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
+    LayerPreviewEffect<N, ROW, COL, NUM_LAYER>
+{
+    pub fn new(summary: KeymapSummary<ROW, COL, NUM_LAYER>, leds: LedMap<N>, color: RGB8) -> Self {
+        Self { summary, leds, color }
+    }
+}
+
+impl<const N: usize, const ROW: usize, const COL: usize, const NUM_LAYER: usize> RgbEffect<N>
+    for LayerPreviewEffect<N, ROW, COL, NUM_LAYER>
+{
+    fn render(&mut self, frame: &mut [RGB8; N], _now: Instant) {
+        let Some(layer) = previewed_layer() else {
+            return;
+        };
+        for (pixel, position) in frame.iter_mut().zip(self.leds.positions.iter()) {
+            *pixel = match position {
+                Some((row, col)) if self.summary.is_bound(layer, *row, *col) => self.color,
+                _ => RGB8::default(),
+            };
+        }
+    }
+}
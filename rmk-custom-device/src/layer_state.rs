@@ -0,0 +1,308 @@
+//! Active layer tracked in the firmware from the key events reaching rmk, for the lighting, the displays,
+//! the encoders and the like. rmk keeps its layer state to itself, so [`LayerTrackerHook`] follows the
+//! same rules on a copy of the default keymap and publishes a [`DeviceEvent::LayerChange`] on each change.
+//!
+//! Layer keys added by Vial at runtime are not followed. Layer-tap keys count as held once held for the
+//! tapping term or when another key is pressed meanwhile, the latter as rmk's permissive hold decides it.
+
+use core::cell::Cell;
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Instant};
+use heapless::Vec;
+use portable_atomic::{AtomicU8, Ordering};
+use rmk::{
+    action::{Action, KeyAction},
+    event::KeyEvent,
+};
+
+use crate::bus::{publish_device_event, DeviceEvent};
+use crate::event::{HookContext, KeyEventHook};
+
+
+/// Layer keys held at once, more are not followed
+const MAX_HELD_LAYER_KEYS: usize = 8;
+
+static ACTIVE_LAYER: AtomicU8 = AtomicU8::new(0);
+/// Layer of the layer-tap key held but not yet decided, and when it turns into a hold
+static PENDING_LAYER_TAP: Mutex<CriticalSectionRawMutex, Cell<Option<(u8, Instant)>>> = Mutex::new(Cell::new(None));
+
+/// Highest layer active in rmk's keymap, as [`LayerTrackerHook`] follows it.
+/// A layer-tap key held past the tapping term counts even before the next key event.
+pub fn active_layer() -> u8 {
+    let layer = ACTIVE_LAYER.load(Ordering::Relaxed);
+    match PENDING_LAYER_TAP.lock(|p| p.get()) {
+        Some((pending, since)) if Instant::now() >= since => layer.max(pending),
+        _ => layer,
+    }
+}
+
+pub(crate) fn set_active_layer(layer: u8) {
+    if ACTIVE_LAYER.swap(layer, Ordering::Relaxed) != layer {
+        publish_device_event(DeviceEvent::LayerChange(layer));
+    }
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum LayerKeyState {
+    /// `MO`, the layer is on while held
+    Momentary,
+    /// `LT` not decided yet, it turns into a hold at the time
+    TapPending(Instant),
+    /// `LT` decided as a hold
+    TapHeld,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct HeldLayerKey {
+    position: (u8, u8),
+    layer: u8,
+    state: LayerKeyState,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum OneShotState {
+    /// `OSL` released, waiting for the next key
+    Armed(u8),
+    /// The next key is held on the layer, which turns off on its release
+    Used(u8, (u8, u8)),
+}
+
+/// Hook following rmk's layer state from the events it forwards, put it last in the chain so that it sees
+/// what reaches rmk. Forwards every event.
+pub struct LayerTrackerHook<const ROW: usize, const COL: usize, const NUM_LAYER: usize> {
+    keymap: [[[KeyAction; COL]; ROW]; NUM_LAYER],
+    tapping_term: Duration,
+    /// Layers switched on by `TG`, a bit per layer
+    toggled: u32,
+    held: Vec<HeldLayerKey, MAX_HELD_LAYER_KEYS>,
+    one_shot: Option<OneShotState>,
+}
+
+impl<const ROW: usize, const COL: usize, const NUM_LAYER: usize> LayerTrackerHook<ROW, COL, NUM_LAYER> {
+    /// `tapping_term` is rmk's hold timeout
+    pub fn new(keymap: &[[[KeyAction; COL]; ROW]; NUM_LAYER], tapping_term: Duration) -> Self {
+        assert!(NUM_LAYER <= 32, "too many layers to track");
+        Self {
+            keymap: *keymap,
+            tapping_term,
+            toggled: 0,
+            held: Vec::new(),
+            one_shot: None,
+        }
+    }
+
+    /// Layers on, a bit per layer, the base layer always
+    fn layer_mask(&self) -> u32 {
+        let mut mask = 1 | self.toggled;
+        for key in &self.held {
+            if matches!(key.state, LayerKeyState::Momentary | LayerKeyState::TapHeld) {
+                mask |= 1 << key.layer;
+            }
+        }
+        match self.one_shot {
+            Some(OneShotState::Armed(layer) | OneShotState::Used(layer, _)) => mask | 1 << layer,
+            None => mask,
+        }
+    }
+
+    /// Action of the position on the highest layer on, falling through the transparent keys like rmk
+    fn resolve(&self, row: u8, col: u8) -> KeyAction {
+        let mask = self.layer_mask();
+        let (row, col) = (row as usize, col as usize);
+        if row >= ROW || col >= COL {
+            return KeyAction::No;
+        }
+        (0..NUM_LAYER)
+            .rev()
+            .filter(|layer| mask & 1 << layer != 0)
+            .map(|layer| self.keymap[layer][row][col])
+            .find(|action| !matches!(action, KeyAction::Transparent))
+            .unwrap_or(KeyAction::No)
+    }
+
+    /// Turn the layer-tap keys held past the term into holds
+    fn expire_pending(&mut self, now: Instant) {
+        for key in self.held.iter_mut() {
+            if let LayerKeyState::TapPending(at) = key.state {
+                if now >= at {
+                    key.state = LayerKeyState::TapHeld;
+                }
+            }
+        }
+    }
+
+    fn press(&mut self, position: (u8, u8), now: Instant) {
+        // Another key decides the pending layer-tap as a hold
+        for key in self.held.iter_mut() {
+            if matches!(key.state, LayerKeyState::TapPending(_)) {
+                key.state = LayerKeyState::TapHeld;
+            }
+        }
+        let action = self.resolve(position.0, position.1);
+        if let Some(OneShotState::Armed(layer)) = self.one_shot {
+            self.one_shot = Some(OneShotState::Used(layer, position));
+        }
+        let held = |layer: u8, state| HeldLayerKey { position, layer, state };
+        match action {
+            KeyAction::Single(Action::LayerOn(layer)) if (layer as usize) < NUM_LAYER => {
+                let _ = self.held.push(held(layer, LayerKeyState::Momentary));
+            }
+            KeyAction::Single(Action::LayerOff(layer)) if (layer as usize) < NUM_LAYER => {
+                self.toggled &= !(1 << layer);
+                self.held.retain(|key| key.layer != layer);
+            }
+            KeyAction::Single(Action::LayerToggle(layer)) if (layer as usize) < NUM_LAYER => {
+                self.toggled ^= 1 << layer;
+            }
+            KeyAction::LayerTapHold(_, layer) if (layer as usize) < NUM_LAYER => {
+                let _ = self.held.push(held(layer, LayerKeyState::TapPending(now + self.tapping_term)));
+            }
+            KeyAction::OneShot(Action::LayerOn(layer)) if (layer as usize) < NUM_LAYER => {
+                // The one shot key itself doesn't use the one shot
+                self.one_shot = Some(OneShotState::Armed(layer));
+            }
+            _ => {}
+        }
+    }
+
+    fn release(&mut self, position: (u8, u8)) {
+        self.held.retain(|key| key.position != position);
+        if matches!(self.one_shot, Some(OneShotState::Used(_, used)) if used == position) {
+            self.one_shot = None;
+        }
+    }
+
+    fn publish(&self) {
+        let mask = self.layer_mask();
+        set_active_layer((31 - mask.leading_zeros()) as u8);
+        let pending = self.held.iter().find_map(|key| match key.state {
+            LayerKeyState::TapPending(at) => Some((key.layer, at)),
+            _ => None,
+        });
+        PENDING_LAYER_TAP.lock(|p| p.set(pending));
+    }
+}
+
+impl<const ROW: usize, const COL: usize, const NUM_LAYER: usize> KeyEventHook
+    for LayerTrackerHook<ROW, COL, NUM_LAYER>
+{
+    async fn process(&mut self, event: KeyEvent, _ctx: &mut HookContext) -> Option<KeyEvent> {
+        let now = Instant::now();
+        self.expire_pending(now);
+        let position = (event.row, event.col);
+        if event.pressed {
+            self.press(position, now);
+        } else {
+            self.release(position);
+        }
+        self.publish();
+        Some(event)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::serial;
+
+    const MO1: KeyAction = KeyAction::Single(Action::LayerOn(1));
+    const TG2: KeyAction = KeyAction::Single(Action::LayerToggle(2));
+    const LT1: KeyAction = KeyAction::LayerTapHold(Action::No, 1);
+    const OSL2: KeyAction = KeyAction::OneShot(Action::LayerOn(2));
+    const ___: KeyAction = KeyAction::Transparent;
+    const XXX: KeyAction = KeyAction::No;
+
+    /// Layer keys on the base layer, a key bound on layer 1 only and a plain key
+    const KEYMAP: [[[KeyAction; 6]; 1]; 3] = [
+        [[MO1, TG2, LT1, OSL2, XXX, XXX]],
+        [[___, ___, ___, ___, TG2, ___]],
+        [[___, ___, ___, ___, ___, ___]],
+    ];
+
+    fn tracker(tapping_term: Duration) -> LayerTrackerHook<1, 6, 3> {
+        ACTIVE_LAYER.store(0, Ordering::Relaxed);
+        PENDING_LAYER_TAP.lock(|p| p.set(None));
+        LayerTrackerHook::new(&KEYMAP, tapping_term)
+    }
+
+    fn key(hook: &mut LayerTrackerHook<1, 6, 3>, col: u8, pressed: bool) -> u8 {
+        let event = KeyEvent { row: 0, col, pressed };
+        assert_eq!(embassy_futures::block_on(hook.process(event, &mut HookContext::new())), Some(event));
+        active_layer()
+    }
+
+    #[test]
+    fn momentary() {
+        let _serial = serial();
+        let mut hook = tracker(Duration::from_secs(1));
+        assert_eq!(key(&mut hook, 0, true), 1);
+        assert_eq!(key(&mut hook, 0, false), 0);
+    }
+
+    #[test]
+    fn toggle() {
+        let _serial = serial();
+        let mut hook = tracker(Duration::from_secs(1));
+        key(&mut hook, 1, true);
+        assert_eq!(key(&mut hook, 1, false), 2);
+        key(&mut hook, 1, true);
+        assert_eq!(key(&mut hook, 1, false), 0);
+    }
+
+    #[test]
+    fn layer_tap_tapped_stays_off() {
+        let _serial = serial();
+        let mut hook = tracker(Duration::from_secs(1));
+        assert_eq!(key(&mut hook, 2, true), 0);
+        assert_eq!(key(&mut hook, 2, false), 0);
+    }
+
+    #[test]
+    fn layer_tap_held_by_another_key() {
+        let _serial = serial();
+        let mut hook = tracker(Duration::from_secs(1));
+        key(&mut hook, 2, true);
+        assert_eq!(key(&mut hook, 5, true), 1);
+        key(&mut hook, 5, false);
+        assert_eq!(key(&mut hook, 2, false), 0);
+    }
+
+    #[test]
+    fn layer_tap_held_past_the_term() {
+        let _serial = serial();
+        let mut hook = tracker(Duration::from_millis(5));
+        assert_eq!(key(&mut hook, 2, true), 0);
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        // Before any other event
+        assert_eq!(active_layer(), 1);
+        assert_eq!(key(&mut hook, 2, false), 0);
+    }
+
+    #[test]
+    fn one_shot_lasts_one_key() {
+        let _serial = serial();
+        let mut hook = tracker(Duration::from_secs(1));
+        key(&mut hook, 3, true);
+        assert_eq!(key(&mut hook, 3, false), 2);
+        assert_eq!(key(&mut hook, 4, true), 2);
+        assert_eq!(key(&mut hook, 4, false), 0);
+    }
+
+    #[test]
+    fn transparent_keys_skip_layers_off() {
+        let _serial = serial();
+        let mut hook = tracker(Duration::from_secs(1));
+        // Layer 2 on, layer 1 off: the last key falls through to the base layer's no-op
+        key(&mut hook, 1, true);
+        key(&mut hook, 1, false);
+        key(&mut hook, 4, true);
+        assert_eq!(key(&mut hook, 4, false), 2);
+        // Layer 1 held under layer 2: it toggles layer 2 off
+        key(&mut hook, 0, true);
+        key(&mut hook, 4, true);
+        key(&mut hook, 4, false);
+        assert_eq!(key(&mut hook, 0, false), 0);
+    }
+}
@@ -0,0 +1,312 @@
+//! Host keyboard layouts, converting chars into the keystrokes producing them on the host.
+//! Chars missing on a layout's keys are typed with its dead keys, e.g. `é` on DE is `´` then `e`.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+use heapless::Vec;
+
+use crate::log::LogModule;
+use crate::log_info;
+use crate::typing::ascii_to_usage;
+
+
+pub const MODIFIER_LEFT_SHIFT: u8 = 0x02;
+pub const MODIFIER_RIGHT_ALT: u8 = 0x40;
+
+/// Usage id of the space key, to type a dead key's own char
+const USAGE_SPACE: u8 = 0x2C;
+/// Usage id of the key between left shift and Z on ISO keyboards
+const USAGE_NON_US_BACKSLASH: u8 = 0x64;
+/// Usage id of the key left of enter on ISO keyboards, `]` on JIS
+const USAGE_NON_US_HASH: u8 = 0x32;
+/// Usage id of JIS `\` right of `/`, International1
+const USAGE_RO: u8 = 0x87;
+/// Usage id of JIS `¥` left of backspace, International3
+const USAGE_YEN: u8 = 0x89;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct Keystroke {
+    pub modifier: u8,
+    pub usage: u8,
+}
+
+impl Keystroke {
+    const fn plain(usage: u8) -> Self {
+        Self { modifier: 0, usage }
+    }
+
+    const fn shift(usage: u8) -> Self {
+        Self {
+            modifier: MODIFIER_LEFT_SHIFT,
+            usage,
+        }
+    }
+
+    const fn altgr(usage: u8) -> Self {
+        Self {
+            modifier: MODIFIER_RIGHT_ALT,
+            usage,
+        }
+    }
+}
+
+/// Keystrokes typing a char, in order
+pub type Keystrokes = Vec<Keystroke, 2>;
+
+fn single(keystroke: Keystroke) -> Keystrokes {
+    let mut keystrokes = Vec::new();
+    let _ = keystrokes.push(keystroke);
+    keystrokes
+}
+
+/// Dead key followed by the key it composes with
+fn dead(dead_key: Keystroke, base: Keystroke) -> Keystrokes {
+    let mut keystrokes = Vec::new();
+    let _ = keystrokes.push(dead_key);
+    let _ = keystrokes.push(base);
+    keystrokes
+}
+
+/// Usage id of a latin letter at its US position
+fn letter(c: char) -> Keystroke {
+    let lower = c.to_ascii_lowercase();
+    let usage = 0x04 + (lower as u8 - b'a');
+    if c.is_ascii_uppercase() {
+        Keystroke::shift(usage)
+    } else {
+        Keystroke::plain(usage)
+    }
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+#[repr(u8)]
+pub enum HostLayout {
+    Us = 0,
+    De = 1,
+    Fr = 2,
+    Jp = 3,
+}
+
+const LAYOUT_COUNT: u8 = 4;
+
+impl HostLayout {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Self::Us),
+            1 => Some(Self::De),
+            2 => Some(Self::Fr),
+            3 => Some(Self::Jp),
+            _ => None,
+        }
+    }
+
+    /// Keystrokes typing the char on this layout, `None` if it can't be typed
+    pub fn keystrokes(self, c: char) -> Option<Keystrokes> {
+        match self {
+            Self::Us => us_keystrokes(c),
+            Self::De => de_keystrokes(c),
+            Self::Fr => fr_keystrokes(c),
+            Self::Jp => jp_keystrokes(c),
+        }
+    }
+}
+
+static HOST_LAYOUT: AtomicU8 = AtomicU8::new(HostLayout::Us as u8);
+
+pub fn host_layout() -> HostLayout {
+    HostLayout::from_u8(HOST_LAYOUT.load(Ordering::Relaxed)).unwrap_or(HostLayout::Us)
+}
+
+pub fn set_host_layout(layout: HostLayout) {
+    log_info!(LogModule::Action, "Host layout: {}", layout);
+    HOST_LAYOUT.store(layout as u8, Ordering::Relaxed);
+}
+
+pub fn cycle_host_layout() {
+    let next = (host_layout() as u8 + 1) % LAYOUT_COUNT;
+    set_host_layout(HostLayout::from_u8(next).unwrap_or(HostLayout::Us));
+}
+
+
+fn us_keystrokes(c: char) -> Option<Keystrokes> {
+    let (usage, shift) = ascii_to_usage(c)?;
+    Some(single(if shift {
+        Keystroke::shift(usage)
+    } else {
+        Keystroke::plain(usage)
+    }))
+}
+
+/// German QWERTZ
+fn de_keystrokes(c: char) -> Option<Keystrokes> {
+    const CIRCUMFLEX: Keystroke = Keystroke::plain(0x35);
+    const ACUTE: Keystroke = Keystroke::plain(0x2E);
+    const GRAVE: Keystroke = Keystroke::shift(0x2E);
+    const SPACE: Keystroke = Keystroke::plain(USAGE_SPACE);
+    let keystroke = match c {
+        'y' | 'Y' => Keystroke { usage: 0x1D, ..letter(c) },
+        'z' | 'Z' => Keystroke { usage: 0x1C, ..letter(c) },
+        'a'..='z' | 'A'..='Z' | '1'..='9' | '0' | '\n' | '\t' | ' ' | '!' | '$' | '%' => {
+            return us_keystrokes(c);
+        }
+        '"' => Keystroke::shift(0x1F),
+        '§' => Keystroke::shift(0x20),
+        '&' => Keystroke::shift(0x23),
+        '/' => Keystroke::shift(0x24),
+        '(' => Keystroke::shift(0x25),
+        ')' => Keystroke::shift(0x26),
+        '=' => Keystroke::shift(0x27),
+        '{' => Keystroke::altgr(0x24),
+        '[' => Keystroke::altgr(0x25),
+        ']' => Keystroke::altgr(0x26),
+        '}' => Keystroke::altgr(0x27),
+        'ß' => Keystroke::plain(0x2D),
+        '?' => Keystroke::shift(0x2D),
+        '\\' => Keystroke::altgr(0x2D),
+        '@' => Keystroke::altgr(0x14),
+        '€' => Keystroke::altgr(0x08),
+        'ü' => Keystroke::plain(0x2F),
+        'Ü' => Keystroke::shift(0x2F),
+        '+' => Keystroke::plain(0x30),
+        '*' => Keystroke::shift(0x30),
+        '~' => Keystroke::altgr(0x30),
+        'ö' => Keystroke::plain(0x33),
+        'Ö' => Keystroke::shift(0x33),
+        'ä' => Keystroke::plain(0x34),
+        'Ä' => Keystroke::shift(0x34),
+        '#' => Keystroke::plain(0x32),
+        '\'' => Keystroke::shift(0x32),
+        ',' => Keystroke::plain(0x36),
+        ';' => Keystroke::shift(0x36),
+        '.' => Keystroke::plain(0x37),
+        ':' => Keystroke::shift(0x37),
+        '-' => Keystroke::plain(0x38),
+        '_' => Keystroke::shift(0x38),
+        '<' => Keystroke::plain(USAGE_NON_US_BACKSLASH),
+        '>' => Keystroke::shift(USAGE_NON_US_BACKSLASH),
+        '|' => Keystroke::altgr(USAGE_NON_US_BACKSLASH),
+        '°' => Keystroke::shift(0x35),
+        '^' => return Some(dead(CIRCUMFLEX, SPACE)),
+        '`' => return Some(dead(GRAVE, SPACE)),
+        'é' | 'á' | 'í' | 'ó' | 'ú' => return Some(dead(ACUTE, letter(base_letter(c)?))),
+        'è' | 'à' | 'ì' | 'ò' | 'ù' => return Some(dead(GRAVE, letter(base_letter(c)?))),
+        'ê' | 'â' | 'î' | 'ô' | 'û' => return Some(dead(CIRCUMFLEX, letter(base_letter(c)?))),
+        _ => return None,
+    };
+    Some(single(keystroke))
+}
+
+/// French AZERTY
+fn fr_keystrokes(c: char) -> Option<Keystrokes> {
+    const CIRCUMFLEX: Keystroke = Keystroke::plain(0x2F);
+    const DIAERESIS: Keystroke = Keystroke::shift(0x2F);
+    const SPACE: Keystroke = Keystroke::plain(USAGE_SPACE);
+    let keystroke = match c {
+        'a' | 'A' => Keystroke { usage: 0x14, ..letter(c) },
+        'q' | 'Q' => Keystroke { usage: 0x04, ..letter(c) },
+        'z' | 'Z' => Keystroke { usage: 0x1A, ..letter(c) },
+        'w' | 'W' => Keystroke { usage: 0x1D, ..letter(c) },
+        'm' | 'M' => Keystroke { usage: 0x33, ..letter(c) },
+        'a'..='z' | 'A'..='Z' | '\n' | '\t' | ' ' => return us_keystrokes(c),
+        '1'..='9' => Keystroke::shift(0x1E + (c as u8 - b'1')),
+        '0' => Keystroke::shift(0x27),
+        '&' => Keystroke::plain(0x1E),
+        'é' => Keystroke::plain(0x1F),
+        '"' => Keystroke::plain(0x20),
+        '\'' => Keystroke::plain(0x21),
+        '(' => Keystroke::plain(0x22),
+        '-' => Keystroke::plain(0x23),
+        'è' => Keystroke::plain(0x24),
+        '_' => Keystroke::plain(0x25),
+        'ç' => Keystroke::plain(0x26),
+        'à' => Keystroke::plain(0x27),
+        '#' => Keystroke::altgr(0x20),
+        '{' => Keystroke::altgr(0x21),
+        '[' => Keystroke::altgr(0x22),
+        '|' => Keystroke::altgr(0x23),
+        '\\' => Keystroke::altgr(0x25),
+        '@' => Keystroke::altgr(0x27),
+        ')' => Keystroke::plain(0x2D),
+        '°' => Keystroke::shift(0x2D),
+        ']' => Keystroke::altgr(0x2D),
+        '=' => Keystroke::plain(0x2E),
+        '+' => Keystroke::shift(0x2E),
+        '}' => Keystroke::altgr(0x2E),
+        '$' => Keystroke::plain(0x30),
+        '£' => Keystroke::shift(0x30),
+        'ù' => Keystroke::plain(0x34),
+        '%' => Keystroke::shift(0x34),
+        '*' => Keystroke::plain(0x31),
+        'µ' => Keystroke::shift(0x31),
+        ',' => Keystroke::plain(0x10),
+        '?' => Keystroke::shift(0x10),
+        ';' => Keystroke::plain(0x36),
+        '.' => Keystroke::shift(0x36),
+        ':' => Keystroke::plain(0x37),
+        '/' => Keystroke::shift(0x37),
+        '!' => Keystroke::plain(0x38),
+        '§' => Keystroke::shift(0x38),
+        '<' => Keystroke::plain(USAGE_NON_US_BACKSLASH),
+        '>' => Keystroke::shift(USAGE_NON_US_BACKSLASH),
+        '€' => Keystroke::altgr(0x08),
+        '^' => return Some(dead(CIRCUMFLEX, SPACE)),
+        '~' => return Some(dead(Keystroke::altgr(0x1F), SPACE)),
+        '`' => return Some(dead(Keystroke::altgr(0x24), SPACE)),
+        'â' | 'ê' | 'î' | 'ô' | 'û' => return Some(dead(CIRCUMFLEX, fr_letter(base_letter(c)?))),
+        'ä' | 'ë' | 'ï' | 'ö' | 'ü' => return Some(dead(DIAERESIS, fr_letter(base_letter(c)?))),
+        _ => return None,
+    };
+    Some(single(keystroke))
+}
+
+/// Japanese JIS, typed in the direct input mode of the IME
+fn jp_keystrokes(c: char) -> Option<Keystrokes> {
+    let keystroke = match c {
+        'a'..='z' | 'A'..='Z' | '1'..='9' | '0' | '\n' | '\t' | ' ' | '!' | '#' | '$' | '%' | '-' | ',' | '.'
+        | '/' | '<' | '>' | '?' => return us_keystrokes(c),
+        '"' => Keystroke::shift(0x1F),
+        '&' => Keystroke::shift(0x23),
+        '\'' => Keystroke::shift(0x24),
+        '(' => Keystroke::shift(0x25),
+        ')' => Keystroke::shift(0x26),
+        '=' => Keystroke::shift(0x2D),
+        '^' => Keystroke::plain(0x2E),
+        '~' => Keystroke::shift(0x2E),
+        '¥' => Keystroke::plain(USAGE_YEN),
+        '|' => Keystroke::shift(USAGE_YEN),
+        '@' => Keystroke::plain(0x2F),
+        '`' => Keystroke::shift(0x2F),
+        '[' => Keystroke::plain(0x30),
+        '{' => Keystroke::shift(0x30),
+        ';' => Keystroke::plain(0x33),
+        '+' => Keystroke::shift(0x33),
+        ':' => Keystroke::plain(0x34),
+        '*' => Keystroke::shift(0x34),
+        ']' => Keystroke::plain(USAGE_NON_US_HASH),
+        '}' => Keystroke::shift(USAGE_NON_US_HASH),
+        '\\' => Keystroke::plain(USAGE_RO),
+        '_' => Keystroke::shift(USAGE_RO),
+        _ => return None,
+    };
+    Some(single(keystroke))
+}
+
+/// Letter key on AZERTY
+fn fr_letter(c: char) -> Keystroke {
+    fr_keystrokes(c)
+        .and_then(|keystrokes| keystrokes.first().copied())
+        .unwrap_or(letter(c))
+}
+
+/// Latin letter of an accented vowel
+fn base_letter(c: char) -> Option<char> {
+    let base = match c {
+        'á' | 'à' | 'â' | 'ä' => 'a',
+        'é' | 'è' | 'ê' | 'ë' => 'e',
+        'í' | 'ì' | 'î' | 'ï' => 'i',
+        'ó' | 'ò' | 'ô' | 'ö' => 'o',
+        'ú' | 'ù' | 'û' | 'ü' => 'u',
+        _ => return None,
+    };
+    Some(base)
+}
@@ -1,3 +1,97 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 
+pub mod action;
+pub mod alert;
+pub mod battery;
+pub mod bench;
+pub mod bilateral;
+#[cfg(feature = "bitmap_upload")]
+pub mod bitmap;
+pub mod brightness;
+pub mod brown_out;
+pub mod bus;
+pub mod charger;
+pub mod clock;
+pub mod config_reset;
+#[cfg(feature = "crash_log")]
+pub mod crash;
+pub mod debounce;
+pub mod dedup;
+pub mod demo;
+pub mod encoder;
+#[cfg(feature = "dfu")]
+pub mod dfu;
+#[cfg(feature = "rp2040")]
+pub mod dormant;
+pub mod event;
+pub mod feature_flags;
+#[cfg(feature = "rp2040")]
+pub mod handoff;
+pub mod heatmap;
+pub mod host_sleep;
+pub mod info;
+pub mod jiggler;
+pub mod key_lock;
+pub mod key_stream;
+pub mod keymap_check;
+pub mod keymap_macro;
+pub mod keymap_names;
+pub mod layer_colors;
+pub mod layer_preview;
+pub mod layer_state;
+pub mod layout;
+pub mod lighting;
+pub mod lock_led;
+pub mod log;
 pub mod matrix;
+pub mod metrics;
+#[cfg(feature = "minimal")]
+pub mod minimal;
+#[cfg(feature = "core1_matrix")]
+pub mod multicore;
+pub mod oled;
+pub mod output;
+pub mod physical;
+pub mod pointer_settings;
+pub mod pointing;
+pub mod presenter;
+#[cfg(feature = "priority_tasks")]
+pub mod priority;
+pub mod queues;
+pub mod quiesce;
+pub mod ram_budget;
+pub mod raw_hid;
+pub mod reboot;
+pub mod recorder;
+#[cfg(feature = "rp2040")]
+pub mod reserved;
+pub mod rgb;
+pub mod scheduler;
+pub mod shared_pin;
+pub mod shell;
+#[cfg(feature = "signed_config")]
+pub mod signed;
+pub mod snippets;
+pub mod socd;
+pub mod soft_off;
+pub mod split_link;
+pub mod split_order;
+pub mod split_transport;
+pub mod stuck;
+#[cfg(any(feature = "core1_matrix", feature = "priority_tasks"))]
+pub mod task_arena;
+pub mod telemetry;
+#[cfg(test)]
+mod test_support;
+pub mod timer;
+#[cfg(feature = "totp")]
+pub mod totp;
+pub mod training;
+pub mod typing;
+pub mod typing_game;
+pub mod usb;
+pub mod usb_power;
+#[cfg(feature = "secret_vault")]
+pub mod vault;
+#[cfg(feature = "ws2812")]
+pub mod ws2812;
@@ -0,0 +1,233 @@
+//! Vial's lighting tab bound to an RGB effect, so the GUI controls the mode, color and speed live.
+//! Implements the QMK rgblight values of the VIA lighting commands, which the board's `vial.json`
+//! enables with `"lighting": "qmk_rgblight"`. It works as far as rmk passes the commands through
+//! to [`handle_vendor_command`](crate::raw_hid::handle_vendor_command).
+
+use core::cell::Cell;
+use core::fmt::Write as _;
+#[cfg(feature = "rp2040")]
+use embassy_rp::flash::{Flash, Instance, Mode};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_sync::signal::Signal;
+use embassy_time::Instant;
+use smart_leds::hsv::{hsv2rgb, Hsv};
+
+use crate::log::LogModule;
+use crate::log_info;
+use crate::quiesce::quiesce_flash;
+#[cfg(feature = "rp2040")]
+use crate::reserved::write_reserved_sector;
+use crate::rgb::{RgbEffect, RGB8};
+use crate::shell::{parse_arg, ShellCommand, ShellReply};
+
+
+/// VIA command ids
+pub const LIGHTING_SET_VALUE: u8 = 0x07;
+pub const LIGHTING_GET_VALUE: u8 = 0x08;
+pub const LIGHTING_SAVE: u8 = 0x09;
+
+/// QMK rgblight value ids following the command id, `[command, value_id, data...]`
+const RGBLIGHT_BRIGHTNESS: u8 = 0x80;
+const RGBLIGHT_EFFECT: u8 = 0x81;
+const RGBLIGHT_EFFECT_SPEED: u8 = 0x82;
+/// Hue and saturation
+const RGBLIGHT_COLOR: u8 = 0x83;
+
+/// Modes, numbered as QMK's rgblight effects which the GUI lists.
+/// Other effects render as solid.
+pub const MODE_OFF: u8 = 0;
+pub const MODE_SOLID: u8 = 1;
+pub const MODE_BREATHING: u8 = 2;
+pub const MODE_RAINBOW_MOOD: u8 = 6;
+pub const MODE_RAINBOW_SWIRL: u8 = 9;
+
+/// Lighting set by the GUI, persisted in the reserved sector
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct LightingSettings {
+    pub mode: u8,
+    pub hue: u8,
+    pub sat: u8,
+    pub val: u8,
+    pub speed: u8,
+}
+
+impl LightingSettings {
+    const MAGIC: u32 = 0x4554_494C; // "LITE"
+
+    pub const DEFAULT: Self = Self {
+        mode: MODE_SOLID,
+        hue: 0,
+        sat: 255,
+        val: 128,
+        speed: 128,
+    };
+
+    /// `[magic, mode, hue, sat, val, speed]`, LE
+    pub fn to_bytes(&self) -> [u8; 12] {
+        let mut bytes = [0u8; 12];
+        bytes[0..4].copy_from_slice(&Self::MAGIC.to_le_bytes());
+        bytes[4..9].copy_from_slice(&[self.mode, self.hue, self.sat, self.val, self.speed]);
+        bytes
+    }
+
+    /// Returns `None` if the bytes aren't settings, e.g. erased
+    pub fn from_bytes(bytes: &[u8; 12]) -> Option<Self> {
+        (bytes[0..4] == Self::MAGIC.to_le_bytes()).then(|| Self {
+            mode: bytes[4],
+            hue: bytes[5],
+            sat: bytes[6],
+            val: bytes[7],
+            speed: bytes[8],
+        })
+    }
+}
+
+static LIGHTING: Mutex<CriticalSectionRawMutex, Cell<LightingSettings>> =
+    Mutex::new(Cell::new(LightingSettings::DEFAULT));
+
+static LIGHTING_SAVE_REQUEST: Signal<CriticalSectionRawMutex, LightingSettings> = Signal::new();
+
+pub fn lighting_settings() -> LightingSettings {
+    LIGHTING.lock(|l| l.get())
+}
+
+pub fn set_lighting_settings(settings: LightingSettings) {
+    LIGHTING.lock(|l| l.set(settings));
+}
+
+/// Read the settings saved by the previous boot. Call it before handing the flash to rmk.
+#[cfg(feature = "rp2040")]
+pub fn load_lighting_settings<T: Instance, M: Mode, const FLASH_SIZE: usize>(
+    flash: &mut Flash<'_, T, M, FLASH_SIZE>,
+    offset: u32,
+) -> bool {
+    let mut bytes = [0u8; 12];
+    if flash.blocking_read(offset, &mut bytes).is_err() {
+        return false;
+    }
+    match LightingSettings::from_bytes(&bytes) {
+        Some(settings) => {
+            set_lighting_settings(settings);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Save the settings to the reserved sector at `offset`
+#[cfg(feature = "rp2040")]
+pub fn save_lighting_settings<const FLASH_SIZE: usize>(offset: u32, settings: &LightingSettings) {
+    if let Err(e) = write_reserved_sector::<FLASH_SIZE>(offset, &settings.to_bytes()) {
+        defmt::warn!("Failed to save lighting settings: {}", e);
+    }
+}
+
+/// Answer a VIA lighting command in place, get responds `[LIGHTING_GET_VALUE, value_id, data...]`.
+/// Returns false if the report isn't a lighting command.
+pub fn handle_lighting_command(report: &mut [u8]) -> bool {
+    if report.len() < 5 || !matches!(report[0], LIGHTING_SET_VALUE | LIGHTING_GET_VALUE | LIGHTING_SAVE) {
+        return false;
+    }
+    let mut settings = lighting_settings();
+    match (report[0], report[1]) {
+        (LIGHTING_SAVE, _) => LIGHTING_SAVE_REQUEST.signal(settings),
+        (LIGHTING_SET_VALUE, RGBLIGHT_BRIGHTNESS) => settings.val = report[2],
+        (LIGHTING_SET_VALUE, RGBLIGHT_EFFECT) => settings.mode = report[2],
+        (LIGHTING_SET_VALUE, RGBLIGHT_EFFECT_SPEED) => settings.speed = report[2],
+        (LIGHTING_SET_VALUE, RGBLIGHT_COLOR) => {
+            settings.hue = report[2];
+            settings.sat = report[3];
+        }
+        (LIGHTING_GET_VALUE, RGBLIGHT_BRIGHTNESS) => report[2] = settings.val,
+        (LIGHTING_GET_VALUE, RGBLIGHT_EFFECT) => report[2] = settings.mode,
+        (LIGHTING_GET_VALUE, RGBLIGHT_EFFECT_SPEED) => report[2] = settings.speed,
+        (LIGHTING_GET_VALUE, RGBLIGHT_COLOR) => {
+            report[2] = settings.hue;
+            report[3] = settings.sat;
+        }
+        _ => return false,
+    }
+    set_lighting_settings(settings);
+    true
+}
+
+/// Shell commands of the lighting, saved by `rgb save` like the GUI's save
+pub struct LightingShell;
+
+impl ShellCommand for LightingShell {
+    fn name(&self) -> &'static str {
+        "rgb"
+    }
+
+    fn usage(&self) -> &'static str {
+        "rgb show | rgb <mode|hue|sat|val|speed> <n> | rgb save"
+    }
+
+    fn run(&self, args: &[&str], reply: &mut ShellReply) -> Result<(), &'static str> {
+        let mut settings = lighting_settings();
+        match args.first().copied() {
+            Some("show") => {
+                let LightingSettings { mode, hue, sat, val, speed } = settings;
+                let _ = writeln!(reply, "mode {} hue {} sat {} val {} speed {}", mode, hue, sat, val, speed);
+                return Ok(());
+            }
+            Some("save") => {
+                LIGHTING_SAVE_REQUEST.signal(settings);
+                return Ok(());
+            }
+            Some("mode") => settings.mode = parse_arg(args.get(1))?,
+            Some("hue") => settings.hue = parse_arg(args.get(1))?,
+            Some("sat") => settings.sat = parse_arg(args.get(1))?,
+            Some("val") => settings.val = parse_arg(args.get(1))?,
+            Some("speed") => settings.speed = parse_arg(args.get(1))?,
+            _ => return Err(self.usage()),
+        }
+        set_lighting_settings(settings);
+        Ok(())
+    }
+}
+
+/// Wait for the GUI's save and pass the settings to `on_save`, e.g. [`save_lighting_settings`].
+/// This function should never return.
+pub async fn run_lighting_save<F: FnMut(&LightingSettings)>(mut on_save: F) -> ! {
+    loop {
+        let settings = LIGHTING_SAVE_REQUEST.wait().await;
+        log_info!(LogModule::Device, "Lighting saved: {}", settings);
+        quiesce_flash(async { on_save(&settings) }).await;
+    }
+}
+
+
+/// Effect of the lighting settings, before the global brightness
+pub struct LightingEffect;
+
+impl<const N: usize> RgbEffect<N> for LightingEffect {
+    fn render(&mut self, frame: &mut [RGB8; N], now: Instant) {
+        let settings = lighting_settings();
+        // Cycle of the animated modes, from 4s at speed 0 to 0.5s at 255
+        let period_ms = 4000 - settings.speed as u64 * 3500 / 255;
+        let phase = (now.as_millis() % period_ms * 256 / period_ms) as u8;
+        let color = |hue: u8, val: u8| {
+            hsv2rgb(Hsv {
+                hue,
+                sat: settings.sat,
+                val,
+            })
+        };
+        match settings.mode {
+            MODE_OFF => frame.fill(RGB8::default()),
+            MODE_BREATHING => {
+                let triangle = if phase < 128 { phase * 2 } else { (255 - phase) * 2 };
+                frame.fill(color(settings.hue, (settings.val as u16 * triangle as u16 / 255) as u8));
+            }
+            MODE_RAINBOW_MOOD => frame.fill(color(settings.hue.wrapping_add(phase), settings.val)),
+            MODE_RAINBOW_SWIRL => {
+                for (i, pixel) in frame.iter_mut().enumerate() {
+                    let offset = (i * 256 / N.max(1)) as u8;
+                    *pixel = color(settings.hue.wrapping_add(phase).wrapping_add(offset), settings.val);
+                }
+            }
+            _ => frame.fill(color(settings.hue, settings.val)),
+        }
+    }
+}
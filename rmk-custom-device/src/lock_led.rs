@@ -0,0 +1,299 @@
+//! Host lock LEDs drawn on the RGB LEDs, configured by a table instead of a custom hook.
+//!
+//! rmk consumes the host's LED output report itself, so [`LockLedDriver`] wraps the USB driver handed to it
+//! and picks the report up on its way. The last report is cached per transport and shown again once
+//! the transport comes back, e.g. after the host's sleep, since hosts don't always resend it on resume.
+
+use core::ops::Range;
+use portable_atomic::{AtomicU8, Ordering};
+use embassy_time::Instant;
+use embassy_usb_driver::{
+    Bus, ControlPipe, Driver, Endpoint, EndpointAddress, EndpointAllocError, EndpointError, EndpointInfo,
+    EndpointOut, EndpointType, Event, Unsupported,
+};
+
+use crate::bus::{publish_device_event, DeviceEvent};
+use crate::rgb::{RgbEffect, RGB8};
+
+
+/// Lock LED, the bit of the HID keyboard LED output report
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+#[repr(u8)]
+pub enum LockLed {
+    NumLock = 0x01,
+    CapsLock = 0x02,
+    ScrollLock = 0x04,
+    Compose = 0x08,
+    Kana = 0x10,
+}
+
+/// Link the host's LED output reports come over
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+#[repr(u8)]
+pub enum LedTransport {
+    Usb = 0,
+    Ble = 1,
+}
+
+const NO_TRANSPORT: u8 = u8::MAX;
+
+/// Last report of each transport
+static CACHED_LOCK_LEDS: [AtomicU8; 2] = [AtomicU8::new(0), AtomicU8::new(0)];
+/// Transport whose report is shown, none while every host is away
+static ACTIVE_TRANSPORT: AtomicU8 = AtomicU8::new(NO_TRANSPORT);
+/// Report shown, published on changes
+static LOCK_LEDS: AtomicU8 = AtomicU8::new(0);
+
+fn update_lock_leds() {
+    let leds = match ACTIVE_TRANSPORT.load(Ordering::Relaxed) {
+        NO_TRANSPORT => 0,
+        transport => CACHED_LOCK_LEDS[transport as usize].load(Ordering::Relaxed),
+    };
+    if LOCK_LEDS.swap(leds, Ordering::Relaxed) != leds {
+        publish_device_event(DeviceEvent::LockLeds(leds));
+    }
+}
+
+/// Record the host's LED output report received over the transport.
+/// [`LockLedDriver`] does it for USB, call this wherever the report of another transport is available.
+pub fn set_lock_leds(transport: LedTransport, report: u8) {
+    CACHED_LOCK_LEDS[transport as usize].store(report, Ordering::Relaxed);
+    update_lock_leds();
+}
+
+/// Show the cached report of the transport, or nothing while the host is away, e.g. suspended or unplugged
+pub fn set_lock_led_transport(transport: Option<LedTransport>) {
+    ACTIVE_TRANSPORT.store(transport.map_or(NO_TRANSPORT, |t| t as u8), Ordering::Relaxed);
+    update_lock_leds();
+}
+
+/// Report shown, the active transport's last one
+pub fn lock_leds() -> u8 {
+    LOCK_LEDS.load(Ordering::Relaxed)
+}
+
+pub fn is_lock_led_on(led: LockLed) -> bool {
+    lock_leds() & led as u8 != 0
+}
+
+/// Pixels lit in the color while the lock LED is on
+#[derive(Clone, Debug)]
+pub struct LockLedZone {
+    pub led: LockLed,
+    pub pixels: Range<u8>,
+    pub color: RGB8,
+}
+
+impl LockLedZone {
+    pub const fn new(led: LockLed, pixels: Range<u8>, color: RGB8) -> Self {
+        Self { led, pixels, color }
+    }
+}
+
+/// Effect drawing the zones of the lock LEDs which are on, later zones on top.
+/// Draws nothing while all are off, so put it on top of the regular effect, e.g.
+/// `LockLedEffect::new([LockLedZone::new(LockLed::CapsLock, 0..36, RGB8::new(255, 0, 0))])` for the left half.
+pub struct LockLedEffect<const Z: usize> {
+    zones: [LockLedZone; Z],
+}
+
+impl<const Z: usize> LockLedEffect<Z> {
+    pub const fn new(zones: [LockLedZone; Z]) -> Self {
+        Self { zones }
+    }
+}
+
+impl<const N: usize, const Z: usize> RgbEffect<N> for LockLedEffect<Z> {
+    fn render(&mut self, frame: &mut [RGB8; N], _now: Instant) {
+        for zone in self.zones.iter().filter(|zone| is_lock_led_on(zone.led)) {
+            let start = (zone.pixels.start as usize).min(N);
+            let end = (zone.pixels.end as usize).clamp(start, N);
+            frame[start..end].fill(zone.color);
+        }
+    }
+}
+
+
+/// USB driver picking up the host's LED output reports for [`set_lock_leds`].
+/// Reports come as SET_REPORT on the control pipe or on the keyboard's interrupt OUT endpoint,
+/// told apart from raw HID's by its 1 byte packets.
+pub struct LockLedDriver<D> {
+    inner: D,
+    poll_interval_ms: Option<u8>,
+}
+
+impl<D> LockLedDriver<D> {
+    pub fn new(inner: D) -> Self {
+        Self {
+            inner,
+            poll_interval_ms: None,
+        }
+    }
+
+    /// Ask the host to send the LED reports on the interrupt endpoint at this interval instead of rmk's
+    pub fn with_poll_interval(mut self, interval_ms: u8) -> Self {
+        self.poll_interval_ms = Some(interval_ms);
+        self
+    }
+}
+
+impl<'a, D: Driver<'a>> Driver<'a> for LockLedDriver<D> {
+    type EndpointOut = LockLedEndpointOut<D::EndpointOut>;
+    type EndpointIn = D::EndpointIn;
+    type ControlPipe = LockLedControlPipe<D::ControlPipe>;
+    type Bus = LockLedBus<D::Bus>;
+
+    fn alloc_endpoint_out(
+        &mut self,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<Self::EndpointOut, EndpointAllocError> {
+        let leds = ep_type == EndpointType::Interrupt && max_packet_size == 1;
+        let interval_ms = match self.poll_interval_ms {
+            Some(poll_interval_ms) if leds => poll_interval_ms,
+            _ => interval_ms,
+        };
+        let inner = self.inner.alloc_endpoint_out(ep_type, max_packet_size, interval_ms)?;
+        Ok(LockLedEndpointOut { inner, leds })
+    }
+
+    fn alloc_endpoint_in(
+        &mut self,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<Self::EndpointIn, EndpointAllocError> {
+        self.inner.alloc_endpoint_in(ep_type, max_packet_size, interval_ms)
+    }
+
+    fn start(self, control_max_packet_size: u16) -> (Self::Bus, Self::ControlPipe) {
+        let (bus, control) = self.inner.start(control_max_packet_size);
+        let control = LockLedControlPipe {
+            inner: control,
+            request: [0; 8],
+        };
+        (LockLedBus { inner: bus }, control)
+    }
+}
+
+/// Bus showing the cached USB report while the host is there, nothing while it's suspended or gone
+pub struct LockLedBus<B> {
+    inner: B,
+}
+
+impl<B: Bus> Bus for LockLedBus<B> {
+    async fn enable(&mut self) {
+        self.inner.enable().await
+    }
+
+    async fn disable(&mut self) {
+        self.inner.disable().await
+    }
+
+    async fn poll(&mut self) -> Event {
+        let event = self.inner.poll().await;
+        match event {
+            Event::Reset | Event::Resume => set_lock_led_transport(Some(LedTransport::Usb)),
+            Event::Suspend | Event::PowerRemoved => set_lock_led_transport(None),
+            _ => {}
+        }
+        event
+    }
+
+    fn endpoint_set_enabled(&mut self, ep_addr: EndpointAddress, enabled: bool) {
+        self.inner.endpoint_set_enabled(ep_addr, enabled)
+    }
+
+    fn endpoint_set_stalled(&mut self, ep_addr: EndpointAddress, stalled: bool) {
+        self.inner.endpoint_set_stalled(ep_addr, stalled)
+    }
+
+    fn endpoint_is_stalled(&mut self, ep_addr: EndpointAddress) -> bool {
+        self.inner.endpoint_is_stalled(ep_addr)
+    }
+
+    async fn remote_wakeup(&mut self) -> Result<(), Unsupported> {
+        self.inner.remote_wakeup().await
+    }
+}
+
+/// OUT endpoint recording the packets of the LED endpoint
+pub struct LockLedEndpointOut<E> {
+    inner: E,
+    leds: bool,
+}
+
+impl<E: Endpoint> Endpoint for LockLedEndpointOut<E> {
+    fn info(&self) -> &EndpointInfo {
+        self.inner.info()
+    }
+
+    async fn wait_enabled(&mut self) {
+        self.inner.wait_enabled().await
+    }
+}
+
+impl<E: EndpointOut> EndpointOut for LockLedEndpointOut<E> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, EndpointError> {
+        let len = self.inner.read(buf).await?;
+        if self.leds && len == 1 {
+            set_lock_leds(LedTransport::Usb, buf[0]);
+        }
+        Ok(len)
+    }
+}
+
+/// Control pipe recording the data of the 1 byte output SET_REPORT requests
+pub struct LockLedControlPipe<C> {
+    inner: C,
+    request: [u8; 8],
+}
+
+impl<C: ControlPipe> LockLedControlPipe<C> {
+    const SET_REPORT: u8 = 0x09;
+    const REPORT_TYPE_OUTPUT: u8 = 0x02;
+
+    /// Host to device class request to an interface, of an output report of 1 byte
+    fn is_led_report(&self) -> bool {
+        self.request[0] == 0x21
+            && self.request[1] == Self::SET_REPORT
+            && self.request[3] == Self::REPORT_TYPE_OUTPUT
+            && u16::from_le_bytes([self.request[6], self.request[7]]) == 1
+    }
+}
+
+impl<C: ControlPipe> ControlPipe for LockLedControlPipe<C> {
+    fn max_packet_size(&self) -> usize {
+        self.inner.max_packet_size()
+    }
+
+    async fn setup(&mut self) -> [u8; 8] {
+        self.request = self.inner.setup().await;
+        self.request
+    }
+
+    async fn data_out(&mut self, buf: &mut [u8], first: bool, last: bool) -> Result<usize, EndpointError> {
+        let len = self.inner.data_out(buf, first, last).await?;
+        if first && len == 1 && self.is_led_report() {
+            set_lock_leds(LedTransport::Usb, buf[0]);
+        }
+        Ok(len)
+    }
+
+    async fn data_in(&mut self, data: &[u8], first: bool, last: bool) -> Result<(), EndpointError> {
+        self.inner.data_in(data, first, last).await
+    }
+
+    async fn accept(&mut self) {
+        self.inner.accept().await
+    }
+
+    async fn reject(&mut self) {
+        self.inner.reject().await
+    }
+
+    async fn accept_set_address(&mut self, addr: u8) {
+        self.inner.accept_set_address(addr).await
+    }
+}
@@ -0,0 +1,157 @@
+//! Runtime log filter on top of defmt's compile-time `DEFMT_LOG` filter.
+//! Messages must be compiled in by `DEFMT_LOG` to be enabled here, and the `minimal` feature compiles them all out.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+
+/// Raw HID command id setting a log level, `[LOG_COMMAND, module, level]`, module `0xFF` sets all
+pub const LOG_COMMAND: u8 = 0xE2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, defmt::Format)]
+#[repr(u8)]
+pub enum LogLevel {
+    Off = 0,
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+    Trace = 5,
+}
+
+impl LogLevel {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Self::Off),
+            1 => Some(Self::Error),
+            2 => Some(Self::Warn),
+            3 => Some(Self::Info),
+            4 => Some(Self::Debug),
+            5 => Some(Self::Trace),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+#[repr(u8)]
+pub enum LogModule {
+    Matrix = 0,
+    Action = 1,
+    Device = 2,
+}
+
+const MODULE_COUNT: usize = 3;
+
+/// Whether the log macros are compiled in, false on `minimal` builds so their formatting code is dropped
+pub const LOGS_COMPILED_IN: bool = !cfg!(feature = "minimal");
+
+impl LogModule {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Self::Matrix),
+            1 => Some(Self::Action),
+            2 => Some(Self::Device),
+            _ => None,
+        }
+    }
+}
+
+static LOG_LEVELS: [AtomicU8; MODULE_COUNT] = [
+    AtomicU8::new(LogLevel::Info as u8),
+    AtomicU8::new(LogLevel::Info as u8),
+    AtomicU8::new(LogLevel::Info as u8),
+];
+
+pub fn log_level(module: LogModule) -> LogLevel {
+    LogLevel::from_u8(LOG_LEVELS[module as usize].load(Ordering::Relaxed)).unwrap_or(LogLevel::Info)
+}
+
+pub fn set_log_level(module: LogModule, level: LogLevel) {
+    LOG_LEVELS[module as usize].store(level as u8, Ordering::Relaxed);
+}
+
+pub fn log_enabled(module: LogModule, level: LogLevel) -> bool {
+    level <= log_level(module)
+}
+
+/// Switch the matrix log between info and debug, for diagnosing chatter
+pub fn toggle_matrix_debug_log() {
+    let level = match log_level(LogModule::Matrix) {
+        LogLevel::Debug | LogLevel::Trace => LogLevel::Info,
+        _ => LogLevel::Debug,
+    };
+    defmt::info!("Matrix log level: {}", level);
+    set_log_level(LogModule::Matrix, level);
+}
+
+/// Answer a log command in place, response is `[LOG_COMMAND, status]`, status 0 on success.
+/// Returns false if the report isn't a log command.
+pub fn handle_log_command(report: &mut [u8]) -> bool {
+    if report.len() < 3 || report[0] != LOG_COMMAND {
+        return false;
+    }
+    let status = match (report[1], LogLevel::from_u8(report[2])) {
+        (0xFF, Some(level)) => {
+            for level_slot in LOG_LEVELS.iter() {
+                level_slot.store(level as u8, Ordering::Relaxed);
+            }
+            0
+        }
+        (module, Some(level)) => match LogModule::from_u8(module) {
+            Some(module) => {
+                set_log_level(module, level);
+                0
+            }
+            None => 1,
+        },
+        _ => 1,
+    };
+    report[1..].fill(0);
+    report[1] = status;
+    true
+}
+
+#[macro_export]
+macro_rules! log_error {
+    ($module:expr, $($arg:tt)+) => {
+        if $crate::log::LOGS_COMPILED_IN && $crate::log::log_enabled($module, $crate::log::LogLevel::Error) {
+            defmt::error!($($arg)+);
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! log_warn {
+    ($module:expr, $($arg:tt)+) => {
+        if $crate::log::LOGS_COMPILED_IN && $crate::log::log_enabled($module, $crate::log::LogLevel::Warn) {
+            defmt::warn!($($arg)+);
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! log_info {
+    ($module:expr, $($arg:tt)+) => {
+        if $crate::log::LOGS_COMPILED_IN && $crate::log::log_enabled($module, $crate::log::LogLevel::Info) {
+            defmt::info!($($arg)+);
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! log_debug {
+    ($module:expr, $($arg:tt)+) => {
+        if $crate::log::LOGS_COMPILED_IN && $crate::log::log_enabled($module, $crate::log::LogLevel::Debug) {
+            defmt::debug!($($arg)+);
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! log_trace {
+    ($module:expr, $($arg:tt)+) => {
+        if $crate::log::LOGS_COMPILED_IN && $crate::log::log_enabled($module, $crate::log::LogLevel::Trace) {
+            defmt::trace!($($arg)+);
+        }
+    };
+}
@@ -1,15 +1,56 @@
 use rmk::{
   debounce::{DebounceState, DebouncerTrait},
-  keyboard::KEY_EVENT_CHANNEL,
   event::KeyEvent,
   matrix::{MatrixTrait, KeyState},
 };
-use embassy_time::{Instant, Timer};
+use embassy_time::{Duration, Instant, Timer};
 use embedded_hal::digital::{InputPin, OutputPin};
 #[cfg(feature = "async_matrix")]
+use embassy_futures::select::select;
+#[cfg(feature = "async_matrix")]
 use embedded_hal_async::digital::Wait;
+#[cfg(feature = "async_matrix")]
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::{AtomicU32, Ordering};
+#[cfg(feature = "rp2040")]
+use embassy_rp::gpio::{Drive, Input, Output, SlewRate};
+
+use crate::bench::SCAN_TIMING;
+use crate::event::{process_key_event, KeyEventHook, KeyEventSink, RmkSink, INPUT_EVENT_CHANNEL};
+use crate::log::LogModule;
+use crate::{log_debug, log_info};
+use crate::quiesce::park_if_paused;
+
+
+/// Whether a sequential matrix waits for a key with `any_not` low, its input going high on any press
+#[cfg(feature = "async_matrix")]
+static MATRIX_WAITING: AtomicBool = AtomicBool::new(false);
 
+#[cfg(feature = "async_matrix")]
+pub fn is_matrix_waiting() -> bool {
+    MATRIX_WAITING.load(Ordering::Relaxed)
+}
+
+/// Wait between the scans while the host sleeps, in microseconds, 0 for the regular rate
+static SLEEP_SCAN_INTERVAL_US: AtomicU32 = AtomicU32::new(0);
+
+/// Slow the scans down, e.g. by the [sleep profile](crate::host_sleep::SleepProfile), `None` restores the regular rate
+pub fn set_sleep_scan_interval(interval: Option<Duration>) {
+    let us = interval.map_or(0, |i| i.as_micros().clamp(1, u32::MAX as u64) as u32);
+    SLEEP_SCAN_INTERVAL_US.store(us, Ordering::Relaxed);
+}
 
+/// Wait between the scans, never faster than `regular`
+fn scan_interval(regular: Duration) -> Duration {
+    match SLEEP_SCAN_INTERVAL_US.load(Ordering::Relaxed) {
+        0 => regular,
+        us => Duration::from_micros(us as u64).max(regular),
+    }
+}
+
+/// Pins driving the sequential matrix.
+/// On a split, the [attention line](crate::split_link::AttentionLink) isn't one of them: the peripheral
+/// drives it along the UART, and the central only passes its GPIO number to the dormant sleep next to `input`'s.
 pub struct SequentialMatrixPins<
     #[cfg(feature = "async_matrix")] In: Wait + InputPin,
     #[cfg(not(feature = "async_matrix"))] In: InputPin,
@@ -45,19 +86,96 @@ impl <
 }
 
 
+/// RP2040 pad settings of the sequential matrix pins, e.g. a stronger drive for long flex cables
+/// or slow edges for boards seeing double clocking of the shift registers
+#[cfg(feature = "rp2040")]
+#[derive(Clone, Copy, Debug)]
+pub struct MatrixPadConfig {
+    /// Drive strength of the clock, `any_not` and reset outputs
+    pub drive: Drive,
+    /// Edge rate of the outputs
+    pub slew: SlewRate,
+    /// Schmitt trigger of the input
+    pub hysteresis: bool,
+}
+
+#[cfg(feature = "rp2040")]
+impl MatrixPadConfig {
+    /// The pads out of reset
+    pub const DEFAULT: Self = Self {
+        drive: Drive::_4mA,
+        slew: SlewRate::Slow,
+        hysteresis: true,
+    };
+
+    pub fn apply_output(&self, pin: &mut Output<'_>) {
+        pin.set_drive_strength(self.drive);
+        pin.set_slew_rate(self.slew);
+    }
+
+    pub fn apply_input(&self, pin: &mut Input<'_>) {
+        pin.set_schmitt(self.hysteresis);
+    }
+}
+
+
+/// Positions with a physical switch. Masked ones are still clocked through the shift registers,
+/// but their readings are ignored, so floating bits don't make phantom events.
+#[derive(Clone, Copy, Debug)]
+pub struct MatrixMask<const ROW: usize, const COL: usize> {
+    pub present: [[bool; COL]; ROW],
+}
+
+impl<const ROW: usize, const COL: usize> MatrixMask<ROW, COL> {
+    pub const fn new(present: [[bool; COL]; ROW]) -> Self {
+        Self { present }
+    }
+
+    /// Every position has a switch
+    pub const fn all() -> Self {
+        Self { present: [[true; COL]; ROW] }
+    }
+
+    pub fn is_present(&self, row: usize, col: usize) -> bool {
+        self.present[row][col]
+    }
+}
+
+
+/// Majority of 3 reads of a cell, the third one only taken when the first two disagree
+fn majority_of_reads(mut read: impl FnMut() -> bool) -> bool {
+    let (a, b) = (read(), read());
+    if a == b {
+        a
+    } else {
+        read()
+    }
+}
+
+
 pub struct SequentialMatrix<
     #[cfg(feature = "async_matrix")] In: Wait + InputPin,
     #[cfg(not(feature = "async_matrix"))] In: InputPin,
     Out: OutputPin,
     D: DebouncerTrait,
+    H: KeyEventHook,
     const ROW: usize,
     const COL: usize,
+    S: KeyEventSink = RmkSink,
 > {
     pins: SequentialMatrixPins<In, Out>,
     /// Debouncer
     debouncer: D,
+    /// Hook applied to debounced events before sending them
+    hook: H,
+    /// Destination of the events, rmk by default
+    sink: S,
     /// Key state matrix
     key_states: [[KeyState; COL]; ROW],
+    /// Positions with a switch
+    mask: MatrixMask<ROW, COL>,
+    /// Whether each cell is read 3 times, taking the majority
+    majority_vote: bool,
     /// Start scanning
     #[allow(dead_code)]
     scan_start: Option<Instant>,
@@ -68,19 +186,25 @@ impl<
     #[cfg(not(feature = "async_matrix"))] In: InputPin,
     Out: OutputPin,
     D: DebouncerTrait,
+    H: KeyEventHook,
     const ROW: usize,
     const COL: usize,
-> SequentialMatrix<In, Out, D, ROW, COL> {
+> SequentialMatrix<In, Out, D, H, ROW, COL> {
     const PROPAGATION_DELAY: u64 = 50;
 
     pub fn new(
         pins: SequentialMatrixPins<In, Out>,
         debouncer: D,
+        hook: H,
     ) -> Self {
         Self {
             pins,
             debouncer,
+            hook,
+            sink: RmkSink,
             key_states: [[KeyState::new(); COL]; ROW],
+            mask: MatrixMask::all(),
+            majority_vote: false,
             scan_start: None,
         }
     }
@@ -91,9 +215,57 @@ impl<
     #[cfg(not(feature = "async_matrix"))] In: InputPin,
     Out: OutputPin,
     D: DebouncerTrait,
+    H: KeyEventHook,
     const ROW: usize,
     const COL: usize,
-> MatrixTrait for SequentialMatrix<In, Out, D, ROW, COL> {
+    S: KeyEventSink,
+> SequentialMatrix<In, Out, D, H, ROW, COL, S> {
+    /// Ignore the positions without a switch
+    pub fn with_mask(mut self, mask: MatrixMask<ROW, COL>) -> Self {
+        self.mask = mask;
+        self
+    }
+
+    /// Read each cell 3 times and take the majority, rejecting glitches picked up along the shift register chain.
+    /// The reads are back to back, adding a GPIO read or two per cell.
+    pub fn with_majority_vote(mut self) -> Self {
+        self.majority_vote = true;
+        self
+    }
+
+    fn read_input(&mut self) -> bool {
+        let mut read = || self.pins.input.is_high().ok().unwrap_or_default();
+        if !self.majority_vote {
+            return read();
+        }
+        majority_of_reads(read)
+    }
+
+    /// Send the events to the sink instead of rmk
+    pub fn with_sink<S2: KeyEventSink>(self, sink: S2) -> SequentialMatrix<In, Out, D, H, ROW, COL, S2> {
+        SequentialMatrix {
+            pins: self.pins,
+            debouncer: self.debouncer,
+            hook: self.hook,
+            sink,
+            key_states: self.key_states,
+            mask: self.mask,
+            majority_vote: self.majority_vote,
+            scan_start: self.scan_start,
+        }
+    }
+}
+
+impl<
+    #[cfg(feature = "async_matrix")] In: Wait + InputPin,
+    #[cfg(not(feature = "async_matrix"))] In: InputPin,
+    Out: OutputPin,
+    D: DebouncerTrait,
+    H: KeyEventHook,
+    const ROW: usize,
+    const COL: usize,
+    S: KeyEventSink,
+> MatrixTrait for SequentialMatrix<In, Out, D, H, ROW, COL, S> {
     const ROW: usize = ROW;
     const COL: usize = COL;
 
@@ -112,7 +284,10 @@ impl<
         self.pins.any_not.set_low().ok();
         Timer::after_nanos(Self::PROPAGATION_DELAY).await;
 
-        let _ = self.pins.input.wait_for_high().await;
+        // Wake on a non-matrix input too, so it doesn't wait for a key press
+        MATRIX_WAITING.store(true, Ordering::Relaxed);
+        select(self.pins.input.wait_for_high(), INPUT_EVENT_CHANNEL.ready_to_receive()).await;
+        MATRIX_WAITING.store(false, Ordering::Relaxed);
 
         // Set any_not pin back to high
         self.pins.any_not.set_high().ok();
@@ -121,12 +296,21 @@ impl<
     }
 
     async fn scan(&mut self) {
-        defmt::info!("Matrix scanning");
+        log_info!(LogModule::Matrix, "Matrix scanning");
         loop {
             #[cfg(feature = "async_matrix")]
             self.wait_for_key().await;
 
+            // Keep the key states latched through flash operations
+            park_if_paused().await;
+
+            // Non-matrix inputs, debounced by their source if needed
+            while let Ok(event) = INPUT_EVENT_CHANNEL.try_receive() {
+                process_key_event(&mut self.hook, &mut self.sink, event).await;
+            }
+
             // Reset
+            let scan_started = Instant::now();
             self.pins.row_clock.set_low().ok();
             self.pins.col_clock.set_low().ok();
             self.pins.any_not.set_high().ok();
@@ -138,11 +322,12 @@ impl<
             // Scan matrix and send report
             for row in 0..ROW {
                 for col in 0..COL {
-                    // Check input pins and debounce
+                    // Check input pins and debounce, masked positions read as released
+                    let pin_state = self.mask.is_present(row, col) && self.read_input();
                     let debounce_state = self.debouncer.detect_change_with_debounce(
                         row,
                         col,
-                        self.pins.input.is_high().ok().unwrap_or_default(),
+                        pin_state,
                         &self.key_states[row][col],
                     );
 
@@ -151,13 +336,13 @@ impl<
                             self.key_states[row][col].toggle_pressed();
                             let key_state = self.key_states[row][col];
 
-                            KEY_EVENT_CHANNEL
-                                .send(KeyEvent {
-                                    row: row as u8,
-                                    col: col as u8,
-                                    pressed: key_state.pressed,
-                                })
-                                .await;
+                            let event = KeyEvent {
+                                row: row as u8,
+                                col: col as u8,
+                                pressed: key_state.pressed,
+                            };
+                            log_debug!(LogModule::Matrix, "Key event: {}", event);
+                            process_key_event(&mut self.hook, &mut self.sink, event).await;
                         }
                         _ => (),
                     }
@@ -179,8 +364,9 @@ impl<
                 self.pins.row_clock.set_low().ok();
                 Timer::after_nanos(Self::PROPAGATION_DELAY).await;
             }
+            SCAN_TIMING.record(scan_started.elapsed());
 
-            embassy_time::Timer::after_micros(100).await;
+            Timer::after(scan_interval(Duration::from_micros(100))).await;
         }
     }
 
@@ -250,3 +436,57 @@ impl<
         self.matrix.update_key_state(row - ROW_OFFSET, col - COL_OFFSET, f);
     }
 }
+
+
+/// Stand-in for a matrix scanning elsewhere, e.g. on core1 or at the interrupt priority, handed to rmk.
+/// Its scan never finishes and key states are not mirrored, rmk only consumes the events.
+pub struct MatrixProxy<const ROW: usize, const COL: usize>;
+
+impl<const ROW: usize, const COL: usize> MatrixTrait for MatrixProxy<ROW, COL> {
+    const ROW: usize = ROW;
+    const COL: usize = COL;
+
+    #[cfg(feature = "async_matrix")]
+    async fn wait_for_key(&mut self) {
+        core::future::pending::<()>().await
+    }
+
+    async fn scan(&mut self) {
+        core::future::pending::<()>().await
+    }
+
+    fn get_key_state(&mut self, _row: usize, _col: usize) -> KeyState {
+        KeyState::new()
+    }
+
+    fn update_key_state(&mut self, _row: usize, _col: usize, _f: impl FnOnce(&mut KeyState)) {}
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Majority of the scripted reads, with the number of reads taken
+    fn vote(reads: &[bool]) -> (bool, usize) {
+        let mut taken = 0;
+        let result = majority_of_reads(|| {
+            taken += 1;
+            reads[taken - 1]
+        });
+        (result, taken)
+    }
+
+    #[test]
+    fn agreeing_reads_take_two() {
+        assert_eq!(vote(&[true, true]), (true, 2));
+        assert_eq!(vote(&[false, false]), (false, 2));
+    }
+
+    #[test]
+    fn glitch_is_outvoted() {
+        assert_eq!(vote(&[true, false, true]), (true, 3));
+        assert_eq!(vote(&[false, true, false]), (false, 3));
+        assert_eq!(vote(&[true, false, false]), (false, 3));
+    }
+}
@@ -0,0 +1,62 @@
+use portable_atomic::{AtomicU32, AtomicU8, Ordering};
+
+
+/// Counters of the event and report queues towards rmk
+pub struct QueueMetrics {
+    /// Items pushed
+    pub sent: AtomicU32,
+    /// Pushes which had to wait for a full queue
+    pub stalled: AtomicU32,
+    /// Items dropped since waiting wasn't allowed
+    pub dropped: AtomicU32,
+    /// Items merged into the one queued ahead of them
+    pub coalesced: AtomicU32,
+    /// Deepest queue length observed on push
+    pub max_depth: AtomicU8,
+}
+
+impl QueueMetrics {
+    pub const fn new() -> Self {
+        Self {
+            sent: AtomicU32::new(0),
+            stalled: AtomicU32::new(0),
+            dropped: AtomicU32::new(0),
+            coalesced: AtomicU32::new(0),
+            max_depth: AtomicU8::new(0),
+        }
+    }
+
+    pub fn record_sent(&self, depth: usize) {
+        self.sent.fetch_add(1, Ordering::Relaxed);
+        self.max_depth.fetch_max(depth.min(u8::MAX as usize) as u8, Ordering::Relaxed);
+    }
+
+    pub fn record_stalled(&self) {
+        self.stalled.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_dropped(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_coalesced(&self) {
+        self.coalesced.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// `[sent, stalled, dropped, coalesced (u32 LE each), max_depth]`
+    pub fn to_bytes(&self) -> [u8; 17] {
+        let mut bytes = [0u8; 17];
+        bytes[0..4].copy_from_slice(&self.sent.load(Ordering::Relaxed).to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.stalled.load(Ordering::Relaxed).to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.dropped.load(Ordering::Relaxed).to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.coalesced.load(Ordering::Relaxed).to_le_bytes());
+        bytes[16] = self.max_depth.load(Ordering::Relaxed);
+        bytes
+    }
+}
+
+/// Key events sent to rmk's key event channel
+pub static KEY_EVENT_METRICS: QueueMetrics = QueueMetrics::new();
+
+/// Keyboard reports sent by the firmware side, e.g. typed text
+pub static REPORT_METRICS: QueueMetrics = QueueMetrics::new();
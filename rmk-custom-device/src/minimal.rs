@@ -0,0 +1,35 @@
+//! Pieces of the `minimal` build without RTT: a defmt logger discarding everything
+//! and a panic handler resetting the MCU, replacing `defmt_rtt` and `panic_probe`.
+//!
+//! Build with `DEFMT_LOG=off` as well, so that rmk's own messages are compiled out too.
+
+/// defmt logger discarding the frames, for the messages `DEFMT_LOG` still compiles in
+#[defmt::global_logger]
+struct NullLogger;
+
+unsafe impl defmt::Logger for NullLogger {
+    fn acquire() {}
+
+    unsafe fn flush() {}
+
+    unsafe fn release() {}
+
+    unsafe fn write(_bytes: &[u8]) {}
+}
+
+/// Reset the MCU on panic, the keyboard comes back instead of hanging
+pub fn reset_on_panic() -> ! {
+    cortex_m::peripheral::SCB::sys_reset();
+}
+
+/// Define the panic handler resetting the MCU. Replaces `panic_probe`, use `crash_log_panic_handler` instead
+/// to keep the crash info.
+#[macro_export]
+macro_rules! minimal_panic_handler {
+    () => {
+        #[panic_handler]
+        fn panic(_info: &core::panic::PanicInfo) -> ! {
+            $crate::minimal::reset_on_panic()
+        }
+    };
+}
@@ -0,0 +1,52 @@
+//! Matrix scanning offloaded to the RP2040's second core.
+//! Key events still go through rmk's key event channel, which is safe across the cores
+//! since embassy-rp's critical section takes the hardware spinlock.
+
+use embassy_executor::Executor;
+use embassy_rp::{
+    multicore::{spawn_core1, Stack},
+    peripherals::CORE1,
+};
+use rmk::matrix::MatrixTrait;
+use static_cell::StaticCell;
+
+use crate::matrix::MatrixProxy;
+use crate::task_arena::TaskArena;
+
+
+const CORE1_STACK_SIZE: usize = 4096;
+/// Room for the task of the matrix scan, whose future holds the matrix, checked when the build spawns it
+const CORE1_TASK_ARENA_SIZE: usize = 4096;
+
+/// RAM taken by the second core's stack and task, for the [RAM budget](crate::ram_budget)
+pub(crate) const CORE1_RAM: usize = CORE1_STACK_SIZE + CORE1_TASK_ARENA_SIZE;
+
+static CORE1_STACK: StaticCell<Stack<CORE1_STACK_SIZE>> = StaticCell::new();
+static CORE1_EXECUTOR: StaticCell<Executor> = StaticCell::new();
+static CORE1_TASKS: TaskArena<CORE1_TASK_ARENA_SIZE, 1> = TaskArena::new();
+
+/// Run the matrix scan on core1 and return the proxy to pass to rmk.
+///
+/// Core1 runs its own executor with the scan as the only task, so the scan timing isn't affected
+/// by USB, storage or lighting tasks running on core0, while its timers still work.
+pub fn spawn_matrix_on_core1<M, const ROW: usize, const COL: usize>(
+    core1: CORE1,
+    mut matrix: M,
+) -> MatrixProxy<ROW, COL>
+where
+    M: MatrixTrait + Send + 'static,
+{
+    let stack = CORE1_STACK.init(Stack::new());
+    spawn_core1(core1, stack, move || {
+        let executor = CORE1_EXECUTOR.init(Executor::new());
+        executor.run(|spawner| {
+            defmt::info!("Matrix scanning on core1");
+            let scan = CORE1_TASKS.spawn(async move {
+                matrix.scan().await;
+                defmt::panic!("Matrix scan on core1 returned");
+            });
+            defmt::unwrap!(spawner.spawn(scan));
+        })
+    });
+    MatrixProxy
+}
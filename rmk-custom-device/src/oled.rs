@@ -0,0 +1,296 @@
+//! 128x32 SSD1306 OLED showing a status page, and a screensaver cycling animations while idle.
+
+use core::fmt::Write as _;
+use embassy_time::{Duration, Instant, Ticker};
+use embedded_hal::i2c::I2c;
+use heapless::{String, Vec};
+
+use crate::brightness::brightness;
+use crate::demo::demo_oled_page;
+use crate::event::idle_time;
+use crate::host_sleep::is_oled_asleep;
+use crate::layer_state::active_layer;
+use crate::soft_off::is_soft_off;
+use crate::training::{draw_training, is_training};
+use crate::typing_game::{draw_typing_game, is_typing_game_running};
+
+
+pub const OLED_WIDTH: usize = 128;
+pub const OLED_HEIGHT: usize = 32;
+
+/// Frame in the SSD1306 page layout: a byte is 8 vertical pixels, LSB on top, pages of 8 rows
+pub type OledFrame = [u8; OLED_WIDTH * OLED_HEIGHT / 8];
+
+/// Set or clear a pixel, out of the frame is ignored
+pub fn set_pixel(frame: &mut OledFrame, x: usize, y: usize, on: bool) {
+    if x >= OLED_WIDTH || y >= OLED_HEIGHT {
+        return;
+    }
+    let byte = &mut frame[y / 8 * OLED_WIDTH + x];
+    if on {
+        *byte |= 1 << (y % 8);
+    } else {
+        *byte &= !(1 << (y % 8));
+    }
+}
+
+/// 3x5 digits, a row per 3 bits from the top, MSB on the left
+const DIGITS: [[u8; 5]; 10] = [
+    [0b111, 0b101, 0b101, 0b101, 0b111],
+    [0b010, 0b110, 0b010, 0b010, 0b111],
+    [0b111, 0b001, 0b111, 0b100, 0b111],
+    [0b111, 0b001, 0b111, 0b001, 0b111],
+    [0b101, 0b101, 0b111, 0b001, 0b001],
+    [0b111, 0b100, 0b111, 0b001, 0b111],
+    [0b111, 0b100, 0b111, 0b101, 0b111],
+    [0b111, 0b001, 0b010, 0b010, 0b010],
+    [0b111, 0b101, 0b111, 0b101, 0b111],
+    [0b111, 0b101, 0b111, 0b001, 0b111],
+];
+
+/// 3x5 capitals, like [`DIGITS`]
+const LETTERS: [[u8; 5]; 26] = [
+    [0b010, 0b101, 0b111, 0b101, 0b101],
+    [0b110, 0b101, 0b110, 0b101, 0b110],
+    [0b011, 0b100, 0b100, 0b100, 0b011],
+    [0b110, 0b101, 0b101, 0b101, 0b110],
+    [0b111, 0b100, 0b110, 0b100, 0b111],
+    [0b111, 0b100, 0b110, 0b100, 0b100],
+    [0b011, 0b100, 0b101, 0b101, 0b011],
+    [0b101, 0b101, 0b111, 0b101, 0b101],
+    [0b111, 0b010, 0b010, 0b010, 0b111],
+    [0b001, 0b001, 0b001, 0b101, 0b010],
+    [0b101, 0b101, 0b110, 0b101, 0b101],
+    [0b100, 0b100, 0b100, 0b100, 0b111],
+    [0b101, 0b111, 0b111, 0b101, 0b101],
+    [0b110, 0b101, 0b101, 0b101, 0b101],
+    [0b010, 0b101, 0b101, 0b101, 0b010],
+    [0b110, 0b101, 0b110, 0b100, 0b100],
+    [0b010, 0b101, 0b101, 0b110, 0b011],
+    [0b110, 0b101, 0b110, 0b101, 0b101],
+    [0b011, 0b100, 0b010, 0b001, 0b110],
+    [0b111, 0b010, 0b010, 0b010, 0b010],
+    [0b101, 0b101, 0b101, 0b101, 0b111],
+    [0b101, 0b101, 0b101, 0b101, 0b010],
+    [0b101, 0b101, 0b111, 0b111, 0b101],
+    [0b101, 0b101, 0b010, 0b101, 0b101],
+    [0b101, 0b101, 0b010, 0b010, 0b010],
+    [0b111, 0b001, 0b010, 0b100, 0b111],
+];
+
+/// 3x5 glyph of the char, lowercase drawn as capitals, `?` for chars without one
+pub fn glyph_3x5(c: char) -> [u8; 5] {
+    match c.to_ascii_uppercase() {
+        c @ '0'..='9' => DIGITS[c as usize - '0' as usize],
+        c @ 'A'..='Z' => LETTERS[c as usize - 'A' as usize],
+        ' ' => [0; 5],
+        '-' => [0b000, 0b000, 0b111, 0b000, 0b000],
+        '_' => [0b000, 0b000, 0b000, 0b000, 0b111],
+        '+' => [0b000, 0b010, 0b111, 0b010, 0b000],
+        '/' => [0b001, 0b001, 0b010, 0b100, 0b100],
+        '(' => [0b010, 0b100, 0b100, 0b100, 0b010],
+        ')' => [0b010, 0b001, 0b001, 0b001, 0b010],
+        '.' => [0b000, 0b000, 0b000, 0b000, 0b010],
+        _ => [0b111, 0b001, 0b010, 0b000, 0b010],
+    }
+}
+
+/// Draw the glyph with its top left at `(x, y)`, each dot `scale` pixels wide
+pub fn draw_glyph(frame: &mut OledFrame, glyph: [u8; 5], x: usize, y: usize, scale: usize) {
+    for (row, bits) in glyph.iter().enumerate() {
+        for col in 0..3 {
+            if bits & (0b100 >> col) == 0 {
+                continue;
+            }
+            for d in 0..scale * scale {
+                set_pixel(frame, x + col * scale + d % scale, y + row * scale + d / scale, true);
+            }
+        }
+    }
+}
+
+/// Draw the text in the 3x5 font from `(x, y)`, 4 dots per char, clipped at the right edge
+pub fn draw_text(frame: &mut OledFrame, text: &str, x: usize, y: usize, scale: usize) {
+    for (i, c) in text.chars().enumerate() {
+        draw_glyph(frame, glyph_3x5(c), x + i * 4 * scale, y, scale);
+    }
+}
+
+/// SSD1306 over I2C, 128x32
+pub struct Ssd1306<I: I2c> {
+    i2c: I,
+    contrast: u8,
+}
+
+impl<I: I2c> Ssd1306<I> {
+    const ADDRESS: u8 = 0x3C;
+    const COMMAND: u8 = 0x00;
+    const DATA: u8 = 0x40;
+
+    pub fn new(mut i2c: I) -> Self {
+        #[rustfmt::skip]
+        let init = [
+            Self::COMMAND,
+            0xAE,       // display off
+            0xD5, 0x80, // clock divide
+            0xA8, 0x1F, // multiplex, 32 rows
+            0xD3, 0x00, // no offset
+            0x40,       // start line 0
+            0x8D, 0x14, // charge pump on
+            0x20, 0x00, // horizontal addressing
+            0xA1, 0xC8, // flipped for the usual mounting
+            0xDA, 0x02, // COM pins for 32 rows
+            0xD9, 0xF1, // precharge
+            0xDB, 0x40, // VCOMH
+            0xA4, 0xA6, // RAM content, not inverted
+            0xAF,       // display on
+        ];
+        let _ = i2c.write(Self::ADDRESS, &init);
+        Self { i2c, contrast: 0 }
+    }
+
+    pub fn set_contrast(&mut self, contrast: u8) {
+        if contrast != self.contrast {
+            self.contrast = contrast;
+            let _ = self.i2c.write(Self::ADDRESS, &[Self::COMMAND, 0x81, contrast]);
+        }
+    }
+
+    pub fn set_display_on(&mut self, on: bool) {
+        let _ = self.i2c.write(Self::ADDRESS, &[Self::COMMAND, if on { 0xAF } else { 0xAE }]);
+    }
+
+    pub fn flush(&mut self, frame: &OledFrame) {
+        let window = [Self::COMMAND, 0x21, 0, (OLED_WIDTH - 1) as u8, 0x22, 0, (OLED_HEIGHT / 8 - 1) as u8];
+        if self.i2c.write(Self::ADDRESS, &window).is_err() {
+            return;
+        }
+        let mut buf = [0u8; 33];
+        buf[0] = Self::DATA;
+        for chunk in frame.chunks(32) {
+            buf[1..1 + chunk.len()].copy_from_slice(chunk);
+            if self.i2c.write(Self::ADDRESS, &buf[..1 + chunk.len()]).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Provider of the frames, e.g. the status page or a screensaver animation
+pub trait OledAnimation {
+    fn draw(&mut self, frame: &mut OledFrame, now: Instant);
+}
+
+/// Status page of the active layer, `LAYER 2`
+pub struct LayerPage;
+
+impl OledAnimation for LayerPage {
+    fn draw(&mut self, frame: &mut OledFrame, _now: Instant) {
+        let mut line: String<8> = String::new();
+        let _ = write!(line, "LAYER {}", active_layer());
+        draw_text(frame, &line, 0, 8, 3);
+    }
+}
+
+/// Bitmaps played in a loop, e.g. bongo cat frames or a logo in flash.
+/// `bitmaps` is a multiple of [`OledFrame`] in its layout, a trailing partial frame is ignored.
+pub struct BitmapAnimation {
+    bitmaps: &'static [u8],
+    interval: Duration,
+}
+
+impl BitmapAnimation {
+    pub const fn new(bitmaps: &'static [u8], interval: Duration) -> Self {
+        Self { bitmaps, interval }
+    }
+}
+
+impl OledAnimation for BitmapAnimation {
+    fn draw(&mut self, frame: &mut OledFrame, now: Instant) {
+        let count = self.bitmaps.len() / frame.len();
+        if count == 0 {
+            return;
+        }
+        let index = (now.as_ticks() / self.interval.as_ticks().max(1)) as usize % count;
+        frame.copy_from_slice(&self.bitmaps[index * frame.len()..(index + 1) * frame.len()]);
+    }
+}
+
+/// Animations cycled while idle, each for `duration`
+pub struct Screensaver<'a, const N: usize> {
+    animations: Vec<&'a mut dyn OledAnimation, N>,
+    duration: Duration,
+}
+
+impl<'a, const N: usize> Screensaver<'a, N> {
+    pub fn new(duration: Duration) -> Self {
+        Self {
+            animations: Vec::new(),
+            duration,
+        }
+    }
+
+    /// Add an animation to the cycle, returns false if full
+    pub fn register(&mut self, animation: &'a mut dyn OledAnimation) -> bool {
+        self.animations.push(animation).is_ok()
+    }
+
+    fn draw(&mut self, frame: &mut OledFrame, now: Instant, since: Instant) {
+        let elapsed = now.duration_since(since).as_ticks();
+        let index = (elapsed / self.duration.as_ticks().max(1)) as usize;
+        self.draw_animation(index, frame, now);
+    }
+
+    fn draw_animation(&mut self, index: usize, frame: &mut OledFrame, now: Instant) {
+        if self.animations.is_empty() {
+            frame.fill(0);
+            return;
+        }
+        let count = self.animations.len();
+        self.animations[index % count].draw(frame, now);
+    }
+}
+
+/// Show the status page, the typing game while it runs, the pressed actions while training, the demo page while the demo runs, or the screensaver after `idle_timeout` without key events, at the frame rate.
+/// The contrast follows the global brightness, and the display is off while the host sleeps if the sleep profile says so.
+/// This function should never return.
+pub async fn run_oled<I: I2c, S: OledAnimation, const N: usize>(
+    mut display: Ssd1306<I>,
+    mut status: S,
+    mut screensaver: Screensaver<'_, N>,
+    idle_timeout: Duration,
+    fps: u64,
+) -> ! {
+    let mut ticker = Ticker::every(Duration::from_hz(fps));
+    let mut frame: OledFrame = [0; OLED_WIDTH * OLED_HEIGHT / 8];
+    let mut idle_since = None;
+    let mut display_on = true;
+    loop {
+        if display_on == (is_soft_off() || is_oled_asleep()) {
+            display_on = !display_on;
+            display.set_display_on(display_on);
+        }
+        if !display_on {
+            ticker.next().await;
+            continue;
+        }
+        let now = Instant::now();
+        frame.fill(0);
+        if is_typing_game_running() {
+            draw_typing_game(&mut frame, now);
+        } else if is_training() {
+            draw_training(&mut frame);
+        } else if let Some(page) = demo_oled_page() {
+            screensaver.draw_animation(page as usize, &mut frame, now);
+        } else if idle_time() < idle_timeout {
+            idle_since = None;
+            status.draw(&mut frame, now);
+        } else {
+            let since = *idle_since.get_or_insert(now);
+            screensaver.draw(&mut frame, now, since);
+        }
+        display.set_contrast(brightness());
+        display.flush(&frame);
+        ticker.next().await;
+    }
+}
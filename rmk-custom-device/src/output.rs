@@ -0,0 +1,270 @@
+//! Output of the reports made by the firmware, e.g. typed text and pointer motion.
+//! Emitters queue [`OutputReport`]s and the output task hands them to an [`OutputTransport`],
+//! so a new output like a dongle or a test dump doesn't touch the emitters.
+//!
+//! Modifiers held by an action are added to the keyboard reports over USB by [`HeldModifiersDriver`],
+//! rmk's own included, so they cover its macros and mouse keys too. They aren't added over BLE.
+
+use core::cell::Cell;
+use core::fmt::Write as _;
+use portable_atomic::{AtomicU8, Ordering};
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    channel::{Channel, TrySendError},
+};
+use embassy_usb_driver::{Driver, Endpoint, EndpointAllocError, EndpointError, EndpointIn, EndpointInfo, EndpointType};
+use embedded_io_async::Write;
+use heapless::String;
+use rmk::keyboard::{KeyboardReportMessage, KEYBOARD_REPORT_CHANNEL};
+use rmk::usb::descriptor::{CompositeReport, CompositeReportType};
+use usbd_hid::descriptor::{KeyboardReport, MouseReport};
+
+use crate::metrics::REPORT_METRICS;
+use crate::queues::OUTPUT_REPORT_QUEUE_DEPTH;
+
+
+#[derive(Clone, Copy)]
+pub enum OutputReport {
+    Keyboard(KeyboardReport),
+    Mouse(MouseReport),
+}
+
+static OUTPUT_CHANNEL: Channel<CriticalSectionRawMutex, OutputReport, OUTPUT_REPORT_QUEUE_DEPTH> = Channel::new();
+/// Modifiers added to every keyboard report while held by [`hold_modifiers`]
+static HELD_MODIFIERS: AtomicU8 = AtomicU8::new(0);
+/// Last keyboard report as written to USB, before adding the held modifiers
+static LAST_KEYBOARD_REPORT: Mutex<CriticalSectionRawMutex, Cell<[u8; KEYBOARD_REPORT_SIZE]>> =
+    Mutex::new(Cell::new([0; KEYBOARD_REPORT_SIZE]));
+
+/// rmk's boot keyboard report, `[modifier, reserved, keycodes...]`
+const KEYBOARD_REPORT_SIZE: usize = 8;
+
+/// Queue the report for the output task, waiting if the queue is full
+pub async fn send_output_report(report: OutputReport) {
+    OUTPUT_CHANNEL.send(report).await;
+}
+
+/// Queue the report without waiting, returns false if the queue is full
+pub fn try_send_output_report(report: OutputReport) -> bool {
+    OUTPUT_CHANNEL.try_send(report).is_ok()
+}
+
+/// The last keyboard report again, to show the host a change of the held modifiers without touching the keys
+fn last_keyboard_report() -> OutputReport {
+    let last = LAST_KEYBOARD_REPORT.lock(|r| r.get());
+    let mut report = KeyboardReport::default();
+    report.modifier = last[0];
+    report.keycodes.copy_from_slice(&last[2..]);
+    OutputReport::Keyboard(report)
+}
+
+/// Hold the modifiers, sent right away and added to the keyboard reports until [`release_modifiers`]
+pub async fn hold_modifiers(modifier: u8) {
+    HELD_MODIFIERS.fetch_or(modifier, Ordering::Relaxed);
+    send_output_report(last_keyboard_report()).await;
+}
+
+/// Like [`hold_modifiers`] without waiting, for a hook holding them around a key of rmk.
+/// The report goes to rmk's queue directly, so the host has the modifiers ahead of rmk's reports for the key
+pub fn try_hold_modifiers(modifier: u8) {
+    HELD_MODIFIERS.fetch_or(modifier, Ordering::Relaxed);
+    if let OutputReport::Keyboard(report) = last_keyboard_report() {
+        let _ = KEYBOARD_REPORT_CHANNEL.try_send(KeyboardReportMessage::KeyboardReport(report));
+    }
+}
+
+/// Release the modifiers held by [`hold_modifiers`]
+pub async fn release_modifiers(modifier: u8) {
+    HELD_MODIFIERS.fetch_and(!modifier, Ordering::Relaxed);
+    send_output_report(last_keyboard_report()).await;
+}
+
+/// Destination of the reports
+#[allow(async_fn_in_trait)]
+pub trait OutputTransport {
+    async fn send(&mut self, report: &OutputReport);
+}
+
+/// Send to both, e.g. the host and a test dump
+impl<A: OutputTransport, B: OutputTransport> OutputTransport for (A, B) {
+    async fn send(&mut self, report: &OutputReport) {
+        self.0.send(report).await;
+        self.1.send(report).await;
+    }
+}
+
+/// rmk's report queue, sent by whichever of USB or BLE rmk is running
+pub struct RmkOutput;
+
+impl OutputTransport for RmkOutput {
+    async fn send(&mut self, report: &OutputReport) {
+        let message = match *report {
+            OutputReport::Keyboard(report) => KeyboardReportMessage::KeyboardReport(report),
+            OutputReport::Mouse(report) => KeyboardReportMessage::CompositeReport(
+                CompositeReport {
+                    buttons: report.buttons,
+                    x: report.x,
+                    y: report.y,
+                    wheel: report.wheel,
+                    pan: report.pan,
+                    ..Default::default()
+                },
+                CompositeReportType::Mouse,
+            ),
+        };
+        if let Err(TrySendError::Full(message)) = KEYBOARD_REPORT_CHANNEL.try_send(message) {
+            REPORT_METRICS.record_stalled();
+            KEYBOARD_REPORT_CHANNEL.send(message).await;
+        }
+        REPORT_METRICS.record_sent(KEYBOARD_REPORT_CHANNEL.len());
+    }
+}
+
+/// One line of hex per report, e.g. `K 02 00 04 00 00 00 00 00`, for testing over a serial port
+pub struct TextDumpOutput<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> TextDumpOutput<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+}
+
+impl<W: Write> OutputTransport for TextDumpOutput<W> {
+    async fn send(&mut self, report: &OutputReport) {
+        let mut line: String<32> = String::new();
+        let _ = match report {
+            OutputReport::Keyboard(r) => {
+                let _ = write!(line, "K {:02x} {:02x}", r.modifier, r.leds);
+                r.keycodes.iter().try_for_each(|k| write!(line, " {:02x}", k))
+            }
+            OutputReport::Mouse(r) => write!(
+                line,
+                "M {:02x} {} {} {} {}",
+                r.buttons, r.x, r.y, r.wheel, r.pan
+            ),
+        };
+        let _ = line.push('\n');
+        let _ = self.writer.write_all(line.as_bytes()).await;
+    }
+}
+
+/// The report making the same change as `first` followed by `next`, if there's one.
+/// Mouse motion under the same buttons adds up while it fits, and a repeated keyboard report changes nothing,
+/// so no click or key stroke is ever merged away.
+fn coalesce(first: &OutputReport, next: &OutputReport) -> Option<OutputReport> {
+    match (first, next) {
+        (OutputReport::Mouse(a), OutputReport::Mouse(b)) if a.buttons == b.buttons => {
+            let mut merged = *a;
+            merged.x = a.x.checked_add(b.x)?;
+            merged.y = a.y.checked_add(b.y)?;
+            merged.wheel = a.wheel.checked_add(b.wheel)?;
+            merged.pan = a.pan.checked_add(b.pan)?;
+            Some(OutputReport::Mouse(merged))
+        }
+        (OutputReport::Keyboard(a), OutputReport::Keyboard(b))
+            if a.modifier == b.modifier && a.keycodes == b.keycodes =>
+        {
+            Some(*first)
+        }
+        _ => None,
+    }
+}
+
+/// Hand the queued reports to the transport. The reports piling up while it's stalled, e.g. by a sleeping host,
+/// are [coalesced](coalesce) with the one ahead of them and counted in [`REPORT_METRICS`].
+/// This function should never return.
+pub async fn run_output<T: OutputTransport>(mut transport: T) -> ! {
+    let mut pending = OUTPUT_CHANNEL.receive().await;
+    loop {
+        while let Ok(next) = OUTPUT_CHANNEL.try_receive() {
+            match coalesce(&pending, &next) {
+                Some(merged) => {
+                    pending = merged;
+                    REPORT_METRICS.record_coalesced();
+                }
+                None => {
+                    transport.send(&pending).await;
+                    pending = next;
+                }
+            }
+        }
+        transport.send(&pending).await;
+        pending = OUTPUT_CHANNEL.receive().await;
+    }
+}
+
+
+/// USB driver adding the [held modifiers](hold_modifiers) to the keyboard reports written by rmk, wrap the driver handed to it
+pub struct HeldModifiersDriver<D> {
+    inner: D,
+}
+
+impl<D> HeldModifiersDriver<D> {
+    pub fn new(inner: D) -> Self {
+        Self { inner }
+    }
+}
+
+impl<'a, D: Driver<'a>> Driver<'a> for HeldModifiersDriver<D> {
+    type EndpointOut = D::EndpointOut;
+    type EndpointIn = HeldModifiersEndpointIn<D::EndpointIn>;
+    type ControlPipe = D::ControlPipe;
+    type Bus = D::Bus;
+
+    fn alloc_endpoint_out(
+        &mut self,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<Self::EndpointOut, EndpointAllocError> {
+        self.inner.alloc_endpoint_out(ep_type, max_packet_size, interval_ms)
+    }
+
+    fn alloc_endpoint_in(
+        &mut self,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<Self::EndpointIn, EndpointAllocError> {
+        let inner = self.inner.alloc_endpoint_in(ep_type, max_packet_size, interval_ms)?;
+        Ok(HeldModifiersEndpointIn {
+            inner,
+            keyboard: ep_type == EndpointType::Interrupt && max_packet_size as usize == KEYBOARD_REPORT_SIZE,
+        })
+    }
+
+    fn start(self, control_max_packet_size: u16) -> (Self::Bus, Self::ControlPipe) {
+        self.inner.start(control_max_packet_size)
+    }
+}
+
+/// IN endpoint recording the keyboard reports and adding the held modifiers
+pub struct HeldModifiersEndpointIn<E> {
+    inner: E,
+    keyboard: bool,
+}
+
+impl<E: Endpoint> Endpoint for HeldModifiersEndpointIn<E> {
+    fn info(&self) -> &EndpointInfo {
+        self.inner.info()
+    }
+
+    async fn wait_enabled(&mut self) {
+        self.inner.wait_enabled().await
+    }
+}
+
+impl<E: EndpointIn> EndpointIn for HeldModifiersEndpointIn<E> {
+    async fn write(&mut self, buf: &[u8]) -> Result<(), EndpointError> {
+        if !self.keyboard || buf.len() != KEYBOARD_REPORT_SIZE {
+            return self.inner.write(buf).await;
+        }
+        let mut report = [0; KEYBOARD_REPORT_SIZE];
+        report.copy_from_slice(buf);
+        LAST_KEYBOARD_REPORT.lock(|r| r.set(report));
+        report[0] |= HELD_MODIFIERS.load(Ordering::Relaxed);
+        self.inner.write(&report).await
+    }
+}
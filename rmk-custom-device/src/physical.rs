@@ -0,0 +1,224 @@
+//! Physical positions of the keys, for the effects and mappings laid out in space instead of by matrix position.
+//! The firmwares generate the table from the KLE layout of their keyboard.toml at build time.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use embassy_time::Instant;
+use rmk::event::KeyEvent;
+use smart_leds::hsv::{hsv2rgb, Hsv};
+
+use crate::event::{HookContext, KeyEventHook};
+use crate::oled::{set_pixel, OledFrame, OLED_HEIGHT, OLED_WIDTH};
+use crate::rgb::{LedMap, RgbEffect, RGB8};
+
+
+/// Centers of the keys by matrix position, (x, y) in mm from the top left of the layout, `None` without a key
+pub type KeyPositions<const ROW: usize, const COL: usize> = [[Option<(u16, u16)>; COL]; ROW];
+
+#[derive(Clone, Copy)]
+pub struct PhysicalLayout<const ROW: usize, const COL: usize> {
+    positions: &'static KeyPositions<ROW, COL>,
+}
+
+impl<const ROW: usize, const COL: usize> PhysicalLayout<ROW, COL> {
+    pub const fn new(positions: &'static KeyPositions<ROW, COL>) -> Self {
+        Self { positions }
+    }
+
+    pub fn position(&self, row: u8, col: u8) -> Option<(u16, u16)> {
+        *self.positions.get(row as usize)?.get(col as usize)?
+    }
+
+    fn keys(&self) -> impl Iterator<Item = ((u8, u8), (u16, u16))> + '_ {
+        self.positions.iter().enumerate().flat_map(|(row, cols)| {
+            cols.iter()
+                .enumerate()
+                .filter_map(move |(col, position)| position.map(|p| ((row as u8, col as u8), p)))
+        })
+    }
+
+    /// Right and bottom most key centers
+    pub fn extent(&self) -> (u16, u16) {
+        self.keys().fold((0, 0), |(w, h), (_, (x, y))| (w.max(x), h.max(y)))
+    }
+
+    /// Key nearest to the position mirrored across the vertical center of the layout, for swap hands
+    pub fn mirrored(&self, row: u8, col: u8) -> Option<(u8, u8)> {
+        let (x, y) = self.position(row, col)?;
+        let left = self.keys().map(|(_, (kx, _))| kx).min()?;
+        let target = (left + self.extent().0) as i32 - x as i32;
+        self.keys()
+            .min_by_key(|(_, (kx, ky))| (*kx as i32 - target).pow(2) + (*ky as i32 - y as i32).pow(2))
+            .map(|(key, _)| key)
+    }
+}
+
+
+static SWAP_HANDS: AtomicBool = AtomicBool::new(false);
+
+pub fn set_swap_hands(swapped: bool) {
+    SWAP_HANDS.store(swapped, Ordering::Relaxed);
+}
+
+/// Hook sending the keys at their [mirrored](PhysicalLayout::mirrored) positions while swap hands is on,
+/// e.g. to type with one hand. A key is released where it was pressed, even if swap hands changed meanwhile.
+pub struct SwapHandsHook<const ROW: usize, const COL: usize> {
+    layout: PhysicalLayout<ROW, COL>,
+    /// Position each held key was sent at
+    sent_at: [[Option<(u8, u8)>; COL]; ROW],
+}
+
+impl<const ROW: usize, const COL: usize> SwapHandsHook<ROW, COL> {
+    pub const fn new(layout: PhysicalLayout<ROW, COL>) -> Self {
+        Self {
+            layout,
+            sent_at: [[None; COL]; ROW],
+        }
+    }
+}
+
+impl<const ROW: usize, const COL: usize> KeyEventHook for SwapHandsHook<ROW, COL> {
+    async fn process(&mut self, event: KeyEvent, _ctx: &mut HookContext) -> Option<KeyEvent> {
+        let Some(sent_at) = self
+            .sent_at
+            .get_mut(event.row as usize)
+            .and_then(|cols| cols.get_mut(event.col as usize))
+        else {
+            return Some(event);
+        };
+        let (row, col) = if event.pressed {
+            let position = if SWAP_HANDS.load(Ordering::Relaxed) {
+                self.layout.mirrored(event.row, event.col).unwrap_or((event.row, event.col))
+            } else {
+                (event.row, event.col)
+            };
+            *sent_at = Some(position);
+            position
+        } else {
+            sent_at.take().unwrap_or((event.row, event.col))
+        };
+        Some(KeyEvent {
+            row,
+            col,
+            pressed: event.pressed,
+        })
+    }
+}
+
+
+/// Rainbow sweeping across the layout from left to right
+pub struct WaveEffect<const N: usize, const ROW: usize, const COL: usize> {
+    map: LedMap<N>,
+    layout: PhysicalLayout<ROW, COL>,
+    /// Distance of a full hue cycle, in mm
+    wavelength: u16,
+    /// Sweep speed, in mm per second
+    speed: u16,
+    val: u8,
+}
+
+impl<const N: usize, const ROW: usize, const COL: usize> WaveEffect<N, ROW, COL> {
+    pub const fn new(map: LedMap<N>, layout: PhysicalLayout<ROW, COL>, wavelength: u16, speed: u16, val: u8) -> Self {
+        Self {
+            map,
+            layout,
+            wavelength,
+            speed,
+            val,
+        }
+    }
+}
+
+impl<const N: usize, const ROW: usize, const COL: usize> RgbEffect<N> for WaveEffect<N, ROW, COL> {
+    fn render(&mut self, frame: &mut [RGB8; N], now: Instant) {
+        let wavelength = self.wavelength.max(1) as u64;
+        let travelled = now.as_millis() * self.speed as u64 / 1000;
+        for (pixel, key) in frame.iter_mut().zip(self.map.positions.iter()) {
+            let Some((x, _)) = key.and_then(|(row, col)| self.layout.position(row, col)) else {
+                continue;
+            };
+            let hue = ((x as u64 + wavelength - travelled % wavelength) % wavelength * 256 / wavelength) as u8;
+            *pixel = hsv2rgb(Hsv {
+                hue,
+                sat: 255,
+                val: self.val,
+            });
+        }
+    }
+}
+
+
+/// Draw a 3x3 square per key scaled into the OLED, hollow unless `pressed` returns true for its matrix position
+pub fn draw_key_positions<const ROW: usize, const COL: usize>(
+    frame: &mut OledFrame,
+    layout: &PhysicalLayout<ROW, COL>,
+    pressed: impl Fn(u8, u8) -> bool,
+) {
+    let (width, height) = layout.extent();
+    // Same scale on both axes, keeping the margin of the dots
+    let scale_x = (OLED_WIDTH - 3) as u32 * 1000 / width.max(1) as u32;
+    let scale_y = (OLED_HEIGHT - 3) as u32 * 1000 / height.max(1) as u32;
+    let scale = scale_x.min(scale_y);
+    for ((row, col), (x, y)) in layout.keys() {
+        let (cx, cy) = ((x as u32 * scale / 1000) as usize + 1, (y as u32 * scale / 1000) as usize + 1);
+        let filled = pressed(row, col);
+        for dy in 0..3 {
+            for dx in 0..3 {
+                let edge = dx != 1 || dy != 1;
+                set_pixel(frame, cx + dx - 1, cy + dy - 1, filled || edge);
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+
+    use super::*;
+    use crate::event::{process_key_event, ChannelSink};
+    use crate::test_support::serial;
+
+    /// Two keys on each half with a gap between the halves, like the split's layout
+    static POSITIONS: KeyPositions<1, 4> = [[Some((10, 10)), Some((29, 10)), Some((67, 10)), Some((86, 10))]];
+    const LAYOUT: PhysicalLayout<1, 4> = PhysicalLayout::new(&POSITIONS);
+
+    fn run(hook: &mut SwapHandsHook<1, 4>, col: u8, pressed: bool) -> std::vec::Vec<(u8, u8, bool)> {
+        let channel: Channel<CriticalSectionRawMutex, KeyEvent, 8> = Channel::new();
+        let event = KeyEvent { row: 0, col, pressed };
+        embassy_futures::block_on(process_key_event(hook, &mut ChannelSink::new(&channel), event));
+        core::iter::from_fn(|| channel.try_receive().ok())
+            .map(|e| (e.row, e.col, e.pressed))
+            .collect()
+    }
+
+    #[test]
+    fn keys_mirror_across_the_halves() {
+        assert_eq!(LAYOUT.mirrored(0, 0), Some((0, 3)));
+        assert_eq!(LAYOUT.mirrored(0, 1), Some((0, 2)));
+        assert_eq!(LAYOUT.mirrored(0, 2), Some((0, 1)));
+    }
+
+    #[test]
+    fn swapped_keys_are_sent_mirrored() {
+        let _serial = serial();
+        let mut hook = SwapHandsHook::new(LAYOUT);
+        set_swap_hands(false);
+        assert_eq!(run(&mut hook, 0, true), [(0, 0, true)]);
+        assert_eq!(run(&mut hook, 0, false), [(0, 0, false)]);
+        set_swap_hands(true);
+        assert_eq!(run(&mut hook, 0, true), [(0, 3, true)]);
+        assert_eq!(run(&mut hook, 0, false), [(0, 3, false)]);
+        set_swap_hands(false);
+    }
+
+    #[test]
+    fn keys_are_released_where_they_were_pressed() {
+        let _serial = serial();
+        let mut hook = SwapHandsHook::new(LAYOUT);
+        set_swap_hands(true);
+        assert_eq!(run(&mut hook, 1, true), [(0, 2, true)]);
+        set_swap_hands(false);
+        assert_eq!(run(&mut hook, 1, false), [(0, 2, false)]);
+    }
+}
@@ -0,0 +1,264 @@
+//! Persisted settings of the pointing devices: CPI, acceleration, the sensor mounting angle, angle snapping
+//! and axis inversion. Adjusted by custom actions and over raw HID, applied by [`run_pointing`](crate::pointing::run_pointing).
+
+use core::cell::Cell;
+#[cfg(feature = "rp2040")]
+use embassy_rp::flash::{Flash, Instance, Mode};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_sync::signal::Signal;
+
+use crate::log::LogModule;
+use crate::log_info;
+use crate::quiesce::quiesce_flash;
+use crate::pointing::Motion;
+#[cfg(feature = "rp2040")]
+use crate::reserved::write_reserved_sector;
+
+
+/// Raw HID command id of the pointer settings, `[POINTER_COMMAND, subcommand, device, ...]`
+pub const POINTER_COMMAND: u8 = 0xF1;
+/// `[POINTER_COMMAND, POINTER_GET, device]`, responds `[.., status, settings...]`
+pub const POINTER_GET: u8 = 0;
+/// `[POINTER_COMMAND, POINTER_SET, device, settings...]`, see [`PointerSettings::to_bytes`] for the layout
+pub const POINTER_SET: u8 = 1;
+/// `[POINTER_COMMAND, POINTER_SAVE]`, persist the settings
+pub const POINTER_SAVE: u8 = 2;
+
+/// Status of the responses, `[POINTER_COMMAND, subcommand, status, ...]`
+const STATUS_OK: u8 = 0;
+const STATUS_OUT_OF_RANGE: u8 = 1;
+
+pub const MAX_POINTING_DEVICES: usize = 2;
+/// CPI the motion is scaled against when the sensor can't take the CPI itself
+pub const DEFAULT_CPI: u16 = 800;
+pub const MIN_CPI: u16 = 100;
+pub const MAX_CPI: u16 = 3200;
+/// Step of the CPI actions
+pub const CPI_STEP: u16 = 100;
+/// Acceleration strengths the cycle action steps through
+const ACCEL_STEPS: [u8; 4] = [0, 4, 8, 16];
+/// Motion isn't accelerated beyond this factor
+const MAX_ACCEL_FACTOR: i32 = 4;
+
+const POINTER_MAGIC: u32 = 0x5452_4E50; // "PNRT"
+const SETTINGS_SIZE: usize = 8;
+/// Bytes of the persisted table, `[magic (u32 LE), settings per device]`
+const TABLE_SIZE: usize = 4 + MAX_POINTING_DEVICES * SETTINGS_SIZE;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct PointerSettings {
+    pub cpi: u16,
+    /// Extra gain per count of motion per report in 1/256, 0 for linear
+    pub accel: u8,
+    /// Degrees the sensor is mounted rotated clockwise, undone before anything else
+    pub rotation: i16,
+    /// Degrees around the axes within which the motion snaps onto them, 0 to disable
+    pub snap_angle: u8,
+    pub invert_x: bool,
+    pub invert_y: bool,
+}
+
+impl PointerSettings {
+    pub const DEFAULT: Self = Self {
+        cpi: DEFAULT_CPI,
+        accel: 0,
+        rotation: 0,
+        snap_angle: 0,
+        invert_x: false,
+        invert_y: false,
+    };
+
+    /// `[cpi (u16 LE), accel, rotation (i16 LE), snap_angle, invert_x, invert_y]`
+    pub fn to_bytes(&self) -> [u8; SETTINGS_SIZE] {
+        let cpi = self.cpi.to_le_bytes();
+        let rotation = self.rotation.to_le_bytes();
+        [
+            cpi[0],
+            cpi[1],
+            self.accel,
+            rotation[0],
+            rotation[1],
+            self.snap_angle,
+            self.invert_x as u8,
+            self.invert_y as u8,
+        ]
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let bytes: &[u8; SETTINGS_SIZE] = bytes.get(..SETTINGS_SIZE)?.try_into().ok()?;
+        Some(Self {
+            cpi: u16::from_le_bytes([bytes[0], bytes[1]]).clamp(MIN_CPI, MAX_CPI),
+            accel: bytes[2],
+            rotation: i16::from_le_bytes([bytes[3], bytes[4]]).rem_euclid(360),
+            snap_angle: bytes[5].min(45),
+            invert_x: bytes[6] != 0,
+            invert_y: bytes[7] != 0,
+        })
+    }
+
+    /// Motion corrected by the settings. CPI is only scaled in when `scale_cpi`, for sensors not taking it.
+    pub fn apply(&self, motion: Motion, scale_cpi: bool) -> Motion {
+        let (mut x, mut y) = (motion.x as i32, motion.y as i32);
+        if self.rotation != 0 {
+            // Screen y points down, so the clockwise rotation of the mounting is undone by rotating back
+            let (sin, cos) = (sin_1024(self.rotation as i32), sin_1024(self.rotation as i32 + 90));
+            (x, y) = ((x * cos + y * sin) / 1024, (y * cos - x * sin) / 1024);
+        }
+        if self.snap_angle != 0 {
+            let (sin, cos) = (sin_1024(self.snap_angle as i32), sin_1024(self.snap_angle as i32 + 90));
+            if y.abs() * cos <= x.abs() * sin {
+                y = 0;
+            } else if x.abs() * cos <= y.abs() * sin {
+                x = 0;
+            }
+        }
+        if scale_cpi {
+            (x, y) = (x * self.cpi as i32 / DEFAULT_CPI as i32, y * self.cpi as i32 / DEFAULT_CPI as i32);
+        }
+        if self.accel != 0 {
+            let speed = x.abs().max(y.abs());
+            let factor = (256 + self.accel as i32 * speed).min(256 * MAX_ACCEL_FACTOR);
+            (x, y) = (x * factor / 256, y * factor / 256);
+        }
+        if self.invert_x {
+            x = -x;
+        }
+        if self.invert_y {
+            y = -y;
+        }
+        let clamp = |v: i32| v.clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+        Motion { x: clamp(x), y: clamp(y) }
+    }
+}
+
+/// Sine of the degrees times 1024, by Bhaskara's approximation
+fn sin_1024(degrees: i32) -> i32 {
+    let degrees = degrees.rem_euclid(360);
+    let (x, sign) = if degrees < 180 { (degrees, 1) } else { (degrees - 180, -1) };
+    sign * 4096 * x * (180 - x) / (40500 - x * (180 - x))
+}
+
+static POINTER_SETTINGS: Mutex<CriticalSectionRawMutex, Cell<[PointerSettings; MAX_POINTING_DEVICES]>> =
+    Mutex::new(Cell::new([PointerSettings::DEFAULT; MAX_POINTING_DEVICES]));
+static POINTER_SAVE_REQUEST: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Settings of the device, the defaults beyond the table
+pub fn pointer_settings(device: u8) -> PointerSettings {
+    POINTER_SETTINGS
+        .lock(|s| s.get().get(device as usize).copied())
+        .unwrap_or(PointerSettings::DEFAULT)
+}
+
+/// Returns false if the device is out of the table
+pub fn set_pointer_settings(device: u8, settings: PointerSettings) -> bool {
+    POINTER_SETTINGS.lock(|s| {
+        let mut table = s.get();
+        let Some(entry) = table.get_mut(device as usize) else {
+            return false;
+        };
+        *entry = settings;
+        s.set(table);
+        true
+    })
+}
+
+fn update_settings(device: u8, update: impl FnOnce(&mut PointerSettings)) {
+    let mut settings = pointer_settings(device);
+    update(&mut settings);
+    if set_pointer_settings(device, settings) {
+        log_info!(LogModule::Action, "Pointer {}: {}", device, settings);
+        POINTER_SAVE_REQUEST.signal(());
+    }
+}
+
+/// Step the CPI of the device by [`CPI_STEP`], persisted
+pub fn step_pointer_cpi(device: u8, up: bool) {
+    update_settings(device, |s| {
+        let cpi = if up { s.cpi.saturating_add(CPI_STEP) } else { s.cpi.saturating_sub(CPI_STEP) };
+        s.cpi = cpi.clamp(MIN_CPI, MAX_CPI);
+    });
+}
+
+/// Step the acceleration of the device through off, low, medium and high, persisted
+pub fn cycle_pointer_accel(device: u8) {
+    update_settings(device, |s| {
+        let next = ACCEL_STEPS.iter().position(|a| *a == s.accel).map_or(0, |i| (i + 1) % ACCEL_STEPS.len());
+        s.accel = ACCEL_STEPS[next];
+    });
+}
+
+fn table_to_bytes(table: &[PointerSettings; MAX_POINTING_DEVICES]) -> [u8; TABLE_SIZE] {
+    let mut bytes = [0u8; TABLE_SIZE];
+    bytes[0..4].copy_from_slice(&POINTER_MAGIC.to_le_bytes());
+    for (settings, chunk) in table.iter().zip(bytes[4..].chunks_exact_mut(SETTINGS_SIZE)) {
+        chunk.copy_from_slice(&settings.to_bytes());
+    }
+    bytes
+}
+
+/// Read the settings saved by the previous boot. Call it before handing the flash to rmk.
+#[cfg(feature = "rp2040")]
+pub fn load_pointer_settings<T: Instance, M: Mode, const FLASH_SIZE: usize>(
+    flash: &mut Flash<'_, T, M, FLASH_SIZE>,
+    offset: u32,
+) -> bool {
+    let mut bytes = [0u8; TABLE_SIZE];
+    if flash.blocking_read(offset, &mut bytes).is_err() || bytes[0..4] != POINTER_MAGIC.to_le_bytes() {
+        return false;
+    }
+    let mut table = [PointerSettings::DEFAULT; MAX_POINTING_DEVICES];
+    for (settings, chunk) in table.iter_mut().zip(bytes[4..].chunks_exact(SETTINGS_SIZE)) {
+        *settings = PointerSettings::from_bytes(chunk).unwrap_or(PointerSettings::DEFAULT);
+    }
+    POINTER_SETTINGS.lock(|s| s.set(table));
+    true
+}
+
+/// Save the settings to the reserved sector at `offset`, the argument of [`run_pointer_settings_save`]
+#[cfg(feature = "rp2040")]
+pub fn save_pointer_settings<const FLASH_SIZE: usize>(offset: u32) {
+    let bytes = table_to_bytes(&POINTER_SETTINGS.lock(|s| s.get()));
+    if let Err(e) = write_reserved_sector::<FLASH_SIZE>(offset, &bytes) {
+        defmt::warn!("Failed to save pointer settings: {}", e);
+    }
+}
+
+/// Answer a pointer settings command in place.
+/// Returns false if the report isn't a pointer settings command.
+pub fn handle_pointer_command(report: &mut [u8]) -> bool {
+    if report.len() < 3 + SETTINGS_SIZE || report[0] != POINTER_COMMAND {
+        return false;
+    }
+    let device = report[2];
+    let in_range = (device as usize) < MAX_POINTING_DEVICES;
+    let status = match report[1] {
+        POINTER_GET => {
+            let settings = pointer_settings(device).to_bytes();
+            report[2..].fill(0);
+            report[2] = if in_range { STATUS_OK } else { STATUS_OUT_OF_RANGE };
+            report[3..3 + SETTINGS_SIZE].copy_from_slice(&settings);
+            return true;
+        }
+        POINTER_SET => match PointerSettings::from_bytes(&report[3..]) {
+            Some(settings) if set_pointer_settings(device, settings) => STATUS_OK,
+            _ => STATUS_OUT_OF_RANGE,
+        },
+        POINTER_SAVE => {
+            POINTER_SAVE_REQUEST.signal(());
+            STATUS_OK
+        }
+        _ => return false,
+    };
+    report[2..].fill(0);
+    report[2] = status;
+    true
+}
+
+/// Wait for changes by the actions or the save command and call `on_save`, e.g. with [`save_pointer_settings`].
+/// This function should never return.
+pub async fn run_pointer_settings_save<F: FnMut()>(mut on_save: F) -> ! {
+    loop {
+        POINTER_SAVE_REQUEST.wait().await;
+        quiesce_flash(async { on_save() }).await;
+    }
+}
@@ -0,0 +1,248 @@
+//! Pointing devices with per-layer behavior, e.g. a trackball moving the cursor on the base layer
+//! and tapping arrow keys on the nav layer.
+//! The layer is the tracked [`active_layer`].
+//! The motion is corrected by the device's [`PointerSettings`](crate::pointer_settings::PointerSettings) first.
+//! A [`ScrollEmulation`] selected at runtime overrides the layer's mode, and scrolling can coast on by momentum.
+
+use core::cell::Cell;
+use core::sync::atomic::{AtomicBool, Ordering};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Timer};
+use usbd_hid::descriptor::MouseReport;
+
+use crate::event::tap_key;
+use crate::feature_flags::{feature_enabled, Feature};
+use crate::layer_state::active_layer;
+use crate::output::{send_output_report, OutputReport};
+use crate::log::LogModule;
+use crate::log_info;
+use crate::pointer_settings::pointer_settings;
+use crate::presenter::{is_presenter_active, PRESENTER_POINTER_DIVISOR};
+use crate::typing::tap_usage;
+
+
+/// Counts of motion per line of scrolling
+const SCROLL_DIVISOR: i16 = 8;
+/// Counts of motion per arrow key of the caret scroll emulation
+const CARET_STEP: u16 = 24;
+/// Velocity kept per report while coasting, in 1/16
+const MOMENTUM_DECAY: i32 = 15;
+/// Coasting stops below a count per report, in 1/256 counts
+const MOMENTUM_STOP: i32 = 256;
+
+const USAGE_RIGHT: u8 = 0x4F;
+const USAGE_LEFT: u8 = 0x50;
+const USAGE_DOWN: u8 = 0x51;
+const USAGE_UP: u8 = 0x52;
+
+/// Relative motion, in sensor counts
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, defmt::Format)]
+pub struct Motion {
+    pub x: i16,
+    pub y: i16,
+}
+
+/// Relative pointing sensor like a trackball or a trackpad
+#[allow(async_fn_in_trait)]
+pub trait PointingSensor {
+    /// Motion accumulated since the last read, `None` if the sensor didn't respond
+    async fn motion(&mut self) -> Option<Motion>;
+
+    /// Set the resolution, returns false if unsupported and the motion is scaled in software instead
+    async fn set_cpi(&mut self, _cpi: u16) -> bool {
+        false
+    }
+}
+
+/// What the motion does on a layer
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum PointingMode {
+    Cursor,
+    /// Vertical and horizontal wheel
+    Scroll,
+    /// Tap the positions for each `step` counts of motion, so the keymap decides the keys, e.g. arrows
+    Keys {
+        up: (u8, u8),
+        down: (u8, u8),
+        left: (u8, u8),
+        right: (u8, u8),
+        step: u16,
+    },
+    /// Tap the arrow keys for each `step` counts of motion, moving the caret
+    Caret { step: u16 },
+    Disabled,
+}
+
+/// Scroll behavior overriding the layer's mode while selected
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum ScrollEmulation {
+    /// The layer's mode
+    Off,
+    /// The motion scrolls, e.g. while a key is held
+    Drag,
+    /// The motion taps the arrow keys
+    Caret,
+}
+
+static SCROLL_EMULATION: Mutex<CriticalSectionRawMutex, Cell<ScrollEmulation>> =
+    Mutex::new(Cell::new(ScrollEmulation::Off));
+static SCROLL_MOMENTUM: AtomicBool = AtomicBool::new(false);
+
+pub fn scroll_emulation() -> ScrollEmulation {
+    SCROLL_EMULATION.lock(|s| s.get())
+}
+
+pub fn set_scroll_emulation(emulation: ScrollEmulation) {
+    SCROLL_EMULATION.lock(|s| s.set(emulation));
+}
+
+/// Select the caret scroll, or go back to the layer's mode
+pub fn toggle_caret_scroll() {
+    let emulation = match scroll_emulation() {
+        ScrollEmulation::Caret => ScrollEmulation::Off,
+        _ => ScrollEmulation::Caret,
+    };
+    log_info!(LogModule::Action, "Scroll emulation: {}", emulation);
+    set_scroll_emulation(emulation);
+}
+
+/// Enable or disable the scrolling coasting on after the motion stops
+pub fn toggle_scroll_momentum() {
+    let enabled = !SCROLL_MOMENTUM.load(Ordering::Relaxed);
+    log_info!(LogModule::Action, "Scroll momentum {}", if enabled { "on" } else { "off" });
+    SCROLL_MOMENTUM.store(enabled, Ordering::Relaxed);
+}
+
+/// Send a mouse report to the output, unless mouse keys are disabled by [`Feature::MouseKeys`]
+pub async fn send_mouse_report(buttons: u8, x: i8, y: i8, wheel: i8, pan: i8) {
+    if !feature_enabled(Feature::MouseKeys) {
+        return;
+    }
+    let report = MouseReport {
+        buttons,
+        x,
+        y,
+        wheel,
+        pan,
+    };
+    send_output_report(OutputReport::Mouse(report)).await;
+}
+
+fn clamp_i8(value: i16) -> i8 {
+    value.clamp(i8::MIN as i16, i8::MAX as i16) as i8
+}
+
+/// Poll the sensor and act by the mode of the active layer, layers beyond the table are disabled.
+/// `device` is the index of the sensor's settings. This function should never return.
+pub async fn run_pointing<P: PointingSensor, const NUM_LAYER: usize>(
+    mut sensor: P,
+    device: u8,
+    modes: [PointingMode; NUM_LAYER],
+    interval: Duration,
+) -> ! {
+    let mut layer = active_layer();
+    // CPI set on the sensor, `None` while scaled in software
+    let mut sensor_cpi = None;
+    // Motion left over from the previous reads, below a scroll line or a key step
+    let mut rest = Motion::default();
+    // Scroll velocity for the momentum, in 1/256 counts per report
+    let mut velocity = (0i32, 0i32);
+    loop {
+        Timer::after(interval).await;
+        let settings = pointer_settings(device);
+        if sensor_cpi != Some(settings.cpi) {
+            sensor_cpi = sensor.set_cpi(settings.cpi).await.then_some(settings.cpi);
+        }
+        let Some(motion) = sensor.motion().await else {
+            continue;
+        };
+        let motion = settings.apply(motion, sensor_cpi.is_none());
+        if active_layer() != layer {
+            layer = active_layer();
+            rest = Motion::default();
+        }
+        let mode = match scroll_emulation() {
+            ScrollEmulation::Off => modes.get(layer as usize).copied().unwrap_or(PointingMode::Disabled),
+            ScrollEmulation::Drag => PointingMode::Scroll,
+            ScrollEmulation::Caret => PointingMode::Caret { step: CARET_STEP },
+        };
+        if mode != PointingMode::Scroll {
+            velocity = (0, 0);
+        }
+        match mode {
+            PointingMode::Cursor if is_presenter_active() => {
+                rest.x = rest.x.saturating_add(motion.x);
+                rest.y = rest.y.saturating_add(motion.y);
+                let x = rest.x / PRESENTER_POINTER_DIVISOR;
+                let y = rest.y / PRESENTER_POINTER_DIVISOR;
+                rest.x -= x * PRESENTER_POINTER_DIVISOR;
+                rest.y -= y * PRESENTER_POINTER_DIVISOR;
+                if x != 0 || y != 0 {
+                    send_mouse_report(0, clamp_i8(x), clamp_i8(y), 0, 0).await;
+                }
+            }
+            PointingMode::Cursor => {
+                if motion != Motion::default() {
+                    send_mouse_report(0, clamp_i8(motion.x), clamp_i8(motion.y), 0, 0).await;
+                }
+            }
+            PointingMode::Scroll => {
+                let motion = if motion != Motion::default() {
+                    velocity = ((velocity.0 + motion.x as i32 * 256) / 2, (velocity.1 + motion.y as i32 * 256) / 2);
+                    motion
+                } else if SCROLL_MOMENTUM.load(Ordering::Relaxed) {
+                    velocity = (velocity.0 * MOMENTUM_DECAY / 16, velocity.1 * MOMENTUM_DECAY / 16);
+                    if velocity.0.abs().max(velocity.1.abs()) < MOMENTUM_STOP {
+                        velocity = (0, 0);
+                    }
+                    Motion {
+                        x: (velocity.0 / 256) as i16,
+                        y: (velocity.1 / 256) as i16,
+                    }
+                } else {
+                    velocity = (0, 0);
+                    motion
+                };
+                rest.x = rest.x.saturating_add(motion.x);
+                rest.y = rest.y.saturating_add(motion.y);
+                let pan = rest.x / SCROLL_DIVISOR;
+                let wheel = rest.y / SCROLL_DIVISOR;
+                rest.x -= pan * SCROLL_DIVISOR;
+                rest.y -= wheel * SCROLL_DIVISOR;
+                if pan != 0 || wheel != 0 {
+                    // Moving up scrolls up, the wheel is positive upwards
+                    send_mouse_report(0, 0, 0, clamp_i8(-wheel), clamp_i8(pan)).await;
+                }
+            }
+            PointingMode::Keys { up, down, left, right, step } => {
+                let step = step.clamp(1, i16::MAX as u16) as i16;
+                rest.x = rest.x.saturating_add(motion.x);
+                rest.y = rest.y.saturating_add(motion.y);
+                while rest.x.abs() >= step {
+                    let (row, col) = if rest.x > 0 { right } else { left };
+                    tap_key(row, col).await;
+                    rest.x -= step * rest.x.signum();
+                }
+                while rest.y.abs() >= step {
+                    let (row, col) = if rest.y > 0 { down } else { up };
+                    tap_key(row, col).await;
+                    rest.y -= step * rest.y.signum();
+                }
+            }
+            PointingMode::Caret { step } => {
+                let step = step.clamp(1, i16::MAX as u16) as i16;
+                rest.x = rest.x.saturating_add(motion.x);
+                rest.y = rest.y.saturating_add(motion.y);
+                while rest.x.abs() >= step {
+                    tap_usage(if rest.x > 0 { USAGE_RIGHT } else { USAGE_LEFT }).await;
+                    rest.x -= step * rest.x.signum();
+                }
+                while rest.y.abs() >= step {
+                    tap_usage(if rest.y > 0 { USAGE_DOWN } else { USAGE_UP }).await;
+                    rest.y -= step * rest.y.signum();
+                }
+            }
+            PointingMode::Disabled => {}
+        }
+    }
+}
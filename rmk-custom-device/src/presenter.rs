@@ -0,0 +1,207 @@
+//! Presenter mode bundling what a talk needs: keys turned into slide controls, the pointer slowed down
+//! for laser pointer like moves, and the LEDs filling up with the elapsed talk time.
+
+use core::cell::Cell;
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_sync::channel::Channel;
+use embassy_time::{Duration, Instant};
+use rmk::event::KeyEvent;
+
+use crate::event::{HookContext, KeyEventHook};
+use crate::log::LogModule;
+use crate::log_info;
+use crate::rgb::{RgbEffect, RGB8};
+use crate::typing::tap_usage;
+
+
+/// Pointer motion is divided by this while presenting
+pub const PRESENTER_POINTER_DIVISOR: i16 = 4;
+
+const USAGE_PAGE_UP: u8 = 0x4B;
+const USAGE_PAGE_DOWN: u8 = 0x4E;
+/// `B` blacks out the screen in PowerPoint, Keynote and Impress
+const USAGE_B: u8 = 0x05;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum PresenterKey {
+    /// Page down
+    Next,
+    /// Page up
+    Previous,
+    /// Black out the screen, or bring it back
+    Blank,
+}
+
+impl PresenterKey {
+    fn usage(self) -> u8 {
+        match self {
+            Self::Next => USAGE_PAGE_DOWN,
+            Self::Previous => USAGE_PAGE_UP,
+            Self::Blank => USAGE_B,
+        }
+    }
+}
+
+/// Start of the talk while presenting
+static PRESENTER_START: Mutex<CriticalSectionRawMutex, Cell<Option<Instant>>> = Mutex::new(Cell::new(None));
+static PRESENTER_TAPS: Channel<CriticalSectionRawMutex, PresenterKey, 4> = Channel::new();
+
+pub fn is_presenter_active() -> bool {
+    PRESENTER_START.lock(|s| s.get().is_some())
+}
+
+/// Time since the talk started, `None` unless presenting
+pub fn presenter_elapsed() -> Option<Duration> {
+    PRESENTER_START.lock(|s| s.get()).map(|start| start.elapsed())
+}
+
+/// Start presenting with the talk timer from zero, or stop
+pub fn toggle_presenter() {
+    let active = PRESENTER_START.lock(|s| {
+        s.set(match s.get() {
+            Some(_) => None,
+            None => Some(Instant::now()),
+        });
+        s.get().is_some()
+    });
+    log_info!(LogModule::Action, "Presenter mode {}", if active { "on" } else { "off" });
+}
+
+
+/// Hook turning the keys into slide controls while presenting, consuming their presses and releases.
+/// The other keys pass, so notes can still be typed.
+pub struct PresenterHook {
+    keys: &'static [(u8, u8, PresenterKey)],
+}
+
+impl PresenterHook {
+    /// `keys` are `(row, col, key)`
+    pub const fn new(keys: &'static [(u8, u8, PresenterKey)]) -> Self {
+        Self { keys }
+    }
+}
+
+impl KeyEventHook for PresenterHook {
+    async fn process(&mut self, event: KeyEvent, _ctx: &mut HookContext) -> Option<KeyEvent> {
+        if !is_presenter_active() {
+            return Some(event);
+        }
+        let Some(&(_, _, key)) = self.keys.iter().find(|(row, col, _)| (*row, *col) == (event.row, event.col)) else {
+            return Some(event);
+        };
+        // Never block the scan loop, the presenter task taps the key
+        if event.pressed && PRESENTER_TAPS.try_send(key).is_err() {
+            defmt::warn!("Presenter key {} dropped", key);
+        }
+        None
+    }
+}
+
+/// Tap the slide controls of [`PresenterHook`]. This function should never return.
+pub async fn run_presenter() -> ! {
+    loop {
+        let key = PRESENTER_TAPS.receive().await;
+        tap_usage(key.usage()).await;
+    }
+}
+
+
+/// Effect filling the pixels in order with the elapsed talk time while presenting, drawing nothing otherwise.
+/// Past the talk duration every pixel blinks in the overtime color. Put it on top of the regular effect.
+pub struct PresenterEffect {
+    talk: Duration,
+    color: RGB8,
+    overtime: RGB8,
+}
+
+impl PresenterEffect {
+    pub const fn new(talk: Duration, color: RGB8, overtime: RGB8) -> Self {
+        Self { talk, color, overtime }
+    }
+}
+
+impl<const N: usize> RgbEffect<N> for PresenterEffect {
+    fn render(&mut self, frame: &mut [RGB8; N], now: Instant) {
+        let Some(elapsed) = presenter_elapsed() else {
+            return;
+        };
+        if elapsed >= self.talk {
+            let lit = now.as_millis() / 500 % 2 == 0;
+            frame.fill(if lit { self.overtime } else { RGB8::default() });
+            return;
+        }
+        let filled = (elapsed.as_ticks() * N as u64 / self.talk.as_ticks().max(1)) as usize;
+        for (i, pixel) in frame.iter_mut().enumerate() {
+            *pixel = if i <= filled { self.color } else { RGB8::default() };
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::{process_key_event, ChannelSink};
+    use crate::test_support::serial;
+
+    static KEYS: [(u8, u8, PresenterKey); 1] = [(0, 1, PresenterKey::Next)];
+    const TALK: Duration = Duration::from_secs(60);
+    const COLOR: RGB8 = RGB8::new(0, 0, 255);
+    const OVERTIME: RGB8 = RGB8::new(255, 0, 0);
+
+    fn run(col: u8, pressed: bool) -> std::vec::Vec<(u8, u8, bool)> {
+        let channel: Channel<CriticalSectionRawMutex, KeyEvent, 8> = Channel::new();
+        let event = KeyEvent { row: 0, col, pressed };
+        let mut hook = PresenterHook::new(&KEYS);
+        embassy_futures::block_on(process_key_event(&mut hook, &mut ChannelSink::new(&channel), event));
+        core::iter::from_fn(|| channel.try_receive().ok())
+            .map(|e| (e.row, e.col, e.pressed))
+            .collect()
+    }
+
+    fn set_presenting(presenting: bool) {
+        if is_presenter_active() != presenting {
+            toggle_presenter();
+        }
+        while PRESENTER_TAPS.try_receive().is_ok() {}
+    }
+
+    #[test]
+    fn keys_pass_unless_presenting() {
+        let _serial = serial();
+        set_presenting(false);
+        assert_eq!(run(1, true), [(0, 1, true)]);
+        assert!(PRESENTER_TAPS.try_receive().is_err());
+    }
+
+    #[test]
+    fn slide_keys_are_tapped_while_presenting() {
+        let _serial = serial();
+        set_presenting(true);
+        assert!(run(1, true).is_empty());
+        assert!(run(1, false).is_empty());
+        assert_eq!(PRESENTER_TAPS.try_receive(), Ok(PresenterKey::Next));
+        assert!(PRESENTER_TAPS.try_receive().is_err());
+        // The other keys type notes
+        assert_eq!(run(0, true), [(0, 0, true)]);
+        set_presenting(false);
+    }
+
+    #[test]
+    fn effect_fills_with_the_talk_time() {
+        let _serial = serial();
+        let mut effect = PresenterEffect::new(TALK, COLOR, OVERTIME);
+        set_presenting(false);
+        let mut frame = [RGB8::default(); 4];
+        effect.render(&mut frame, Instant::now());
+        assert_eq!(frame, [RGB8::default(); 4]);
+        set_presenting(true);
+        effect.render(&mut frame, Instant::now());
+        assert_eq!(frame, [COLOR, RGB8::default(), RGB8::default(), RGB8::default()]);
+        // Every talk is over at zero length
+        let mut effect = PresenterEffect::new(Duration::from_ticks(0), COLOR, OVERTIME);
+        effect.render(&mut frame, Instant::from_millis(0));
+        assert_eq!(frame, [OVERTIME; 4]);
+        set_presenting(false);
+    }
+}
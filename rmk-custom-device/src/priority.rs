@@ -0,0 +1,60 @@
+//! Time-critical tasks run by an interrupt executor, preempting the tasks of the thread executor.
+//!
+//! The matrix scan and the split transport run here while USB, storage, lighting and the display stay
+//! on the thread executor, so a long render or a busy task doesn't delay a scan pass. Flash erases
+//! still stall everything running from flash, the matrix parks through them as before.
+//! The firmware defines the interrupt handler with [`priority_interrupt_handler`](crate::priority_interrupt_handler).
+
+use core::future::Future;
+use embassy_executor::InterruptExecutor;
+use embassy_rp::interrupt::{self, InterruptExt, Priority};
+use portable_atomic::{AtomicBool, Ordering};
+
+use crate::task_arena::TaskArena;
+
+
+/// Room for the tasks run at the interrupt priority, the matrix scan and the split transport
+pub const PRIORITY_TASK_ARENA_SIZE: usize = 8192;
+/// Tasks run at the interrupt priority, each gets an even share of the arena
+const PRIORITY_TASKS: usize = 2;
+/// Lowest NVIC priority, above every thread task but below the peripherals' interrupts like USB's
+const PRIORITY: Priority = Priority::P3;
+
+/// RAM taken by the tasks, for the [RAM budget](crate::ram_budget)
+pub(crate) const PRIORITY_TASK_RAM: usize = PRIORITY_TASK_ARENA_SIZE;
+
+static EXECUTOR: InterruptExecutor = InterruptExecutor::new();
+static STARTED: AtomicBool = AtomicBool::new(false);
+static TASKS: TaskArena<PRIORITY_TASK_ARENA_SIZE, PRIORITY_TASKS> = TaskArena::new();
+
+/// Run the executor's tasks, called by the interrupt handler.
+///
+/// # Safety
+/// Only from the `SWI_IRQ_0` handler.
+pub unsafe fn on_interrupt() {
+    // SAFETY: called from the interrupt the executor was started on
+    unsafe { EXECUTOR.on_interrupt() }
+}
+
+/// Spawn the future as a task of the interrupt executor instead of the calling one, e.g. a matrix scan.
+/// The executor starts on the first call. The build fails for a future over half of [`PRIORITY_TASK_ARENA_SIZE`],
+/// and a third call panics.
+pub fn spawn_at_priority<F: Future<Output = ()> + Send + 'static>(future: F) {
+    if !STARTED.swap(true, Ordering::Relaxed) {
+        interrupt::SWI_IRQ_0.set_priority(PRIORITY);
+        EXECUTOR.start(interrupt::SWI_IRQ_0);
+    }
+    defmt::unwrap!(EXECUTOR.spawner().spawn(TASKS.spawn(future)));
+}
+
+/// Define the `SWI_IRQ_0` handler running the tasks of [`spawn_at_priority`]
+#[macro_export]
+macro_rules! priority_interrupt_handler {
+    () => {
+        #[embassy_rp::interrupt]
+        #[allow(non_snake_case)]
+        unsafe fn SWI_IRQ_0() {
+            $crate::priority::on_interrupt();
+        }
+    };
+}
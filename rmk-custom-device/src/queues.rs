@@ -0,0 +1,45 @@
+//! Depths of the firmware's queues, set at build time for bursty inputs or small RAM.
+//!
+//! Each depth is read from an env var when the library builds, e.g. in the firmware's `.cargo/config.toml`:
+//! ```toml
+//! [env]
+//! DFLIPDAISY_INPUT_EVENT_QUEUE = "16"
+//! ```
+//! A full key queue makes the matrix scan wait, a full report queue makes the emitters wait,
+//! so macros and fast chords want deeper ones. rmk's own key event and report channels keep the depths
+//! the rmk fork compiles in.
+
+/// Parse the depth of the env var, `default` if unset. Fails the build if it isn't a positive number.
+const fn queue_depth(value: Option<&str>, default: usize) -> usize {
+    let Some(value) = value else {
+        return default;
+    };
+    let bytes = value.as_bytes();
+    if bytes.is_empty() {
+        panic!("queue depth must be a positive number");
+    }
+    let mut depth = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        if !bytes[i].is_ascii_digit() {
+            panic!("queue depth must be a positive number");
+        }
+        depth = depth * 10 + (bytes[i] - b'0') as usize;
+        i += 1;
+    }
+    if depth == 0 {
+        panic!("queue depth must be a positive number");
+    }
+    depth
+}
+
+/// Non-matrix input events waiting for the matrix scan, `DFLIPDAISY_INPUT_EVENT_QUEUE`
+pub const INPUT_EVENT_QUEUE_DEPTH: usize = queue_depth(option_env!("DFLIPDAISY_INPUT_EVENT_QUEUE"), 8);
+/// Central events held back by the split ordering, `DFLIPDAISY_SPLIT_ORDER_QUEUE`
+pub const SPLIT_ORDER_QUEUE_DEPTH: usize = queue_depth(option_env!("DFLIPDAISY_SPLIT_ORDER_QUEUE"), 16);
+/// Messages waiting for the split transport's window, `DFLIPDAISY_SPLIT_OUTBOX_QUEUE`
+pub const SPLIT_OUTBOX_QUEUE_DEPTH: usize = queue_depth(option_env!("DFLIPDAISY_SPLIT_OUTBOX_QUEUE"), 16);
+/// Reports of the firmware's own output, `DFLIPDAISY_OUTPUT_REPORT_QUEUE`
+pub const OUTPUT_REPORT_QUEUE_DEPTH: usize = queue_depth(option_env!("DFLIPDAISY_OUTPUT_REPORT_QUEUE"), 8);
+/// Custom actions waiting for their task, `DFLIPDAISY_CUSTOM_ACTION_QUEUE`
+pub const CUSTOM_ACTION_QUEUE_DEPTH: usize = queue_depth(option_env!("DFLIPDAISY_CUSTOM_ACTION_QUEUE"), 4);
@@ -0,0 +1,112 @@
+//! Quiescent state of the scan and lighting during long flash operations.
+//!
+//! Flash erase stalls XIP on RP2040, which glitches the timing of whatever is running
+//! and may turn into spurious keystrokes. Storage operations request a pause, the matrix parks
+//! between scan passes with its key states latched, and the RGB renderer holds its last frame.
+//!
+//! The firmware-side sectors are erased and written with blocking operations next to rmk's storage,
+//! whose async reads run by DMA. Interrupts being off during a blocking erase doesn't stop a DMA read
+//! in flight, so every flash operation, rmk's through [`QuiescentFlash`] and the others through
+//! [`quiesce_flash`], holds one lock.
+
+use core::cell::Cell;
+use core::future::Future;
+use core::sync::atomic::{AtomicBool, Ordering};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_sync::mutex::Mutex as AsyncMutex;
+use embassy_time::{with_timeout, Duration, Timer};
+use embedded_storage_async::nor_flash::{ErrorType, NorFlash, ReadNorFlash};
+
+use crate::brown_out::wait_for_supply;
+
+
+/// Longest wait for the matrix to park, e.g. when it isn't scanning at all
+const PARK_TIMEOUT: Duration = Duration::from_millis(10);
+const POLL_INTERVAL: Duration = Duration::from_micros(500);
+
+/// Nested pause requests
+static PAUSE_REQUESTS: Mutex<CriticalSectionRawMutex, Cell<u8>> = Mutex::new(Cell::new(0));
+static MATRIX_PARKED: AtomicBool = AtomicBool::new(false);
+/// Held by a flash operation, see the [module](self)
+static FLASH_LOCK: AsyncMutex<CriticalSectionRawMutex, ()> = AsyncMutex::new(());
+
+pub fn is_paused() -> bool {
+    PAUSE_REQUESTS.lock(|r| r.get()) > 0
+}
+
+/// Park here while a pause is requested. Called by the matrix between scan passes.
+pub async fn park_if_paused() {
+    if !is_paused() {
+        return;
+    }
+    MATRIX_PARKED.store(true, Ordering::Release);
+    while is_paused() {
+        Timer::after(POLL_INTERVAL).await;
+    }
+    MATRIX_PARKED.store(false, Ordering::Release);
+}
+
+/// Run the operation with the matrix parked and the lighting idle, once the supply is healthy
+pub async fn quiesce<R>(operation: impl Future<Output = R>) -> R {
+    wait_for_supply().await;
+    PAUSE_REQUESTS.lock(|r| r.set(r.get().saturating_add(1)));
+    let _ = with_timeout(PARK_TIMEOUT, async {
+        while !MATRIX_PARKED.load(Ordering::Acquire) {
+            Timer::after(POLL_INTERVAL).await;
+        }
+    })
+    .await;
+    let result = operation.await;
+    PAUSE_REQUESTS.lock(|r| r.set(r.get().saturating_sub(1)));
+    result
+}
+
+/// Run the flash operation like [`quiesce`], excluding every other flash operation including rmk's reads.
+/// Erases and writes of the reserved sectors run in here, e.g. [`write_reserved_sector`](crate::reserved::write_reserved_sector).
+pub async fn quiesce_flash<R>(operation: impl Future<Output = R>) -> R {
+    let _lock = FLASH_LOCK.lock().await;
+    quiesce(operation).await
+}
+
+
+/// Flash wrapper quiescing around erases and writes and holding the flash lock for every operation,
+/// hand it to rmk so that Vial saves and storage reads are covered
+pub struct QuiescentFlash<F> {
+    flash: F,
+}
+
+impl<F> QuiescentFlash<F> {
+    pub fn new(flash: F) -> Self {
+        Self { flash }
+    }
+}
+
+impl<F: ErrorType> ErrorType for QuiescentFlash<F> {
+    type Error = F::Error;
+}
+
+impl<F: ReadNorFlash> ReadNorFlash for QuiescentFlash<F> {
+    const READ_SIZE: usize = F::READ_SIZE;
+
+    async fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        let _lock = FLASH_LOCK.lock().await;
+        self.flash.read(offset, bytes).await
+    }
+
+    fn capacity(&self) -> usize {
+        self.flash.capacity()
+    }
+}
+
+impl<F: NorFlash> NorFlash for QuiescentFlash<F> {
+    const WRITE_SIZE: usize = F::WRITE_SIZE;
+    const ERASE_SIZE: usize = F::ERASE_SIZE;
+
+    async fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        quiesce_flash(self.flash.erase(from, to)).await
+    }
+
+    async fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        quiesce_flash(self.flash.write(offset, bytes)).await
+    }
+}
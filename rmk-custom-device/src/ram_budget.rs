@@ -0,0 +1,83 @@
+//! Compile-time accounting of the large static buffers against the target's RAM.
+//!
+//! The firmware adds its own buffers, e.g. the keymap, the RGB frame and the split link's buffers,
+//! onto the library's and checks the sum in a `const`, so going over fails the build with a plain message
+//! instead of a linker error about overflowing `.bss`. Only the large buffers are counted,
+//! leave the margin of the stack and the small statics out of the limit.
+//!
+//! ```ignore
+//! const _: usize = RamBudget::new(256 * 1024 - 64 * 1024)
+//!     .with_library_statics()
+//!     .with::<[[[KeyAction; COL]; ROW]; NUM_LAYER]>()
+//!     .with_bytes(2 * SPLIT_MESSAGE_MAX_SIZE)
+//!     .check();
+//! ```
+
+#[cfg(feature = "bitmap_upload")]
+use crate::bitmap::BITMAP_UPLOAD_RAM;
+use crate::heatmap::HEATMAP_RAM;
+use crate::key_stream::KEY_STREAM_RAM;
+use crate::keymap_names::KEYMAP_SNAPSHOT_RAM;
+#[cfg(feature = "core1_matrix")]
+use crate::multicore::CORE1_RAM;
+#[cfg(feature = "priority_tasks")]
+use crate::priority::PRIORITY_TASK_RAM;
+use crate::recorder::RECORDER_RAM;
+use crate::snippets::SNIPPETS_RAM;
+
+
+/// Large static buffers of the library enabled by the features, in bytes
+pub const fn library_static_ram() -> usize {
+    #[allow(unused_mut)]
+    let mut bytes = HEATMAP_RAM + KEY_STREAM_RAM + KEYMAP_SNAPSHOT_RAM + RECORDER_RAM + SNIPPETS_RAM;
+    #[cfg(feature = "bitmap_upload")]
+    {
+        bytes += BITMAP_UPLOAD_RAM;
+    }
+    #[cfg(feature = "core1_matrix")]
+    {
+        bytes += CORE1_RAM;
+    }
+    #[cfg(feature = "priority_tasks")]
+    {
+        bytes += PRIORITY_TASK_RAM;
+    }
+    bytes
+}
+
+/// Sum of the static buffers checked against a limit, built in a const
+#[derive(Clone, Copy, Debug)]
+pub struct RamBudget {
+    limit: usize,
+    used: usize,
+}
+
+impl RamBudget {
+    pub const fn new(limit: usize) -> Self {
+        Self { limit, used: 0 }
+    }
+
+    pub const fn with_library_statics(self) -> Self {
+        self.with_bytes(library_static_ram())
+    }
+
+    /// Add a static of the type, e.g. the keymap
+    pub const fn with<T>(self) -> Self {
+        self.with_bytes(core::mem::size_of::<T>())
+    }
+
+    pub const fn with_bytes(self, bytes: usize) -> Self {
+        Self {
+            limit: self.limit,
+            used: self.used + bytes,
+        }
+    }
+
+    /// Bytes left under the limit, failing the build in a const when over it
+    pub const fn check(self) -> usize {
+        if self.used > self.limit {
+            panic!("static buffers exceed the RAM budget, disable a feature or shrink a buffer");
+        }
+        self.limit - self.used
+    }
+}
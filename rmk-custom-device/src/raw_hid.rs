@@ -0,0 +1,421 @@
+use core::cell::RefCell;
+#[cfg(feature = "signed_config")]
+use embassy_futures::select::{select, Either};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+#[cfg(feature = "signed_config")]
+use embassy_sync::channel::Channel;
+use embassy_usb_driver::{
+    Driver, Endpoint, EndpointAllocError, EndpointError, EndpointIn, EndpointInfo, EndpointOut, EndpointType,
+};
+#[cfg(feature = "event_injection")]
+use rmk::event::KeyEvent;
+
+use crate::alert::{handle_alert_command, ALERT_COMMAND};
+#[cfg(feature = "bitmap_upload")]
+use crate::bitmap::{handle_bitmap_command, BITMAP_BEGIN, BITMAP_COMMAND, BITMAP_COMMIT};
+use crate::clock::{handle_clock_command, CLOCK_COMMAND};
+#[cfg(feature = "crash_log")]
+use crate::crash::{handle_crash_command, CRASH_CLEAR, CRASH_COMMAND};
+#[cfg(feature = "dfu")]
+use crate::dfu::{handle_dfu_command, DFU_BEGIN, DFU_COMMAND, DFU_FINISH};
+#[cfg(feature = "event_injection")]
+use crate::event::try_send_input_event;
+use crate::feature_flags::{handle_feature_command, FEATURE_COMMAND};
+use crate::heatmap::{handle_heatmap_command, HEATMAP_COMMAND};
+use crate::info::{BuildInfo, INFO_COMMAND};
+use crate::key_stream::{handle_key_stream_command, KEY_STREAM_ANONYMOUS, KEY_STREAM_COMMAND};
+use crate::keymap_names::{handle_keymap_name_command, KEYMAP_NAME_COMMAND};
+use crate::layer_colors::{
+    handle_layer_color_command, LAYER_COLOR_CLEAR, LAYER_COLOR_COMMAND, LAYER_COLOR_SAVE, LAYER_COLOR_SET,
+};
+use crate::lighting::{handle_lighting_command, LIGHTING_GET_VALUE, LIGHTING_SAVE, LIGHTING_SET_VALUE};
+use crate::log::{handle_log_command, LOG_COMMAND};
+use crate::pointer_settings::{handle_pointer_command, POINTER_COMMAND, POINTER_SAVE, POINTER_SET};
+use crate::recorder::{handle_recorder_command, RECORDER_COMMAND};
+#[cfg(feature = "signed_config")]
+use crate::signed::{handle_signed_command, SIGNED_COMMAND};
+use crate::snippets::{handle_snippet_command, SNIPPET_COMMAND, SNIPPET_SAVE, SNIPPET_WRITE};
+#[cfg(feature = "secret_vault")]
+use crate::vault::{handle_vault_command, VAULT_CLEAR, VAULT_COMMAND, VAULT_SEAL, VAULT_WRITE};
+
+
+/// Raw HID command id injecting a key event, `[INJECT_COMMAND, row, col, pressed]`
+#[cfg(feature = "event_injection")]
+pub const INJECT_COMMAND: u8 = 0xE1;
+
+/// Size of Vial's raw HID reports
+pub const RAW_HID_REPORT_SIZE: usize = 32;
+/// Via command id rmk answers as unhandled, what a vendor command is turned into on its way to rmk
+const VIA_UNHANDLED: u8 = 0xFF;
+
+/// Response of the last vendor command, written instead of rmk's answer to it
+static PENDING_RESPONSE: Mutex<CriticalSectionRawMutex, RefCell<Option<[u8; RAW_HID_REPORT_SIZE]>>> =
+    Mutex::new(RefCell::new(None));
+
+/// Vial writes accepted by [`crate::signed`], read by rmk as if from the host
+#[cfg(feature = "signed_config")]
+static SIGNED_VIA_REPORTS: Channel<CriticalSectionRawMutex, [u8; RAW_HID_REPORT_SIZE], 2> = Channel::new();
+/// rmk's answer to the injected report is dropped, the host only asked for the signed status
+#[cfg(feature = "signed_config")]
+static DROP_NEXT_RESPONSE: portable_atomic::AtomicBool = portable_atomic::AtomicBool::new(false);
+
+/// Whether the vendor command changes the settings, the clock or the flash, refused on the plain channel
+/// with `signed_config`. The data packets of the uploads don't fit a signed payload, their begin and
+/// their commit are signed instead.
+pub fn is_config_command(report: &[u8]) -> bool {
+    match (report.first(), report.get(1)) {
+        (Some(&(LIGHTING_SET_VALUE | LIGHTING_SAVE)), _) => true,
+        (Some(&(CLOCK_COMMAND | LOG_COMMAND)), _) => true,
+        (Some(&KEY_STREAM_COMMAND), Some(&KEY_STREAM_ANONYMOUS)) => true,
+        (Some(&SNIPPET_COMMAND), Some(&(SNIPPET_WRITE | SNIPPET_SAVE))) => true,
+        (Some(&LAYER_COLOR_COMMAND), Some(&(LAYER_COLOR_SET | LAYER_COLOR_CLEAR | LAYER_COLOR_SAVE))) => true,
+        (Some(&POINTER_COMMAND), Some(&(POINTER_SET | POINTER_SAVE))) => true,
+        #[cfg(feature = "secret_vault")]
+        (Some(&VAULT_COMMAND), Some(&(VAULT_WRITE | VAULT_SEAL | VAULT_CLEAR))) => true,
+        #[cfg(feature = "crash_log")]
+        (Some(&CRASH_COMMAND), Some(&CRASH_CLEAR)) => true,
+        #[cfg(feature = "dfu")]
+        (Some(&DFU_COMMAND), Some(&(DFU_BEGIN | DFU_FINISH))) => true,
+        #[cfg(feature = "bitmap_upload")]
+        (Some(&BITMAP_COMMAND), Some(&(BITMAP_BEGIN | BITMAP_COMMIT))) => true,
+        _ => false,
+    }
+}
+
+/// Whether the Via or Vial command changes the keymap, the macros or rmk's storage, or reboots
+pub fn is_via_write(report: &[u8]) -> bool {
+    match (report.first(), report.get(1), report.get(2)) {
+        // Keyboard value e.g. the layout options, set keycode, keymap reset, eeprom reset, bootloader jump,
+        // macro buffer and reset, keymap buffer, encoder
+        (Some(&(0x03 | 0x05 | 0x06 | 0x0A | 0x0B | 0x0F | 0x10 | 0x13 | 0x15)), _, _) => true,
+        // Vial set encoder, QMK settings set and reset
+        (Some(&0xFE), Some(&(0x04 | 0x0B | 0x0C)), _) => true,
+        // Vial tap dance, combo and key override set
+        (Some(&0xFE), Some(&0x0D), Some(&(0x02 | 0x04 | 0x06))) => true,
+        _ => false,
+    }
+}
+
+/// Hand a signed Vial write to rmk, waiting while earlier ones are queued
+#[cfg(feature = "signed_config")]
+pub(crate) async fn inject_via_report(report: [u8; RAW_HID_REPORT_SIZE]) {
+    SIGNED_VIA_REPORTS.send(report).await;
+}
+
+/// Dispatch vendor raw HID commands in place.
+/// [`RawHidDriver`] calls it for every raw HID report, returns false if unknown to leave it to rmk.
+/// With `signed_config`, config commands are answered as unhandled, they only come through [`crate::signed`].
+pub fn handle_vendor_command(report: &mut [u8], build_info: &BuildInfo) -> bool {
+    #[cfg(feature = "signed_config")]
+    if is_config_command(report) {
+        report.fill(0);
+        report[0] = VIA_UNHANDLED;
+        return true;
+    }
+    match report.first() {
+        Some(&INFO_COMMAND) => build_info.handle_info_command(report),
+        Some(&LOG_COMMAND) => handle_log_command(report),
+        Some(&HEATMAP_COMMAND) => handle_heatmap_command(report),
+        Some(&CLOCK_COMMAND) => handle_clock_command(report),
+        Some(&RECORDER_COMMAND) => handle_recorder_command(report),
+        Some(&FEATURE_COMMAND) => handle_feature_command(report),
+        Some(&KEY_STREAM_COMMAND) => handle_key_stream_command(report),
+        Some(&LAYER_COLOR_COMMAND) => handle_layer_color_command(report),
+        Some(&ALERT_COMMAND) => handle_alert_command(report),
+        Some(&SNIPPET_COMMAND) => handle_snippet_command(report),
+        Some(&POINTER_COMMAND) => handle_pointer_command(report),
+        Some(&KEYMAP_NAME_COMMAND) => handle_keymap_name_command(report),
+        Some(&LIGHTING_SET_VALUE | &LIGHTING_GET_VALUE | &LIGHTING_SAVE) => handle_lighting_command(report),
+        #[cfg(feature = "signed_config")]
+        Some(&SIGNED_COMMAND) => handle_signed_command(report),
+        #[cfg(feature = "bitmap_upload")]
+        Some(&BITMAP_COMMAND) => handle_bitmap_command(report),
+        #[cfg(feature = "crash_log")]
+        Some(&CRASH_COMMAND) => handle_crash_command(report),
+        #[cfg(feature = "dfu")]
+        Some(&DFU_COMMAND) => handle_dfu_command(report),
+        #[cfg(feature = "secret_vault")]
+        Some(&VAULT_COMMAND) => handle_vault_command(report),
+        #[cfg(feature = "event_injection")]
+        Some(&INJECT_COMMAND) => handle_inject_command(report),
+        _ => false,
+    }
+}
+
+/// Dispatch the commands changing the settings, i.e. [`is_config_command`]'s.
+/// With `signed_config` they only come through [`crate::signed`].
+pub fn handle_config_command(report: &mut [u8]) -> bool {
+    if !is_config_command(report) {
+        return false;
+    }
+    match report.first() {
+        Some(&(LIGHTING_SET_VALUE | LIGHTING_SAVE)) => handle_lighting_command(report),
+        Some(&CLOCK_COMMAND) => handle_clock_command(report),
+        Some(&LOG_COMMAND) => handle_log_command(report),
+        Some(&KEY_STREAM_COMMAND) => handle_key_stream_command(report),
+        Some(&SNIPPET_COMMAND) => handle_snippet_command(report),
+        Some(&LAYER_COLOR_COMMAND) => handle_layer_color_command(report),
+        Some(&POINTER_COMMAND) => handle_pointer_command(report),
+        #[cfg(feature = "secret_vault")]
+        Some(&VAULT_COMMAND) => handle_vault_command(report),
+        #[cfg(feature = "crash_log")]
+        Some(&CRASH_COMMAND) => handle_crash_command(report),
+        #[cfg(feature = "dfu")]
+        Some(&DFU_COMMAND) => handle_dfu_command(report),
+        #[cfg(feature = "bitmap_upload")]
+        Some(&BITMAP_COMMAND) => handle_bitmap_command(report),
+        _ => false,
+    }
+}
+
+/// Push a synthetic key event through the matrix's hooks, skipping the debouncer.
+/// Response: `[INJECT_COMMAND, status]`, status is 0 on success and 1 if the input event queue is full.
+#[cfg(feature = "event_injection")]
+fn handle_inject_command(report: &mut [u8]) -> bool {
+    if report.len() < 4 {
+        return false;
+    }
+    let event = KeyEvent {
+        row: report[1],
+        col: report[2],
+        pressed: report[3] != 0,
+    };
+    defmt::debug!("Injected key event: {}", event);
+    let status = if try_send_input_event(event) { 0 } else { 1 };
+    report[1..].fill(0);
+    report[1] = status;
+    true
+}
+
+
+/// USB driver answering the vendor commands on the raw HID endpoints rmk's Vial handler owns.
+/// rmk reads every report itself, so a report handled by [`handle_vendor_command`] reaches rmk as
+/// the unhandled Via command, and the response replaces rmk's unhandled answer on the IN endpoint.
+/// With `signed_config`, unsigned Vial writes reach rmk the same way, and signed ones are read in between.
+/// Raw HID endpoints are told apart from the keyboard's by their 32 byte packets.
+pub struct RawHidDriver<D> {
+    inner: D,
+    build_info: &'static BuildInfo,
+}
+
+impl<D> RawHidDriver<D> {
+    pub fn new(inner: D, build_info: &'static BuildInfo) -> Self {
+        Self { inner, build_info }
+    }
+}
+
+fn is_raw_hid(ep_type: EndpointType, max_packet_size: u16) -> bool {
+    ep_type == EndpointType::Interrupt && max_packet_size as usize == RAW_HID_REPORT_SIZE
+}
+
+impl<'a, D: Driver<'a>> Driver<'a> for RawHidDriver<D> {
+    type EndpointOut = RawHidEndpointOut<D::EndpointOut>;
+    type EndpointIn = RawHidEndpointIn<D::EndpointIn>;
+    type ControlPipe = D::ControlPipe;
+    type Bus = D::Bus;
+
+    fn alloc_endpoint_out(
+        &mut self,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<Self::EndpointOut, EndpointAllocError> {
+        let inner = self.inner.alloc_endpoint_out(ep_type, max_packet_size, interval_ms)?;
+        Ok(RawHidEndpointOut {
+            inner,
+            build_info: is_raw_hid(ep_type, max_packet_size).then_some(self.build_info),
+        })
+    }
+
+    fn alloc_endpoint_in(
+        &mut self,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<Self::EndpointIn, EndpointAllocError> {
+        let inner = self.inner.alloc_endpoint_in(ep_type, max_packet_size, interval_ms)?;
+        Ok(RawHidEndpointIn {
+            inner,
+            raw_hid: is_raw_hid(ep_type, max_packet_size),
+        })
+    }
+
+    fn start(self, control_max_packet_size: u16) -> (Self::Bus, Self::ControlPipe) {
+        self.inner.start(control_max_packet_size)
+    }
+}
+
+/// OUT endpoint handling the vendor commands of the raw HID reports
+pub struct RawHidEndpointOut<E> {
+    inner: E,
+    /// Set on the raw HID endpoint only
+    build_info: Option<&'static BuildInfo>,
+}
+
+impl<E: Endpoint> Endpoint for RawHidEndpointOut<E> {
+    fn info(&self) -> &EndpointInfo {
+        self.inner.info()
+    }
+
+    async fn wait_enabled(&mut self) {
+        self.inner.wait_enabled().await
+    }
+}
+
+impl<E: EndpointOut> RawHidEndpointOut<E> {
+    /// Read the host's report, or a signed Vial write accepted meanwhile, returning whether it's the latter
+    #[cfg(feature = "signed_config")]
+    async fn read_report(&mut self, buf: &mut [u8]) -> Result<(usize, bool), EndpointError> {
+        if self.build_info.is_none() || buf.len() < RAW_HID_REPORT_SIZE {
+            return Ok((self.inner.read(buf).await?, false));
+        }
+        match select(self.inner.read(buf), SIGNED_VIA_REPORTS.receive()).await {
+            Either::First(len) => Ok((len?, false)),
+            Either::Second(report) => {
+                DROP_NEXT_RESPONSE.store(true, portable_atomic::Ordering::Relaxed);
+                buf[..RAW_HID_REPORT_SIZE].copy_from_slice(&report);
+                Ok((RAW_HID_REPORT_SIZE, true))
+            }
+        }
+    }
+
+    #[cfg(not(feature = "signed_config"))]
+    async fn read_report(&mut self, buf: &mut [u8]) -> Result<(usize, bool), EndpointError> {
+        Ok((self.inner.read(buf).await?, false))
+    }
+}
+
+impl<E: EndpointOut> EndpointOut for RawHidEndpointOut<E> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, EndpointError> {
+        let (len, signed) = self.read_report(buf).await?;
+        let Some(build_info) = self.build_info else {
+            return Ok(len);
+        };
+        if signed || len != RAW_HID_REPORT_SIZE {
+            return Ok(len);
+        }
+        let mut report = [0; RAW_HID_REPORT_SIZE];
+        report.copy_from_slice(&buf[..len]);
+        if handle_vendor_command(&mut report, build_info) {
+            PENDING_RESPONSE.lock(|r| *r.borrow_mut() = Some(report));
+            buf[..len].fill(0);
+            buf[0] = VIA_UNHANDLED;
+        } else if cfg!(feature = "signed_config") && is_via_write(&report) {
+            defmt::warn!("Unsigned Vial write {:#x} refused", report[0]);
+            buf[..len].fill(0);
+            buf[0] = VIA_UNHANDLED;
+        }
+        Ok(len)
+    }
+}
+
+/// IN endpoint writing the pending vendor response instead of rmk's unhandled answer
+pub struct RawHidEndpointIn<E> {
+    inner: E,
+    raw_hid: bool,
+}
+
+impl<E: Endpoint> Endpoint for RawHidEndpointIn<E> {
+    fn info(&self) -> &EndpointInfo {
+        self.inner.info()
+    }
+
+    async fn wait_enabled(&mut self) {
+        self.inner.wait_enabled().await
+    }
+}
+
+impl<E: EndpointIn> EndpointIn for RawHidEndpointIn<E> {
+    async fn write(&mut self, buf: &[u8]) -> Result<(), EndpointError> {
+        #[cfg(feature = "signed_config")]
+        if self.raw_hid && DROP_NEXT_RESPONSE.swap(false, portable_atomic::Ordering::Relaxed) {
+            return Ok(());
+        }
+        let pending = match buf.first() {
+            Some(&VIA_UNHANDLED) if self.raw_hid => PENDING_RESPONSE.lock(|r| r.borrow_mut().take()),
+            _ => None,
+        };
+        match pending {
+            Some(response) => self.inner.write(&response).await,
+            None => self.inner.write(buf).await,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn via_writes_are_told_from_reads() {
+        let cases: &[(&[u8], bool)] = &[
+            // Get protocol version, keyboard value, keycode, layer count, keymap buffer, encoder
+            (&[0x01], false),
+            (&[0x02, 0x02], false),
+            (&[0x04, 0, 0, 0], false),
+            (&[0x11], false),
+            (&[0x12, 0, 0, 28], false),
+            (&[0x14, 0, 0, 0], false),
+            // Set keyboard value, the layout options
+            (&[0x03, 0x02, 0, 0, 0, 1], true),
+            (&[0x05, 0, 0, 0, 0, 4], true),
+            (&[0x06], true),
+            (&[0x0A], true),
+            (&[0x0B], true),
+            (&[0x0F, 0, 0, 1, 0], true),
+            (&[0x10], true),
+            (&[0x13, 0, 0, 2, 0, 4], true),
+            (&[0x15, 0, 0, 0, 0, 0], true),
+            // Vial get keyboard id and definition, set encoder, QMK settings
+            (&[0xFE, 0x00], false),
+            (&[0xFE, 0x02], false),
+            (&[0xFE, 0x04], true),
+            (&[0xFE, 0x0B], true),
+            (&[0xFE, 0x0C], true),
+            // Vial dynamic entries, get count and tap dance, set tap dance, combo and key override
+            (&[0xFE, 0x0D, 0x00], false),
+            (&[0xFE, 0x0D, 0x01], false),
+            (&[0xFE, 0x0D, 0x02], true),
+            (&[0xFE, 0x0D, 0x04], true),
+            (&[0xFE, 0x0D, 0x06], true),
+            (&[], false),
+        ];
+        for &(report, write) in cases {
+            assert_eq!(is_via_write(report), write, "{:02x?}", report);
+        }
+    }
+
+    #[test]
+    fn config_commands_are_told_from_queries() {
+        let cases: &[(&[u8], bool)] = &[
+            (&[INFO_COMMAND, 0], false),
+            (&[HEATMAP_COMMAND, 0], false),
+            (&[RECORDER_COMMAND, 0], false),
+            (&[FEATURE_COMMAND, 0, 0], false),
+            (&[KEYMAP_NAME_COMMAND, 0, 0, 0], false),
+            (&[ALERT_COMMAND, 0], false),
+            (&[LIGHTING_GET_VALUE, 0], false),
+            (&[LIGHTING_SET_VALUE, 0, 0], true),
+            (&[LIGHTING_SAVE], true),
+            (&[CLOCK_COMMAND, 0xEA, 0x07, 1, 1, 0, 0, 0], true),
+            (&[LOG_COMMAND, 0xFF, 0], true),
+            (&[KEY_STREAM_COMMAND, crate::key_stream::KEY_STREAM_READ], false),
+            (&[KEY_STREAM_COMMAND, KEY_STREAM_ANONYMOUS, 0], true),
+            (&[SNIPPET_COMMAND, crate::snippets::SNIPPET_GET], false),
+            (&[SNIPPET_COMMAND, SNIPPET_WRITE], true),
+            (&[SNIPPET_COMMAND, SNIPPET_SAVE], true),
+            (&[LAYER_COLOR_COMMAND, crate::layer_colors::LAYER_COLOR_GET], false),
+            (&[LAYER_COLOR_COMMAND, LAYER_COLOR_SET], true),
+            (&[LAYER_COLOR_COMMAND, LAYER_COLOR_CLEAR], true),
+            (&[LAYER_COLOR_COMMAND, LAYER_COLOR_SAVE], true),
+            (&[POINTER_COMMAND, crate::pointer_settings::POINTER_GET], false),
+            (&[POINTER_COMMAND, POINTER_SET], true),
+            (&[POINTER_COMMAND, POINTER_SAVE], true),
+            (&[], false),
+        ];
+        for &(report, config) in cases {
+            assert_eq!(is_config_command(report), config, "{:02x?}", report);
+        }
+    }
+}
@@ -0,0 +1,77 @@
+//! Soft reboot for recovering from odd states without unplugging.
+//! Held keys are released on the host and pending flash writes finish before the MCU resets.
+
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{with_timeout, Duration, Timer};
+use usbd_hid::descriptor::MouseReport;
+
+use crate::log::LogModule;
+use crate::log_info;
+use crate::output::{send_output_report, OutputReport};
+use crate::quiesce::is_paused;
+use crate::typing::release_keys;
+
+
+/// Time for the release reports to reach the host and for rmk to take queued saves
+const RESET_SETTLE: Duration = Duration::from_millis(200);
+/// Longest wait for a running flash operation, a stuck one doesn't block the reset forever
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(2);
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Where the reset ends up
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum ResetTarget {
+    Firmware,
+    /// The bootloader, for a firmware update
+    Bootloader,
+}
+
+static SYSTEM_RESET_REQUEST: Signal<CriticalSectionRawMutex, ResetTarget> = Signal::new();
+
+pub fn request_system_reset() {
+    SYSTEM_RESET_REQUEST.signal(ResetTarget::Firmware);
+}
+
+pub fn request_bootloader() {
+    SYSTEM_RESET_REQUEST.signal(ResetTarget::Bootloader);
+}
+
+/// Wait for reset requests, release the keys and flush the storage, then call `reset`.
+/// This function should never return.
+pub async fn run_system_reset(reset: fn(ResetTarget) -> !) -> ! {
+    let target = SYSTEM_RESET_REQUEST.wait().await;
+    log_info!(LogModule::Device, "System reset to {}", target);
+    release_keys().await;
+    let mouse = MouseReport {
+        buttons: 0,
+        x: 0,
+        y: 0,
+        wheel: 0,
+        pan: 0,
+    };
+    send_output_report(OutputReport::Mouse(mouse)).await;
+    Timer::after(RESET_SETTLE).await;
+    // Flash operations hold the pause until they finish
+    if with_timeout(FLUSH_TIMEOUT, async {
+        while is_paused() {
+            Timer::after(POLL_INTERVAL).await;
+        }
+    })
+    .await
+    .is_err()
+    {
+        defmt::warn!("Flash still busy, resetting anyway");
+    }
+    reset(target)
+}
+
+/// Reset the RP2040 by the watchdog, like a power cycle except for the debug probe.
+/// The bootloader is entered with the [handoff flag](crate::handoff) set.
+#[cfg(feature = "rp2040")]
+pub fn rp2040_reset(target: ResetTarget) -> ! {
+    if target == ResetTarget::Bootloader {
+        crate::handoff::reboot_to_bootloader();
+    }
+    embassy_rp::pac::WATCHDOG.ctrl().write(|w| w.set_trigger(true));
+    loop {}
+}
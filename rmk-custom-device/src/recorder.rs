@@ -0,0 +1,140 @@
+//! Flight recorder of the last key events with timestamps and the layer, for debugging tap-hold
+//! and combo misfires reported by users. Dumped over raw HID or defmt on demand.
+
+use core::cell::RefCell;
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::Instant;
+use heapless::HistoryBuffer;
+use rmk::event::KeyEvent;
+
+use crate::event::{HookContext, KeyEventHook};
+use crate::layer_state::active_layer;
+
+
+/// Raw HID command id reading the recorded events, `[RECORDER_COMMAND, first]`, oldest first
+pub const RECORDER_COMMAND: u8 = 0xE9;
+
+/// Events kept, older ones are overwritten
+pub const RECORDER_CAPACITY: usize = 64;
+
+/// Bytes of an event in the raw HID response
+const RECORD_SIZE: usize = 7;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct EventRecord {
+    /// Milliseconds since boot, wrapping
+    pub time_ms: u32,
+    pub row: u8,
+    pub col: u8,
+    pub pressed: bool,
+    pub layer: u8,
+}
+
+impl EventRecord {
+    /// `[time_ms (u32 LE), row, col, pressed << 7 | layer]`
+    fn to_bytes(self) -> [u8; RECORD_SIZE] {
+        let time = self.time_ms.to_le_bytes();
+        let flags = (self.pressed as u8) << 7 | (self.layer & 0x7F);
+        [time[0], time[1], time[2], time[3], self.row, self.col, flags]
+    }
+}
+
+/// RAM taken by the history, for the [RAM budget](crate::ram_budget)
+pub(crate) const RECORDER_RAM: usize = core::mem::size_of::<HistoryBuffer<EventRecord, RECORDER_CAPACITY>>();
+
+static RECORDER: Mutex<CriticalSectionRawMutex, RefCell<HistoryBuffer<EventRecord, RECORDER_CAPACITY>>> =
+    Mutex::new(RefCell::new(HistoryBuffer::new()));
+
+pub fn clear_flight_recorder() {
+    RECORDER.lock(|r| r.borrow_mut().clear());
+}
+
+/// Print the recorded events by defmt, oldest first
+pub fn dump_flight_recorder() {
+    RECORDER.lock(|r| {
+        let r = r.borrow();
+        defmt::info!("Flight recorder: {} events", r.len());
+        for record in r.oldest_ordered() {
+            defmt::info!("{}", record);
+        }
+    });
+}
+
+/// Answer a recorder command in place.
+/// Response: `[RECORDER_COMMAND, events, first, records...]`, 7 bytes each from `first`, see [`EventRecord`].
+/// Returns false if the report isn't a recorder command.
+pub fn handle_recorder_command(report: &mut [u8]) -> bool {
+    if report.len() < 2 || report[0] != RECORDER_COMMAND {
+        return false;
+    }
+    let first = report[1] as usize;
+    report[1..].fill(0);
+    RECORDER.lock(|r| {
+        let r = r.borrow();
+        report[1] = r.len() as u8;
+        report[2] = first as u8;
+        for (chunk, record) in report[3..].chunks_exact_mut(RECORD_SIZE).zip(r.oldest_ordered().skip(first)) {
+            chunk.copy_from_slice(&record.to_bytes());
+        }
+    });
+    true
+}
+
+
+/// Hook recording every event, forwards every event.
+/// Put it first to record the events as debounced, before other hooks change them.
+pub struct FlightRecorderHook;
+
+impl KeyEventHook for FlightRecorderHook {
+    async fn process(&mut self, event: KeyEvent, _ctx: &mut HookContext) -> Option<KeyEvent> {
+        let record = EventRecord {
+            time_ms: Instant::now().as_millis() as u32,
+            row: event.row,
+            col: event.col,
+            pressed: event.pressed,
+            layer: active_layer(),
+        };
+        RECORDER.lock(|r| r.borrow_mut().write(record));
+        Some(event)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layer_state::set_active_layer;
+    use crate::test_support::serial;
+
+    fn record(row: u8, col: u8, pressed: bool) {
+        let event = KeyEvent { row, col, pressed };
+        let forwarded = embassy_futures::block_on(FlightRecorderHook.process(event, &mut HookContext::new()));
+        assert_eq!(forwarded.map(|e| (e.row, e.col, e.pressed)), Some((row, col, pressed)));
+    }
+
+    #[test]
+    fn events_are_read_oldest_first() {
+        let _serial = serial();
+        clear_flight_recorder();
+        set_active_layer(2);
+        record(1, 2, true);
+        set_active_layer(0);
+        record(1, 2, false);
+
+        let mut report = [0u8; 32];
+        report[0] = RECORDER_COMMAND;
+        assert!(handle_recorder_command(&mut report));
+        assert_eq!(report[1..3], [2, 0]);
+        assert_eq!(report[7..10], [1, 2, 0x82]);
+        assert_eq!(report[14..17], [1, 2, 0x00]);
+
+        report.fill(0);
+        report[0] = RECORDER_COMMAND;
+        report[1] = 1;
+        assert!(handle_recorder_command(&mut report));
+        assert_eq!(report[1..3], [2, 1]);
+        assert_eq!(report[7..10], [1, 2, 0x00]);
+        assert_eq!(report[10..17], [0; 7]);
+        clear_flight_recorder();
+    }
+}
@@ -0,0 +1,48 @@
+//! Flash sectors reserved outside of rmk's storage, for firmware-side data.
+//!
+//! The flash is owned by rmk, so these borrow it through the peripheral with blocking operations.
+//! Call them in [`quiesce_flash`](crate::quiesce::quiesce_flash), or at boot before rmk's storage starts.
+
+use embassy_rp::flash::{Blocking, Error, Flash, ERASE_SIZE};
+use embassy_time::Instant;
+
+use crate::bench::FLASH_WRITE_TIMING;
+
+
+fn borrow_flash<const FLASH_SIZE: usize>() -> Flash<'static, embassy_rp::peripherals::FLASH, Blocking, FLASH_SIZE> {
+    // SAFETY: the callers hold the flash lock or run before rmk's storage, so no other flash operation
+    // overlaps, including a DMA read of rmk which a blocking erase with interrupts off doesn't stop
+    let flash_peripheral = unsafe { embassy_rp::peripherals::FLASH::steal() };
+    Flash::new_blocking(flash_peripheral)
+}
+
+/// Erase the sector at `offset` and write the data from its start.
+/// `offset` must be erase size aligned and outside of rmk's storage.
+pub fn write_reserved_sector<const FLASH_SIZE: usize>(offset: u32, data: &[u8]) -> Result<(), Error> {
+    let mut flash = borrow_flash::<FLASH_SIZE>();
+    let start = Instant::now();
+    flash.blocking_erase(offset, offset + ERASE_SIZE as u32)?;
+    flash.blocking_write(offset, data)?;
+    FLASH_WRITE_TIMING.record(start.elapsed());
+    Ok(())
+}
+
+/// Write the data at `offset` without erasing, e.g. appending a record to the erased end of a reserved sector
+pub fn append_reserved<const FLASH_SIZE: usize>(offset: u32, data: &[u8]) -> Result<(), Error> {
+    let mut flash = borrow_flash::<FLASH_SIZE>();
+    let start = Instant::now();
+    flash.blocking_write(offset, data)?;
+    FLASH_WRITE_TIMING.record(start.elapsed());
+    Ok(())
+}
+
+/// Read from `offset`, e.g. a reserved sector after rmk's storage started
+pub fn read_reserved<const FLASH_SIZE: usize>(offset: u32, buf: &mut [u8]) -> Result<(), Error> {
+    borrow_flash::<FLASH_SIZE>().blocking_read(offset, buf)
+}
+
+/// Erase `count` sectors from `offset`, e.g. rmk's storage to start over from the compiled keymap.
+/// `offset` must be erase size aligned.
+pub fn erase_sectors<const FLASH_SIZE: usize>(offset: u32, count: u32) -> Result<(), Error> {
+    borrow_flash::<FLASH_SIZE>().blocking_erase(offset, offset + count * ERASE_SIZE as u32)
+}
@@ -0,0 +1,82 @@
+//! Per-key RGB rendering, independent of the LED backend.
+
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Duration, Instant, Ticker};
+pub use smart_leds::RGB8;
+
+use crate::brightness::{brightness, scale_brightness};
+use crate::brown_out::is_low_voltage;
+use crate::host_sleep::is_rgb_asleep;
+use crate::quiesce::is_paused;
+use crate::soft_off::is_soft_off;
+
+
+/// Back buffer of the double buffered frames.
+/// The renderer signals a finished frame, the output task picks up the latest one while the previous one is streamed.
+pub type FrameSignal<const N: usize> = Signal<CriticalSectionRawMutex, [RGB8; N]>;
+
+/// Matrix position under each LED, `None` for LEDs not under a key like underglow
+#[derive(Clone, Copy, Debug)]
+pub struct LedMap<const N: usize> {
+    pub positions: [Option<(u8, u8)>; N],
+}
+
+impl<const N: usize> LedMap<N> {
+    pub const fn new(positions: [Option<(u8, u8)>; N]) -> Self {
+        Self { positions }
+    }
+}
+
+/// Effect drawing into the frame
+pub trait RgbEffect<const N: usize> {
+    fn render(&mut self, frame: &mut [RGB8; N], now: Instant);
+}
+
+/// Fill every LED with the color
+impl<const N: usize> RgbEffect<N> for RGB8 {
+    fn render(&mut self, frame: &mut [RGB8; N], _now: Instant) {
+        frame.fill(*self);
+    }
+}
+
+/// No-op effect, leaves the frame as is
+impl<const N: usize> RgbEffect<N> for () {
+    fn render(&mut self, _frame: &mut [RGB8; N], _now: Instant) {}
+}
+
+/// Layer two effects, the second one draws on top of the first
+impl<A: RgbEffect<N>, B: RgbEffect<N>, const N: usize> RgbEffect<N> for (A, B) {
+    fn render(&mut self, frame: &mut [RGB8; N], now: Instant) {
+        self.0.render(frame, now);
+        self.1.render(frame, now);
+    }
+}
+
+/// Render the effect at the frame rate and hand the frames to the backend, dimmed by the global brightness
+/// and dark while the host sleeps if the sleep profile says so.
+/// This function should never return.
+pub async fn run_rgb_renderer<E: RgbEffect<N>, const N: usize>(
+    mut effect: E,
+    frame_signal: &FrameSignal<N>,
+    fps: u64,
+) -> ! {
+    let mut ticker = Ticker::every(Duration::from_hz(fps));
+    let mut frame = [RGB8::default(); N];
+    loop {
+        // Keep the last frame through flash operations
+        if is_paused() {
+            ticker.next().await;
+            continue;
+        }
+        effect.render(&mut frame, Instant::now());
+        let brightness = if is_soft_off() || is_low_voltage() || is_rgb_asleep() { 0 } else { brightness() };
+        let mut dimmed = frame;
+        for pixel in dimmed.iter_mut() {
+            pixel.r = scale_brightness(pixel.r, brightness);
+            pixel.g = scale_brightness(pixel.g, brightness);
+            pixel.b = scale_brightness(pixel.b, brightness);
+        }
+        frame_signal.signal(dimmed);
+        ticker.next().await;
+    }
+}
@@ -0,0 +1,192 @@
+//! Scheduler running timed action scripts in their own task, so waits and repeats never block the key event pipeline.
+//! Macros, turbo keys and other sequenced actions are scripts of [`ActionStep`].
+
+use embassy_futures::select::select;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel, signal::Signal};
+use embassy_time::{Duration, Timer};
+use portable_atomic::{AtomicBool, Ordering};
+
+use crate::action::{CustomAction, CustomActionEvent, CUSTOM_ACTION_CHANNEL};
+use crate::log::LogModule;
+use crate::log_info;
+use crate::typing::{press_char, release_keys, type_char, type_text};
+
+
+/// Interval between the taps of a turbo key
+pub const TURBO_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum ActionStep {
+    /// Press and release the char on the host layout
+    Tap(char),
+    /// Type the text on the host layout
+    Text(&'static str),
+    /// Hold the char until [`ActionStep::Release`] or the end of the script
+    Press(char),
+    /// Release the held keys
+    Release,
+    /// Hold the char and release it after the duration
+    HoldFor(char, Duration),
+    Wait(Duration),
+    /// Repeat the steps, nested repeats are skipped
+    Repeat(u8, &'static [ActionStep]),
+    /// Trigger the custom action, dropped if the custom action task is busy
+    Custom(CustomAction),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+enum Schedule {
+    Script(&'static [ActionStep]),
+    /// Tap the char until cancelled
+    Turbo(char),
+}
+
+static SCHEDULE_CHANNEL: Channel<CriticalSectionRawMutex, Schedule, 4> = Channel::new();
+static CANCEL: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+/// A schedule is running, its keys released included
+static RUNNING: AtomicBool = AtomicBool::new(false);
+const IDLE_POLL: Duration = Duration::from_millis(1);
+
+/// Queue the script after the running ones, returns false if the queue is full
+pub fn schedule_actions(script: &'static [ActionStep]) -> bool {
+    SCHEDULE_CHANNEL.try_send(Schedule::Script(script)).is_ok()
+}
+
+/// Tap the char every [`TURBO_INTERVAL`] until [`cancel_scheduled`], returns false if the queue is full
+pub fn schedule_turbo(c: char) -> bool {
+    SCHEDULE_CHANNEL.try_send(Schedule::Turbo(c)).is_ok()
+}
+
+/// Stop the running script and drop the queued ones, held keys are released
+pub fn cancel_scheduled() {
+    while SCHEDULE_CHANNEL.try_receive().is_ok() {}
+    CANCEL.signal(());
+}
+
+/// Wait until the queued and running scripts are done, e.g. to release the modifiers held around a macro
+pub async fn wait_scheduler_idle() {
+    while RUNNING.load(Ordering::Acquire) || !SCHEDULE_CHANNEL.is_empty() {
+        Timer::after(IDLE_POLL).await;
+    }
+}
+
+async fn run_step(step: &ActionStep) {
+    match *step {
+        ActionStep::Tap(c) => {
+            type_char(c).await;
+        }
+        ActionStep::Text(text) => type_text(text).await,
+        ActionStep::Press(c) => {
+            press_char(c).await;
+        }
+        ActionStep::Release => release_keys().await,
+        ActionStep::HoldFor(c, duration) => {
+            press_char(c).await;
+            Timer::after(duration).await;
+            release_keys().await;
+        }
+        ActionStep::Wait(duration) => Timer::after(duration).await,
+        ActionStep::Repeat(..) => defmt::warn!("Nested repeat skipped"),
+        ActionStep::Custom(action) => {
+            let event = CustomActionEvent {
+                action,
+                pressed: true,
+                rmk_event: 0,
+            };
+            if CUSTOM_ACTION_CHANNEL.try_send(event).is_err() {
+                defmt::warn!("Custom action {} dropped", event);
+            }
+        }
+    }
+}
+
+async fn run_schedule(schedule: Schedule) {
+    match schedule {
+        Schedule::Script(script) => {
+            for step in script {
+                match step {
+                    ActionStep::Repeat(count, steps) => {
+                        for _ in 0..*count {
+                            for step in *steps {
+                                run_step(step).await;
+                            }
+                        }
+                    }
+                    step => run_step(step).await,
+                }
+            }
+        }
+        Schedule::Turbo(c) => loop {
+            type_char(c).await;
+            Timer::after(TURBO_INTERVAL).await;
+        },
+    }
+}
+
+/// Run the scheduled scripts one after another. This function should never return.
+pub async fn run_action_scheduler() -> ! {
+    loop {
+        let schedule = SCHEDULE_CHANNEL.receive().await;
+        RUNNING.store(true, Ordering::Release);
+        // A cancel before this schedule was received is for the previous ones
+        CANCEL.reset();
+        log_info!(LogModule::Action, "Scheduled: {}", schedule);
+        select(run_schedule(schedule), CANCEL.wait()).await;
+        release_keys().await;
+        RUNNING.store(false, Ordering::Release);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::serial;
+
+    fn custom_actions() -> std::vec::Vec<CustomAction> {
+        core::iter::from_fn(|| CUSTOM_ACTION_CHANNEL.try_receive().ok())
+            .map(|e| e.action)
+            .collect()
+    }
+
+    #[test]
+    fn repeat_runs_its_steps() {
+        let _serial = serial();
+        custom_actions();
+        const SCRIPT: &[ActionStep] = &[
+            ActionStep::Repeat(3, &[ActionStep::Custom(CustomAction::Version)]),
+            ActionStep::Custom(CustomAction::Settings),
+        ];
+        embassy_futures::block_on(run_schedule(Schedule::Script(SCRIPT)));
+        let (version, settings) = (CustomAction::Version, CustomAction::Settings);
+        assert_eq!(custom_actions(), [version, version, version, settings]);
+    }
+
+    #[test]
+    fn nested_repeat_is_skipped() {
+        let _serial = serial();
+        custom_actions();
+        const INNER: &[ActionStep] = &[ActionStep::Custom(CustomAction::Version)];
+        const SCRIPT: &[ActionStep] = &[ActionStep::Repeat(
+            2,
+            &[ActionStep::Repeat(2, INNER), ActionStep::Custom(CustomAction::Settings)],
+        )];
+        embassy_futures::block_on(run_schedule(Schedule::Script(SCRIPT)));
+        assert_eq!(custom_actions(), [CustomAction::Settings, CustomAction::Settings]);
+    }
+
+    #[test]
+    fn cancel_drops_the_queued_scripts() {
+        let _serial = serial();
+        const SCRIPT: &[ActionStep] = &[ActionStep::Wait(Duration::from_secs(1))];
+        cancel_scheduled();
+        for _ in 0..4 {
+            assert!(schedule_actions(SCRIPT));
+        }
+        assert!(!schedule_turbo('a'));
+        cancel_scheduled();
+        assert!(SCHEDULE_CHANNEL.is_empty());
+        embassy_futures::block_on(wait_scheduler_idle());
+        CANCEL.reset();
+    }
+}
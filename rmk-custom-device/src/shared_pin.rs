@@ -0,0 +1,43 @@
+//! Output pin driven by several tasks, e.g. the board's only LED blinked by each indicator.
+
+use core::cell::RefCell;
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embedded_hal::digital::{ErrorType, OutputPin};
+
+
+/// Owner of the shared pin, lending a [`SharedPin`] to each task
+pub struct SharedOutput<P: OutputPin> {
+    pin: Mutex<CriticalSectionRawMutex, RefCell<P>>,
+}
+
+impl<P: OutputPin> SharedOutput<P> {
+    pub const fn new(pin: P) -> Self {
+        Self {
+            pin: Mutex::new(RefCell::new(pin)),
+        }
+    }
+
+    pub fn handle(&self) -> SharedPin<'_, P> {
+        SharedPin { output: self }
+    }
+}
+
+/// Handle of the shared pin, an output pin of its own for the task.
+/// Writes go straight to the pin, the last one wins while two tasks drive it at once.
+pub struct SharedPin<'a, P: OutputPin> {
+    output: &'a SharedOutput<P>,
+}
+
+impl<P: OutputPin> ErrorType for SharedPin<'_, P> {
+    type Error = P::Error;
+}
+
+impl<P: OutputPin> OutputPin for SharedPin<'_, P> {
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        self.output.pin.lock(|pin| pin.borrow_mut().set_low())
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        self.output.pin.lock(|pin| pin.borrow_mut().set_high())
+    }
+}
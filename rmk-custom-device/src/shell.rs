@@ -0,0 +1,101 @@
+//! Line based command shell over a serial port like USB CDC, an alternative to the raw HID tools.
+//! Subsystems register their commands by implementing [`ShellCommand`], e.g. `rgb mode 3` or `layer show`.
+//! A line replies the command's output, then `ok` or `error: <reason>`. `help` lists the commands.
+
+use core::fmt::Write as _;
+use core::str::FromStr;
+use embedded_io_async::{Read, Write};
+use heapless::{String, Vec};
+
+use crate::log::LogModule;
+use crate::log_info;
+
+
+/// Longest line accepted, longer ones are discarded
+const MAX_LINE: usize = 64;
+/// Most words in a line, the command name included
+const MAX_WORDS: usize = 8;
+
+/// Output of a command, written before its `ok`
+pub type ShellReply = String<128>;
+
+/// Commands of a subsystem, all starting with its name
+pub trait ShellCommand {
+    /// First word of the commands, e.g. `rgb`
+    fn name(&self) -> &'static str;
+    /// One line usage printed by `help`, e.g. `rgb show | rgb mode <n>`
+    fn usage(&self) -> &'static str;
+    /// Run the command with the words after the name
+    fn run(&self, args: &[&str], reply: &mut ShellReply) -> Result<(), &'static str>;
+}
+
+/// Parse an argument as a number or the like, for the commands
+pub fn parse_arg<T: FromStr>(arg: Option<&&str>) -> Result<T, &'static str> {
+    arg.ok_or("missing argument")?.parse().map_err(|_| "invalid argument")
+}
+
+/// Parse `on` or `off`, for the commands
+pub fn parse_switch(arg: Option<&&str>) -> Result<bool, &'static str> {
+    match arg.copied() {
+        Some("on") => Ok(true),
+        Some("off") => Ok(false),
+        Some(_) => Err("expected on or off"),
+        None => Err("missing argument"),
+    }
+}
+
+fn run_line(line: &str, commands: &[&dyn ShellCommand], reply: &mut ShellReply) -> Result<(), &'static str> {
+    let mut words: Vec<&str, MAX_WORDS> = Vec::new();
+    for word in line.split_ascii_whitespace() {
+        words.push(word).map_err(|_| "too many arguments")?;
+    }
+    let Some((name, args)) = words.split_first() else {
+        return Ok(());
+    };
+    if *name == "help" {
+        for command in commands {
+            let _ = writeln!(reply, "{}", command.usage());
+        }
+        return Ok(());
+    }
+    let command = commands.iter().find(|c| c.name() == *name).ok_or("unknown command")?;
+    log_info!(LogModule::Device, "Shell: {}", line);
+    command.run(args, reply)
+}
+
+/// Serve the commands on the serial port. This function should never return.
+pub async fn run_shell<S: Read + Write>(mut serial: S, commands: &[&dyn ShellCommand]) -> ! {
+    let mut line: Vec<u8, MAX_LINE> = Vec::new();
+    let mut overflowed = false;
+    loop {
+        let mut byte = [0u8; 1];
+        if serial.read(&mut byte).await.unwrap_or(0) == 0 {
+            continue;
+        }
+        match byte[0] {
+            b'\r' | b'\n' => {
+                if line.is_empty() && !overflowed {
+                    continue;
+                }
+                let mut reply = ShellReply::new();
+                let result = match core::str::from_utf8(&line) {
+                    _ if overflowed => Err("line too long"),
+                    Ok(text) => run_line(text, commands, &mut reply),
+                    Err(_) => Err("invalid utf-8"),
+                };
+                let _ = serial.write_all(reply.as_bytes()).await;
+                let _ = match result {
+                    Ok(()) => serial.write_all(b"ok\n").await,
+                    Err(e) => {
+                        let _ = serial.write_all(b"error: ").await;
+                        let _ = serial.write_all(e.as_bytes()).await;
+                        serial.write_all(b"\n").await
+                    }
+                };
+                line.clear();
+                overflowed = false;
+            }
+            b => overflowed |= line.push(b).is_err(),
+        }
+    }
+}
@@ -0,0 +1,307 @@
+//! Signed configuration commands with rollback protection, so only authorized tooling can change
+//! the settings on shared machines.
+//!
+//! With the `signed_config` feature, config commands are refused on the plain vendor channel and must be
+//! wrapped as `counter (u32 LE) || command` signed by the ed25519 key baked into the firmware.
+//! The counter must exceed the last accepted one, which is persisted, so old blobs can't be replayed.
+//! Vial writes are covered too, a signed one is handed to rmk by the [raw HID driver](crate::raw_hid::RawHidDriver).
+
+use core::cell::RefCell;
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_sync::signal::Signal;
+
+use crate::log::LogModule;
+use crate::quiesce::quiesce_flash;
+use crate::raw_hid::{handle_config_command, inject_via_report, is_via_write, RAW_HID_REPORT_SIZE};
+use crate::reserved::{read_reserved, write_reserved_sector};
+use crate::{log_info, log_warn};
+
+
+/// Raw HID command id of the signed config, `[SIGNED_COMMAND, subcommand, ...]`
+pub const SIGNED_COMMAND: u8 = 0xE8;
+
+/// `[SIGNED_COMMAND, SIGNED_PAYLOAD, counter (u32 LE), len, command...]`, `len` up to 25
+pub const SIGNED_PAYLOAD: u8 = 0x00;
+/// `[SIGNED_COMMAND, SIGNED_SIGNATURE, part, 16 bytes]`, 4 parts of the 64 bytes signature
+pub const SIGNED_SIGNATURE: u8 = 0x01;
+/// `[SIGNED_COMMAND, SIGNED_COMMIT]`, verify and apply
+pub const SIGNED_COMMIT: u8 = 0x02;
+/// `[SIGNED_COMMAND, SIGNED_STATUS]`, responds `[SIGNED_COMMAND, status, last counter (u32 LE)]`
+pub const SIGNED_STATUS: u8 = 0x03;
+
+/// What fits a payload packet after its header, Via buffer writes longer than that are split by the host
+const MAX_PAYLOAD: usize = RAW_HID_REPORT_SIZE - 7;
+const COUNTER_MAGIC: u32 = 0x4E47_4953; // "SIGN"
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+#[repr(u8)]
+pub enum SignedStatus {
+    Idle = 0,
+    Pending = 1,
+    Applied = 2,
+    BadSignature = 3,
+    /// The counter isn't above the last accepted one
+    Replayed = 4,
+    /// The payload isn't a known config command
+    Rejected = 5,
+    /// The payload packet's length is over [`MAX_PAYLOAD`], nothing to commit until a valid one
+    TooLong = 6,
+}
+
+/// Payload and signature as they were at the commit, verified and applied as a whole
+#[derive(Clone, Copy)]
+struct SignedCommand {
+    counter: u32,
+    payload: [u8; MAX_PAYLOAD],
+    len: usize,
+    signature: [u8; 64],
+}
+
+struct SignedState {
+    counter: u32,
+    payload: [u8; MAX_PAYLOAD],
+    len: usize,
+    signature: [u8; 64],
+    status: SignedStatus,
+    last_counter: u32,
+    committed: Option<SignedCommand>,
+}
+
+static SIGNED_STATE: Mutex<CriticalSectionRawMutex, RefCell<SignedState>> = Mutex::new(RefCell::new(SignedState {
+    counter: 0,
+    payload: [0; MAX_PAYLOAD],
+    len: 0,
+    signature: [0; 64],
+    status: SignedStatus::Idle,
+    last_counter: 0,
+    committed: None,
+}));
+
+static SIGNED_COMMIT_REQUEST: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Answer a signed config command in place. Returns false if the report isn't one.
+pub fn handle_signed_command(report: &mut [u8]) -> bool {
+    if report.len() < 2 || report[0] != SIGNED_COMMAND {
+        return false;
+    }
+    let (status, last_counter) = SIGNED_STATE.lock(|s| {
+        let mut s = s.borrow_mut();
+        match report[1] {
+            SIGNED_PAYLOAD if report.len() >= 7 => {
+                let len = report[6] as usize;
+                let fits = len <= MAX_PAYLOAD && len <= report.len() - 7;
+                s.counter = u32::from_le_bytes([report[2], report[3], report[4], report[5]]);
+                s.len = if fits { len } else { 0 };
+                if fits {
+                    s.payload[..len].copy_from_slice(&report[7..7 + len]);
+                }
+                if s.status != SignedStatus::Pending {
+                    s.status = if fits { SignedStatus::Idle } else { SignedStatus::TooLong };
+                }
+            }
+            SIGNED_SIGNATURE if report.len() >= 19 && report[2] < 4 => {
+                let part = report[2] as usize;
+                s.signature[part * 16..(part + 1) * 16].copy_from_slice(&report[3..19]);
+            }
+            SIGNED_COMMIT if !matches!(s.status, SignedStatus::Pending | SignedStatus::TooLong) => {
+                // Later payload or signature packets can't change what gets verified
+                s.committed = Some(SignedCommand {
+                    counter: s.counter,
+                    payload: s.payload,
+                    len: s.len,
+                    signature: s.signature,
+                });
+                s.status = SignedStatus::Pending;
+                SIGNED_COMMIT_REQUEST.signal(());
+            }
+            _ => {}
+        }
+        (s.status, s.last_counter)
+    });
+    report[1..].fill(0);
+    report[1] = status as u8;
+    report[2..6].copy_from_slice(&last_counter.to_le_bytes());
+    true
+}
+
+fn verify(public_key: &[u8; 32], counter: u32, payload: &[u8], signature: &[u8; 64]) -> bool {
+    let Ok(public_key) = salty::PublicKey::try_from(public_key) else {
+        return false;
+    };
+    let mut message = [0u8; 4 + MAX_PAYLOAD];
+    message[..4].copy_from_slice(&counter.to_le_bytes());
+    message[4..4 + payload.len()].copy_from_slice(payload);
+    public_key
+        .verify(&message[..4 + payload.len()], &salty::Signature::from(signature))
+        .is_ok()
+}
+
+/// Why the committed command isn't applied, `None` if it's signed and newer than the last one
+fn refusal(public_key: &[u8; 32], last_counter: u32, command: &SignedCommand) -> Option<SignedStatus> {
+    if command.counter <= last_counter {
+        Some(SignedStatus::Replayed)
+    } else if !verify(public_key, command.counter, &command.payload[..command.len], &command.signature) {
+        Some(SignedStatus::BadSignature)
+    } else {
+        None
+    }
+}
+
+
+/// Verify and apply the committed commands, persisting the counter to the reserved sector at `offset`.
+/// Verification takes a while on Cortex-M0+, so it runs here instead of in the raw HID handler.
+/// This function should never return.
+pub async fn run_signed_config<const FLASH_SIZE: usize>(public_key: &'static [u8; 32], offset: u32) -> ! {
+    {
+        let mut bytes = [0u8; 8];
+        let read = quiesce_flash(async { read_reserved::<FLASH_SIZE>(offset, &mut bytes) }).await;
+        if read.is_ok() && bytes[0..4] == COUNTER_MAGIC.to_le_bytes() {
+            let last_counter = u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+            SIGNED_STATE.lock(|s| s.borrow_mut().last_counter = last_counter);
+        }
+    }
+    loop {
+        SIGNED_COMMIT_REQUEST.wait().await;
+        let (committed, last_counter) = SIGNED_STATE.lock(|s| {
+            let mut s = s.borrow_mut();
+            (s.committed.take(), s.last_counter)
+        });
+        let Some(committed) = committed else {
+            continue;
+        };
+        let SignedCommand {
+            counter, payload, len, ..
+        } = committed;
+        let status = if let Some(refused) = refusal(public_key, last_counter, &committed) {
+            refused
+        } else {
+            let mut command = [0u8; RAW_HID_REPORT_SIZE];
+            command[..len].copy_from_slice(&payload[..len]);
+            let applied = if handle_config_command(&mut command) {
+                true
+            } else if is_via_write(&command) {
+                inject_via_report(command).await;
+                true
+            } else {
+                false
+            };
+            if applied {
+                let mut bytes = [0u8; 8];
+                bytes[0..4].copy_from_slice(&COUNTER_MAGIC.to_le_bytes());
+                bytes[4..8].copy_from_slice(&counter.to_le_bytes());
+                if let Err(e) = quiesce_flash(async { write_reserved_sector::<FLASH_SIZE>(offset, &bytes) }).await {
+                    log_warn!(LogModule::Device, "Failed to persist the config counter: {}", e);
+                }
+                SIGNED_STATE.lock(|s| s.borrow_mut().last_counter = counter);
+                SignedStatus::Applied
+            } else {
+                SignedStatus::Rejected
+            }
+        };
+        log_info!(LogModule::Device, "Signed config {}: {}", counter, status);
+        SIGNED_STATE.lock(|s| s.borrow_mut().status = status);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lighting::LIGHTING_SAVE;
+    use crate::test_support::serial;
+
+    const SEED: [u8; 32] = [0x42; 32];
+
+    fn signed(keypair: &salty::Keypair, counter: u32, payload: &[u8]) -> SignedCommand {
+        let mut message = std::vec::Vec::from(counter.to_le_bytes());
+        message.extend_from_slice(payload);
+        let mut command = SignedCommand {
+            counter,
+            payload: [0; MAX_PAYLOAD],
+            len: payload.len(),
+            signature: keypair.sign(&message).to_bytes(),
+        };
+        command.payload[..payload.len()].copy_from_slice(payload);
+        command
+    }
+
+    #[test]
+    fn signed_newer_command_is_accepted() {
+        let keypair = salty::Keypair::from(&SEED);
+        let public_key = keypair.public.to_bytes();
+        let command = signed(&keypair, 5, &[LIGHTING_SAVE]);
+        assert_eq!(refusal(&public_key, 4, &command), None);
+    }
+
+    #[test]
+    fn replayed_command_is_refused() {
+        let keypair = salty::Keypair::from(&SEED);
+        let public_key = keypair.public.to_bytes();
+        let command = signed(&keypair, 5, &[LIGHTING_SAVE]);
+        assert_eq!(refusal(&public_key, 5, &command), Some(SignedStatus::Replayed));
+        assert_eq!(refusal(&public_key, 9, &command), Some(SignedStatus::Replayed));
+    }
+
+    #[test]
+    fn bad_signature_is_refused() {
+        let keypair = salty::Keypair::from(&SEED);
+        let public_key = keypair.public.to_bytes();
+        let mut command = signed(&keypair, 5, &[LIGHTING_SAVE]);
+        command.payload[0] ^= 1;
+        assert_eq!(refusal(&public_key, 0, &command), Some(SignedStatus::BadSignature));
+        // Signed for another counter
+        let mut command = signed(&keypair, 5, &[LIGHTING_SAVE]);
+        command.counter = 6;
+        assert_eq!(refusal(&public_key, 0, &command), Some(SignedStatus::BadSignature));
+        let other = salty::Keypair::from(&[0x24; 32]);
+        let command = signed(&other, 5, &[LIGHTING_SAVE]);
+        assert_eq!(refusal(&public_key, 0, &command), Some(SignedStatus::BadSignature));
+    }
+
+    fn reset() {
+        SIGNED_STATE.lock(|s| {
+            let mut s = s.borrow_mut();
+            s.len = 0;
+            s.status = SignedStatus::Idle;
+            s.committed = None;
+        });
+        SIGNED_COMMIT_REQUEST.reset();
+    }
+
+    /// The status of the response
+    fn command(bytes: &[u8]) -> u8 {
+        let mut report = [0u8; RAW_HID_REPORT_SIZE];
+        report[..bytes.len()].copy_from_slice(bytes);
+        assert!(handle_signed_command(&mut report));
+        report[1]
+    }
+
+    #[test]
+    fn commit_takes_the_payload_as_it_was() {
+        let _serial = serial();
+        reset();
+        assert_eq!(command(&[SIGNED_COMMAND, SIGNED_PAYLOAD, 7, 0, 0, 0, 2, 0xAA, 0xBB]), SignedStatus::Idle as u8);
+        assert_eq!(command(&[SIGNED_COMMAND, SIGNED_SIGNATURE, 3, 0x11]), SignedStatus::Idle as u8);
+        assert_eq!(command(&[SIGNED_COMMAND, SIGNED_COMMIT]), SignedStatus::Pending as u8);
+        // Too late to change what gets verified
+        command(&[SIGNED_COMMAND, SIGNED_PAYLOAD, 8, 0, 0, 0, 1, 0xCC]);
+        let committed = SIGNED_STATE.lock(|s| s.borrow().committed).unwrap();
+        assert_eq!((committed.counter, &committed.payload[..committed.len]), (7, &[0xAA, 0xBB][..]));
+        assert_eq!(committed.signature[48], 0x11);
+        reset();
+    }
+
+    #[test]
+    fn too_long_payload_is_refused() {
+        let _serial = serial();
+        reset();
+        let status = command(&[SIGNED_COMMAND, SIGNED_PAYLOAD, 7, 0, 0, 0, MAX_PAYLOAD as u8 + 1]);
+        assert_eq!(status, SignedStatus::TooLong as u8);
+        assert_eq!(command(&[SIGNED_COMMAND, SIGNED_COMMIT]), SignedStatus::TooLong as u8);
+        assert!(SIGNED_STATE.lock(|s| s.borrow().committed.is_none()));
+        // The longest one fits
+        let status = command(&[SIGNED_COMMAND, SIGNED_PAYLOAD, 7, 0, 0, 0, MAX_PAYLOAD as u8]);
+        assert_eq!(status, SignedStatus::Idle as u8);
+        reset();
+    }
+}
@@ -0,0 +1,194 @@
+//! Text snippets written over raw HID and typed by [`CustomAction::Snippet`](crate::action::CustomAction::Snippet),
+//! e.g. an email signature or an address. Edits are typed right away, the save command persists them.
+
+use core::cell::RefCell;
+#[cfg(feature = "rp2040")]
+use embassy_rp::flash::{Flash, Instance, Mode};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_sync::signal::Signal;
+use heapless::Vec;
+
+use crate::log::LogModule;
+use crate::log_info;
+use crate::quiesce::quiesce_flash;
+#[cfg(feature = "rp2040")]
+use crate::reserved::write_reserved_sector;
+use crate::typing::type_text;
+
+
+/// Raw HID command id of the snippets, `[SNIPPET_COMMAND, subcommand, ...]`
+pub const SNIPPET_COMMAND: u8 = 0xEF;
+/// `[SNIPPET_COMMAND, SNIPPET_GET, index, offset]`, responds `[.., status, len, bytes from offset...]`
+pub const SNIPPET_GET: u8 = 0;
+/// `[SNIPPET_COMMAND, SNIPPET_WRITE, index, offset, count, bytes...]`, the snippet ends after the written bytes
+pub const SNIPPET_WRITE: u8 = 1;
+/// `[SNIPPET_COMMAND, SNIPPET_SAVE]`, persist the snippets
+pub const SNIPPET_SAVE: u8 = 2;
+
+/// Status of the responses, `[SNIPPET_COMMAND, subcommand, status, ...]`
+const STATUS_OK: u8 = 0;
+const STATUS_OUT_OF_RANGE: u8 = 1;
+
+pub const MAX_SNIPPETS: usize = 8;
+/// Bytes of UTF-8 text per snippet
+pub const SNIPPET_CAPACITY: usize = 255;
+
+/// Bytes of the persisted store, `[magic (u32 LE), length per snippet, text per snippet padded to the capacity]`
+const STORE_SIZE: usize = 4 + MAX_SNIPPETS + MAX_SNIPPETS * SNIPPET_CAPACITY;
+
+type Snippet = Vec<u8, SNIPPET_CAPACITY>;
+
+struct Snippets {
+    texts: [Snippet; MAX_SNIPPETS],
+}
+
+impl Snippets {
+    const MAGIC: u32 = 0x5450_4E53; // "SNPT"
+
+    const fn new() -> Self {
+        const EMPTY: Snippet = Vec::new();
+        Self {
+            texts: [EMPTY; MAX_SNIPPETS],
+        }
+    }
+
+    /// Write the bytes at the offset and drop the rest, returns false if out of the capacity or a gap would be left
+    fn write(&mut self, index: usize, offset: usize, bytes: &[u8]) -> bool {
+        let Some(text) = self.texts.get_mut(index) else {
+            return false;
+        };
+        if offset > text.len() || offset + bytes.len() > SNIPPET_CAPACITY {
+            return false;
+        }
+        text.truncate(offset);
+        text.extend_from_slice(bytes).is_ok()
+    }
+
+    fn to_bytes(&self) -> [u8; STORE_SIZE] {
+        let mut bytes = [0u8; STORE_SIZE];
+        bytes[0..4].copy_from_slice(&Self::MAGIC.to_le_bytes());
+        for (i, text) in self.texts.iter().enumerate() {
+            bytes[4 + i] = text.len() as u8;
+            let start = 4 + MAX_SNIPPETS + i * SNIPPET_CAPACITY;
+            bytes[start..start + text.len()].copy_from_slice(text);
+        }
+        bytes
+    }
+
+    /// Returns `None` if the bytes aren't snippets, e.g. erased
+    fn from_bytes(bytes: &[u8; STORE_SIZE]) -> Option<Self> {
+        if bytes[0..4] != Self::MAGIC.to_le_bytes() {
+            return None;
+        }
+        let mut snippets = Self::new();
+        for (i, text) in snippets.texts.iter_mut().enumerate() {
+            let start = 4 + MAX_SNIPPETS + i * SNIPPET_CAPACITY;
+            let len = (bytes[4 + i] as usize).min(SNIPPET_CAPACITY);
+            let _ = text.extend_from_slice(&bytes[start..start + len]);
+        }
+        Some(snippets)
+    }
+}
+
+/// RAM taken by the texts, for the [RAM budget](crate::ram_budget)
+pub(crate) const SNIPPETS_RAM: usize = core::mem::size_of::<Snippets>();
+
+static SNIPPETS: Mutex<CriticalSectionRawMutex, RefCell<Snippets>> = Mutex::new(RefCell::new(Snippets::new()));
+static SNIPPETS_SAVE_REQUEST: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Type the snippet on the host layout, an invalid UTF-8 tail is skipped
+pub async fn type_snippet(index: u8) {
+    let Some(text) = SNIPPETS.lock(|s| s.borrow().texts.get(index as usize).cloned()) else {
+        defmt::warn!("No snippet {}", index);
+        return;
+    };
+    let valid = match core::str::from_utf8(&text) {
+        Ok(valid) => valid,
+        Err(e) => core::str::from_utf8(&text[..e.valid_up_to()]).unwrap_or_default(),
+    };
+    type_text(valid).await;
+}
+
+/// Read the snippets saved by the previous boot. Call it before handing the flash to rmk.
+#[cfg(feature = "rp2040")]
+pub fn load_snippets<T: Instance, M: Mode, const FLASH_SIZE: usize>(
+    flash: &mut Flash<'_, T, M, FLASH_SIZE>,
+    offset: u32,
+) -> bool {
+    let mut bytes = [0u8; STORE_SIZE];
+    if flash.blocking_read(offset, &mut bytes).is_err() {
+        return false;
+    }
+    match Snippets::from_bytes(&bytes) {
+        Some(snippets) => {
+            SNIPPETS.lock(|s| *s.borrow_mut() = snippets);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Save the snippets to the reserved sector at `offset`, the argument of [`run_snippets_save`]
+#[cfg(feature = "rp2040")]
+pub fn save_snippets<const FLASH_SIZE: usize>(offset: u32) {
+    let bytes = SNIPPETS.lock(|s| s.borrow().to_bytes());
+    if let Err(e) = write_reserved_sector::<FLASH_SIZE>(offset, &bytes) {
+        defmt::warn!("Failed to save snippets: {}", e);
+    }
+}
+
+/// Answer a snippet command in place.
+/// Returns false if the report isn't a snippet command.
+pub fn handle_snippet_command(report: &mut [u8]) -> bool {
+    if report.len() < 6 || report[0] != SNIPPET_COMMAND {
+        return false;
+    }
+    let (index, offset) = (report[2] as usize, report[3] as usize);
+    let status = match report[1] {
+        SNIPPET_GET => {
+            let body_len = report.len() - 4;
+            let (status, len) = SNIPPETS.lock(|s| match s.borrow().texts.get(index) {
+                Some(text) => {
+                    let page = text.get(offset..).unwrap_or_default();
+                    let count = page.len().min(body_len);
+                    report[4..].fill(0);
+                    report[4..4 + count].copy_from_slice(&page[..count]);
+                    (STATUS_OK, text.len() as u8)
+                }
+                None => {
+                    report[4..].fill(0);
+                    (STATUS_OUT_OF_RANGE, 0)
+                }
+            });
+            report[2] = status;
+            report[3] = len;
+            return true;
+        }
+        SNIPPET_WRITE => {
+            let count = (report[4] as usize).min(report.len() - 5);
+            let written = SNIPPETS.lock(|s| s.borrow_mut().write(index, offset, &report[5..5 + count]));
+            if written {
+                STATUS_OK
+            } else {
+                STATUS_OUT_OF_RANGE
+            }
+        }
+        SNIPPET_SAVE => {
+            SNIPPETS_SAVE_REQUEST.signal(());
+            STATUS_OK
+        }
+        _ => return false,
+    };
+    report[2..].fill(0);
+    report[2] = status;
+    true
+}
+
+/// Wait for the save command and call `on_save`, e.g. with [`save_snippets`]. This function should never return.
+pub async fn run_snippets_save<F: FnMut()>(mut on_save: F) -> ! {
+    loop {
+        SNIPPETS_SAVE_REQUEST.wait().await;
+        log_info!(LogModule::Device, "Snippets saved");
+        quiesce_flash(async { on_save() }).await;
+    }
+}
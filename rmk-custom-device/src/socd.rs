@@ -0,0 +1,220 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+use rmk::event::KeyEvent;
+
+use crate::event::{HookContext, KeyEventHook};
+use crate::layer_state::active_layer;
+
+
+/// How to resolve both keys of a pair held at once
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum SocdMode {
+    /// The latest pressed key wins, a.k.a. snap tap
+    LastInputPriority,
+    /// Neither key is reported
+    Neutral,
+    /// The key pressed first stays, the other one is ignored
+    FirstInputPriority,
+}
+
+/// Pair of opposite keys, e.g. A and D of WASD
+#[derive(Clone, Copy, Debug, defmt::Format)]
+pub struct SocdPair {
+    /// (row, col) of each key
+    pub keys: [(u8, u8); 2],
+    pub mode: SocdMode,
+    /// The layer the keys are opposite on, every layer if `None`
+    pub layer: Option<u8>,
+}
+
+impl SocdPair {
+    pub const fn new(first: (u8, u8), second: (u8, u8), mode: SocdMode) -> Self {
+        Self {
+            keys: [first, second],
+            mode,
+            layer: None,
+        }
+    }
+
+    /// Only clean the keys pressed while the layer is the [active one](active_layer), e.g. to leave
+    /// the layer keys at the same positions alone
+    pub const fn on_layer(self, layer: u8) -> Self {
+        Self {
+            layer: Some(layer),
+            ..self
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct SocdPairState {
+    /// Physical state of the keys
+    pressed: [bool; 2],
+    /// State reported to rmk
+    reported: [bool; 2],
+    /// Index of the key pressed last
+    last: usize,
+}
+
+static SOCD_ENABLED: AtomicBool = AtomicBool::new(true);
+
+pub fn set_socd_enabled(enabled: bool) {
+    SOCD_ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn toggle_socd() {
+    set_socd_enabled(!SOCD_ENABLED.load(Ordering::Relaxed));
+}
+
+
+/// Hook cleaning simultaneous opposite cardinal direction inputs.
+/// It may emit the release of the other key of the pair, ahead of the event itself.
+pub struct SocdHook<const N: usize> {
+    pairs: [SocdPair; N],
+    states: [SocdPairState; N],
+}
+
+impl<const N: usize> SocdHook<N> {
+    pub fn new(pairs: [SocdPair; N]) -> Self {
+        Self {
+            pairs,
+            states: [SocdPairState::default(); N],
+        }
+    }
+
+    fn resolve(mode: SocdMode, state: &SocdPairState) -> [bool; 2] {
+        match state.pressed {
+            [true, true] => match mode {
+                SocdMode::LastInputPriority => [state.last == 0, state.last == 1],
+                SocdMode::Neutral => [false, false],
+                SocdMode::FirstInputPriority => state.reported,
+            },
+            pressed => pressed,
+        }
+    }
+}
+
+impl<const N: usize> KeyEventHook for SocdHook<N> {
+    async fn process(&mut self, event: KeyEvent, ctx: &mut HookContext) -> Option<KeyEvent> {
+        let position = (event.row, event.col);
+        let Some((pair, state)) = self
+            .pairs
+            .iter()
+            .zip(self.states.iter_mut())
+            .find(|(pair, _)| pair.keys.contains(&position))
+        else {
+            return Some(event);
+        };
+        let index = if pair.keys[0] == position { 0 } else { 1 };
+        // Pressed off the pair's layer, the key goes its way until released
+        let off_layer = pair.layer.is_some_and(|layer| layer != active_layer());
+        if (event.pressed && off_layer) || (!event.pressed && !state.pressed[index]) {
+            return Some(event);
+        }
+        state.pressed[index] = event.pressed;
+        if event.pressed {
+            state.last = index;
+        }
+
+        let resolved = if SOCD_ENABLED.load(Ordering::Relaxed) {
+            Self::resolve(pair.mode, state)
+        } else {
+            state.pressed
+        };
+        let other = 1 - index;
+        let other_event = (resolved[other] != state.reported[other]).then(|| KeyEvent {
+            row: pair.keys[other].0,
+            col: pair.keys[other].1,
+            pressed: resolved[other],
+        });
+        let this_event = (resolved[index] != state.reported[index]).then_some(event);
+        state.reported = resolved;
+
+        match (this_event, other_event) {
+            // Release goes first, so that both keys are never reported at once
+            (Some(this_event), Some(other_event)) if this_event.pressed => {
+                ctx.emit(other_event);
+                Some(this_event)
+            }
+            (Some(this_event), Some(other_event)) => {
+                ctx.emit(this_event);
+                Some(other_event)
+            }
+            (this_event, other_event) => this_event.or(other_event),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+
+    use super::*;
+    use crate::event::{process_key_event, ChannelSink};
+    use crate::test_support::serial;
+
+    const A: (u8, u8) = (1, 0);
+    const D: (u8, u8) = (1, 2);
+
+    /// (row, col, pressed) of the events reaching the sink for the event
+    fn run(hook: &mut SocdHook<1>, (row, col): (u8, u8), pressed: bool) -> Vec<(u8, u8, bool)> {
+        let channel: Channel<CriticalSectionRawMutex, KeyEvent, 8> = Channel::new();
+        let event = KeyEvent { row, col, pressed };
+        embassy_futures::block_on(process_key_event(hook, &mut ChannelSink::new(&channel), event));
+        core::iter::from_fn(|| channel.try_receive().ok())
+            .map(|e| (e.row, e.col, e.pressed))
+            .collect()
+    }
+
+    #[test]
+    fn last_input_priority() {
+        let _serial = serial();
+        set_socd_enabled(true);
+        let mut hook = SocdHook::new([SocdPair::new(A, D, SocdMode::LastInputPriority)]);
+        assert_eq!(run(&mut hook, A, true), [(1, 0, true)]);
+        assert_eq!(run(&mut hook, D, true), [(1, 0, false), (1, 2, true)]);
+        assert_eq!(run(&mut hook, D, false), [(1, 2, false), (1, 0, true)]);
+        assert_eq!(run(&mut hook, A, false), [(1, 0, false)]);
+    }
+
+    #[test]
+    fn neutral() {
+        let _serial = serial();
+        set_socd_enabled(true);
+        let mut hook = SocdHook::new([SocdPair::new(A, D, SocdMode::Neutral)]);
+        assert_eq!(run(&mut hook, A, true), [(1, 0, true)]);
+        assert_eq!(run(&mut hook, D, true), [(1, 0, false)]);
+        assert_eq!(run(&mut hook, A, false), [(1, 2, true)]);
+    }
+
+    #[test]
+    fn first_input_priority() {
+        let _serial = serial();
+        set_socd_enabled(true);
+        let mut hook = SocdHook::new([SocdPair::new(A, D, SocdMode::FirstInputPriority)]);
+        assert_eq!(run(&mut hook, A, true), [(1, 0, true)]);
+        assert!(run(&mut hook, D, true).is_empty());
+        assert_eq!(run(&mut hook, A, false), [(1, 0, false), (1, 2, true)]);
+    }
+
+    #[test]
+    fn other_keys_pass() {
+        let _serial = serial();
+        let mut hook = SocdHook::new([SocdPair::new(A, D, SocdMode::Neutral)]);
+        assert_eq!(run(&mut hook, (0, 0), true), [(0, 0, true)]);
+    }
+
+    #[test]
+    fn off_layer_keys_pass() {
+        let _serial = serial();
+        set_socd_enabled(true);
+        crate::layer_state::set_active_layer(0);
+        let mut hook = SocdHook::new([SocdPair::new(A, D, SocdMode::Neutral).on_layer(0)]);
+        assert_eq!(run(&mut hook, A, true), [(1, 0, true)]);
+        crate::layer_state::set_active_layer(1);
+        assert_eq!(run(&mut hook, D, true), [(1, 2, true)]);
+        crate::layer_state::set_active_layer(0);
+        assert_eq!(run(&mut hook, D, false), [(1, 2, false)]);
+        assert_eq!(run(&mut hook, A, false), [(1, 0, false)]);
+    }
+}
@@ -0,0 +1,79 @@
+//! Soft off emulating a power switch for battery builds lacking one.
+//! Scanning stops and the lighting blanks, then the MCU sleeps as deep as it can until the wake key is pressed.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Duration, Timer};
+
+use crate::log::LogModule;
+use crate::log_info;
+use crate::quiesce::quiesce;
+
+
+/// Time for the renderers to blank and the soft off key to be released before sleeping
+const SOFT_OFF_SETTLE: Duration = Duration::from_millis(200);
+
+static SOFT_OFF: AtomicBool = AtomicBool::new(false);
+static SOFT_OFF_REQUEST: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Whether the keyboard is going off, the lighting and displays stay dark while set
+pub fn is_soft_off() -> bool {
+    SOFT_OFF.load(Ordering::Relaxed)
+}
+
+pub fn request_soft_off() {
+    SOFT_OFF_REQUEST.signal(());
+}
+
+/// Deepest sleep of the platform
+#[allow(async_fn_in_trait)]
+pub trait SoftOffSleep {
+    /// Sleep until the wake key is pressed.
+    /// Platforms losing their state in the sleep reset on wake instead of returning.
+    async fn sleep_until_wake(&mut self);
+}
+
+/// Wait for soft off requests and sleep with the matrix parked. This function should never return.
+pub async fn run_soft_off<S: SoftOffSleep>(mut sleep: S) -> ! {
+    loop {
+        SOFT_OFF_REQUEST.wait().await;
+        log_info!(LogModule::Device, "Soft off");
+        SOFT_OFF.store(true, Ordering::Relaxed);
+        Timer::after(SOFT_OFF_SETTLE).await;
+        quiesce(sleep.sleep_until_wake()).await;
+        SOFT_OFF.store(false, Ordering::Relaxed);
+        log_info!(LogModule::Device, "Woke up from soft off");
+    }
+}
+
+
+/// RP2040 dormant mode, waking on a low level of the wake pin.
+/// The clocks run from the XOSC while dormant, the chip resets by the watchdog on wake.
+#[cfg(feature = "rp2040")]
+pub struct Rp2040SoftOff<'d> {
+    wake: embassy_rp::gpio::Input<'d>,
+    pin: usize,
+}
+
+#[cfg(feature = "rp2040")]
+impl<'d> Rp2040SoftOff<'d> {
+    /// `pin` is the GPIO number of `wake`, active low with a pull-up
+    pub fn new(wake: embassy_rp::gpio::Input<'d>, pin: usize) -> Self {
+        Self { wake, pin }
+    }
+}
+
+#[cfg(feature = "rp2040")]
+impl SoftOffSleep for Rp2040SoftOff<'_> {
+    async fn sleep_until_wake(&mut self) {
+        // The wake key may be the soft off key itself
+        self.wake.wait_for_high().await;
+        critical_section::with(|_| {
+            // SAFETY: in a critical section, and the clocks aren't used again before the reset
+            unsafe { crate::dormant::enter_dormant(&[self.pin], false) };
+            // Nothing is configured for the clocks anymore, start over from the reset
+            embassy_rp::pac::WATCHDOG.ctrl().write(|w| w.set_trigger(true));
+            loop {}
+        })
+    }
+}
@@ -0,0 +1,269 @@
+//! Detection of the other half on the split UART link, the link's traffic, error and latency counters,
+//! and the attention line waking the dormant central for the peripheral's messages.
+
+use core::fmt::Write as _;
+use embassy_time::{Duration, Instant, Ticker, Timer};
+use embedded_hal::digital::{InputPin, OutputPin};
+use embedded_io_async::{ErrorType, Read, Write};
+use heapless::String;
+use portable_atomic::{AtomicU32, AtomicU64, Ordering};
+
+use crate::log::LogModule;
+use crate::log_info;
+use crate::oled::{draw_text, OledAnimation, OledFrame};
+
+
+/// Wait for the other half's UART TX to idle high on `rx`, pulled down, within `timeout`.
+/// Returns false if it doesn't, e.g. the cable is unplugged. Call it before handing the pin to the UART.
+pub async fn wait_for_split_peer<P: InputPin>(mut rx: P, timeout: Duration) -> bool {
+    let start = Instant::now();
+    while start.elapsed() < timeout {
+        if rx.is_high().unwrap_or(false) {
+            return true;
+        }
+        Timer::after_millis(1).await;
+    }
+    false
+}
+
+
+/// Idle time of the link after which the central may be dormant, needing the attention line before a message
+const ATTENTION_IDLE: Duration = Duration::from_millis(100);
+/// Time for the central's XOSC and PLLs to come back before the message, the UART loses bytes until then
+const ATTENTION_LEAD: Duration = Duration::from_millis(2);
+
+/// Peripheral's split link raising a dedicated attention GPIO, wired to one of the central's
+/// [dormant wake pins](crate::dormant::run_dormant_sleep), before sending after the link idled.
+/// The line stays high while the message is written and idles low, pull it down on the central.
+pub struct AttentionLink<S, P: OutputPin> {
+    link: S,
+    attention: P,
+    last_write: Option<Instant>,
+}
+
+impl<S, P: OutputPin> AttentionLink<S, P> {
+    pub fn new(link: S, mut attention: P) -> Self {
+        let _ = attention.set_low();
+        Self {
+            link,
+            attention,
+            last_write: None,
+        }
+    }
+}
+
+impl<S: ErrorType, P: OutputPin> ErrorType for AttentionLink<S, P> {
+    type Error = S::Error;
+}
+
+impl<S: Read, P: OutputPin> Read for AttentionLink<S, P> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        self.link.read(buf).await
+    }
+}
+
+impl<S: Write, P: OutputPin> Write for AttentionLink<S, P> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        let _ = self.attention.set_high();
+        if self.last_write.map_or(true, |at| at.elapsed() >= ATTENTION_IDLE) {
+            Timer::after(ATTENTION_LEAD).await;
+        }
+        let result = self.link.write(buf).await;
+        self.last_write = Some(Instant::now());
+        let _ = self.attention.set_low();
+        result
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        self.link.flush().await
+    }
+}
+
+
+/// Counters of the split UART link, for diagnosing flaky cables
+pub struct SplitLinkStats {
+    pub rx_bytes: AtomicU32,
+    pub tx_bytes: AtomicU32,
+    /// Failed reads, e.g. framing, parity or overrun errors of the UART
+    pub read_errors: AtomicU32,
+    pub write_errors: AtomicU32,
+    /// Frames of the [split transport](crate::split_transport) sent again for a missing ack
+    pub retransmits: AtomicU32,
+    /// Received frames dropped for a bad CRC or framing
+    pub crc_errors: AtomicU32,
+    /// Ticks of the last received byte, 0 before the first one
+    last_rx: AtomicU64,
+    /// Smoothed round trip from a frame to its ack in microseconds, `u32::MAX` before the first one
+    rtt_us: AtomicU32,
+}
+
+impl SplitLinkStats {
+    pub const fn new() -> Self {
+        Self {
+            rx_bytes: AtomicU32::new(0),
+            tx_bytes: AtomicU32::new(0),
+            read_errors: AtomicU32::new(0),
+            write_errors: AtomicU32::new(0),
+            retransmits: AtomicU32::new(0),
+            crc_errors: AtomicU32::new(0),
+            last_rx: AtomicU64::new(0),
+            rtt_us: AtomicU32::new(u32::MAX),
+        }
+    }
+
+    /// Round trip to the other half, `None` before the first acked frame
+    pub fn rtt(&self) -> Option<Duration> {
+        match self.rtt_us.load(Ordering::Relaxed) {
+            u32::MAX => None,
+            us => Some(Duration::from_micros(us as u64)),
+        }
+    }
+
+    /// Fold a round trip into the smoothed one, by 1/8 like TCP's SRTT so a single slow ack doesn't dominate
+    pub(crate) fn record_rtt(&self, sample: Duration) {
+        let sample = sample.as_micros().min(u32::MAX as u64 - 1) as u32;
+        let rtt = match self.rtt_us.load(Ordering::Relaxed) {
+            u32::MAX => sample,
+            rtt => ((rtt as u64 * 7 + sample as u64) / 8) as u32,
+        };
+        self.rtt_us.store(rtt, Ordering::Relaxed);
+    }
+
+    /// Time since the other half sent anything, `None` if it never did
+    pub fn silence(&self) -> Option<Duration> {
+        match self.last_rx.load(Ordering::Relaxed) {
+            0 => None,
+            ticks => Some(Instant::now().duration_since(Instant::from_ticks(ticks))),
+        }
+    }
+
+    /// `[rx_bytes, tx_bytes, read_errors, write_errors, silence_ms, retransmits, crc_errors, rtt_us (u32 LE each)]`,
+    /// silence and rtt are `u32::MAX` before the first byte and ack
+    pub fn to_bytes(&self) -> [u8; 32] {
+        let silence = self.silence().map_or(u32::MAX, |s| s.as_millis().min(u32::MAX as u64) as u32);
+        let fields = [
+            self.rx_bytes.load(Ordering::Relaxed),
+            self.tx_bytes.load(Ordering::Relaxed),
+            self.read_errors.load(Ordering::Relaxed),
+            self.write_errors.load(Ordering::Relaxed),
+            silence,
+            self.retransmits.load(Ordering::Relaxed),
+            self.crc_errors.load(Ordering::Relaxed),
+            self.rtt_us.load(Ordering::Relaxed),
+        ];
+        let mut bytes = [0u8; 32];
+        for (chunk, field) in bytes.chunks_exact_mut(4).zip(fields) {
+            chunk.copy_from_slice(&field.to_le_bytes());
+        }
+        bytes
+    }
+}
+
+/// Stats of the link wrapped by [`MonitoredLink`]
+pub static SPLIT_LINK_STATS: SplitLinkStats = SplitLinkStats::new();
+
+/// Split link counting its traffic and errors into [`SPLIT_LINK_STATS`], wrapping each UART half
+/// handed to the [split transport](crate::split_transport::run_split_transport).
+pub struct MonitoredLink<S> {
+    link: S,
+}
+
+impl<S> MonitoredLink<S> {
+    pub fn new(link: S) -> Self {
+        Self { link }
+    }
+}
+
+impl<S: ErrorType> ErrorType for MonitoredLink<S> {
+    type Error = S::Error;
+}
+
+impl<S: Read> Read for MonitoredLink<S> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let result = self.link.read(buf).await;
+        match result {
+            Ok(n) => {
+                SPLIT_LINK_STATS.rx_bytes.fetch_add(n as u32, Ordering::Relaxed);
+                SPLIT_LINK_STATS.last_rx.store(Instant::now().as_ticks().max(1), Ordering::Relaxed);
+            }
+            Err(_) => {
+                SPLIT_LINK_STATS.read_errors.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        result
+    }
+}
+
+impl<S: Write> Write for MonitoredLink<S> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        let result = self.link.write(buf).await;
+        match result {
+            Ok(n) => {
+                SPLIT_LINK_STATS.tx_bytes.fetch_add(n as u32, Ordering::Relaxed);
+            }
+            Err(_) => {
+                SPLIT_LINK_STATS.write_errors.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        result
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        self.link.flush().await
+    }
+}
+
+
+/// Status page of the link quality, `RTT 1.2MS` over `RETX 3 CRC 0`, for the OLED while diagnosing a cable
+pub struct SplitLinkPage;
+
+impl OledAnimation for SplitLinkPage {
+    fn draw(&mut self, frame: &mut OledFrame, _now: Instant) {
+        let mut line: String<32> = String::new();
+        match SPLIT_LINK_STATS.rtt() {
+            Some(rtt) => {
+                let us = rtt.as_micros();
+                let _ = write!(line, "RTT {}.{}MS", us / 1000, us % 1000 / 100);
+            }
+            None => {
+                let _ = write!(line, "RTT -");
+            }
+        }
+        draw_text(frame, &line, 0, 0, 2);
+        line.clear();
+        let _ = write!(
+            line,
+            "RETX {} CRC {}",
+            SPLIT_LINK_STATS.retransmits.load(Ordering::Relaxed),
+            SPLIT_LINK_STATS.crc_errors.load(Ordering::Relaxed)
+        );
+        draw_text(frame, &line, 0, 16, 2);
+    }
+}
+
+
+/// Log the link quality every `interval` in which retransmits or CRC errors were added, so a flaky cable shows in the defmt log.
+/// This function should never return.
+pub async fn run_split_link_log(interval: Duration) -> ! {
+    let mut ticker = Ticker::every(interval);
+    let mut reported = (0, 0);
+    loop {
+        ticker.next().await;
+        let errors = (
+            SPLIT_LINK_STATS.retransmits.load(Ordering::Relaxed),
+            SPLIT_LINK_STATS.crc_errors.load(Ordering::Relaxed),
+        );
+        if errors == reported {
+            continue;
+        }
+        reported = errors;
+        let rtt_us = SPLIT_LINK_STATS.rtt().map(|rtt| rtt.as_micros());
+        log_info!(
+            LogModule::Device,
+            "Split link: {} retransmits, {} CRC errors, RTT {} us",
+            errors.0,
+            errors.1,
+            rtt_us
+        );
+    }
+}
@@ -0,0 +1,270 @@
+//! Ordering of the central's key events against the peripheral's across the split link.
+//!
+//! The peripheral's events reach the central a link latency after their scan, so a shift held on the
+//! peripheral could lose against a letter typed on the central a moment later. Every event the central scans
+//! is held back with a barrier token sent to the peripheral, which answers it behind the events it has queued.
+//! The ordering contract: a peripheral event arriving before the barrier of a central event reaches rmk first,
+//! central events keep their own order. Without the peripheral answering, the held events go after
+//! `timeout` and later ones pass straight through until it answers again.
+//!
+//! Put [`SplitOrderHook`] first in the chain. The held events go back through the matrix's
+//! [input events](crate::event::INPUT_EVENT_CHANNEL) when released, so the matrix stays the only writer
+//! to rmk and the hooks after this one see every event once, in the final order.
+
+use core::cell::RefCell;
+use embassy_futures::select::select;
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    signal::Signal,
+};
+use embassy_time::{Duration, Instant, Timer};
+use heapless::{Deque, Vec};
+use rmk::event::KeyEvent;
+
+use crate::event::{send_input_event, HookContext, KeyEventHook};
+use crate::log::LogModule;
+use crate::{log_info, log_warn};
+use crate::queues::SPLIT_ORDER_QUEUE_DEPTH;
+use crate::split_transport::{try_send_split_message, SplitMessage};
+
+
+struct Held {
+    token: u8,
+    since: Instant,
+    event: KeyEvent,
+}
+
+struct SplitOrder {
+    /// Central events waiting for their barrier
+    held: Deque<Held, SPLIT_ORDER_QUEUE_DEPTH>,
+    /// Released events on their way back through the input events
+    released: Deque<KeyEvent, SPLIT_ORDER_QUEUE_DEPTH>,
+    next_token: u8,
+    /// Latest barrier the peripheral answered
+    answered: Option<u8>,
+    /// The peripheral answers the barriers, false until its first answer and after a timeout
+    peer_present: bool,
+}
+
+static SPLIT_ORDER: Mutex<CriticalSectionRawMutex, RefCell<SplitOrder>> = Mutex::new(RefCell::new(SplitOrder {
+    held: Deque::new(),
+    released: Deque::new(),
+    next_token: 0,
+    answered: None,
+    peer_present: false,
+}));
+
+/// Wakes [`run_split_order`] for a newly held event or an answered barrier
+static SPLIT_ORDER_CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+fn same_event(a: &KeyEvent, b: &KeyEvent) -> bool {
+    a.row == b.row && a.col == b.col && a.pressed == b.pressed
+}
+
+/// Whether the answered barrier comes at or after the token, in the wrapping tokens
+fn covers(answered: u8, token: u8) -> bool {
+    answered.wrapping_sub(token) < 0x80
+}
+
+/// Record the peripheral's answer of a barrier, called by the central's split handler
+pub(crate) fn barrier_answered(token: u8) {
+    SPLIT_ORDER.lock(|s| {
+        let mut s = s.borrow_mut();
+        if !s.peer_present {
+            log_info!(LogModule::Device, "Split peripheral answers, ordering the central's events");
+        }
+        s.answered = Some(token);
+        s.peer_present = true;
+    });
+    SPLIT_ORDER_CHANGED.signal(());
+}
+
+/// Hook holding the central's events until the peripheral's events before them are in, see the [module](self).
+/// The peripheral's `ROW`x`COL` matrix at the offsets of the keymap passes straight through.
+/// It never waits, so the scan isn't stalled by the link.
+pub struct SplitOrderHook<const ROW: usize, const COL: usize, const ROW_OFFSET: usize, const COL_OFFSET: usize>;
+
+impl<const ROW: usize, const COL: usize, const ROW_OFFSET: usize, const COL_OFFSET: usize>
+    SplitOrderHook<ROW, COL, ROW_OFFSET, COL_OFFSET>
+{
+    fn is_peripheral(event: &KeyEvent) -> bool {
+        (ROW_OFFSET..ROW_OFFSET + ROW).contains(&(event.row as usize))
+            && (COL_OFFSET..COL_OFFSET + COL).contains(&(event.col as usize))
+    }
+}
+
+impl<const ROW: usize, const COL: usize, const ROW_OFFSET: usize, const COL_OFFSET: usize> KeyEventHook
+    for SplitOrderHook<ROW, COL, ROW_OFFSET, COL_OFFSET>
+{
+    async fn process(&mut self, event: KeyEvent, _ctx: &mut HookContext) -> Option<KeyEvent> {
+        let token = SPLIT_ORDER.lock(|s| {
+            let mut s = s.borrow_mut();
+            if s.released.front().is_some_and(|e| same_event(e, &event)) {
+                s.released.pop_front();
+                return None;
+            }
+            if Self::is_peripheral(&event) {
+                return None;
+            }
+            let token = s.next_token;
+            s.next_token = token.wrapping_add(1);
+            // Nothing ahead of it, so it only waits for a peripheral known to answer
+            let in_order = s.held.is_empty() && s.released.is_empty();
+            if in_order && !s.peer_present {
+                return Some((token, Some(event)));
+            }
+            // Released events stay counted until they're back, so that both queues always fit
+            if s.held.len() + s.released.len() >= SPLIT_ORDER_QUEUE_DEPTH {
+                log_warn!(LogModule::Device, "Split order queue full, {} passes unordered", event);
+                return Some((token, Some(event)));
+            }
+            let _ = s.held.push_back(Held {
+                token,
+                since: Instant::now(),
+                event,
+            });
+            Some((token, None))
+        });
+        let Some((token, passing)) = token else {
+            return Some(event);
+        };
+        // Sent even for a passing event, the answer tells the peripheral is back
+        let _ = try_send_split_message(SplitMessage::BarrierRequest(token));
+        SPLIT_ORDER_CHANGED.signal(());
+        passing
+    }
+}
+
+
+/// Take the held events whose barrier the peripheral answered, or held for `timeout` without an answer,
+/// counting them as released
+fn take_released(timeout: Duration) -> Vec<KeyEvent, SPLIT_ORDER_QUEUE_DEPTH> {
+    let now = Instant::now();
+    SPLIT_ORDER.lock(|s| {
+        let mut s = s.borrow_mut();
+        let mut released = Vec::new();
+        while let Some(front) = s.held.front() {
+            let answered = s.answered.is_some_and(|answered| covers(answered, front.token));
+            let expired = now >= front.since + timeout;
+            // The ones held behind a timed out event don't wait for the absent peripheral again
+            if !answered && !expired && s.peer_present {
+                break;
+            }
+            if !answered && s.peer_present {
+                log_warn!(LogModule::Device, "Split peripheral didn't answer, passing the central's events unordered");
+                s.peer_present = false;
+            }
+            let Some(held) = s.held.pop_front() else {
+                break;
+            };
+            let _ = s.released.push_back(held.event);
+            let _ = released.push(held.event);
+        }
+        released
+    })
+}
+
+/// Release the held events once the peripheral answers their barrier, or `timeout` after they were held if it doesn't.
+/// They go back through the matrix and [`SplitOrderHook`]. This function should never return.
+pub async fn run_split_order(timeout: Duration) -> ! {
+    loop {
+        let deadline = SPLIT_ORDER.lock(|s| s.borrow().held.front().map(|h| h.since + timeout));
+        match deadline {
+            Some(deadline) => {
+                select(SPLIT_ORDER_CHANGED.wait(), Timer::at(deadline)).await;
+            }
+            None => SPLIT_ORDER_CHANGED.wait().await,
+        }
+        for event in take_released(timeout) {
+            send_input_event(event).await;
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::serial;
+
+    const WAIT: Duration = Duration::from_secs(60);
+
+    fn reset() {
+        SPLIT_ORDER.lock(|s| {
+            let mut s = s.borrow_mut();
+            s.held.clear();
+            s.released.clear();
+            s.next_token = 0;
+            s.answered = None;
+            s.peer_present = false;
+        });
+    }
+
+    fn key(row: u8, col: u8, pressed: bool) -> KeyEvent {
+        KeyEvent { row, col, pressed }
+    }
+
+    /// Through the hook with the peripheral's 2x2 right of the central's
+    fn run(event: KeyEvent) -> Option<(u8, u8, bool)> {
+        let mut hook = SplitOrderHook::<2, 2, 0, 2>;
+        embassy_futures::block_on(hook.process(event, &mut HookContext::new())).map(|e| (e.row, e.col, e.pressed))
+    }
+
+    fn released(timeout: Duration) -> std::vec::Vec<(u8, u8, bool)> {
+        take_released(timeout).iter().map(|e| (e.row, e.col, e.pressed)).collect()
+    }
+
+    #[test]
+    fn token_coverage_wraps() {
+        assert!(covers(5, 5));
+        assert!(covers(6, 5));
+        assert!(!covers(4, 5));
+        assert!(covers(2, 250));
+        assert!(!covers(250, 2));
+    }
+
+    #[test]
+    fn events_pass_without_a_peripheral() {
+        let _serial = serial();
+        reset();
+        assert_eq!(run(key(0, 2, true)), Some((0, 2, true)));
+        assert_eq!(run(key(0, 0, true)), Some((0, 0, true)));
+        assert!(released(WAIT).is_empty());
+    }
+
+    #[test]
+    fn central_event_waits_for_its_barrier() {
+        let _serial = serial();
+        reset();
+        barrier_answered(255);
+        assert_eq!(run(key(0, 0, true)), None);
+        assert_eq!(run(key(1, 1, true)), None);
+        // The peripheral's events in the meantime go first
+        assert_eq!(run(key(1, 3, true)), Some((1, 3, true)));
+        assert!(released(WAIT).is_empty());
+
+        barrier_answered(0);
+        assert_eq!(released(WAIT), [(0, 0, true)]);
+        // Back through the matrix, it passes once
+        assert_eq!(run(key(0, 0, true)), Some((0, 0, true)));
+        barrier_answered(1);
+        assert_eq!(released(WAIT), [(1, 1, true)]);
+        assert_eq!(run(key(1, 1, true)), Some((1, 1, true)));
+        assert!(SPLIT_ORDER.lock(|s| s.borrow().released.is_empty()));
+    }
+
+    #[test]
+    fn unanswered_barriers_time_out() {
+        let _serial = serial();
+        reset();
+        barrier_answered(255);
+        assert_eq!(run(key(0, 0, true)), None);
+        assert_eq!(run(key(0, 0, false)), None);
+        assert_eq!(released(Duration::from_ticks(0)), [(0, 0, true), (0, 0, false)]);
+        assert!(!SPLIT_ORDER.lock(|s| s.borrow().peer_present));
+        assert_eq!(run(key(0, 0, true)), Some((0, 0, true)));
+        assert_eq!(run(key(0, 0, false)), Some((0, 0, false)));
+        // Passing straight through again
+        assert_eq!(run(key(0, 1, true)), Some((0, 1, true)));
+    }
+}
@@ -0,0 +1,1053 @@
+//! Framed transport between the split halves over the UART, in place of rmk's serial split messages.
+//!
+//! A frame is `[epoch (LE), peer epoch (LE), seq, ack, kind, payload..., crc16 (LE)]`, COBS encoded and ended by a zero byte, so a corrupted
+//! frame costs only itself and the receiver picks up again at the next one. Data frames stay queued until the
+//! other half acks their sequence number, and go again in order when the ack doesn't come within
+//! [`RETRANSMIT_TIMEOUT`], so a flipped bit on a flaky cable delays a key instead of losing or reordering it.
+//! The retransmits, the CRC failures and the round trip to the ack are counted into
+//! [`SPLIT_LINK_STATS`](crate::split_link::SPLIT_LINK_STATS).
+//!
+//! Either half may reboot on its own. Each half picks an epoch at boot and puts it in every frame, along with
+//! the epoch of the other half it has seen. On a new epoch of the other half both streams start over from
+//! sequence 0, the unacked frames renumbered, and the handler releases what the old stream held. Frames and acks
+//! meant for an earlier boot of the receiver are dropped, so that a rebooted half never takes the old stream's
+//! ack for its new frames.
+//!
+//! Key events queued while the link is busy, e.g. during a fast roll or while the window waits for its acks,
+//! share one frame along with their time after the first of them, and the central plays them back at that spacing.
+//! A lone event goes right away in a frame of its own, batching never holds one back.
+
+use embassy_futures::select::{select3, Either3};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+use embassy_time::{Duration, Instant, Timer};
+use embedded_io_async::{Read, Write};
+use heapless::{Deque, Vec};
+use portable_atomic::Ordering;
+use rmk::event::KeyEvent;
+
+use crate::battery::set_peripheral_battery;
+use crate::event::{send_input_event, KeyEventSink};
+use crate::log::LogModule;
+use crate::log_warn;
+use crate::queues::SPLIT_OUTBOX_QUEUE_DEPTH;
+use crate::split_link::SPLIT_LINK_STATS;
+use crate::split_order::barrier_answered;
+
+
+/// Data frames sent ahead of their acks
+const WINDOW: usize = 8;
+/// Time for the ack of the oldest frame before the unacked frames go again, a few frames' time at 115200 baud
+pub const RETRANSMIT_TIMEOUT: Duration = Duration::from_millis(10);
+
+/// `[epoch (LE), peer epoch (LE), seq, ack, kind]`
+const HEADER_LEN: usize = 7;
+/// Key events sharing a frame at most
+const MAX_BATCH: usize = 4;
+const MAX_PAYLOAD_LEN: usize = 4 * MAX_BATCH;
+const CRC_LEN: usize = 2;
+const MAX_RAW_LEN: usize = HEADER_LEN + MAX_PAYLOAD_LEN + CRC_LEN;
+/// COBS adds a code byte per 254 bytes, a frame is shorter
+const MAX_ENCODED_LEN: usize = MAX_RAW_LEN + 1;
+
+/// Ack only, its `seq` is meaningless
+const KIND_ACK: u8 = 0;
+/// `[row, col, pressed]`
+const KIND_KEY: u8 = 1;
+/// `[token]`
+const KIND_BARRIER_REQUEST: u8 = 2;
+/// `[token]`
+const KIND_BARRIER: u8 = 3;
+/// `[percent]`
+const KIND_BATTERY: u8 = 4;
+/// `[row, col, pressed, ms after the first]` of each event of a [`KeyBatch`]
+const KIND_KEYS: u8 = 5;
+/// Peer epoch of the frames sent before any frame of the other half came
+const NO_EPOCH: u16 = 0;
+/// Spacing of a batch played back by the central at most, well within the other half's [`RETRANSMIT_TIMEOUT`]
+const MAX_BATCH_SPAN: Duration = Duration::from_millis(4);
+
+
+/// Message between the halves
+#[derive(Clone, Copy, defmt::Format)]
+pub enum SplitMessage {
+    /// Key event of the peripheral, at its own matrix position
+    Key(KeyEvent),
+    /// Central's request to answer the token behind the peripheral's queued events, see [`split_order`](crate::split_order)
+    BarrierRequest(u8),
+    /// Peripheral's answer of a barrier request
+    Barrier(u8),
+    /// Peripheral's battery level in percent, see [`battery`](crate::battery)
+    Battery(u8),
+    /// Key events of the peripheral queued together, see [`KeyBatch`]
+    Keys(KeyBatch),
+}
+
+/// Key events of the peripheral sharing a frame, in order, each with its time after the first
+#[derive(Clone, Copy, defmt::Format)]
+pub struct KeyBatch {
+    events: [(KeyEvent, u8); MAX_BATCH],
+    len: usize,
+}
+
+impl KeyBatch {
+    fn new(event: KeyEvent) -> Self {
+        let none = KeyEvent {
+            row: 0,
+            col: 0,
+            pressed: false,
+        };
+        let mut events = [(none, 0); MAX_BATCH];
+        events[0] = (event, 0);
+        Self { events, len: 1 }
+    }
+
+    fn is_full(&self) -> bool {
+        self.len == MAX_BATCH
+    }
+
+    /// Add an event `after` the first, the caller checks the batch has room
+    fn push(&mut self, event: KeyEvent, after: Duration) {
+        self.events[self.len] = (event, after.as_millis().min(u8::MAX as u64) as u8);
+        self.len += 1;
+    }
+
+    /// The events with their time after the first
+    pub fn iter(&self) -> impl Iterator<Item = (KeyEvent, Duration)> + '_ {
+        self.events[..self.len]
+            .iter()
+            .map(|&(event, after)| (event, Duration::from_millis(after as u64)))
+    }
+}
+
+/// Message queued in the outbox, with the time it was queued at
+#[derive(Clone, Copy)]
+struct Queued {
+    message: SplitMessage,
+    at: Instant,
+}
+
+impl Queued {
+    fn now(message: SplitMessage) -> Self {
+        Self {
+            message,
+            at: Instant::now(),
+        }
+    }
+}
+
+/// The message to send for the first one queued, taking the key events queued right behind a key event
+/// into a [`KeyBatch`]. Returns the message taken from `next` that doesn't fit the batch, to send next
+fn batch_keys(first: Queued, mut next: impl FnMut() -> Option<Queued>) -> (SplitMessage, Option<Queued>) {
+    let SplitMessage::Key(event) = first.message else {
+        return (first.message, None);
+    };
+    let mut batch = KeyBatch::new(event);
+    let mut rest = None;
+    while !batch.is_full() {
+        let Some(queued) = next() else {
+            break;
+        };
+        match queued.message {
+            SplitMessage::Key(event) => batch.push(event, queued.at.saturating_duration_since(first.at)),
+            _ => {
+                rest = Some(queued);
+                break;
+            }
+        }
+    }
+    match batch.len {
+        1 => (first.message, rest),
+        _ => (SplitMessage::Keys(batch), rest),
+    }
+}
+
+impl SplitMessage {
+    /// Write the payload, returns the frame kind and the payload length
+    fn encode(&self, payload: &mut [u8; MAX_PAYLOAD_LEN]) -> (u8, usize) {
+        match self {
+            Self::Key(event) => {
+                payload[..3].copy_from_slice(&[event.row, event.col, event.pressed as u8]);
+                (KIND_KEY, 3)
+            }
+            Self::BarrierRequest(token) => {
+                payload[0] = *token;
+                (KIND_BARRIER_REQUEST, 1)
+            }
+            Self::Barrier(token) => {
+                payload[0] = *token;
+                (KIND_BARRIER, 1)
+            }
+            Self::Battery(level) => {
+                payload[0] = *level;
+                (KIND_BATTERY, 1)
+            }
+            Self::Keys(batch) => {
+                for (chunk, &(event, after)) in payload.chunks_exact_mut(4).zip(&batch.events[..batch.len]) {
+                    chunk.copy_from_slice(&[event.row, event.col, event.pressed as u8, after]);
+                }
+                (KIND_KEYS, 4 * batch.len)
+            }
+        }
+    }
+
+    fn decode(kind: u8, payload: &[u8]) -> Option<Self> {
+        match (kind, payload) {
+            (KIND_KEY, &[row, col, pressed]) => Some(Self::Key(KeyEvent {
+                row,
+                col,
+                pressed: pressed != 0,
+            })),
+            (KIND_BARRIER_REQUEST, &[token]) => Some(Self::BarrierRequest(token)),
+            (KIND_BARRIER, &[token]) => Some(Self::Barrier(token)),
+            (KIND_BATTERY, &[level]) => Some(Self::Battery(level)),
+            (KIND_KEYS, payload) if payload.len() % 4 == 0 && (8..=MAX_PAYLOAD_LEN).contains(&payload.len()) => {
+                let event = |chunk: &[u8]| KeyEvent {
+                    row: chunk[0],
+                    col: chunk[1],
+                    pressed: chunk[2] != 0,
+                };
+                let mut chunks = payload.chunks_exact(4);
+                let mut batch = KeyBatch::new(event(chunks.next()?));
+                for chunk in chunks {
+                    batch.events[batch.len] = (event(chunk), chunk[3]);
+                    batch.len += 1;
+                }
+                Some(Self::Keys(batch))
+            }
+            _ => None,
+        }
+    }
+}
+
+static SPLIT_OUTBOX: Channel<CriticalSectionRawMutex, Queued, SPLIT_OUTBOX_QUEUE_DEPTH> = Channel::new();
+
+/// Queue a message to the other half, waiting while the outbox is full
+pub async fn send_split_message(message: SplitMessage) {
+    SPLIT_OUTBOX.send(Queued::now(message)).await;
+}
+
+/// Queue a message to the other half without waiting, returns false if the outbox is full
+pub fn try_send_split_message(message: SplitMessage) -> bool {
+    SPLIT_OUTBOX.try_send(Queued::now(message)).is_ok()
+}
+
+/// Sink sending the events to the central, for the peripheral's matrix
+pub struct SplitSink;
+
+impl KeyEventSink for SplitSink {
+    async fn send(&mut self, event: KeyEvent) {
+        send_split_message(SplitMessage::Key(event)).await
+    }
+}
+
+
+/// Receiver of the other half's messages
+#[allow(async_fn_in_trait)]
+pub trait SplitHandler {
+    async fn receive(&mut self, message: SplitMessage);
+
+    /// A stream of the other half begins, after either half booted. Nothing of the previous one comes anymore
+    async fn restart(&mut self) {}
+}
+
+/// Central's handler placing the peripheral's `ROW`x`COL` matrix at the offsets of the keymap.
+/// The events go through the central matrix's hooks as [input events](send_input_event), like the central's own keys.
+pub struct CentralSplitHandler<const ROW: usize, const COL: usize, const ROW_OFFSET: usize, const COL_OFFSET: usize> {
+    /// Peripheral keys pressed, released when the peripheral starts over
+    held: [[bool; COL]; ROW],
+}
+
+impl<const ROW: usize, const COL: usize, const ROW_OFFSET: usize, const COL_OFFSET: usize>
+    CentralSplitHandler<ROW, COL, ROW_OFFSET, COL_OFFSET>
+{
+    pub const fn new() -> Self {
+        Self {
+            held: [[false; COL]; ROW],
+        }
+    }
+
+    async fn receive_key(&mut self, event: KeyEvent) {
+        let (row, col) = (event.row as usize, event.col as usize);
+        if row >= ROW || col >= COL {
+            log_warn!(LogModule::Matrix, "Peripheral key out of its {}x{} matrix: {}", ROW, COL, event);
+            return;
+        }
+        self.held[row][col] = event.pressed;
+        Self::send(row, col, event.pressed).await;
+    }
+
+    async fn send(row: usize, col: usize, pressed: bool) {
+        send_input_event(KeyEvent {
+            row: (row + ROW_OFFSET) as u8,
+            col: (col + COL_OFFSET) as u8,
+            pressed,
+        })
+        .await;
+    }
+}
+
+impl<const ROW: usize, const COL: usize, const ROW_OFFSET: usize, const COL_OFFSET: usize> Default
+    for CentralSplitHandler<ROW, COL, ROW_OFFSET, COL_OFFSET>
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const ROW: usize, const COL: usize, const ROW_OFFSET: usize, const COL_OFFSET: usize> SplitHandler
+    for CentralSplitHandler<ROW, COL, ROW_OFFSET, COL_OFFSET>
+{
+    async fn receive(&mut self, message: SplitMessage) {
+        match message {
+            SplitMessage::Key(event) => self.receive_key(event).await,
+            // The spacing of the peripheral's presses matters to tap-hold and combos, capped so that the acks
+            // are still in time
+            SplitMessage::Keys(batch) => {
+                let start = Instant::now();
+                for (event, after) in batch.iter() {
+                    Timer::at(start + after.min(MAX_BATCH_SPAN)).await;
+                    self.receive_key(event).await;
+                }
+            }
+            SplitMessage::Barrier(token) => barrier_answered(token),
+            SplitMessage::Battery(level) => set_peripheral_battery(level),
+            SplitMessage::BarrierRequest(_) => {
+                log_warn!(LogModule::Device, "Unexpected split message of the peripheral: {}", message);
+            }
+        }
+    }
+
+    /// The releases of the keys held by the previous stream are lost with it, e.g. when the peripheral rebooted
+    async fn restart(&mut self) {
+        for row in 0..ROW {
+            for col in 0..COL {
+                if core::mem::take(&mut self.held[row][col]) {
+                    Self::send(row, col, false).await;
+                }
+            }
+        }
+    }
+}
+
+/// Peripheral's handler, answering the central's barriers behind the key events queued so far
+pub struct PeripheralSplitHandler;
+
+impl SplitHandler for PeripheralSplitHandler {
+    async fn receive(&mut self, message: SplitMessage) {
+        match message {
+            // The transport is the outbox's only reader, waiting for room here would never end.
+            // A dropped answer makes the central time the barrier out
+            SplitMessage::BarrierRequest(token) => {
+                let _ = try_send_split_message(SplitMessage::Barrier(token));
+            }
+            SplitMessage::Key(_) | SplitMessage::Keys(_) | SplitMessage::Barrier(_) | SplitMessage::Battery(_) => {
+                log_warn!(LogModule::Device, "Unexpected split message of the central: {}", message);
+            }
+        }
+    }
+}
+
+
+/// CRC-16/CCITT-FALSE of the frame
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xFFFFu16;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+/// COBS encode the frame, shorter than 254 bytes, returns the encoded length without the delimiter
+fn cobs_encode(input: &[u8], output: &mut [u8]) -> usize {
+    let mut code_index = 0;
+    let mut code = 1u8;
+    let mut len = 1;
+    for &byte in input {
+        if byte == 0 {
+            output[code_index] = code;
+            code_index = len;
+            code = 1;
+        } else {
+            output[len] = byte;
+            code += 1;
+        }
+        len += 1;
+    }
+    output[code_index] = code;
+    len
+}
+
+/// COBS decode the bytes before the delimiter, `None` if they aren't valid COBS or don't fit
+fn cobs_decode(input: &[u8], output: &mut [u8]) -> Option<usize> {
+    let mut i = 0;
+    let mut len = 0;
+    while i < input.len() {
+        let code = input[i] as usize;
+        if code == 0 {
+            return None;
+        }
+        let end = i + code;
+        let block = input.get(i + 1..end)?;
+        output.get_mut(len..len + block.len())?.copy_from_slice(block);
+        len += block.len();
+        i = end;
+        if code < 0xFF && i < input.len() {
+            *output.get_mut(len)? = 0;
+            len += 1;
+        }
+    }
+    Some(len)
+}
+
+/// Collects the COBS bytes of a frame up to its delimiter
+struct FrameDecoder {
+    encoded: Vec<u8, MAX_ENCODED_LEN>,
+    overflow: bool,
+    raw: [u8; MAX_RAW_LEN],
+}
+
+impl FrameDecoder {
+    const fn new() -> Self {
+        Self {
+            encoded: Vec::new(),
+            overflow: false,
+            raw: [0; MAX_RAW_LEN],
+        }
+    }
+
+    /// Take a byte, returns `[seq, ack, kind, payload...]` when it ends a valid frame.
+    /// Frames failing the framing or CRC are counted and dropped, empty ones between delimiters are ignored.
+    fn push(&mut self, byte: u8) -> Option<&[u8]> {
+        if byte != 0 {
+            self.overflow |= self.encoded.push(byte).is_err();
+            return None;
+        }
+        let empty = self.encoded.is_empty() && !self.overflow;
+        let len = match self.overflow {
+            true => None,
+            false => cobs_decode(&self.encoded, &mut self.raw),
+        };
+        self.encoded.clear();
+        self.overflow = false;
+        let valid = len.filter(|&len| {
+            len >= HEADER_LEN + CRC_LEN
+                && crc16(&self.raw[..len - CRC_LEN]).to_le_bytes() == self.raw[len - CRC_LEN..len]
+        });
+        if valid.is_none() && !empty {
+            SPLIT_LINK_STATS.crc_errors.fetch_add(1, Ordering::Relaxed);
+        }
+        valid.map(|len| &self.raw[..len - CRC_LEN])
+    }
+}
+
+
+#[derive(Clone, Copy)]
+struct InFlight {
+    seq: u8,
+    message: SplitMessage,
+    sent_at: Instant,
+    /// Sent more than once, its ack doesn't time the round trip since it may be of either copy
+    retransmitted: bool,
+}
+
+/// Both directions of the link, the frames this half sends and the stream of the other half
+struct Link<W: Write> {
+    tx: W,
+    /// This half's boot
+    epoch: u16,
+    /// The other half's boot, [`NO_EPOCH`] until a frame of it came
+    peer_epoch: u16,
+    in_flight: Deque<InFlight, WINDOW>,
+    next_seq: u8,
+    /// Next sequence number of the other half's stream
+    expected: u8,
+    /// A data frame of the other half came since the last frame sent, its ack is due
+    ack_pending: bool,
+}
+
+impl<W: Write> Link<W> {
+    /// `epoch` must differ from the previous boot's and not be [`NO_EPOCH`]
+    fn new(tx: W, epoch: u16) -> Self {
+        Self {
+            tx,
+            epoch,
+            peer_epoch: NO_EPOCH,
+            in_flight: Deque::new(),
+            next_seq: 0,
+            expected: 0,
+            ack_pending: false,
+        }
+    }
+
+    async fn send_frame(&mut self, seq: u8, kind: u8, payload: &[u8]) {
+        let mut raw = [0u8; MAX_RAW_LEN];
+        raw[..2].copy_from_slice(&self.epoch.to_le_bytes());
+        raw[2..4].copy_from_slice(&self.peer_epoch.to_le_bytes());
+        raw[4..HEADER_LEN].copy_from_slice(&[seq, self.expected, kind]);
+        raw[HEADER_LEN..HEADER_LEN + payload.len()].copy_from_slice(payload);
+        let len = HEADER_LEN + payload.len();
+        let crc = crc16(&raw[..len]);
+        raw[len..len + CRC_LEN].copy_from_slice(&crc.to_le_bytes());
+        let mut encoded = [0u8; MAX_ENCODED_LEN + 1];
+        let encoded_len = cobs_encode(&raw[..len + CRC_LEN], &mut encoded);
+        // Write errors are counted by the monitored link, the frame goes again for the missing ack
+        let _ = self.tx.write_all(&encoded[..encoded_len + 1]).await;
+        self.ack_pending = false;
+    }
+
+    async fn send_data(&mut self, frame: InFlight) {
+        let mut payload = [0u8; MAX_PAYLOAD_LEN];
+        let (kind, len) = frame.message.encode(&mut payload);
+        self.send_frame(frame.seq, kind, &payload[..len]).await;
+    }
+
+    /// Send a message in a new data frame, the caller checks the window has room
+    async fn send(&mut self, message: SplitMessage) {
+        let frame = InFlight {
+            seq: self.next_seq,
+            message,
+            sent_at: Instant::now(),
+            retransmitted: false,
+        };
+        self.next_seq = self.next_seq.wrapping_add(1);
+        let _ = self.in_flight.push_back(frame);
+        self.send_data(frame).await;
+    }
+
+    /// Also tells the other half this half's epoch, e.g. right after booting
+    async fn send_ack(&mut self) {
+        self.send_frame(0, KIND_ACK, &[]).await;
+    }
+
+    /// Send every unacked frame again in order
+    async fn retransmit(&mut self) {
+        let now = Instant::now();
+        for frame in self.in_flight.iter_mut() {
+            frame.sent_at = now;
+            frame.retransmitted = true;
+        }
+        let frames: Vec<InFlight, WINDOW> = self.in_flight.iter().copied().collect();
+        SPLIT_LINK_STATS.retransmits.fetch_add(frames.len() as u32, Ordering::Relaxed);
+        for frame in frames {
+            self.send_data(frame).await;
+        }
+    }
+
+    /// Start both streams over for a new boot of the other half, the unacked frames renumbered from 0
+    /// and due right away
+    async fn restart<H: SplitHandler>(&mut self, peer_epoch: u16, handler: &mut H) {
+        if self.peer_epoch != NO_EPOCH {
+            log_warn!(LogModule::Device, "Split peer rebooted, starting over");
+        }
+        self.peer_epoch = peer_epoch;
+        self.expected = 0;
+        for (seq, frame) in self.in_flight.iter_mut().enumerate() {
+            frame.seq = seq as u8;
+            frame.sent_at = Instant::from_ticks(0);
+        }
+        self.next_seq = self.in_flight.len() as u8;
+        // Tell the other half this half's epoch, it may have just booted
+        self.ack_pending = true;
+        handler.restart().await;
+    }
+
+    /// Drop the frames up to the cumulative ack, timing the round trip by the newest one
+    fn handle_ack(&mut self, ack: u8) {
+        let Some(oldest) = self.in_flight.front() else {
+            return;
+        };
+        let acked = ack.wrapping_sub(oldest.seq) as usize;
+        if acked == 0 || acked > self.in_flight.len() {
+            return;
+        }
+        let mut newest = None;
+        for _ in 0..acked {
+            newest = self.in_flight.pop_front();
+        }
+        if let Some(frame) = newest.filter(|f| !f.retransmitted) {
+            SPLIT_LINK_STATS.record_rtt(frame.sent_at.elapsed());
+        }
+    }
+
+    async fn receive<H: SplitHandler>(&mut self, frame: &[u8], handler: &mut H) {
+        let epoch = u16::from_le_bytes([frame[0], frame[1]]);
+        let peer_epoch = u16::from_le_bytes([frame[2], frame[3]]);
+        let (seq, ack, kind) = (frame[4], frame[5], frame[6]);
+        if epoch != self.peer_epoch {
+            self.restart(epoch, handler).await;
+        }
+        // Sent before the other half knew of this boot, its ack and sequence number are of an earlier stream
+        let current = peer_epoch == self.epoch;
+        if current {
+            self.handle_ack(ack);
+        }
+        if kind == KIND_ACK {
+            return;
+        }
+        // Acked even if dropped, the other half learns what this half expects
+        self.ack_pending = true;
+        if !current || seq != self.expected {
+            return;
+        }
+        self.expected = self.expected.wrapping_add(1);
+        match SplitMessage::decode(kind, &frame[HEADER_LEN..]) {
+            Some(message) => handler.receive(message).await,
+            None => log_warn!(LogModule::Device, "Unknown split frame kind {}", kind),
+        }
+    }
+}
+
+
+/// Epoch of the boot for [`run_split_transport`], from the ROSC's random bits
+#[cfg(feature = "rp2040")]
+pub fn rp2040_boot_epoch() -> u16 {
+    let mut epoch = 0u16;
+    for _ in 0..16 {
+        epoch = epoch << 1 | embassy_rp::pac::ROSC.randombit().read().randombit() as u16;
+    }
+    epoch
+}
+
+/// Run the split transport over the UART halves, handing the other half's messages to the handler
+/// and sending the ones queued by [`send_split_message`].
+/// `epoch` tells this boot apart from the previous ones, e.g. [`rp2040_boot_epoch`], a repeated one may lose
+/// the frames in flight when the other half missed the reboot.
+/// Wrap the halves in [`MonitoredLink`](crate::split_link::MonitoredLink) to count their traffic.
+/// This function should never return.
+pub async fn run_split_transport<R: Read, W: Write, H: SplitHandler>(mut rx: R, tx: W, mut handler: H, epoch: u16) -> ! {
+    let epoch = if epoch == NO_EPOCH { 1 } else { epoch };
+    let mut link = Link::new(tx, epoch);
+    let mut decoder = FrameDecoder::new();
+    link.send_ack().await;
+    let mut chunk = [0u8; 16];
+    // Taken from the outbox behind a batch it didn't fit
+    let mut next_queued = None;
+    loop {
+        let deadline = link.in_flight.front().map(|frame| frame.sent_at + RETRANSMIT_TIMEOUT);
+        let window_open = !link.in_flight.is_full();
+        let outbox = async {
+            match window_open {
+                true => match next_queued.take() {
+                    Some(queued) => queued,
+                    None => SPLIT_OUTBOX.receive().await,
+                },
+                false => core::future::pending().await,
+            }
+        };
+        let retransmit = async {
+            match deadline {
+                Some(at) => Timer::at(at).await,
+                None => core::future::pending().await,
+            }
+        };
+        let ready = select3(rx.read(&mut chunk), outbox, retransmit).await;
+        match ready {
+            Either3::First(Ok(len)) => {
+                for &byte in &chunk[..len] {
+                    if let Some(frame) = decoder.push(byte) {
+                        link.receive(frame, &mut handler).await;
+                    }
+                }
+                if link.ack_pending {
+                    link.send_ack().await;
+                }
+            }
+            // Counted by the monitored link, the frame it broke fails its CRC
+            Either3::First(Err(_)) => {}
+            Either3::Second(queued) => {
+                let (message, rest) = batch_keys(queued, || SPLIT_OUTBOX.try_receive().ok());
+                next_queued = rest;
+                link.send(message).await;
+            }
+            Either3::Third(()) => link.retransmit().await,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The encoded frame with its delimiter, as [`Link::send_frame`] writes it
+    fn encode_frame(header: [u8; HEADER_LEN], payload: &[u8]) -> Vec<u8, { MAX_ENCODED_LEN + 1 }> {
+        let mut raw: Vec<u8, MAX_RAW_LEN> = Vec::new();
+        raw.extend_from_slice(&header).unwrap();
+        raw.extend_from_slice(payload).unwrap();
+        let crc = crc16(&raw);
+        raw.extend_from_slice(&crc.to_le_bytes()).unwrap();
+        let mut encoded = [0u8; MAX_ENCODED_LEN + 1];
+        let len = cobs_encode(&raw, &mut encoded);
+        Vec::from_slice(&encoded[..len + 1]).unwrap()
+    }
+
+    #[test]
+    fn crc16_check_value() {
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+    }
+
+    #[test]
+    fn cobs_roundtrip() {
+        let mut encoded = [0u8; 8];
+        let len = cobs_encode(&[0x11, 0x22, 0x00, 0x33], &mut encoded);
+        assert_eq!(&encoded[..len], &[0x03, 0x11, 0x22, 0x02, 0x33]);
+        let mut decoded = [0u8; 8];
+        let len = cobs_decode(&encoded[..len], &mut decoded);
+        assert_eq!(len.map(|len| &decoded[..len]), Some(&[0x11, 0x22, 0x00, 0x33][..]));
+
+        let len = cobs_encode(&[0x00], &mut encoded);
+        assert_eq!(&encoded[..len], &[0x01, 0x01]);
+        let len = cobs_decode(&encoded[..len], &mut decoded);
+        assert_eq!(len.map(|len| &decoded[..len]), Some(&[0x00][..]));
+    }
+
+    #[test]
+    fn cobs_rejects_zero_code() {
+        assert_eq!(cobs_decode(&[0x02, 0x11, 0x00], &mut [0u8; 8]), None);
+    }
+
+    /// Push the bytes, returns the frame decoded at the delimiter ending them
+    fn feed(decoder: &mut FrameDecoder, bytes: &[u8]) -> Option<Vec<u8, MAX_RAW_LEN>> {
+        let (last, bytes) = bytes.split_last()?;
+        for &byte in bytes {
+            assert_eq!(decoder.push(byte), None);
+        }
+        decoder.push(*last).map(|frame| Vec::from_slice(frame).unwrap())
+    }
+
+    #[test]
+    fn decoder_yields_valid_frame() {
+        let mut decoder = FrameDecoder::new();
+        let frame = encode_frame([1, 0, 2, 0, 5, 3, KIND_KEY], &[1, 2, 1]);
+        assert_eq!(feed(&mut decoder, &frame).as_deref(), Some(&[1, 0, 2, 0, 5, 3, KIND_KEY, 1, 2, 1][..]));
+    }
+
+    #[test]
+    fn decoder_drops_corrupted_frame() {
+        let mut decoder = FrameDecoder::new();
+        let mut frame = encode_frame([1, 0, 2, 0, 5, 3, KIND_BARRIER], &[7]);
+        frame[6] ^= 0x04;
+        assert_eq!(feed(&mut decoder, &frame), None);
+        // The next frame decodes again
+        let frame = encode_frame([1, 0, 2, 0, 6, 3, KIND_BARRIER], &[8]);
+        assert_eq!(feed(&mut decoder, &frame).as_deref(), Some(&[1, 0, 2, 0, 6, 3, KIND_BARRIER, 8][..]));
+    }
+
+    #[test]
+    fn decoder_ignores_empty_frames() {
+        let mut decoder = FrameDecoder::new();
+        assert_eq!(decoder.push(0), None);
+        assert_eq!(decoder.push(0), None);
+    }
+
+    #[test]
+    fn message_roundtrip() {
+        let mut payload = [0u8; MAX_PAYLOAD_LEN];
+        let event = KeyEvent {
+            row: 2,
+            col: 4,
+            pressed: true,
+        };
+        let (kind, len) = SplitMessage::Key(event).encode(&mut payload);
+        match SplitMessage::decode(kind, &payload[..len]) {
+            Some(SplitMessage::Key(decoded)) => {
+                assert_eq!((decoded.row, decoded.col, decoded.pressed), (2, 4, true));
+            }
+            _ => panic!("not a key message"),
+        }
+
+        let (kind, len) = SplitMessage::BarrierRequest(9).encode(&mut payload);
+        assert!(matches!(SplitMessage::decode(kind, &payload[..len]), Some(SplitMessage::BarrierRequest(9))));
+        let (kind, len) = SplitMessage::Barrier(9).encode(&mut payload);
+        assert!(matches!(SplitMessage::decode(kind, &payload[..len]), Some(SplitMessage::Barrier(9))));
+        let (kind, len) = SplitMessage::Battery(80).encode(&mut payload);
+        assert!(matches!(SplitMessage::decode(kind, &payload[..len]), Some(SplitMessage::Battery(80))));
+        assert!(SplitMessage::decode(KIND_KEY, &payload[..1]).is_none());
+    }
+
+    fn queued(message: SplitMessage, after_ms: u64) -> Queued {
+        Queued {
+            message,
+            at: Instant::from_millis(1000 + after_ms),
+        }
+    }
+
+    fn batch_events(message: SplitMessage) -> std::vec::Vec<(u8, u8, bool, u64)> {
+        let SplitMessage::Keys(batch) = message else {
+            panic!("not a key batch");
+        };
+        batch
+            .iter()
+            .map(|(event, after)| (event.row, event.col, event.pressed, after.as_millis()))
+            .collect()
+    }
+
+    #[test]
+    fn queued_keys_share_a_frame() {
+        let mut rest = [
+            queued(key(0, 1, true), 2),
+            queued(key(0, 0, false), 3),
+            queued(SplitMessage::Battery(50), 4),
+        ]
+        .into_iter();
+        let (message, next) = batch_keys(queued(key(0, 0, true), 0), || rest.next());
+        assert_eq!(batch_events(message), [(0, 0, true, 0), (0, 1, true, 2), (0, 0, false, 3)]);
+        assert!(matches!(next.map(|q| q.message), Some(SplitMessage::Battery(50))));
+        assert!(rest.next().is_none());
+    }
+
+    #[test]
+    fn batch_holds_a_few_keys() {
+        let mut rest = (1..8).map(|i| queued(key(0, i, true), i as u64));
+        let (message, next) = batch_keys(queued(key(0, 0, true), 0), || rest.next());
+        assert_eq!(batch_events(message).len(), MAX_BATCH);
+        assert!(next.is_none());
+        assert_eq!(rest.next().map(|q| q.at), Some(Instant::from_millis(1000 + MAX_BATCH as u64)));
+    }
+
+    #[test]
+    fn lone_messages_go_as_they_are() {
+        let (message, next) = batch_keys(queued(key(1, 1, true), 0), || None);
+        assert!(matches!(message, SplitMessage::Key(KeyEvent { row: 1, col: 1, pressed: true })));
+        assert!(next.is_none());
+        let mut rest = [queued(key(0, 1, true), 1)].into_iter();
+        let (message, _) = batch_keys(queued(SplitMessage::Barrier(3), 0), || rest.next());
+        assert!(matches!(message, SplitMessage::Barrier(3)));
+        // The key behind it stays queued
+        assert!(rest.next().is_some());
+    }
+
+    #[test]
+    fn key_batch_roundtrip() {
+        let mut batch = KeyBatch::new(KeyEvent {
+            row: 1,
+            col: 0,
+            pressed: true,
+        });
+        batch.push(
+            KeyEvent {
+                row: 1,
+                col: 1,
+                pressed: false,
+            },
+            Duration::from_millis(7),
+        );
+        batch.push(
+            KeyEvent {
+                row: 0,
+                col: 1,
+                pressed: true,
+            },
+            Duration::from_secs(1),
+        );
+        let mut payload = [0u8; MAX_PAYLOAD_LEN];
+        let (kind, len) = SplitMessage::Keys(batch).encode(&mut payload);
+        assert_eq!(&payload[..len], &[1, 0, 1, 0, 1, 1, 0, 7, 0, 1, 1, 255]);
+        let decoded = SplitMessage::decode(kind, &payload[..len]).unwrap();
+        assert_eq!(batch_events(decoded), [(1, 0, true, 0), (1, 1, false, 7), (0, 1, true, 255)]);
+        assert!(SplitMessage::decode(KIND_KEYS, &payload[..4]).is_none());
+        assert!(SplitMessage::decode(KIND_KEYS, &payload[..6]).is_none());
+    }
+
+    /// The bytes a half wrote, delivered to the other one by [`deliver`]
+    #[derive(Default)]
+    struct Wire(std::vec::Vec<u8>);
+
+    impl embedded_io_async::ErrorType for Wire {
+        type Error = core::convert::Infallible;
+    }
+
+    impl Write for Wire {
+        async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+            self.0.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+    }
+
+    #[derive(Default)]
+    struct Received {
+        keys: std::vec::Vec<(u8, u8, bool)>,
+        restarts: usize,
+    }
+
+    impl SplitHandler for Received {
+        async fn receive(&mut self, message: SplitMessage) {
+            if let SplitMessage::Key(event) = message {
+                self.keys.push((event.row, event.col, event.pressed));
+            }
+        }
+
+        async fn restart(&mut self) {
+            self.restarts += 1;
+        }
+    }
+
+    fn key(row: u8, col: u8, pressed: bool) -> SplitMessage {
+        SplitMessage::Key(KeyEvent { row, col, pressed })
+    }
+
+    /// Hand the frames `from` wrote to `to`, which acks as [`run_split_transport`] does
+    fn deliver(from: &mut Link<Wire>, to: &mut Link<Wire>, handler: &mut Received) {
+        let bytes = core::mem::take(&mut from.tx.0);
+        let mut decoder = FrameDecoder::new();
+        embassy_futures::block_on(async {
+            for byte in bytes {
+                if let Some(frame) = decoder.push(byte) {
+                    to.receive(frame, handler).await;
+                }
+            }
+            if to.ack_pending {
+                to.send_ack().await;
+            }
+        });
+    }
+
+    /// Both halves booted and exchanged their epochs
+    fn connect(peripheral: &mut Link<Wire>, central: &mut Link<Wire>, received: &mut Received) {
+        embassy_futures::block_on(peripheral.send_ack());
+        deliver(peripheral, central, received);
+        deliver(central, peripheral, &mut Received::default());
+        deliver(peripheral, central, received);
+        assert!(peripheral.tx.0.is_empty() && central.tx.0.is_empty());
+    }
+
+    #[test]
+    fn duplicate_frames_are_dropped() {
+        let (mut peripheral, mut central) = (Link::new(Wire::default(), 1), Link::new(Wire::default(), 2));
+        let mut received = Received::default();
+        connect(&mut peripheral, &mut central, &mut received);
+        embassy_futures::block_on(peripheral.send(key(0, 1, true)));
+        deliver(&mut peripheral, &mut central, &mut received);
+        // The ack is lost, the frame goes again
+        central.tx.0.clear();
+        embassy_futures::block_on(peripheral.retransmit());
+        deliver(&mut peripheral, &mut central, &mut received);
+        assert_eq!(received.keys, [(0, 1, true)]);
+        deliver(&mut central, &mut peripheral, &mut Received::default());
+        assert!(peripheral.in_flight.is_empty());
+        assert_eq!(received.restarts, 1);
+    }
+
+    #[test]
+    fn frames_after_a_lost_one_wait_for_it() {
+        let (mut peripheral, mut central) = (Link::new(Wire::default(), 1), Link::new(Wire::default(), 2));
+        let mut received = Received::default();
+        connect(&mut peripheral, &mut central, &mut received);
+        embassy_futures::block_on(peripheral.send(key(0, 1, true)));
+        peripheral.tx.0.clear();
+        embassy_futures::block_on(peripheral.send(key(0, 1, false)));
+        deliver(&mut peripheral, &mut central, &mut received);
+        assert!(received.keys.is_empty());
+        deliver(&mut central, &mut peripheral, &mut Received::default());
+        assert_eq!(peripheral.in_flight.len(), 2);
+        embassy_futures::block_on(peripheral.retransmit());
+        deliver(&mut peripheral, &mut central, &mut received);
+        assert_eq!(received.keys, [(0, 1, true), (0, 1, false)]);
+    }
+
+    #[test]
+    fn peripheral_reboot_restarts_the_stream() {
+        let (mut peripheral, mut central) = (Link::new(Wire::default(), 1), Link::new(Wire::default(), 2));
+        let mut received = Received::default();
+        connect(&mut peripheral, &mut central, &mut received);
+        embassy_futures::block_on(peripheral.send(key(0, 1, true)));
+        deliver(&mut peripheral, &mut central, &mut received);
+        let stale_ack = core::mem::take(&mut central.tx.0);
+
+        let mut peripheral = Link::new(Wire::default(), 3);
+        embassy_futures::block_on(peripheral.send(key(1, 1, true)));
+        // The old stream's ack of sequence 0 isn't of the new frame 0
+        central.tx.0 = stale_ack;
+        deliver(&mut central, &mut peripheral, &mut Received::default());
+        assert_eq!(peripheral.in_flight.len(), 1);
+
+        deliver(&mut peripheral, &mut central, &mut received);
+        assert_eq!(received.restarts, 2);
+        deliver(&mut central, &mut peripheral, &mut Received::default());
+        embassy_futures::block_on(peripheral.retransmit());
+        deliver(&mut peripheral, &mut central, &mut received);
+        assert_eq!(received.keys, [(0, 1, true), (1, 1, true)]);
+        deliver(&mut central, &mut peripheral, &mut Received::default());
+        assert!(peripheral.in_flight.is_empty());
+    }
+
+    #[test]
+    fn central_reboot_restarts_the_stream() {
+        let (mut peripheral, mut central) = (Link::new(Wire::default(), 1), Link::new(Wire::default(), 2));
+        connect(&mut peripheral, &mut central, &mut Received::default());
+        embassy_futures::block_on(peripheral.send(key(0, 0, true)));
+        embassy_futures::block_on(peripheral.send(key(0, 1, true)));
+        peripheral.tx.0.clear();
+
+        let mut central = Link::new(Wire::default(), 4);
+        let mut received = Received::default();
+        embassy_futures::block_on(peripheral.retransmit());
+        // Of the previous central's stream
+        deliver(&mut peripheral, &mut central, &mut received);
+        assert!(received.keys.is_empty());
+        deliver(&mut central, &mut peripheral, &mut Received::default());
+        assert_eq!(peripheral.in_flight.iter().map(|frame| frame.seq).collect::<std::vec::Vec<_>>(), [0, 1]);
+        embassy_futures::block_on(peripheral.retransmit());
+        deliver(&mut peripheral, &mut central, &mut received);
+        assert_eq!(received.keys, [(0, 0, true), (0, 1, true)]);
+        assert_eq!(received.restarts, 1);
+    }
+
+    #[test]
+    fn central_handler_releases_held_keys_on_restart() {
+        let _serial = crate::test_support::serial();
+        while crate::event::INPUT_EVENT_CHANNEL.try_receive().is_ok() {}
+        let mut handler = CentralSplitHandler::<2, 2, 0, 2>::new();
+        embassy_futures::block_on(async {
+            handler.receive(key(1, 0, true)).await;
+            handler.receive(key(0, 1, true)).await;
+            handler.receive(key(0, 1, false)).await;
+            handler.restart().await;
+        });
+        let mut events = std::vec::Vec::new();
+        while let Ok(event) = crate::event::INPUT_EVENT_CHANNEL.try_receive() {
+            events.push((event.row, event.col, event.pressed));
+        }
+        assert_eq!(events, [(1, 2, true), (0, 3, true), (0, 3, false), (1, 2, false)]);
+    }
+
+    #[test]
+    fn central_handler_plays_a_batch_back_in_order() {
+        let _serial = crate::test_support::serial();
+        while crate::event::INPUT_EVENT_CHANNEL.try_receive().is_ok() {}
+        let mut handler = CentralSplitHandler::<2, 2, 0, 2>::new();
+        let mut batch = KeyBatch::new(KeyEvent {
+            row: 0,
+            col: 0,
+            pressed: true,
+        });
+        batch.push(
+            KeyEvent {
+                row: 0,
+                col: 1,
+                pressed: true,
+            },
+            Duration::from_millis(1),
+        );
+        batch.push(
+            KeyEvent {
+                row: 0,
+                col: 0,
+                pressed: false,
+            },
+            Duration::from_millis(2),
+        );
+        let start = Instant::now();
+        embassy_futures::block_on(handler.receive(SplitMessage::Keys(batch)));
+        assert!(start.elapsed() >= Duration::from_millis(2));
+        let mut events = std::vec::Vec::new();
+        while let Ok(event) = crate::event::INPUT_EVENT_CHANNEL.try_receive() {
+            events.push((event.row, event.col, event.pressed));
+        }
+        assert_eq!(events, [(0, 2, true), (0, 3, true), (0, 2, false)]);
+        assert_eq!(handler.held, [[false, true], [false, false]]);
+    }
+}
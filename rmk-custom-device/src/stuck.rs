@@ -0,0 +1,217 @@
+//! Watchdog force-releasing keys held implausibly long while the matrix reads look broken,
+//! e.g. a shorted trace reporting a whole row as pressed, to stop a runaway key repeat.
+
+use core::cell::RefCell;
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Instant, Timer};
+use embedded_hal::digital::OutputPin;
+use heapless::Vec;
+use rmk::event::KeyEvent;
+
+use crate::bus::{publish_device_event, DeviceEvent, DEVICE_EVENT_BUS};
+use crate::debounce::MatrixRegion;
+use crate::event::{send_input_event, HookContext, KeyEventHook};
+use crate::log::LogModule;
+use crate::log_warn;
+
+
+/// Keys tracked as held, more simultaneous keys count as an anomaly by themselves
+const MAX_HELD_KEYS: usize = 16;
+
+#[derive(Clone, Copy)]
+struct HeldKey {
+    row: u8,
+    col: u8,
+    since: Instant,
+    /// The watchdog sent the release through the hooks, it goes on to rmk
+    releasing: bool,
+    /// Force-released, events are consumed until the matrix reports its release
+    released: bool,
+}
+
+struct HeldKeys {
+    keys: Vec<HeldKey, MAX_HELD_KEYS>,
+    overflow: bool,
+}
+
+static HELD_KEYS: Mutex<CriticalSectionRawMutex, RefCell<HeldKeys>> = Mutex::new(RefCell::new(HeldKeys {
+    keys: Vec::new(),
+    overflow: false,
+}));
+
+/// Whether the held keys look like a broken read rather than typing:
+/// over half of the scanned keys, or a whole row or column of one of the matrices
+fn is_anomaly(held: &HeldKeys, matrices: &[MatrixRegion]) -> bool {
+    let keys: usize = matrices.iter().map(|m| m.rows.len() * m.cols.len()).sum();
+    if held.overflow || held.keys.len() * 2 > keys {
+        return true;
+    }
+    let is_held = |row: u8, col: u8| held.keys.iter().any(|k| k.row == row && k.col == col);
+    matrices.iter().any(|m| {
+        let row_full = m.cols.len() > 1 && m.rows.clone().any(|row| m.cols.clone().all(|col| is_held(row, col)));
+        let col_full = m.rows.len() > 1 && m.cols.clone().any(|col| m.rows.clone().all(|row| is_held(row, col)));
+        row_full || col_full
+    })
+}
+
+
+/// Hook tracking held keys for the watchdog, and consuming the events of force-released keys.
+/// The forced releases come through the hooks from the start, so put it ahead of the hooks moving positions.
+pub struct StuckKeyHook;
+
+impl KeyEventHook for StuckKeyHook {
+    async fn process(&mut self, event: KeyEvent, _ctx: &mut HookContext) -> Option<KeyEvent> {
+        HELD_KEYS.lock(|h| {
+            let mut h = h.borrow_mut();
+            let index = h.keys.iter().position(|k| k.row == event.row && k.col == event.col);
+            match (index, event.pressed) {
+                (None, true) => {
+                    let key = HeldKey {
+                        row: event.row,
+                        col: event.col,
+                        since: Instant::now(),
+                        releasing: false,
+                        released: false,
+                    };
+                    if h.keys.push(key).is_err() {
+                        h.overflow = true;
+                    }
+                    Some(event)
+                }
+                (Some(index), false) if h.keys[index].releasing => {
+                    // The forced release, the matrix still reads the key held
+                    h.keys[index].releasing = false;
+                    h.keys[index].released = true;
+                    Some(event)
+                }
+                (Some(index), false) => {
+                    let key = h.keys.swap_remove(index);
+                    if h.keys.is_empty() {
+                        h.overflow = false;
+                    }
+                    // rmk saw the release already
+                    (!key.released).then_some(event)
+                }
+                (Some(index), true) => (!h.keys[index].released).then_some(event),
+                (None, false) => Some(event),
+            }
+        })
+    }
+}
+
+
+/// Release keys held longer than `limit` while the reads of one of the `matrices` look broken.
+/// Each matrix is the part of the keymap one scan covers, e.g. each half of a split,
+/// whose events all go through [`StuckKeyHook`].
+/// Publishes [`DeviceEvent::StuckKey`] for each, e.g. for [`run_stuck_key_indicator`].
+/// This function should never return.
+pub async fn run_stuck_key_watchdog(limit: Duration, matrices: &[MatrixRegion]) -> ! {
+    const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+    loop {
+        Timer::after(CHECK_INTERVAL).await;
+        let stuck: Vec<(u8, u8), MAX_HELD_KEYS> = HELD_KEYS.lock(|h| {
+            let mut h = h.borrow_mut();
+            if !is_anomaly(&h, matrices) {
+                return Vec::new();
+            }
+            let mut stuck = Vec::new();
+            for key in h.keys.iter_mut().filter(|k| !k.releasing && !k.released && k.since.elapsed() > limit) {
+                key.releasing = true;
+                let _ = stuck.push((key.row, key.col));
+            }
+            stuck
+        });
+        for (row, col) in stuck {
+            log_warn!(LogModule::Matrix, "Stuck key force-released: ({}, {})", row, col);
+            send_input_event(KeyEvent {
+                row,
+                col,
+                pressed: false,
+            })
+            .await;
+            publish_device_event(DeviceEvent::StuckKey(row, col));
+        }
+    }
+}
+
+/// Blink the LED fast a few times on each stuck key. This function should never return.
+pub async fn run_stuck_key_indicator<Out: OutputPin>(mut led: Out) -> ! {
+    const BLINK_COUNT: usize = 10;
+    const BLINK_INTERVAL: Duration = Duration::from_millis(100);
+    let Ok(mut subscriber) = DEVICE_EVENT_BUS.subscriber() else {
+        defmt::panic!("No subscriber slot left on the device event bus");
+    };
+    loop {
+        if let DeviceEvent::StuckKey(..) = subscriber.next_message_pure().await {
+            for _ in 0..BLINK_COUNT {
+                led.set_high().ok();
+                Timer::after(BLINK_INTERVAL).await;
+                led.set_low().ok();
+                Timer::after(BLINK_INTERVAL).await;
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::serial;
+
+    fn reset() {
+        HELD_KEYS.lock(|h| {
+            let mut h = h.borrow_mut();
+            h.keys.clear();
+            h.overflow = false;
+        });
+    }
+
+    fn run(row: u8, col: u8, pressed: bool) -> Option<(u8, u8, bool)> {
+        let event = KeyEvent { row, col, pressed };
+        embassy_futures::block_on(StuckKeyHook.process(event, &mut HookContext::new())).map(|e| (e.row, e.col, e.pressed))
+    }
+
+    fn anomaly(matrices: &[MatrixRegion]) -> bool {
+        HELD_KEYS.lock(|h| is_anomaly(&h.borrow(), matrices))
+    }
+
+    #[test]
+    fn full_row_or_most_keys_held_is_an_anomaly() {
+        let _serial = serial();
+        reset();
+        let matrices = [MatrixRegion::new(0..3, 0..3)];
+        for (row, col) in [(0, 0), (0, 1), (1, 0), (1, 2)] {
+            run(row, col, true);
+        }
+        assert!(!anomaly(&matrices));
+        // 5 of the 9 keys
+        run(2, 1, true);
+        assert!(anomaly(&matrices));
+
+        reset();
+        run(2, 0, true);
+        run(2, 1, true);
+        assert!(!anomaly(&matrices));
+        run(2, 2, true);
+        assert!(anomaly(&matrices));
+        // Another matrix isn't affected by this one's rows
+        assert!(!anomaly(&[MatrixRegion::new(0..2, 0..3)]));
+        reset();
+    }
+
+    #[test]
+    fn force_released_key_is_consumed_until_the_matrix_releases_it() {
+        let _serial = serial();
+        reset();
+        assert_eq!(run(0, 0, true), Some((0, 0, true)));
+        // The watchdog's release
+        HELD_KEYS.lock(|h| h.borrow_mut().keys[0].releasing = true);
+        assert_eq!(run(0, 0, false), Some((0, 0, false)));
+        assert_eq!(run(0, 0, true), None);
+        assert_eq!(run(0, 0, false), None);
+        assert!(HELD_KEYS.lock(|h| h.borrow().keys.is_empty()));
+        assert_eq!(run(0, 0, true), Some((0, 0, true)));
+        reset();
+    }
+}
@@ -0,0 +1,66 @@
+//! Static storage of embassy tasks running generic futures, e.g. the matrix scan of the builder's types.
+//!
+//! `#[embassy_executor::task]` functions can't be generic, so the [`TaskStorage`] of such a future is
+//! carved out of a static buffer when it's spawned instead. The tasks here run forever, nothing is freed.
+//! An arena is split evenly between the tasks it holds, each task is checked against its share at build time.
+
+use core::cell::UnsafeCell;
+use core::future::Future;
+use core::mem::{align_of, size_of, MaybeUninit};
+use embassy_executor::{raw::TaskStorage, SpawnToken};
+use portable_atomic::{AtomicUsize, Ordering};
+
+
+/// Alignment of every allocation, enough for the `u64` of `Instant`s held across awaits
+const ALIGN: usize = 8;
+
+#[repr(C, align(8))]
+struct Buffer<const N: usize>(UnsafeCell<MaybeUninit<[u8; N]>>);
+
+/// Bump allocator of `TASKS` [`TaskStorage`]s of up to `N / TASKS` bytes each, in a static buffer of `N` bytes
+pub struct TaskArena<const N: usize, const TASKS: usize> {
+    buffer: Buffer<N>,
+    used: AtomicUsize,
+}
+
+// SAFETY: each allocation reserves a disjoint range of the buffer by the atomic offset
+unsafe impl<const N: usize, const TASKS: usize> Sync for TaskArena<N, TASKS> {}
+
+impl<const N: usize, const TASKS: usize> TaskArena<N, TASKS> {
+    pub const fn new() -> Self {
+        Self {
+            buffer: Buffer(UnsafeCell::new(MaybeUninit::uninit())),
+            used: AtomicUsize::new(0),
+        }
+    }
+
+    /// Allocate the task of the future, spawned by passing the token to a spawner.
+    /// Fails the build if the task is over its share of the arena, so the first `TASKS` tasks always fit.
+    /// Panics when the arena is full.
+    pub fn spawn<F: Future + 'static>(&'static self, future: F) -> SpawnToken<impl Sized> {
+        const {
+            assert!(align_of::<TaskStorage<F>>() <= ALIGN, "task aligned above the arena's alignment");
+            assert!(
+                size_of::<TaskStorage<F>>().next_multiple_of(ALIGN) <= N / TASKS,
+                "task larger than its share of the arena, raise the arena size"
+            );
+        }
+        let storage = self.alloc(TaskStorage::<F>::new());
+        storage.spawn(move || future)
+    }
+
+    fn alloc<T>(&'static self, value: T) -> &'static mut T {
+        defmt::assert!(align_of::<T>() <= ALIGN);
+        let size = size_of::<T>().next_multiple_of(ALIGN);
+        let start = self.used.fetch_add(size, Ordering::Relaxed);
+        if start + size > N {
+            defmt::panic!("Task arena of {} bytes full, {} more needed", N, start + size - N);
+        }
+        // SAFETY: the range is in the buffer, aligned since the buffer and every size are, and reserved only here
+        unsafe {
+            let slot = self.buffer.0.get().cast::<u8>().add(start).cast::<T>();
+            slot.write(value);
+            &mut *slot
+        }
+    }
+}
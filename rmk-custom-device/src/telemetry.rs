@@ -0,0 +1,93 @@
+use core::cell::Cell;
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+#[cfg(feature = "rp2040")]
+use embassy_rp::adc::{Adc, Async, Channel};
+#[cfg(feature = "rp2040")]
+use embassy_time::{Duration, Timer};
+
+
+/// Latest readings of the MCU internal sensors
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct Telemetry {
+    /// Die temperature in 0.01 degC
+    pub temperature_centi: i16,
+    /// Supply voltage in mV, if the board has the sense divider
+    pub vsys_mv: Option<u16>,
+}
+
+impl Telemetry {
+    /// `[temperature_centi (i16 LE), vsys_mv (u16 LE, 0 if unknown)]`
+    pub fn to_bytes(&self) -> [u8; 4] {
+        let t = self.temperature_centi.to_le_bytes();
+        let v = self.vsys_mv.unwrap_or(0).to_le_bytes();
+        [t[0], t[1], v[0], v[1]]
+    }
+}
+
+static TELEMETRY: Mutex<CriticalSectionRawMutex, Cell<Option<Telemetry>>> = Mutex::new(Cell::new(None));
+
+/// Latest telemetry, `None` until the first reading
+pub fn latest_telemetry() -> Option<Telemetry> {
+    TELEMETRY.lock(|t| t.get())
+}
+
+pub fn update_telemetry(telemetry: Telemetry) {
+    TELEMETRY.lock(|t| t.set(Some(telemetry)));
+}
+
+/// Convert 12bit ADC reading with 3.3V reference into mV
+pub const fn adc_to_mv(raw: u16) -> u32 {
+    raw as u32 * 3300 / 4096
+}
+
+/// RP2040 temperature sensor: 0.706V at 27degC, -1.721mV/degC
+pub const fn rp2040_temperature_centi(raw: u16) -> i16 {
+    let uv = raw as i32 * 3_300_000 / 4096;
+    (2700 - (uv - 706_000) * 100 / 1721) as i16
+}
+
+
+/// RP2040 internal sensors read through the ADC
+#[cfg(feature = "rp2040")]
+pub struct Rp2040Telemetry<'d> {
+    adc: Adc<'d, Async>,
+    temperature: Channel<'d>,
+    /// VSYS through 1/3 divider, at GPIO29 on Pico-compatible boards
+    vsys: Option<Channel<'d>>,
+}
+
+#[cfg(feature = "rp2040")]
+impl<'d> Rp2040Telemetry<'d> {
+    pub fn new(adc: Adc<'d, Async>, temperature: Channel<'d>, vsys: Option<Channel<'d>>) -> Self {
+        Self {
+            adc,
+            temperature,
+            vsys,
+        }
+    }
+
+    pub async fn read(&mut self) -> Option<Telemetry> {
+        let temperature = self.adc.read(&mut self.temperature).await.ok()?;
+        let vsys_mv = match self.vsys.as_mut() {
+            Some(vsys) => Some((adc_to_mv(self.adc.read(vsys).await.ok()?) * 3) as u16),
+            None => None,
+        };
+        Some(Telemetry {
+            temperature_centi: rp2040_temperature_centi(temperature),
+            vsys_mv,
+        })
+    }
+}
+
+/// Read the sensors periodically, the latest value is kept for [`latest_telemetry`].
+/// This function should never return.
+#[cfg(feature = "rp2040")]
+pub async fn run_rp2040_telemetry(mut telemetry: Rp2040Telemetry<'_>, interval: Duration) -> ! {
+    loop {
+        match telemetry.read().await {
+            Some(t) => update_telemetry(t),
+            None => defmt::warn!("Failed to read telemetry"),
+        }
+        Timer::after(interval).await;
+    }
+}
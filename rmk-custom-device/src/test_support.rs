@@ -0,0 +1,28 @@
+//! Pieces of the host unit tests: a defmt logger discarding everything, like the `minimal` build's,
+//! and a lock for the tests going through the statics of the hook chain.
+
+use std::sync::{Mutex, MutexGuard};
+
+
+#[cfg(not(feature = "minimal"))]
+#[defmt::global_logger]
+struct TestLogger;
+
+#[cfg(not(feature = "minimal"))]
+unsafe impl defmt::Logger for TestLogger {
+    fn acquire() {}
+
+    unsafe fn flush() {}
+
+    unsafe fn release() {}
+
+    unsafe fn write(_bytes: &[u8]) {}
+}
+
+static SERIAL: Mutex<()> = Mutex::new(());
+
+/// Hold it through a test using a shared static like the SOCD switch,
+/// the tests run in parallel otherwise
+pub(crate) fn serial() -> MutexGuard<'static, ()> {
+    SERIAL.lock().unwrap_or_else(|e| e.into_inner())
+}
@@ -0,0 +1,145 @@
+//! Pomodoro style countdown timer, notifying by LED, buzzer or typed text on expiry.
+//! Runs as its own task, independent of the scan loop.
+
+use embassy_futures::select::{select, Either};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Duration, Timer};
+use embedded_hal::digital::OutputPin;
+
+use crate::log::LogModule;
+use crate::log_info;
+use crate::typing::type_text;
+
+
+/// Duration of a pomodoro
+pub const POMODORO_DURATION: Duration = Duration::from_secs(25 * 60);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+enum TimerCommand {
+    Start(Duration),
+    Stop,
+}
+
+static TIMER_COMMAND: Signal<CriticalSectionRawMutex, TimerCommand> = Signal::new();
+
+/// Start or restart the timer
+pub fn start_timer(duration: Duration) {
+    TIMER_COMMAND.signal(TimerCommand::Start(duration));
+}
+
+pub fn stop_timer() {
+    TIMER_COMMAND.signal(TimerCommand::Stop);
+}
+
+
+/// Notification on timer expiry
+#[allow(async_fn_in_trait)]
+pub trait TimerNotifier {
+    async fn notify(&mut self);
+}
+
+/// Notify by both, the first one first
+impl<A: TimerNotifier, B: TimerNotifier> TimerNotifier for (A, B) {
+    async fn notify(&mut self) {
+        self.0.notify().await;
+        self.1.notify().await;
+    }
+}
+
+/// Flash an LED a few times
+pub struct LedFlashNotifier<Out: OutputPin> {
+    led: Out,
+}
+
+impl<Out: OutputPin> LedFlashNotifier<Out> {
+    pub fn new(led: Out) -> Self {
+        Self { led }
+    }
+}
+
+impl<Out: OutputPin> TimerNotifier for LedFlashNotifier<Out> {
+    async fn notify(&mut self) {
+        const FLASH_COUNT: usize = 5;
+        const FLASH_INTERVAL: Duration = Duration::from_millis(200);
+        for _ in 0..FLASH_COUNT {
+            self.led.set_high().ok();
+            Timer::after(FLASH_INTERVAL).await;
+            self.led.set_low().ok();
+            Timer::after(FLASH_INTERVAL).await;
+        }
+    }
+}
+
+/// Play a melody on a passive buzzer, driving the square wave from the timer
+pub struct BuzzerNotifier<Out: OutputPin> {
+    buzzer: Out,
+    /// (frequency in Hz, duration in ms) of each note, frequency 0 rests
+    melody: &'static [(u32, u64)],
+}
+
+impl<Out: OutputPin> BuzzerNotifier<Out> {
+    /// C5 E5 G5 C6
+    pub const DEFAULT_MELODY: &'static [(u32, u64)] = &[(523, 150), (659, 150), (784, 150), (1047, 300)];
+
+    pub fn new(buzzer: Out, melody: &'static [(u32, u64)]) -> Self {
+        Self { buzzer, melody }
+    }
+}
+
+impl<Out: OutputPin> TimerNotifier for BuzzerNotifier<Out> {
+    async fn notify(&mut self) {
+        for &(frequency, duration_ms) in self.melody {
+            if frequency == 0 {
+                Timer::after_millis(duration_ms).await;
+                continue;
+            }
+            let half_period = Duration::from_hz(frequency as u64 * 2);
+            let cycles = duration_ms * frequency as u64 / 1000;
+            for _ in 0..cycles {
+                self.buzzer.set_high().ok();
+                Timer::after(half_period).await;
+                self.buzzer.set_low().ok();
+                Timer::after(half_period).await;
+            }
+        }
+    }
+}
+
+/// Type the message to the host, into whatever window has the focus then
+pub struct TypedNotifier {
+    message: &'static str,
+}
+
+impl TypedNotifier {
+    pub fn new(message: &'static str) -> Self {
+        Self { message }
+    }
+}
+
+impl TimerNotifier for TypedNotifier {
+    async fn notify(&mut self) {
+        type_text(self.message).await;
+    }
+}
+
+
+/// Run the timer task. This function should never return.
+pub async fn run_timer<N: TimerNotifier>(mut notifier: N) -> ! {
+    let mut command = TIMER_COMMAND.wait().await;
+    loop {
+        let TimerCommand::Start(duration) = command else {
+            command = TIMER_COMMAND.wait().await;
+            continue;
+        };
+        log_info!(LogModule::Device, "Timer started: {} s", duration.as_secs());
+        match select(Timer::after(duration), TIMER_COMMAND.wait()).await {
+            Either::First(_) => {
+                log_info!(LogModule::Device, "Timer expired");
+                notifier.notify().await;
+                command = TIMER_COMMAND.wait().await;
+            }
+            // Restarted or stopped
+            Either::Second(next) => command = next,
+        }
+    }
+}
@@ -0,0 +1,126 @@
+//! TOTP codes (RFC 6238) typed by a key, making the keyboard a 2FA token.
+//!
+//! The base32 secrets shown by the sites' enrollment are kept sealed in the [vault](crate::vault), written
+//! there like any other secret. The code is computed from the [clock](crate::clock), so the host must have
+//! set it along with its UTC offset. The RP2040's RTC loses it on every power cycle, a [DS3231](crate::clock::Ds3231)
+//! keeps it.
+
+use core::fmt::Write;
+use heapless::String;
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use zeroize::Zeroize;
+
+use crate::clock::current_unix_time;
+use crate::typing::type_text;
+use crate::vault::{open_secret, SECRET_CAPACITY};
+
+
+/// Seconds per code, the usual default
+pub const TOTP_PERIOD: u64 = 30;
+/// Digits per code, the usual default
+pub const TOTP_DIGITS: u32 = 6;
+
+/// Bytes of a decoded key, 5 per 8 base32 chars
+const KEY_CAPACITY: usize = SECRET_CAPACITY * 5 / 8;
+
+/// Decode RFC 4648 base32, ignoring case, spaces, dashes and the padding.
+/// Returns the length or `None` on an invalid char.
+fn decode_base32(text: &[u8], key: &mut [u8; KEY_CAPACITY]) -> Option<usize> {
+    let (mut buffer, mut bits, mut len) = (0u16, 0u32, 0usize);
+    for &c in text {
+        let value = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            b' ' | b'-' | b'=' => continue,
+            _ => return None,
+        };
+        buffer = buffer << 5 | value as u16;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            *key.get_mut(len)? = (buffer >> bits) as u8;
+            len += 1;
+        }
+    }
+    Some(len)
+}
+
+/// HOTP (RFC 4226) of the counter with HMAC-SHA1
+fn hotp(key: &[u8], counter: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(&counter.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+    let offset = (digest[19] & 0x0F) as usize;
+    let code = u32::from_be_bytes([digest[offset], digest[offset + 1], digest[offset + 2], digest[offset + 3]]);
+    (code & 0x7FFF_FFFF) % 10u32.pow(TOTP_DIGITS)
+}
+
+/// The current code of the vault slot holding a base32 secret, `None` while locked or the clock isn't set
+pub fn current_totp(index: u8) -> Option<u32> {
+    let time = current_unix_time()?;
+    let mut text = [0u8; SECRET_CAPACITY];
+    let mut key = [0u8; KEY_CAPACITY];
+    let code = open_secret(index, &mut text)
+        .and_then(|len| decode_base32(&text[..len], &mut key))
+        .map(|len| hotp(&key[..len], time / TOTP_PERIOD));
+    text.zeroize();
+    key.zeroize();
+    code
+}
+
+/// Type the current code of the vault slot, nothing while locked or the clock isn't set
+pub async fn type_totp(index: u8) {
+    let Some(code) = current_totp(index) else {
+        defmt::warn!("No TOTP code for secret {}, the vault may be locked or the clock not set", index);
+        return;
+    };
+    let mut digits = String::<10>::new();
+    let _ = write!(digits, "{:01$}", code, TOTP_DIGITS as usize);
+    type_text(&digits).await;
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The key of the RFC 4226 and RFC 6238 test vectors
+    const RFC_KEY: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn hotp_matches_rfc4226() {
+        assert_eq!(hotp(RFC_KEY, 0), 755224);
+        assert_eq!(hotp(RFC_KEY, 1), 287082);
+        assert_eq!(hotp(RFC_KEY, 2), 359152);
+    }
+
+    #[test]
+    fn totp_matches_rfc6238() {
+        assert_eq!(hotp(RFC_KEY, 59 / TOTP_PERIOD), 287082);
+        assert_eq!(hotp(RFC_KEY, 1111111109 / TOTP_PERIOD), 81804);
+        assert_eq!(hotp(RFC_KEY, 1234567890 / TOTP_PERIOD), 5924);
+    }
+
+    #[test]
+    fn decodes_base32_secret() {
+        let mut key = [0u8; KEY_CAPACITY];
+        let len = decode_base32(b"GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ", &mut key);
+        assert_eq!(len, Some(RFC_KEY.len()));
+        assert_eq!(&key[..RFC_KEY.len()], RFC_KEY);
+    }
+
+    #[test]
+    fn base32_ignores_case_and_separators() {
+        let mut key = [0u8; KEY_CAPACITY];
+        let len = decode_base32(b"gezd-gnbv gy3t qojq gezd gnbv gy3t qojq====", &mut key);
+        assert_eq!(len, Some(RFC_KEY.len()));
+        assert_eq!(&key[..RFC_KEY.len()], RFC_KEY);
+    }
+
+    #[test]
+    fn base32_rejects_invalid_char() {
+        let mut key = [0u8; KEY_CAPACITY];
+        assert_eq!(decode_base32(b"GEZDGNBV1", &mut key), None);
+    }
+}
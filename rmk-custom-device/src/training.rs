@@ -0,0 +1,174 @@
+//! Training mode for learning a new layout: a pressed key briefly lights its LED and
+//! the OLED shows the action it resolved to on the active layer.
+
+use core::cell::Cell;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, Ordering};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Instant};
+use heapless::String;
+use rmk::action::KeyAction;
+use rmk::event::KeyEvent;
+
+use crate::event::{HookContext, KeyEventHook};
+use crate::keymap_names::{key_action_name, resolve_key_action};
+use crate::layer_state::active_layer;
+use crate::log::LogModule;
+use crate::log_info;
+use crate::oled::{draw_text, OledFrame};
+use crate::rgb::{LedMap, RgbEffect, RGB8};
+
+
+/// How long the key stays lit after its press
+const FEEDBACK_DURATION: Duration = Duration::from_millis(300);
+
+static TRAINING: AtomicBool = AtomicBool::new(false);
+/// Last press, its position, resolved action and time
+static LAST_PRESS: Mutex<CriticalSectionRawMutex, Cell<Option<(u8, u8, KeyAction, Instant)>>> =
+    Mutex::new(Cell::new(None));
+
+pub fn is_training() -> bool {
+    TRAINING.load(Ordering::Relaxed)
+}
+
+pub fn toggle_training() {
+    let training = !TRAINING.load(Ordering::Relaxed);
+    log_info!(LogModule::Action, "Training mode {}", if training { "on" } else { "off" });
+    TRAINING.store(training, Ordering::Relaxed);
+    LAST_PRESS.lock(|p| p.set(None));
+}
+
+/// Hook recording the presses and their actions while training, passing every event.
+/// The actions are looked up in the snapshot of [`register_keymap`](crate::keymap_names::register_keymap).
+pub struct TrainingHook;
+
+impl KeyEventHook for TrainingHook {
+    async fn process(&mut self, event: KeyEvent, _ctx: &mut HookContext) -> Option<KeyEvent> {
+        if event.pressed && is_training() {
+            if let Some(action) = resolve_key_action(active_layer(), event.row, event.col) {
+                LAST_PRESS.lock(|p| p.set(Some((event.row, event.col, action, Instant::now()))));
+            }
+        }
+        Some(event)
+    }
+}
+
+
+/// Effect lighting the LED of the last pressed key for a moment while training, drawing nothing otherwise.
+/// Put it on top of the regular effect.
+pub struct TrainingEffect<const N: usize> {
+    map: LedMap<N>,
+    color: RGB8,
+}
+
+impl<const N: usize> TrainingEffect<N> {
+    pub const fn new(map: LedMap<N>, color: RGB8) -> Self {
+        Self { map, color }
+    }
+}
+
+impl<const N: usize> RgbEffect<N> for TrainingEffect<N> {
+    fn render(&mut self, frame: &mut [RGB8; N], now: Instant) {
+        let Some((row, col, _, at)) = LAST_PRESS.lock(|p| p.get()) else {
+            return;
+        };
+        if !is_training() || now.duration_since(at) >= FEEDBACK_DURATION {
+            return;
+        }
+        for (pixel, position) in frame.iter_mut().zip(self.map.positions.iter()) {
+            if *position == Some((row, col)) {
+                *pixel = self.color;
+            }
+        }
+    }
+}
+
+/// Draw the action of the last pressed key and its matrix position, or a prompt before the first press
+pub fn draw_training(frame: &mut OledFrame) {
+    match LAST_PRESS.lock(|p| p.get()) {
+        Some((row, col, action, _)) => {
+            draw_text(frame, &key_action_name(&action), 0, 2, 2);
+            let mut position = String::<16>::new();
+            let _ = write!(position, "R{} C{}", row, col);
+            draw_text(frame, &position, 0, 24, 1);
+        }
+        None => draw_text(frame, "PRESS A KEY", 0, 2, 2),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use embassy_sync::channel::Channel;
+
+    use super::*;
+    use crate::event::{process_key_event, ChannelSink};
+    use crate::keymap_names::register_keymap;
+    use crate::layer_state::set_active_layer;
+    use crate::test_support::serial;
+
+    const KEYMAP: [[[KeyAction; 2]; 1]; 2] = [
+        [[crate::keymap_key!(A), crate::keymap_key!(B)]],
+        [[crate::keymap_key!(C), KeyAction::Transparent]],
+    ];
+    const COLOR: RGB8 = RGB8::new(0, 255, 0);
+
+    fn press(col: u8) -> std::vec::Vec<(u8, u8, bool)> {
+        let channel: Channel<CriticalSectionRawMutex, KeyEvent, 8> = Channel::new();
+        let event = KeyEvent { row: 0, col, pressed: true };
+        embassy_futures::block_on(process_key_event(&mut TrainingHook, &mut ChannelSink::new(&channel), event));
+        core::iter::from_fn(|| channel.try_receive().ok())
+            .map(|e| (e.row, e.col, e.pressed))
+            .collect()
+    }
+
+    /// Start training from no press
+    fn start_training() {
+        if is_training() {
+            toggle_training();
+        }
+        toggle_training();
+    }
+
+    #[test]
+    fn press_records_the_resolved_action() {
+        let _serial = serial();
+        register_keymap(&KEYMAP);
+        start_training();
+        set_active_layer(1);
+        assert_eq!(press(1), [(0, 1, true)]);
+        let (row, col, action, _) = LAST_PRESS.lock(|p| p.get()).unwrap();
+        assert_eq!((row, col), (0, 1));
+        // Through the transparent key
+        assert_eq!(action, crate::keymap_key!(B));
+        set_active_layer(0);
+        toggle_training();
+    }
+
+    #[test]
+    fn presses_are_ignored_off_training() {
+        let _serial = serial();
+        register_keymap(&KEYMAP);
+        start_training();
+        toggle_training();
+        assert_eq!(press(0), [(0, 0, true)]);
+        assert!(LAST_PRESS.lock(|p| p.get()).is_none());
+    }
+
+    #[test]
+    fn effect_lights_the_pressed_key() {
+        let _serial = serial();
+        register_keymap(&KEYMAP);
+        start_training();
+        set_active_layer(0);
+        press(1);
+        let mut effect = TrainingEffect::new(LedMap::new([Some((0, 0)), Some((0, 1))]), COLOR);
+        let mut frame = [RGB8::default(); 2];
+        effect.render(&mut frame, Instant::now());
+        assert_eq!(frame, [RGB8::default(), COLOR]);
+        let mut frame = [RGB8::default(); 2];
+        effect.render(&mut frame, Instant::now() + FEEDBACK_DURATION);
+        assert_eq!(frame, [RGB8::default(); 2]);
+        toggle_training();
+    }
+}
@@ -0,0 +1,115 @@
+use embassy_time::Timer;
+use usbd_hid::descriptor::KeyboardReport;
+
+use crate::layout::host_layout;
+use crate::output::{send_output_report, OutputReport};
+
+
+/// Interval between each report, long enough for hosts polling slowly
+const TYPING_INTERVAL_MS: u64 = 8;
+
+/// Convert an ascii char into HID usage id and whether shift is required.
+/// Assumes the host uses US layout.
+pub fn ascii_to_usage(c: char) -> Option<(u8, bool)> {
+    let usage = match c {
+        'a'..='z' => (0x04 + (c as u8 - b'a'), false),
+        'A'..='Z' => (0x04 + (c as u8 - b'A'), true),
+        '1'..='9' => (0x1E + (c as u8 - b'1'), false),
+        '0' => (0x27, false),
+        '\n' => (0x28, false),
+        '\t' => (0x2B, false),
+        ' ' => (0x2C, false),
+        '-' => (0x2D, false),
+        '=' => (0x2E, false),
+        '[' => (0x2F, false),
+        ']' => (0x30, false),
+        '\\' => (0x31, false),
+        ';' => (0x33, false),
+        '\'' => (0x34, false),
+        '`' => (0x35, false),
+        ',' => (0x36, false),
+        '.' => (0x37, false),
+        '/' => (0x38, false),
+        '!' => (0x1E, true),
+        '@' => (0x1F, true),
+        '#' => (0x20, true),
+        '$' => (0x21, true),
+        '%' => (0x22, true),
+        '^' => (0x23, true),
+        '&' => (0x24, true),
+        '*' => (0x25, true),
+        '(' => (0x26, true),
+        ')' => (0x27, true),
+        '_' => (0x2D, true),
+        '+' => (0x2E, true),
+        '{' => (0x2F, true),
+        '}' => (0x30, true),
+        '|' => (0x31, true),
+        ':' => (0x33, true),
+        '"' => (0x34, true),
+        '~' => (0x35, true),
+        '<' => (0x36, true),
+        '>' => (0x37, true),
+        '?' => (0x38, true),
+        _ => return None,
+    };
+    Some(usage)
+}
+
+async fn send_keyboard_report(modifier: u8, usage: u8) {
+    let mut report = KeyboardReport::default();
+    report.modifier = modifier;
+    report.keycodes[0] = usage;
+    send_output_report(OutputReport::Keyboard(report)).await;
+    Timer::after_millis(TYPING_INTERVAL_MS).await;
+}
+
+/// Type the char on the host layout, sending a press and a release report per keystroke.
+/// Returns false if the layout can't type it.
+pub async fn type_char(c: char) -> bool {
+    let Some(keystrokes) = host_layout().keystrokes(c) else {
+        defmt::warn!("Cannot type char {} on {}", c, host_layout());
+        return false;
+    };
+    for keystroke in keystrokes {
+        send_keyboard_report(keystroke.modifier, keystroke.usage).await;
+        send_keyboard_report(0, 0).await;
+    }
+    true
+}
+
+/// Press the char on the host layout and keep it held until [`release_keys`], e.g. for key repeat.
+/// Leading dead keys are tapped. Returns false if the layout can't type it.
+pub async fn press_char(c: char) -> bool {
+    let Some(keystrokes) = host_layout().keystrokes(c) else {
+        defmt::warn!("Cannot type char {} on {}", c, host_layout());
+        return false;
+    };
+    let Some((last, leading)) = keystrokes.split_last() else {
+        return false;
+    };
+    for keystroke in leading {
+        send_keyboard_report(keystroke.modifier, keystroke.usage).await;
+        send_keyboard_report(0, 0).await;
+    }
+    send_keyboard_report(last.modifier, last.usage).await;
+    true
+}
+
+/// Press and release the key of the HID usage id regardless of the host layout, e.g. F13-F24
+pub async fn tap_usage(usage: u8) {
+    send_keyboard_report(0, usage).await;
+    send_keyboard_report(0, 0).await;
+}
+
+/// Release everything pressed by [`press_char`]
+pub async fn release_keys() {
+    send_keyboard_report(0, 0).await;
+}
+
+/// Type the text on the host layout, chars it can't type are skipped
+pub async fn type_text(text: &str) {
+    for c in text.chars() {
+        type_char(c).await;
+    }
+}
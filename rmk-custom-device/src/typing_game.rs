@@ -0,0 +1,321 @@
+//! Reaction game on the OLED: a random key of the board is prompted, pressing it scores the reaction time.
+//! Key presses are consumed while the game runs, so nothing is typed on the host.
+//! The average of a game is published as [`DeviceEvent::TypingGameFinished`].
+
+use core::cell::RefCell;
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Instant};
+use rmk::event::KeyEvent;
+
+use crate::action::CustomKey;
+use crate::bus::{publish_device_event, DeviceEvent};
+use crate::event::{HookContext, KeyEventHook};
+use crate::layer_state::active_layer;
+use crate::log::LogModule;
+use crate::log_info;
+use crate::oled::{draw_glyph, glyph_3x5, set_pixel, OledFrame, OLED_HEIGHT, OLED_WIDTH};
+
+
+/// Prompts of a game
+pub const GAME_ROUNDS: u8 = 10;
+
+/// Width of the board drawn on the left half, the scores are on the right
+const BOARD_WIDTH: usize = OLED_WIDTH / 2;
+const SCORE_X: usize = BOARD_WIDTH + 4;
+
+#[derive(Clone, Copy)]
+struct GameState {
+    running: bool,
+    /// Matrix size of the board drawn
+    board: (u8, u8),
+    /// Key to press, `None` before the first press and after the last round
+    target: Option<(u8, u8)>,
+    prompted_at: Instant,
+    round: u8,
+    misses: u8,
+    last_reaction: Duration,
+    total_reaction: Duration,
+}
+
+impl GameState {
+    const fn new() -> Self {
+        Self {
+            running: false,
+            board: (0, 0),
+            target: None,
+            prompted_at: Instant::from_ticks(0),
+            round: 0,
+            misses: 0,
+            last_reaction: Duration::from_ticks(0),
+            total_reaction: Duration::from_ticks(0),
+        }
+    }
+
+    fn average_reaction(&self) -> Duration {
+        match self.round {
+            0 => Duration::from_ticks(0),
+            round => self.total_reaction / round as u32,
+        }
+    }
+}
+
+static GAME: Mutex<CriticalSectionRawMutex, RefCell<GameState>> = Mutex::new(RefCell::new(GameState::new()));
+
+pub fn is_typing_game_running() -> bool {
+    GAME.lock(|g| g.borrow().running)
+}
+
+/// Start a game waiting for the first press, or quit the running one
+pub fn toggle_typing_game() {
+    let running = GAME.lock(|g| {
+        let mut game = g.borrow_mut();
+        *game = GameState {
+            running: !game.running,
+            board: game.board,
+            ..GameState::new()
+        };
+        game.running
+    });
+    log_info!(LogModule::Action, "Typing game {}", if running { "started" } else { "quit" });
+}
+
+
+/// Hook consuming key presses while the game runs and prompting the keys of the matrix.
+/// Releases pass, so keys held when the game starts aren't left pressed.
+/// Put it after the custom action hook, the custom keys of the active layer are never prompted.
+pub struct TypingGameHook<const ROW: usize, const COL: usize> {
+    excluded: &'static [CustomKey],
+    layer_keys: &'static [(u8, u8, u8)],
+    random: u32,
+}
+
+impl<const ROW: usize, const COL: usize> TypingGameHook<ROW, COL> {
+    /// `layer_keys` are `(layer, row, col)` passed on to rmk instead of played, the layer keys leading to
+    /// the game's toggle key, so that the game can be quit
+    pub fn new(excluded: &'static [CustomKey], layer_keys: &'static [(u8, u8, u8)]) -> Self {
+        Self {
+            excluded,
+            layer_keys,
+            random: 0x2545_F491,
+        }
+    }
+
+    fn is_layer_key(&self, layer: u8, (row, col): (u8, u8)) -> bool {
+        self.layer_keys.contains(&(layer, row, col))
+    }
+
+    fn is_prompted(&self, position: (u8, u8), previous: Option<(u8, u8)>) -> bool {
+        let layer = active_layer();
+        Some(position) != previous
+            && !self.is_layer_key(layer, position)
+            && !self
+                .excluded
+                .iter()
+                .any(|k| (k.row, k.col) == position && k.layer.map_or(true, |l| l == layer))
+    }
+
+    /// Xorshift mixed with the press timing
+    fn next_random(&mut self, now: Instant) -> u32 {
+        let mut x = self.random ^ now.as_ticks() as u32;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.random = x;
+        x
+    }
+
+    fn next_target(&mut self, previous: Option<(u8, u8)>, now: Instant) -> Option<(u8, u8)> {
+        for _ in 0..16 {
+            let random = self.next_random(now) as usize;
+            let position = ((random % ROW) as u8, (random / ROW % COL) as u8);
+            if self.is_prompted(position, previous) {
+                return Some(position);
+            }
+        }
+        (0..ROW as u8)
+            .flat_map(|row| (0..COL as u8).map(move |col| (row, col)))
+            .find(|p| self.is_prompted(*p, previous))
+    }
+}
+
+impl<const ROW: usize, const COL: usize> KeyEventHook for TypingGameHook<ROW, COL> {
+    async fn process(&mut self, event: KeyEvent, _ctx: &mut HookContext) -> Option<KeyEvent> {
+        if !event.pressed || !is_typing_game_running() || ROW == 0 || COL == 0 {
+            return Some(event);
+        }
+        let position = (event.row, event.col);
+        if self.is_layer_key(active_layer(), position) {
+            return Some(event);
+        }
+        let now = Instant::now();
+        let finished = GAME.lock(|g| {
+            let mut game = g.borrow_mut();
+            game.board = (ROW as u8, COL as u8);
+            match game.target {
+                Some(target) if target == position => {
+                    game.last_reaction = now.duration_since(game.prompted_at);
+                    game.total_reaction += game.last_reaction;
+                    game.round += 1;
+                }
+                Some(_) => {
+                    game.misses = game.misses.saturating_add(1);
+                    return None;
+                }
+                // Any key starts the game, or a new one after the last round
+                None => {
+                    if game.round >= GAME_ROUNDS {
+                        *game = GameState {
+                            running: true,
+                            board: game.board,
+                            ..GameState::new()
+                        };
+                    }
+                }
+            }
+            if game.round >= GAME_ROUNDS {
+                game.target = None;
+                return Some(game.average_reaction());
+            }
+            game.target = self.next_target(game.target.or(Some(position)), now);
+            game.prompted_at = now;
+            None
+        });
+        if let Some(average) = finished {
+            log_info!(LogModule::Action, "Typing game finished, average {} ms", average.as_millis());
+            publish_device_event(DeviceEvent::TypingGameFinished(average.as_millis().min(u16::MAX as u64) as u16));
+        }
+        None
+    }
+}
+
+
+fn draw_rect(frame: &mut OledFrame, x: usize, y: usize, width: usize, height: usize, filled: bool) {
+    for dy in 0..height {
+        for dx in 0..width {
+            let edge = dx == 0 || dy == 0 || dx + 1 == width || dy + 1 == height;
+            if filled || edge {
+                set_pixel(frame, x + dx, y + dy, true);
+            }
+        }
+    }
+}
+
+/// Draw the number with its last digit ending at `right`, digits past the left edge are dropped
+fn draw_number(frame: &mut OledFrame, mut value: u32, right: usize, y: usize, scale: usize) {
+    let mut x = right;
+    loop {
+        let Some(left) = x.checked_sub(4 * scale) else {
+            return;
+        };
+        let digit = char::from_digit(value % 10, 10).unwrap_or('0');
+        draw_glyph(frame, glyph_3x5(digit), left, y, scale);
+        x = left;
+        value /= 10;
+        if value == 0 {
+            return;
+        }
+    }
+}
+
+/// Draw the game: the board with the prompted key filled on the left, the reaction time in ms,
+/// the misses and the progress of the rounds on the right. The board blinks while waiting for a press.
+pub fn draw_typing_game(frame: &mut OledFrame, now: Instant) {
+    let game = GAME.lock(|g| *g.borrow());
+    let (rows, cols) = (game.board.0 as usize, game.board.1 as usize);
+    let waiting = game.target.is_none();
+    let blink = now.as_millis() / 500 % 2 == 0;
+    if rows > 0 && cols > 0 {
+        let (width, height) = (BOARD_WIDTH / cols, OLED_HEIGHT / rows);
+        for row in 0..rows {
+            for col in 0..cols {
+                let filled = game.target == Some((row as u8, col as u8)) || (waiting && blink);
+                draw_rect(frame, col * width, row * height, width.max(1), height.max(1), filled);
+            }
+        }
+    } else if blink {
+        draw_rect(frame, 0, 0, BOARD_WIDTH, OLED_HEIGHT, false);
+    }
+    let reaction = if waiting { game.average_reaction() } else { game.last_reaction };
+    draw_number(frame, reaction.as_millis() as u32, OLED_WIDTH, 2, 3);
+    draw_number(frame, game.misses as u32, OLED_WIDTH, 20, 1);
+    let progress = (OLED_WIDTH - SCORE_X) * game.round as usize / GAME_ROUNDS as usize;
+    draw_rect(frame, SCORE_X, OLED_HEIGHT - 3, progress, 3, true);
+}
+
+
+#[cfg(test)]
+mod tests {
+    use embassy_sync::channel::Channel;
+
+    use super::*;
+    use crate::action::CustomAction;
+    use crate::event::{process_key_event, ChannelSink};
+    use crate::layer_state::set_active_layer;
+    use crate::test_support::serial;
+
+    /// The version key on every layer, never prompted
+    static EXCLUDED: [CustomKey; 1] = [CustomKey::new(0, 2, CustomAction::Version)];
+    /// A layer key of the base layer
+    static LAYER_KEYS: [(u8, u8, u8); 1] = [(0, 0, 1)];
+
+    fn hook() -> TypingGameHook<1, 4> {
+        TypingGameHook::new(&EXCLUDED, &LAYER_KEYS)
+    }
+
+    fn run(hook: &mut TypingGameHook<1, 4>, col: u8, pressed: bool) -> std::vec::Vec<(u8, u8, bool)> {
+        let channel: Channel<CriticalSectionRawMutex, KeyEvent, 8> = Channel::new();
+        let event = KeyEvent { row: 0, col, pressed };
+        embassy_futures::block_on(process_key_event(hook, &mut ChannelSink::new(&channel), event));
+        core::iter::from_fn(|| channel.try_receive().ok())
+            .map(|e| (e.row, e.col, e.pressed))
+            .collect()
+    }
+
+    /// A new game on the base layer
+    fn start_game() {
+        set_active_layer(0);
+        if is_typing_game_running() {
+            toggle_typing_game();
+        }
+        toggle_typing_game();
+    }
+
+    fn game() -> GameState {
+        GAME.lock(|g| *g.borrow())
+    }
+
+    #[test]
+    fn keys_pass_without_a_game() {
+        let _serial = serial();
+        start_game();
+        toggle_typing_game();
+        assert_eq!(run(&mut hook(), 3, true), [(0, 3, true)]);
+    }
+
+    #[test]
+    fn layer_keys_pass_and_are_never_prompted() {
+        let _serial = serial();
+        start_game();
+        let mut hook = hook();
+        assert!(run(&mut hook, 0, true).is_empty());
+        // Neither the pressed key, the layer key nor the excluded key
+        assert_eq!(game().target, Some((0, 3)));
+        assert_eq!(run(&mut hook, 1, true), [(0, 1, true)]);
+        assert_eq!(run(&mut hook, 0, false), [(0, 0, false)]);
+        toggle_typing_game();
+    }
+
+    #[test]
+    fn prompted_press_scores_and_others_miss() {
+        let _serial = serial();
+        start_game();
+        let mut hook = hook();
+        run(&mut hook, 0, true);
+        assert!(run(&mut hook, 0, true).is_empty());
+        assert_eq!(game().misses, 1);
+        assert!(run(&mut hook, 3, true).is_empty());
+        assert_eq!(game().round, 1);
+        assert_eq!(game().target, Some((0, 0)));
+        toggle_typing_game();
+    }
+}
@@ -0,0 +1,113 @@
+//! USB cable unplug and replug handling by VBUS sensing.
+//! rmk's USB stack re-enumerates on the bus reset after a replug; this makes sure no key is left held
+//! on either side of it and publishes the transitions.
+
+use core::cell::RefCell;
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Timer};
+use embedded_hal::digital::InputPin;
+use heapless::Vec;
+use rmk::event::KeyEvent;
+use usbd_hid::descriptor::KeyboardReport;
+
+use crate::bus::{publish_device_event, DeviceEvent};
+use crate::event::{send_key_event, HookContext, KeyEventHook};
+use crate::log::LogModule;
+use crate::log_info;
+use crate::output::{try_send_output_report, OutputReport};
+use crate::usb_power::set_vbus_present;
+
+
+/// Keys tracked as held, more simultaneous keys are not re-synced
+const MAX_HELD_KEYS: usize = 16;
+
+static HELD_KEYS: Mutex<CriticalSectionRawMutex, RefCell<Vec<(u8, u8), MAX_HELD_KEYS>>> =
+    Mutex::new(RefCell::new(Vec::new()));
+
+fn held_keys() -> Vec<(u8, u8), MAX_HELD_KEYS> {
+    HELD_KEYS.lock(|h| h.borrow().clone())
+}
+
+/// Hook tracking the held keys, forwards every event.
+/// Put it last in the hook chain, the keys are resent straight to rmk at the positions it recorded.
+pub struct HeldKeysHook;
+
+impl KeyEventHook for HeldKeysHook {
+    async fn process(&mut self, event: KeyEvent, _ctx: &mut HookContext) -> Option<KeyEvent> {
+        let position = (event.row, event.col);
+        HELD_KEYS.lock(|h| {
+            let mut h = h.borrow_mut();
+            if event.pressed {
+                if !h.contains(&position) {
+                    let _ = h.push(position);
+                }
+            } else {
+                h.retain(|p| *p != position);
+            }
+        });
+        Some(event)
+    }
+}
+
+async fn resend_held_keys(pressed: bool) {
+    for (row, col) in held_keys() {
+        send_key_event(KeyEvent { row, col, pressed }).await;
+    }
+}
+
+
+/// Watch the VBUS sense pin, e.g. GPIO24 on Pico.
+/// On unplug every held key is released in rmk and an empty report is queued, so that nothing repeats
+/// after the replug. Keys still held on replug are pressed again. This function should never return.
+pub async fn run_vbus_monitor<In: InputPin>(mut vbus: In, interval: Duration) -> ! {
+    let mut connected = vbus.is_high().unwrap_or(true);
+    set_vbus_present(connected);
+    loop {
+        Timer::after(interval).await;
+        let Ok(now_connected) = vbus.is_high() else {
+            continue;
+        };
+        if now_connected == connected {
+            continue;
+        }
+        connected = now_connected;
+        log_info!(LogModule::Device, "USB VBUS: {}", connected);
+        set_vbus_present(connected);
+        publish_device_event(DeviceEvent::UsbConnection(connected));
+        if connected {
+            resend_held_keys(true).await;
+        } else {
+            resend_held_keys(false).await;
+            try_send_output_report(OutputReport::Keyboard(KeyboardReport::default()));
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::serial;
+
+    fn run(row: u8, col: u8, pressed: bool) {
+        let event = KeyEvent { row, col, pressed };
+        let forwarded = embassy_futures::block_on(HeldKeysHook.process(event, &mut HookContext::new()));
+        assert_eq!(forwarded.map(|e| (e.row, e.col, e.pressed)), Some((row, col, pressed)));
+    }
+
+    #[test]
+    fn held_keys_are_tracked_once() {
+        let _serial = serial();
+        HELD_KEYS.lock(|h| h.borrow_mut().clear());
+        run(0, 1, true);
+        run(1, 0, true);
+        run(0, 1, true);
+        assert_eq!(held_keys(), [(0, 1), (1, 0)]);
+        run(0, 1, false);
+        assert_eq!(held_keys(), [(1, 0)]);
+        // A release of a key pressed before the hook saw it
+        run(1, 1, false);
+        run(1, 0, false);
+        assert!(held_keys().is_empty());
+    }
+}
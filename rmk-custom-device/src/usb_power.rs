@@ -0,0 +1,270 @@
+//! USB power attributes and the power state of the USB port.
+//!
+//! rmk builds the USB device itself, so [`PowerAwareDriver`] wraps the driver handed to it: the configuration
+//! descriptor and the device status report the configured power source, and the bus events tell
+//! a host apart from a charger which only supplies VBUS.
+
+use embassy_time::{Duration, Instant, Timer};
+use embassy_usb_driver::{
+    Bus, ControlPipe, Driver, EndpointAddress, EndpointAllocError, EndpointError, EndpointType, Event, Unsupported,
+};
+use portable_atomic::{AtomicBool, AtomicU64, Ordering};
+
+use crate::bus::{publish_device_event, DeviceEvent};
+use crate::log::LogModule;
+use crate::log_info;
+
+
+/// VBUS without a bus reset for longer is from a charger
+const ENUMERATION_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Power source reported to the host
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct UsbPowerConfig {
+    /// Powered by a battery or another supply, not only by VBUS
+    pub self_powered: bool,
+    /// Current drawn from VBUS, up to 500
+    pub max_power_ma: u16,
+}
+
+impl UsbPowerConfig {
+    pub const fn bus_powered(max_power_ma: u16) -> Self {
+        Self {
+            self_powered: false,
+            max_power_ma,
+        }
+    }
+
+    pub const fn self_powered(max_power_ma: u16) -> Self {
+        Self {
+            self_powered: true,
+            max_power_ma,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum UsbPowerState {
+    /// No VBUS, running from the battery
+    Unpowered,
+    /// VBUS is present, waiting for a host
+    Enumerating,
+    /// VBUS is present but no host showed up, e.g. a charger
+    ChargingOnly,
+    Connected,
+}
+
+static VBUS_PRESENT: AtomicBool = AtomicBool::new(false);
+static VBUS_SINCE_MS: AtomicU64 = AtomicU64::new(0);
+static HOST_PRESENT: AtomicBool = AtomicBool::new(false);
+static HOST_SUSPENDED: AtomicBool = AtomicBool::new(false);
+/// Whether a VBUS sense pin reports VBUS, overriding the driver's power events
+static VBUS_PIN: AtomicBool = AtomicBool::new(false);
+
+/// Record VBUS read from a sense pin.
+/// Drivers like RP2040's report power regardless of VBUS without it.
+pub fn set_vbus_present(present: bool) {
+    VBUS_PIN.store(true, Ordering::Relaxed);
+    update_vbus(present);
+}
+
+fn update_vbus(present: bool) {
+    if VBUS_PRESENT.swap(present, Ordering::Relaxed) != present {
+        VBUS_SINCE_MS.store(Instant::now().as_millis(), Ordering::Relaxed);
+        if !present {
+            HOST_PRESENT.store(false, Ordering::Relaxed);
+            HOST_SUSPENDED.store(false, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Whether the host suspended the bus, e.g. sleeping
+pub fn is_host_suspended() -> bool {
+    HOST_SUSPENDED.load(Ordering::Relaxed)
+}
+
+pub fn usb_power_state() -> UsbPowerState {
+    if !VBUS_PRESENT.load(Ordering::Relaxed) {
+        UsbPowerState::Unpowered
+    } else if HOST_PRESENT.load(Ordering::Relaxed) {
+        UsbPowerState::Connected
+    } else if Instant::now().as_millis() - VBUS_SINCE_MS.load(Ordering::Relaxed) < ENUMERATION_TIMEOUT.as_millis() {
+        UsbPowerState::Enumerating
+    } else {
+        UsbPowerState::ChargingOnly
+    }
+}
+
+/// Publish changes of the power state to the device event bus. This function should never return.
+pub async fn run_usb_power_monitor(interval: Duration) -> ! {
+    let mut last_state = None;
+    loop {
+        let state = usb_power_state();
+        if last_state != Some(state) {
+            log_info!(LogModule::Device, "USB power: {}", state);
+            last_state = Some(state);
+            publish_device_event(DeviceEvent::UsbPower(state));
+        }
+        Timer::after(interval).await;
+    }
+}
+
+
+/// USB driver reporting the power attributes and tracking the power state
+pub struct PowerAwareDriver<D> {
+    inner: D,
+    config: UsbPowerConfig,
+}
+
+impl<D> PowerAwareDriver<D> {
+    pub fn new(inner: D, config: UsbPowerConfig) -> Self {
+        Self { inner, config }
+    }
+}
+
+impl<'a, D: Driver<'a>> Driver<'a> for PowerAwareDriver<D> {
+    type EndpointOut = D::EndpointOut;
+    type EndpointIn = D::EndpointIn;
+    type ControlPipe = PowerAwareControlPipe<D::ControlPipe>;
+    type Bus = PowerAwareBus<D::Bus>;
+
+    fn alloc_endpoint_out(
+        &mut self,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<Self::EndpointOut, EndpointAllocError> {
+        self.inner.alloc_endpoint_out(ep_type, max_packet_size, interval_ms)
+    }
+
+    fn alloc_endpoint_in(
+        &mut self,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<Self::EndpointIn, EndpointAllocError> {
+        self.inner.alloc_endpoint_in(ep_type, max_packet_size, interval_ms)
+    }
+
+    fn start(self, control_max_packet_size: u16) -> (Self::Bus, Self::ControlPipe) {
+        let (bus, control) = self.inner.start(control_max_packet_size);
+        let control = PowerAwareControlPipe {
+            inner: control,
+            config: self.config,
+            request: [0; 8],
+        };
+        (PowerAwareBus { inner: bus }, control)
+    }
+}
+
+pub struct PowerAwareBus<B> {
+    inner: B,
+}
+
+impl<B: Bus> Bus for PowerAwareBus<B> {
+    async fn enable(&mut self) {
+        self.inner.enable().await
+    }
+
+    async fn disable(&mut self) {
+        self.inner.disable().await
+    }
+
+    async fn poll(&mut self) -> Event {
+        let event = self.inner.poll().await;
+        match event {
+            Event::PowerDetected | Event::PowerRemoved if !VBUS_PIN.load(Ordering::Relaxed) => {
+                update_vbus(matches!(event, Event::PowerDetected))
+            }
+            Event::Reset => HOST_PRESENT.store(true, Ordering::Relaxed),
+            Event::Suspend => HOST_SUSPENDED.store(true, Ordering::Relaxed),
+            Event::Resume => HOST_SUSPENDED.store(false, Ordering::Relaxed),
+            _ => {}
+        }
+        event
+    }
+
+    fn endpoint_set_enabled(&mut self, ep_addr: EndpointAddress, enabled: bool) {
+        self.inner.endpoint_set_enabled(ep_addr, enabled)
+    }
+
+    fn endpoint_set_stalled(&mut self, ep_addr: EndpointAddress, stalled: bool) {
+        self.inner.endpoint_set_stalled(ep_addr, stalled)
+    }
+
+    fn endpoint_is_stalled(&mut self, ep_addr: EndpointAddress) -> bool {
+        self.inner.endpoint_is_stalled(ep_addr)
+    }
+
+    async fn remote_wakeup(&mut self) -> Result<(), Unsupported> {
+        self.inner.remote_wakeup().await
+    }
+}
+
+/// Control pipe patching the power attributes into the responses of the last setup request
+pub struct PowerAwareControlPipe<C> {
+    inner: C,
+    config: UsbPowerConfig,
+    request: [u8; 8],
+}
+
+impl<C: ControlPipe> PowerAwareControlPipe<C> {
+    const GET_STATUS: u8 = 0x00;
+    const GET_DESCRIPTOR: u8 = 0x06;
+    const DESCRIPTOR_CONFIGURATION: u8 = 0x02;
+    const SELF_POWERED: u8 = 0x40;
+
+    /// Device to host standard request to the device
+    fn is_device_request(&self, request: u8) -> bool {
+        self.request[0] == 0x80 && self.request[1] == request
+    }
+}
+
+impl<C: ControlPipe> ControlPipe for PowerAwareControlPipe<C> {
+    fn max_packet_size(&self) -> usize {
+        self.inner.max_packet_size()
+    }
+
+    async fn setup(&mut self) -> [u8; 8] {
+        self.request = self.inner.setup().await;
+        self.request
+    }
+
+    async fn data_out(&mut self, buf: &mut [u8], first: bool, last: bool) -> Result<usize, EndpointError> {
+        self.inner.data_out(buf, first, last).await
+    }
+
+    async fn data_in(&mut self, data: &[u8], first: bool, last: bool) -> Result<(), EndpointError> {
+        let mut patched = [0u8; 64];
+        if !first || data.len() > patched.len() {
+            return self.inner.data_in(data, first, last).await;
+        }
+        let patched = &mut patched[..data.len()];
+        patched.copy_from_slice(data);
+        let self_powered = if self.config.self_powered { Self::SELF_POWERED } else { 0 };
+        if self.is_device_request(Self::GET_DESCRIPTOR)
+            && self.request[3] == Self::DESCRIPTOR_CONFIGURATION
+            && patched.len() >= 9
+        {
+            // bmAttributes and bMaxPower in 2mA units
+            patched[7] = (patched[7] & !Self::SELF_POWERED) | self_powered;
+            patched[8] = (self.config.max_power_ma.min(500) / 2) as u8;
+        } else if self.is_device_request(Self::GET_STATUS) && !patched.is_empty() {
+            // Bit 0 of the device status
+            patched[0] = (patched[0] & !0x01) | self.config.self_powered as u8;
+        }
+        self.inner.data_in(patched, first, last).await
+    }
+
+    async fn accept(&mut self) {
+        self.inner.accept().await
+    }
+
+    async fn reject(&mut self) {
+        self.inner.reject().await
+    }
+
+    async fn accept_set_address(&mut self, addr: u8) {
+        self.inner.accept_set_address(addr).await
+    }
+}
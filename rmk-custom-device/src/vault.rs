@@ -0,0 +1,623 @@
+//! Secret vault typing passwords bound to keys, never storing them in plaintext.
+//!
+//! Secrets are sealed with XChaCha20-Poly1305 under a key derived from an unlock combo, a sequence of key
+//! presses on the keyboard itself. The key only lives in RAM while unlocked, and the vault locks again after
+//! a while without key events. The first combo entered on an empty vault becomes its combo.
+//!
+//! Each press of the combo counts along with how many of its keys are still held, so a chord, a roll and
+//! the same keys one by one make different combos: `n` keys give `n²` symbols per press, 2^72 combos of
+//! 12 presses on 8 keys. That's still less than a good password: the key derivation is slowed down, but it
+//! doesn't stand against someone dumping the flash and trying combos for long. Forgetting the combo loses the secrets.
+
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicBool, Ordering};
+use chacha20poly1305::aead::{AeadInPlace, KeyInit};
+use chacha20poly1305::{Key, Tag, XChaCha20Poly1305, XNonce};
+use embassy_futures::yield_now;
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_sync::channel::Channel;
+use embassy_time::{Duration, Timer};
+use heapless::Vec;
+use rmk::event::KeyEvent;
+use sha2::{Digest, Sha256};
+use zeroize::Zeroize;
+
+use crate::event::{idle_time, HookContext, KeyEventHook};
+use crate::log::LogModule;
+use crate::quiesce::quiesce_flash;
+use crate::reserved::{read_reserved, write_reserved_sector};
+use crate::typing::type_text;
+use crate::{log_info, log_warn};
+
+
+/// Raw HID command id of the vault, `[VAULT_COMMAND, subcommand, ...]`, every response is `[.., status, ...]`
+pub const VAULT_COMMAND: u8 = 0xF0;
+/// `[VAULT_COMMAND, VAULT_STATUS]`, responds `[.., status, initialized, unlocked]`
+pub const VAULT_STATUS: u8 = 0;
+/// `[VAULT_COMMAND, VAULT_WRITE, offset, count, bytes...]`, stage the plaintext of a secret, only while unlocked
+pub const VAULT_WRITE: u8 = 1;
+/// `[VAULT_COMMAND, VAULT_SEAL, index]`, seal the staged plaintext into the slot and persist, wiping the stage
+pub const VAULT_SEAL: u8 = 2;
+/// `[VAULT_COMMAND, VAULT_CLEAR, index]`, empty the slot and persist, only while unlocked
+pub const VAULT_CLEAR: u8 = 3;
+
+const STATUS_OK: u8 = 0;
+const STATUS_LOCKED: u8 = 1;
+const STATUS_OUT_OF_RANGE: u8 = 2;
+const STATUS_BUSY: u8 = 3;
+
+pub const MAX_SECRETS: usize = 4;
+/// Bytes of UTF-8 text per secret
+pub const SECRET_CAPACITY: usize = 64;
+/// Longest unlock combo
+pub const MAX_COMBO_LEN: usize = 16;
+
+/// Rounds of the key derivation, around a second on RP2040
+const KDF_ROUNDS: u32 = 20_000;
+/// Rounds between the yields of the key derivation, a fraction of a millisecond
+const KDF_YIELD_ROUNDS: u32 = 16;
+/// Plaintext of the check slot, which tells a wrong combo apart
+const CHECK_TEXT: &[u8] = b"dflipdaisy vault";
+const VAULT_MAGIC: u32 = 0x544C_5656; // "VVLT"
+const AUTO_LOCK_POLL: Duration = Duration::from_secs(1);
+
+/// Presses of the combo, `(row, col, combo keys held with it)`
+type Combo = Vec<(u8, u8, u8), MAX_COMBO_LEN>;
+
+#[derive(Clone, Copy)]
+struct Sealed {
+    nonce: [u8; 24],
+    tag: [u8; 16],
+    /// Plaintext length, 0 for an empty slot
+    len: u8,
+    ciphertext: [u8; SECRET_CAPACITY],
+}
+
+impl Sealed {
+    const SIZE: usize = 24 + 16 + 1 + SECRET_CAPACITY;
+    const EMPTY: Self = Self {
+        nonce: [0; 24],
+        tag: [0; 16],
+        len: 0,
+        ciphertext: [0; SECRET_CAPACITY],
+    };
+
+    fn seal(key: &[u8; 32], nonce: [u8; 24], slot: u8, plaintext: &[u8]) -> Option<Self> {
+        let mut sealed = Self {
+            nonce,
+            len: plaintext.len() as u8,
+            ..Self::EMPTY
+        };
+        let buffer = &mut sealed.ciphertext[..plaintext.len()];
+        buffer.copy_from_slice(plaintext);
+        let cipher = XChaCha20Poly1305::new(Key::from_slice(key));
+        let tag = cipher.encrypt_in_place_detached(XNonce::from_slice(&nonce), &[slot], buffer).ok()?;
+        sealed.tag.copy_from_slice(&tag);
+        Some(sealed)
+    }
+
+    /// Decrypt into `plaintext`, returns the length or `None` if the key or the slot is wrong
+    fn open(&self, key: &[u8; 32], slot: u8, plaintext: &mut [u8; SECRET_CAPACITY]) -> Option<usize> {
+        let len = (self.len as usize).min(SECRET_CAPACITY);
+        plaintext[..len].copy_from_slice(&self.ciphertext[..len]);
+        let cipher = XChaCha20Poly1305::new(Key::from_slice(key));
+        let tag = Tag::from_slice(&self.tag);
+        match cipher.decrypt_in_place_detached(XNonce::from_slice(&self.nonce), &[slot], &mut plaintext[..len], tag) {
+            Ok(()) => Some(len),
+            Err(_) => {
+                plaintext.zeroize();
+                None
+            }
+        }
+    }
+
+    fn write_bytes(&self, bytes: &mut [u8]) {
+        bytes[0..24].copy_from_slice(&self.nonce);
+        bytes[24..40].copy_from_slice(&self.tag);
+        bytes[40] = self.len;
+        bytes[41..Self::SIZE].copy_from_slice(&self.ciphertext);
+    }
+
+    fn read_bytes(bytes: &[u8]) -> Self {
+        let mut sealed = Self::EMPTY;
+        sealed.nonce.copy_from_slice(&bytes[0..24]);
+        sealed.tag.copy_from_slice(&bytes[24..40]);
+        sealed.len = bytes[40].min(SECRET_CAPACITY as u8);
+        sealed.ciphertext.copy_from_slice(&bytes[41..Self::SIZE]);
+        sealed
+    }
+}
+
+/// Slot of the check text in the associated data, after the secrets
+const CHECK_SLOT: u8 = MAX_SECRETS as u8;
+
+/// Bytes of the persisted vault, `[magic (u32 LE), salt, check, secrets...]`
+const VAULT_SIZE: usize = 4 + 16 + (1 + MAX_SECRETS) * Sealed::SIZE;
+
+#[derive(Clone, Copy)]
+struct SealedStore {
+    salt: [u8; 16],
+    check: Sealed,
+    secrets: [Sealed; MAX_SECRETS],
+}
+
+impl SealedStore {
+    fn to_bytes(&self) -> [u8; VAULT_SIZE] {
+        let mut bytes = [0u8; VAULT_SIZE];
+        bytes[0..4].copy_from_slice(&VAULT_MAGIC.to_le_bytes());
+        bytes[4..20].copy_from_slice(&self.salt);
+        for (sealed, chunk) in core::iter::once(&self.check)
+            .chain(self.secrets.iter())
+            .zip(bytes[20..].chunks_exact_mut(Sealed::SIZE))
+        {
+            sealed.write_bytes(chunk);
+        }
+        bytes
+    }
+
+    /// Returns `None` if the bytes aren't a vault, e.g. erased
+    fn from_bytes(bytes: &[u8; VAULT_SIZE]) -> Option<Self> {
+        if bytes[0..4] != VAULT_MAGIC.to_le_bytes() {
+            return None;
+        }
+        let mut chunks = bytes[20..].chunks_exact(Sealed::SIZE).map(Sealed::read_bytes);
+        let mut store = Self {
+            salt: [0; 16],
+            check: chunks.next()?,
+            secrets: [Sealed::EMPTY; MAX_SECRETS],
+        };
+        store.salt.copy_from_slice(&bytes[4..20]);
+        for (secret, sealed) in store.secrets.iter_mut().zip(chunks) {
+            *secret = sealed;
+        }
+        Some(store)
+    }
+}
+
+struct VaultState {
+    store: Option<SealedStore>,
+    key: Option<[u8; 32]>,
+    staged: [u8; SECRET_CAPACITY],
+    staged_len: usize,
+}
+
+impl VaultState {
+    fn lock(&mut self) {
+        if let Some(key) = self.key.as_mut() {
+            key.zeroize();
+        }
+        self.key = None;
+        self.staged.zeroize();
+        self.staged_len = 0;
+    }
+}
+
+enum VaultRequest {
+    Unlock(Combo),
+    Seal(u8),
+    Clear(u8),
+}
+
+static VAULT: Mutex<CriticalSectionRawMutex, RefCell<VaultState>> = Mutex::new(RefCell::new(VaultState {
+    store: None,
+    key: None,
+    staged: [0; SECRET_CAPACITY],
+    staged_len: 0,
+}));
+static VAULT_REQUESTS: Channel<CriticalSectionRawMutex, VaultRequest, 2> = Channel::new();
+/// Whether the next key presses are the unlock combo
+static COMBO_ENTRY: AtomicBool = AtomicBool::new(false);
+
+pub fn is_vault_unlocked() -> bool {
+    VAULT.lock(|v| v.borrow().key.is_some())
+}
+
+/// Take the next key presses as the unlock combo, see [`VaultHook`]
+pub fn start_vault_unlock() {
+    log_info!(LogModule::Action, "Enter the vault combo");
+    COMBO_ENTRY.store(true, Ordering::Relaxed);
+}
+
+/// Wipe the key and the staged plaintext from RAM
+pub fn lock_vault() {
+    if VAULT.lock(|v| {
+        let mut v = v.borrow_mut();
+        let unlocked = v.key.is_some();
+        v.lock();
+        unlocked
+    }) {
+        log_info!(LogModule::Action, "Vault locked");
+    }
+}
+
+/// Decrypt the secret of the slot into `plaintext`, returns its length or `None` while locked.
+/// Zeroize the plaintext once used.
+pub fn open_secret(index: u8, plaintext: &mut [u8; SECRET_CAPACITY]) -> Option<usize> {
+    VAULT.lock(|v| {
+        let v = v.borrow();
+        let (Some(key), Some(store)) = (v.key.as_ref(), v.store.as_ref()) else {
+            return None;
+        };
+        store.secrets.get(index as usize)?.open(key, index, plaintext)
+    })
+}
+
+/// Type the secret of the slot on the host layout, nothing while locked
+pub async fn type_secret(index: u8) {
+    let mut plaintext = [0u8; SECRET_CAPACITY];
+    match open_secret(index, &mut plaintext) {
+        Some(len) => {
+            if let Ok(text) = core::str::from_utf8(&plaintext[..len]) {
+                type_text(text).await;
+            }
+        }
+        None => defmt::warn!("Secret {} isn't available, the vault may be locked", index),
+    }
+    plaintext.zeroize();
+}
+
+/// Answer a vault command in place.
+/// Returns false if the report isn't a vault command.
+pub fn handle_vault_command(report: &mut [u8]) -> bool {
+    if report.len() < 5 || report[0] != VAULT_COMMAND {
+        return false;
+    }
+    let (initialized, unlocked) = VAULT.lock(|v| {
+        let v = v.borrow();
+        (v.store.is_some(), v.key.is_some())
+    });
+    let status = match report[1] {
+        VAULT_STATUS => {
+            report[2..].fill(0);
+            report[2] = STATUS_OK;
+            report[3] = initialized as u8;
+            report[4] = unlocked as u8;
+            return true;
+        }
+        _ if !unlocked => STATUS_LOCKED,
+        VAULT_WRITE => {
+            let (offset, count) = (report[2] as usize, (report[3] as usize).min(report.len() - 4));
+            if offset + count > SECRET_CAPACITY {
+                STATUS_OUT_OF_RANGE
+            } else {
+                VAULT.lock(|v| {
+                    let mut v = v.borrow_mut();
+                    v.staged[offset..offset + count].copy_from_slice(&report[4..4 + count]);
+                    v.staged_len = offset + count;
+                });
+                STATUS_OK
+            }
+        }
+        VAULT_SEAL | VAULT_CLEAR if report[2] as usize >= MAX_SECRETS => STATUS_OUT_OF_RANGE,
+        VAULT_SEAL | VAULT_CLEAR => {
+            let request = if report[1] == VAULT_SEAL {
+                VaultRequest::Seal(report[2])
+            } else {
+                VaultRequest::Clear(report[2])
+            };
+            match VAULT_REQUESTS.try_send(request) {
+                Ok(()) => STATUS_OK,
+                Err(_) => STATUS_BUSY,
+            }
+        }
+        _ => return false,
+    };
+    // Plaintext written by the host isn't echoed back
+    report[2..].fill(0);
+    report[2] = status;
+    true
+}
+
+
+/// Hook taking the key presses after [`start_vault_unlock`] as the unlock combo, consuming them and their releases.
+/// Put it before the hooks acting on the keys, e.g. custom actions.
+pub struct VaultHook {
+    combo_len: usize,
+    combo: Combo,
+    /// Keys whose presses were consumed, waiting for their releases
+    consumed: Vec<(u8, u8), MAX_COMBO_LEN>,
+}
+
+impl VaultHook {
+    /// `combo_len` presses make the combo, at most [`MAX_COMBO_LEN`]
+    pub fn new(combo_len: usize) -> Self {
+        Self {
+            combo_len: combo_len.clamp(1, MAX_COMBO_LEN),
+            combo: Vec::new(),
+            consumed: Vec::new(),
+        }
+    }
+}
+
+impl KeyEventHook for VaultHook {
+    async fn process(&mut self, event: KeyEvent, _ctx: &mut HookContext) -> Option<KeyEvent> {
+        let position = (event.row, event.col);
+        if !event.pressed {
+            return match self.consumed.iter().position(|p| *p == position) {
+                Some(i) => {
+                    self.consumed.swap_remove(i);
+                    None
+                }
+                None => Some(event),
+            };
+        }
+        if !COMBO_ENTRY.load(Ordering::Relaxed) {
+            return Some(event);
+        }
+        // The keys held with it are the combo keys not released yet
+        let held = self.consumed.len() as u8;
+        let _ = self.combo.push((event.row, event.col, held));
+        if !self.consumed.contains(&position) {
+            let _ = self.consumed.push(position);
+        }
+        if self.combo.len() >= self.combo_len {
+            COMBO_ENTRY.store(false, Ordering::Relaxed);
+            let combo = core::mem::take(&mut self.combo);
+            if VAULT_REQUESTS.try_send(VaultRequest::Unlock(combo)).is_err() {
+                defmt::warn!("Vault busy, combo dropped");
+            }
+        }
+        None
+    }
+}
+
+
+/// Random source of the salt and the nonces
+pub trait VaultRng {
+    fn fill(&mut self, bytes: &mut [u8]);
+}
+
+/// RP2040's ring oscillator random bit, whitened through SHA-256
+pub struct Rp2040RoscRng;
+
+impl VaultRng for Rp2040RoscRng {
+    fn fill(&mut self, bytes: &mut [u8]) {
+        for chunk in bytes.chunks_mut(32) {
+            let mut hasher = Sha256::new();
+            // 4 raw bits per output bit, the ROSC bit is biased
+            for _ in 0..chunk.len() * 4 {
+                let mut byte = 0u8;
+                for _ in 0..8 {
+                    byte = byte << 1 | embassy_rp::pac::ROSC.randombit().read().randombit() as u8;
+                }
+                hasher.update([byte]);
+            }
+            let digest = hasher.finalize();
+            chunk.copy_from_slice(&digest[..chunk.len()]);
+        }
+    }
+}
+
+/// Stretch the combo into the key, yielding now and then since it takes a while
+async fn derive_key(salt: &[u8; 16], combo: &Combo) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(salt);
+    for (row, col, held) in combo {
+        hasher.update([*row, *col, *held]);
+    }
+    let mut key: [u8; 32] = hasher.finalize().into();
+    for round in 0..KDF_ROUNDS {
+        let mut hasher = Sha256::new();
+        hasher.update(key);
+        hasher.update(salt);
+        key = hasher.finalize().into();
+        if round % KDF_YIELD_ROUNDS == 0 {
+            yield_now().await;
+        }
+    }
+    key
+}
+
+async fn unlock<R: VaultRng>(rng: &mut R, mut combo: Combo) -> Option<SealedStore> {
+    let store = VAULT.lock(|v| v.borrow().store);
+    let salt = match store.as_ref() {
+        Some(store) => store.salt,
+        None => {
+            let mut salt = [0u8; 16];
+            rng.fill(&mut salt);
+            salt
+        }
+    };
+    let mut key = derive_key(&salt, &combo).await;
+    for (row, col, held) in combo.iter_mut() {
+        row.zeroize();
+        col.zeroize();
+        held.zeroize();
+    }
+    let (unlocked, created) = match store {
+        Some(store) => {
+            let mut check = [0u8; SECRET_CAPACITY];
+            let unlocked = store.check.open(&key, CHECK_SLOT, &mut check) == Some(CHECK_TEXT.len());
+            (unlocked && check[..CHECK_TEXT.len()] == *CHECK_TEXT, None)
+        }
+        None => {
+            let mut nonce = [0u8; 24];
+            rng.fill(&mut nonce);
+            let created = Sealed::seal(&key, nonce, CHECK_SLOT, CHECK_TEXT).map(|check| SealedStore {
+                salt,
+                check,
+                secrets: [Sealed::EMPTY; MAX_SECRETS],
+            });
+            (created.is_some(), created)
+        }
+    };
+    if unlocked {
+        log_info!(LogModule::Action, "Vault unlocked");
+        VAULT.lock(|v| {
+            let mut v = v.borrow_mut();
+            v.key = Some(key);
+            if created.is_some() {
+                v.store = created;
+            }
+        });
+    } else {
+        log_warn!(LogModule::Action, "Wrong vault combo");
+    }
+    key.zeroize();
+    created
+}
+
+/// Seal the staged plaintext into the slot, or empty it. Returns the store to persist.
+fn update_slot<R: VaultRng>(rng: &mut R, index: u8, seal: bool) -> Option<SealedStore> {
+    let mut nonce = [0u8; 24];
+    rng.fill(&mut nonce);
+    VAULT.lock(|v| {
+        let mut v = v.borrow_mut();
+        let v = &mut *v;
+        // Borrowed, a copy of the key would stay on the stack
+        let key = v.key.as_ref()?;
+        let sealed = if seal {
+            let sealed = Sealed::seal(key, nonce, index, &v.staged[..v.staged_len]);
+            v.staged.zeroize();
+            v.staged_len = 0;
+            sealed?
+        } else {
+            Sealed::EMPTY
+        };
+        let store = v.store.as_mut()?;
+        *store.secrets.get_mut(index as usize)? = sealed;
+        Some(*store)
+    })
+}
+
+/// Load the vault from the reserved sector at `offset`, unlock it by the entered combos, and seal the secrets
+/// written over raw HID, persisting them there. This function should never return.
+pub async fn run_secret_vault<R: VaultRng, const FLASH_SIZE: usize>(mut rng: R, offset: u32) -> ! {
+    {
+        let mut bytes = [0u8; VAULT_SIZE];
+        if quiesce_flash(async { read_reserved::<FLASH_SIZE>(offset, &mut bytes) }).await.is_ok() {
+            let store = SealedStore::from_bytes(&bytes);
+            VAULT.lock(|v| v.borrow_mut().store = store);
+        }
+    }
+    loop {
+        let store = match VAULT_REQUESTS.receive().await {
+            VaultRequest::Unlock(combo) => unlock(&mut rng, combo).await,
+            VaultRequest::Seal(index) => update_slot(&mut rng, index, true),
+            VaultRequest::Clear(index) => update_slot(&mut rng, index, false),
+        };
+        if let Some(store) = store {
+            let bytes = store.to_bytes();
+            if let Err(e) = quiesce_flash(async { write_reserved_sector::<FLASH_SIZE>(offset, &bytes) }).await {
+                defmt::warn!("Failed to save the vault: {}", e);
+            }
+        }
+    }
+}
+
+/// Lock the vault after `timeout` without key events. This function should never return.
+pub async fn run_vault_auto_lock(timeout: Duration) -> ! {
+    loop {
+        Timer::after(AUTO_LOCK_POLL).await;
+        if idle_time() >= timeout {
+            lock_vault();
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Counting bytes, the tests only need distinct nonces
+    struct CountingRng(u8);
+
+    impl VaultRng for CountingRng {
+        fn fill(&mut self, bytes: &mut [u8]) {
+            for byte in bytes {
+                self.0 = self.0.wrapping_add(1);
+                *byte = self.0;
+            }
+        }
+    }
+
+    fn combo(presses: &[(u8, u8, u8)]) -> Combo {
+        Vec::from_slice(presses).unwrap()
+    }
+
+    fn reset_vault() {
+        VAULT.lock(|v| {
+            let mut v = v.borrow_mut();
+            v.lock();
+            v.store = None;
+        });
+    }
+
+    #[test]
+    fn sealed_opens_with_its_key_and_slot_only() {
+        let key = [7u8; 32];
+        let sealed = Sealed::seal(&key, [1; 24], 2, b"hunter2").unwrap();
+        assert_ne!(&sealed.ciphertext[..7], b"hunter2");
+        let mut plaintext = [0u8; SECRET_CAPACITY];
+        assert_eq!(sealed.open(&key, 2, &mut plaintext), Some(7));
+        assert_eq!(&plaintext[..7], b"hunter2");
+
+        let mut plaintext = [0u8; SECRET_CAPACITY];
+        assert_eq!(sealed.open(&[8u8; 32], 2, &mut plaintext), None);
+        assert_eq!(plaintext, [0u8; SECRET_CAPACITY]);
+        // Moved to another slot
+        assert_eq!(sealed.open(&key, 3, &mut plaintext), None);
+        let mut tampered = sealed;
+        tampered.ciphertext[0] ^= 1;
+        assert_eq!(tampered.open(&key, 2, &mut plaintext), None);
+    }
+
+    #[test]
+    fn store_bytes_roundtrip() {
+        let key = [3u8; 32];
+        let mut store = SealedStore {
+            salt: [5; 16],
+            check: Sealed::seal(&key, [1; 24], CHECK_SLOT, CHECK_TEXT).unwrap(),
+            secrets: [Sealed::EMPTY; MAX_SECRETS],
+        };
+        store.secrets[1] = Sealed::seal(&key, [2; 24], 1, b"secret").unwrap();
+        let loaded = SealedStore::from_bytes(&store.to_bytes()).unwrap();
+        assert_eq!(loaded.salt, store.salt);
+        let mut plaintext = [0u8; SECRET_CAPACITY];
+        assert_eq!(loaded.secrets[1].open(&key, 1, &mut plaintext), Some(6));
+        assert_eq!(&plaintext[..6], b"secret");
+        assert_eq!(loaded.secrets[0].len, 0);
+        assert!(SealedStore::from_bytes(&[0xFF; VAULT_SIZE]).is_none());
+    }
+
+    #[test]
+    fn key_depends_on_the_combo_and_the_salt() {
+        let chord = combo(&[(0, 0, 1), (0, 1, 2)]);
+        let roll = combo(&[(0, 0, 1), (0, 1, 1)]);
+        let key = embassy_futures::block_on(derive_key(&[1; 16], &chord));
+        assert_eq!(embassy_futures::block_on(derive_key(&[1; 16], &chord)), key);
+        assert_ne!(embassy_futures::block_on(derive_key(&[1; 16], &roll)), key);
+        assert_ne!(embassy_futures::block_on(derive_key(&[2; 16], &chord)), key);
+    }
+
+    #[test]
+    fn wrong_combo_is_refused() {
+        let _serial = crate::test_support::serial();
+        reset_vault();
+        let mut rng = CountingRng(0);
+        let right = combo(&[(0, 0, 1), (1, 1, 2), (0, 1, 1)]);
+        // The first combo creates the vault
+        assert!(embassy_futures::block_on(unlock(&mut rng, right.clone())).is_some());
+        assert!(is_vault_unlocked());
+        VAULT.lock(|v| {
+            let mut v = v.borrow_mut();
+            v.staged[..6].copy_from_slice(b"secret");
+            v.staged_len = 6;
+        });
+        assert!(update_slot(&mut rng, 0, true).is_some());
+        lock_vault();
+        let mut plaintext = [0u8; SECRET_CAPACITY];
+        assert_eq!(open_secret(0, &mut plaintext), None);
+
+        assert!(embassy_futures::block_on(unlock(&mut rng, combo(&[(0, 0, 1), (1, 1, 1), (0, 1, 1)]))).is_none());
+        assert!(!is_vault_unlocked());
+        assert_eq!(open_secret(0, &mut plaintext), None);
+
+        embassy_futures::block_on(unlock(&mut rng, right));
+        assert!(is_vault_unlocked());
+        assert_eq!(open_secret(0, &mut plaintext), Some(6));
+        assert_eq!(&plaintext[..6], b"secret");
+        reset_vault();
+    }
+}
@@ -0,0 +1,103 @@
+//! WS2812 output streamed by PIO and DMA, so rendering never blocks the executor on bit timing.
+
+use embassy_rp::{
+    clocks,
+    dma::{AnyChannel, Channel},
+    into_ref,
+    pio::{Common, Config, FifoJoin, Instance, PioPin, ShiftConfig, ShiftDirection, StateMachine},
+    Peripheral, PeripheralRef,
+};
+use embassy_time::Timer;
+use fixed::types::U24F8;
+
+use crate::rgb::{FrameSignal, RGB8};
+
+
+pub struct Ws2812<'d, P: Instance, const S: usize, const N: usize> {
+    dma: PeripheralRef<'d, AnyChannel>,
+    sm: StateMachine<'d, P, S>,
+}
+
+impl<'d, P: Instance, const S: usize, const N: usize> Ws2812<'d, P, S, N> {
+    pub fn new(
+        pio: &mut Common<'d, P>,
+        mut sm: StateMachine<'d, P, S>,
+        dma: impl Peripheral<P = impl Channel> + 'd,
+        pin: impl PioPin,
+    ) -> Self {
+        into_ref!(dma);
+
+        // Cycles of each phase of a bit
+        const T1: u8 = 2; // start bit
+        const T2: u8 = 5; // data bit
+        const T3: u8 = 3; // stop bit
+        const CYCLES_PER_BIT: u32 = (T1 + T2 + T3) as u32;
+
+        let side_set = pio::SideSet::new(false, 1, false);
+        let mut a: pio::Assembler<32> = pio::Assembler::new_with_side_set(side_set);
+        let mut wrap_target = a.label();
+        let mut wrap_source = a.label();
+        let mut do_zero = a.label();
+        a.set_with_side_set(pio::SetDestination::PINDIRS, 1, 0);
+        a.bind(&mut wrap_target);
+        // Stop bit
+        a.out_with_delay_and_side_set(pio::OutDestination::X, 1, T3 - 1, 0);
+        // Start bit
+        a.jmp_with_delay_and_side_set(pio::JmpCondition::XIsZero, &mut do_zero, T1 - 1, 1);
+        // Data bit 1
+        a.jmp_with_delay_and_side_set(pio::JmpCondition::Always, &mut wrap_target, T2 - 1, 1);
+        a.bind(&mut do_zero);
+        // Data bit 0
+        a.nop_with_delay_and_side_set(T2 - 1, 0);
+        a.bind(&mut wrap_source);
+        let program = a.assemble_with_wrap(wrap_source, wrap_target);
+
+        let mut cfg = Config::default();
+        let out_pin = pio.make_pio_pin(pin);
+        cfg.set_out_pins(&[&out_pin]);
+        cfg.set_set_pins(&[&out_pin]);
+        cfg.use_program(&pio.load_program(&program), &[&out_pin]);
+
+        // 800kHz bit rate, in kHz to avoid overflow
+        let clock_freq = U24F8::from_num(clocks::clk_sys_freq() / 1000);
+        let bit_freq = U24F8::from_num(800) * CYCLES_PER_BIT;
+        cfg.clock_divider = clock_freq / bit_freq;
+
+        cfg.fifo_join = FifoJoin::TxOnly;
+        cfg.shift_out = ShiftConfig {
+            auto_fill: true,
+            threshold: 24,
+            direction: ShiftDirection::Left,
+        };
+
+        sm.set_config(&cfg);
+        sm.set_enable(true);
+
+        Self {
+            dma: dma.map_into(),
+            sm,
+        }
+    }
+
+    /// Stream a frame, GRB order
+    pub async fn write(&mut self, colors: &[RGB8; N]) {
+        let mut words = [0u32; N];
+        for (word, color) in words.iter_mut().zip(colors.iter()) {
+            *word = (u32::from(color.g) << 24) | (u32::from(color.r) << 16) | (u32::from(color.b) << 8);
+        }
+        self.sm.tx().dma_push(self.dma.reborrow(), &words).await;
+        // Reset code
+        Timer::after_micros(55).await;
+    }
+}
+
+/// Stream the latest frame signaled by the renderer. This function should never return.
+pub async fn run_ws2812<P: Instance, const S: usize, const N: usize>(
+    mut ws2812: Ws2812<'_, P, S, N>,
+    frame: &FrameSignal<N>,
+) -> ! {
+    loop {
+        let colors = frame.wait().await;
+        ws2812.write(&colors).await;
+    }
+}
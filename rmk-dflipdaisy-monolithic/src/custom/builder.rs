@@ -0,0 +1,311 @@
+use core::future::{pending, Future, Pending};
+
+#[cfg(feature = "_esp_ble")]
+use rmk::ble::esp::initialize_esp_ble_keyboard_with_config_and_run;
+#[cfg(feature = "_nrf_ble")]
+use rmk::ble::nrf::initialize_nrf_ble_keyboard_and_run;
+use rmk::config::RmkConfig;
+#[cfg(not(feature = "rapid_debouncer"))]
+use rmk::debounce::default_bouncer::DefaultDebouncer;
+#[cfg(feature = "rapid_debouncer")]
+use rmk::debounce::fast_debouncer::RapidDebouncer;
+
+use rmk::action::KeyAction;
+use rmk::initialize_usb_keyboard_and_run;
+use rmk::debounce::DebouncerTrait;
+#[cfg(feature = "priority_tasks")]
+use rmk::matrix::MatrixTrait;
+
+use rmk_custom_device::debounce::RapidTrigger;
+use rmk_custom_device::event::KeyEventHook;
+use rmk_custom_device::matrix::{SequentialMatrix, SequentialMatrixPins};
+#[cfg(feature = "core1_matrix")]
+use rmk_custom_device::multicore::spawn_matrix_on_core1;
+#[cfg(feature = "priority_tasks")]
+use rmk_custom_device::{matrix::MatrixProxy, priority::spawn_at_priority};
+
+use embassy_futures::join::join3;
+#[cfg(not(feature = "_esp_ble"))]
+use embassy_executor::Spawner;
+#[cfg(feature = "core1_matrix")]
+use embassy_rp::peripherals::CORE1;
+#[cfg(not(feature = "_no_usb"))]
+use embassy_usb::driver::Driver;
+pub use embedded_hal;
+use embedded_hal::digital::{InputPin, OutputPin};
+#[cfg(feature = "async_matrix")]
+use embedded_hal_async::digital::Wait;
+#[cfg(not(feature = "_no_external_storage"))]
+use embedded_storage_async::nor_flash::NorFlash as AsyncNorFlash;
+
+
+
+/// Re-press window of rapid trigger emulation, which is off until toggled
+const RAPID_TRIGGER_WINDOW_MS: u64 = 50;
+
+#[cfg(feature = "rapid_debouncer")]
+type KeyDebouncer<const ROW: usize, const COL: usize> = RapidDebouncer<COL, ROW>;
+#[cfg(not(feature = "rapid_debouncer"))]
+type KeyDebouncer<const ROW: usize, const COL: usize> = DefaultDebouncer<COL, ROW>;
+/// Debouncer of the matrix, also timed by the `bench` shell command
+pub(crate) type MatrixDebouncer<const ROW: usize, const COL: usize> =
+    RapidTrigger<KeyDebouncer<ROW, COL>, ROW, COL, RAPID_TRIGGER_WINDOW_MS>;
+
+#[cfg(all(feature = "core1_matrix", feature = "priority_tasks"))]
+compile_error!("`core1_matrix` and `priority_tasks` both take the matrix scan, enable one of them");
+
+/// Builder of the keyboard service, each optional subsystem is added by its own method.
+///
+/// ```ignore
+/// KeyboardBuilder::new(pins, &mut keymap, keyboard_config)
+///     .hook(hook)
+///     .usb(driver)
+///     .storage(flash)
+///     .build()
+///     .run(spawner)
+///     .await
+/// ```
+///
+/// * `hook` - hook applied to key events before they reach rmk, none by default
+/// * `usb` - embassy usb driver instance, required unless the `_no_usb` feature is enabled implicitly by the chip
+/// * `storage` - async flash storage for the keymap and keyboard configs, required unless the `_no_external_storage` feature is enabled implicitly by the chip
+/// * `rgb` - RGB renderer run alongside the keyboard, e.g. [`run_rgb_renderer`](rmk_custom_device::rgb::run_rgb_renderer)
+/// * `display` - display task run alongside the keyboard, e.g. [`run_oled`](rmk_custom_device::oled::run_oled)
+/// * `core1` - RP2040's second core, which runs the matrix scan. Required with `core1_matrix`
+///
+/// With `priority_tasks`, the matrix is scanned by a task of [`spawn_at_priority`](rmk_custom_device::priority::spawn_at_priority) instead of rmk.
+pub struct KeyboardBuilder<'a, In, Out: OutputPin, H, D, F, R, O, const ROW: usize, const COL: usize, const NUM_LAYER: usize> {
+    pins: SequentialMatrixPins<In, Out>,
+    default_keymap: &'a mut [[[KeyAction; COL]; ROW]; NUM_LAYER],
+    keyboard_config: RmkConfig<'static, Out>,
+    hook: H,
+    usb_driver: D,
+    flash: F,
+    rgb: R,
+    display: O,
+    #[cfg(feature = "core1_matrix")]
+    core1: Option<CORE1>,
+}
+
+impl<'a, In, Out: OutputPin, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
+    KeyboardBuilder<'a, In, Out, (), (), (), Pending<()>, Pending<()>, ROW, COL, NUM_LAYER>
+{
+    /// * `pins` - matrix pins, if `async_matrix` is enabled, the input pins should implement `embedded_hal_async::digital::Wait` trait
+    /// * `default_keymap` - default keymap definition
+    /// * `keyboard_config` - other configurations of the keyboard, check [RmkConfig] struct for details
+    pub fn new(
+        pins: SequentialMatrixPins<In, Out>,
+        default_keymap: &'a mut [[[KeyAction; COL]; ROW]; NUM_LAYER],
+        keyboard_config: RmkConfig<'static, Out>,
+    ) -> Self {
+        Self {
+            pins,
+            default_keymap,
+            keyboard_config,
+            hook: (),
+            usb_driver: (),
+            flash: (),
+            rgb: pending(),
+            display: pending(),
+            #[cfg(feature = "core1_matrix")]
+            core1: None,
+        }
+    }
+}
+
+impl<'a, In, Out: OutputPin, H, D, F, R, O, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
+    KeyboardBuilder<'a, In, Out, H, D, F, R, O, ROW, COL, NUM_LAYER>
+{
+    pub fn hook<H2: KeyEventHook>(self, hook: H2) -> KeyboardBuilder<'a, In, Out, H2, D, F, R, O, ROW, COL, NUM_LAYER> {
+        KeyboardBuilder {
+            pins: self.pins,
+            default_keymap: self.default_keymap,
+            keyboard_config: self.keyboard_config,
+            hook,
+            usb_driver: self.usb_driver,
+            flash: self.flash,
+            rgb: self.rgb,
+            display: self.display,
+            #[cfg(feature = "core1_matrix")]
+            core1: self.core1,
+        }
+    }
+
+    #[cfg(not(feature = "_no_usb"))]
+    pub fn usb<D2: Driver<'static>>(self, usb_driver: D2) -> KeyboardBuilder<'a, In, Out, H, D2, F, R, O, ROW, COL, NUM_LAYER> {
+        KeyboardBuilder {
+            pins: self.pins,
+            default_keymap: self.default_keymap,
+            keyboard_config: self.keyboard_config,
+            hook: self.hook,
+            usb_driver,
+            flash: self.flash,
+            rgb: self.rgb,
+            display: self.display,
+            #[cfg(feature = "core1_matrix")]
+            core1: self.core1,
+        }
+    }
+
+    #[cfg(not(feature = "_no_external_storage"))]
+    pub fn storage<F2: AsyncNorFlash>(self, flash: F2) -> KeyboardBuilder<'a, In, Out, H, D, F2, R, O, ROW, COL, NUM_LAYER> {
+        KeyboardBuilder {
+            pins: self.pins,
+            default_keymap: self.default_keymap,
+            keyboard_config: self.keyboard_config,
+            hook: self.hook,
+            usb_driver: self.usb_driver,
+            flash,
+            rgb: self.rgb,
+            display: self.display,
+            #[cfg(feature = "core1_matrix")]
+            core1: self.core1,
+        }
+    }
+
+    pub fn rgb<R2: Future>(self, rgb: R2) -> KeyboardBuilder<'a, In, Out, H, D, F, R2, O, ROW, COL, NUM_LAYER> {
+        KeyboardBuilder {
+            pins: self.pins,
+            default_keymap: self.default_keymap,
+            keyboard_config: self.keyboard_config,
+            hook: self.hook,
+            usb_driver: self.usb_driver,
+            flash: self.flash,
+            rgb,
+            display: self.display,
+            #[cfg(feature = "core1_matrix")]
+            core1: self.core1,
+        }
+    }
+
+    pub fn display<O2: Future>(self, display: O2) -> KeyboardBuilder<'a, In, Out, H, D, F, R, O2, ROW, COL, NUM_LAYER> {
+        KeyboardBuilder {
+            pins: self.pins,
+            default_keymap: self.default_keymap,
+            keyboard_config: self.keyboard_config,
+            hook: self.hook,
+            usb_driver: self.usb_driver,
+            flash: self.flash,
+            rgb: self.rgb,
+            display,
+            #[cfg(feature = "core1_matrix")]
+            core1: self.core1,
+        }
+    }
+
+    #[cfg(feature = "core1_matrix")]
+    pub fn core1(mut self, core1: CORE1) -> Self {
+        self.core1 = Some(core1);
+        self
+    }
+
+    /// Finish the configuration
+    pub fn build(self) -> Keyboard<'a, In, Out, H, D, F, R, O, ROW, COL, NUM_LAYER> {
+        Keyboard { builder: self }
+    }
+}
+
+
+/// Keyboard service configured by [`KeyboardBuilder`]
+pub struct Keyboard<'a, In, Out: OutputPin, H, D, F, R, O, const ROW: usize, const COL: usize, const NUM_LAYER: usize> {
+    builder: KeyboardBuilder<'a, In, Out, H, D, F, R, O, ROW, COL, NUM_LAYER>,
+}
+
+impl<
+        'a,
+        #[cfg(feature = "async_matrix")] In: Wait + InputPin + Send + 'static,
+        #[cfg(not(feature = "async_matrix"))] In: InputPin + Send + 'static,
+        Out: OutputPin + Send + 'static,
+        H: KeyEventHook + Send + 'static,
+        #[cfg(not(feature = "_no_usb"))] D: Driver<'static>,
+        #[cfg(feature = "_no_usb")] D,
+        #[cfg(not(feature = "_no_external_storage"))] F: AsyncNorFlash,
+        #[cfg(feature = "_no_external_storage")] F,
+        R: Future,
+        O: Future,
+        const ROW: usize,
+        const COL: usize,
+        const NUM_LAYER: usize,
+    > Keyboard<'a, In, Out, H, D, F, R, O, ROW, COL, NUM_LAYER>
+{
+    /// Run RMK keyboard service with the subsystems. This function should never return.
+    ///
+    /// * `spawner`: (optional) embassy spawner used to spawn async tasks. This argument is enabled for non-esp microcontrollers
+    #[allow(unused_variables)]
+    #[allow(unreachable_code)]
+    pub async fn run(self, #[cfg(not(feature = "_esp_ble"))] spawner: Spawner) -> ! {
+        let KeyboardBuilder {
+            pins,
+            default_keymap,
+            keyboard_config,
+            hook,
+            usb_driver,
+            flash,
+            rgb,
+            display,
+            #[cfg(feature = "core1_matrix")]
+            core1,
+        } = self.builder;
+
+        let debouncer = MatrixDebouncer::<ROW, COL>::new();
+
+        let matrix = SequentialMatrix::<
+            In,
+            Out,
+            _,
+            H,
+            ROW,
+            COL,
+        >::new(pins, debouncer, hook);
+        #[cfg(feature = "core1_matrix")]
+        let Some(core1) = core1 else {
+            defmt::panic!("core1_matrix needs the second core, see KeyboardBuilder::core1");
+        };
+        #[cfg(feature = "core1_matrix")]
+        let matrix = spawn_matrix_on_core1::<_, ROW, COL>(core1, matrix);
+        // Scanned from the software interrupt, rmk gets the proxy
+        #[cfg(feature = "priority_tasks")]
+        let matrix = {
+            let mut matrix = matrix;
+            spawn_at_priority(async move { matrix.scan().await });
+            MatrixProxy::<ROW, COL>
+        };
+
+        // Dispatch according to chip and communication type
+        let keyboard = async move {
+            #[cfg(feature = "_nrf_ble")]
+            initialize_nrf_ble_keyboard_and_run(
+                matrix,
+                #[cfg(not(feature = "_no_usb"))]
+                usb_driver,
+                default_keymap,
+                keyboard_config,
+                None,
+                spawner,
+            )
+            .await;
+
+            #[cfg(feature = "_esp_ble")]
+            initialize_esp_ble_keyboard_with_config_and_run(matrix, default_keymap, keyboard_config).await;
+
+            #[cfg(all(
+                not(feature = "_no_usb"),
+                not(any(feature = "_nrf_ble", feature = "_esp_ble"))
+            ))]
+            initialize_usb_keyboard_and_run(
+                matrix,
+                usb_driver,
+                #[cfg(not(feature = "_no_external_storage"))]
+                flash,
+                default_keymap,
+                keyboard_config,
+            )
+            .await;
+        };
+        join3(keyboard, rgb, display).await;
+
+        // The fut should never return.
+        // If there's no fut, the feature flags must not be correct.
+        defmt::panic!("The run_rmk should never return");
+    }
+}
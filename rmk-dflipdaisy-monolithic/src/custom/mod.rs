@@ -1 +1 @@
-pub(crate) mod monolithic;
+pub(crate) mod builder;
@@ -13,21 +13,34 @@
 use const_gen::*;
 use std::fs::File;
 use std::io::{Read, Write};
-use std::path::{Path, PathBuf};
+use std::path::PathBuf;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
 use std::{env, fs};
 use xz2::read::XzEncoder;
 
 fn main() {
-    // Generate vial config at the root of project
-    println!("cargo:rerun-if-changed=vial.json");
+    // Generate vial config from keyboard.toml
+    println!("cargo:rerun-if-changed=keyboard.toml");
     generate_vial_config();
 
+    // Embed build information, read by `rmk_custom_device::build_info!`
+    rerun_on_new_commit();
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    generate_build_info();
+
     // Put `memory.x` in our output directory and ensure it's
     // on the linker search path.
+    // The `dfu` feature links for the partitions of embassy-boot's bootloader instead.
+    let memory: &[u8] = if env::var_os("CARGO_FEATURE_DFU").is_some() {
+        include_bytes!("memory-dfu.x")
+    } else {
+        include_bytes!("memory.x")
+    };
     let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
     File::create(out.join("memory.x"))
         .unwrap()
-        .write_all(include_bytes!("memory.x"))
+        .write_all(memory)
         .unwrap();
     println!("cargo:rustc-link-search={}", out.display());
 
@@ -36,6 +49,7 @@ fn main() {
     // here, we ensure the build script is only re-run when
     // `memory.x` is changed.
     println!("cargo:rerun-if-changed=memory.x");
+    println!("cargo:rerun-if-changed=memory-dfu.x");
 
     // Specify linker arguments.
 
@@ -55,19 +69,19 @@ fn main() {
 
 fn generate_vial_config() {
     // Generated vial config file
-    let out_file = Path::new(&env::var_os("OUT_DIR").unwrap()).join("config_generated.rs");
-
-    let p = Path::new("vial.json");
-    let mut content = String::new();
-    match File::open(p) {
-        Ok(mut file) => {
-            file.read_to_string(&mut content)
-                .expect("Cannot read vial.json");
-        }
-        Err(e) => println!("Cannot find vial.json {:?}: {}", p, e),
-    };
+    let out_dir = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+
+    let content = fs::read_to_string("keyboard.toml").expect("Cannot read keyboard.toml");
+    let config: toml::Table = content.parse().expect("Cannot parse keyboard.toml");
+    let vial_json = vial_definition(&config);
+    let rows = vial_json["matrix"]["rows"].as_usize().unwrap();
+    let cols = vial_json["matrix"]["cols"].as_usize().unwrap();
+    check_vial_layout(&vial_json["layouts"]["keymap"], rows, cols);
+    let key_positions = key_positions(&vial_json["layouts"]["keymap"], rows, cols);
 
-    let vial_cfg = json::stringify(json::parse(&content).unwrap());
+    let vial_cfg = json::stringify(vial_json.clone());
+    // Kept for sideloading into the Vial GUI
+    fs::write(out_dir.join("vial.json"), json::stringify_pretty(vial_json, 4)).unwrap();
     let mut keyboard_def_compressed: Vec<u8> = Vec::new();
     XzEncoder::new(vial_cfg.as_bytes(), 6)
         .read_to_end(&mut keyboard_def_compressed)
@@ -77,7 +91,241 @@ fn generate_vial_config() {
     let const_declarations = [
         const_declaration!(pub VIAL_KEYBOARD_DEF = keyboard_def_compressed),
         const_declaration!(pub VIAL_KEYBOARD_ID = keyboard_id),
+        const_declaration!(pub VIAL_ROWS = rows),
+        const_declaration!(pub VIAL_COLS = cols),
     ]
     .join("\n");
-    fs::write(out_file, const_declarations).unwrap();
+    // Only taken by the physical effects and hooks the firmware enables
+    let key_positions_declaration = format!(
+        "#[allow(dead_code)]\npub const KEY_POSITIONS: [[Option<(u16, u16)>; {}]; {}] = {};",
+        cols, rows, key_positions
+    );
+    fs::write(
+        out_dir.join("config_generated.rs"),
+        [const_declarations, key_positions_declaration].join("\n"),
+    )
+    .unwrap();
+}
+
+/// Build the Vial definition from `[keyboard]`, `[layout]` and `[vial]` of keyboard.toml.
+/// Without `[vial] keymap`, the KLE layout is the plain grid of the matrix.
+/// With the `layout_variants` feature, `[vial.variants]` replaces the KLE layout and adds its layout option labels.
+/// With the `rgb` feature, the lighting tab is QMK's rgblight whatever `[vial] lighting` says.
+fn vial_definition(config: &toml::Table) -> json::JsonValue {
+    let keyboard = config.get("keyboard").expect("keyboard.toml has no [keyboard]");
+    let layout = config.get("layout").expect("keyboard.toml has no [layout]");
+    let vial = config.get("vial");
+    let variants = env::var_os("CARGO_FEATURE_LAYOUT_VARIANTS").map(|_| {
+        vial.and_then(|vial| vial.get("variants"))
+            .expect("layout_variants needs [vial.variants] in keyboard.toml")
+    });
+    let name = keyboard
+        .get("name")
+        .and_then(toml::Value::as_str)
+        .expect("keyboard.toml has no keyboard name");
+    let vendor_id = keyboard
+        .get("vendor_id")
+        .and_then(toml::Value::as_integer)
+        .expect("keyboard.toml has no vendor_id");
+    let product_id = keyboard
+        .get("product_id")
+        .and_then(toml::Value::as_integer)
+        .expect("keyboard.toml has no product_id");
+    let rows = layout
+        .get("rows")
+        .and_then(toml::Value::as_integer)
+        .expect("keyboard.toml has no layout rows") as usize;
+    let cols = layout
+        .get("cols")
+        .and_then(toml::Value::as_integer)
+        .expect("keyboard.toml has no layout cols") as usize;
+    let lighting = match env::var_os("CARGO_FEATURE_RGB") {
+        Some(_) => "qmk_rgblight",
+        None => vial
+            .and_then(|vial| vial.get("lighting"))
+            .and_then(toml::Value::as_str)
+            .unwrap_or("none"),
+    };
+
+    let keymap = match variants.or(vial).and_then(|vial| vial.get("keymap")) {
+        Some(keymap) => toml_to_json(keymap),
+        None => (0..rows)
+            .map(|r| (0..cols).map(|c| format!("{},{}", r, c)).collect::<Vec<_>>())
+            .collect::<Vec<_>>()
+            .into(),
+    };
+
+    let mut vial_json = json::JsonValue::new_object();
+    vial_json["name"] = name.into();
+    vial_json["vendorId"] = format!("0x{:04X}", vendor_id).into();
+    vial_json["productId"] = format!("0x{:04X}", product_id).into();
+    vial_json["lighting"] = lighting.into();
+    vial_json["matrix"]["rows"] = rows.into();
+    vial_json["matrix"]["cols"] = cols.into();
+    vial_json["layouts"]["keymap"] = keymap;
+    if let Some(labels) = variants.and_then(|variants| variants.get("labels")) {
+        vial_json["layouts"]["labels"] = toml_to_json(labels);
+    }
+    vial_json
+}
+
+fn toml_to_json(value: &toml::Value) -> json::JsonValue {
+    match value {
+        toml::Value::String(s) => s.as_str().into(),
+        toml::Value::Integer(i) => (*i).into(),
+        toml::Value::Float(f) => (*f).into(),
+        toml::Value::Boolean(b) => (*b).into(),
+        toml::Value::Array(array) => array.iter().map(toml_to_json).collect::<Vec<_>>().into(),
+        toml::Value::Table(table) => {
+            let mut object = json::JsonValue::new_object();
+            for (key, value) in table {
+                object[key.as_str()] = toml_to_json(value);
+            }
+            object
+        }
+        toml::Value::Datetime(datetime) => datetime.to_string().into(),
+    }
+}
+
+/// Fail the build if a key of the KLE layout is out of the matrix
+fn check_vial_layout(layout: &json::JsonValue, rows: usize, cols: usize) {
+    for row in layout.members() {
+        for key in row.members().filter_map(|key| key.as_str()) {
+            // Labels like "0,1" are matrix positions, the others are KLE properties.
+            // Keys of layout options carry the option in a later line, e.g. "0,1\n\n\n0,1"
+            let position = key.lines().next().unwrap_or_default();
+            let Some((r, c)) = position.split_once(',') else {
+                continue;
+            };
+            let (Ok(r), Ok(c)) = (r.trim().parse::<usize>(), c.trim().parse::<usize>()) else {
+                continue;
+            };
+            if r >= rows || c >= cols {
+                panic!("keyboard.toml: vial key {} is out of the {}x{} matrix", position, rows, cols);
+            }
+        }
+    }
+}
+
+/// Key unit of KLE in mm
+const KEY_UNIT_MM: f64 = 19.05;
+
+/// Centers of the keys of the KLE layout in mm from its top left, by matrix position, as a Rust array literal.
+/// Keys of layout options are placed by their first choice. Rotations aren't supported.
+fn key_positions(layout: &json::JsonValue, rows: usize, cols: usize) -> String {
+    let mut centers = vec![vec![None; cols]; rows];
+    let mut y = 0.0;
+    // Offsets like `{ y = -2 }` may put keys above or left of the first one
+    let (mut left, mut top) = (0.0, 0.0);
+    for row in layout.members() {
+        let (mut x, mut w, mut h) = (0.0, 1.0, 1.0);
+        for key in row.members() {
+            if key.is_object() {
+                x += key["x"].as_f64().unwrap_or(0.0);
+                y += key["y"].as_f64().unwrap_or(0.0);
+                w = key["w"].as_f64().unwrap_or(1.0);
+                h = key["h"].as_f64().unwrap_or(1.0);
+                continue;
+            }
+            let Some(label) = key.as_str() else {
+                continue;
+            };
+            let mut lines = label.lines();
+            let position = lines.next().unwrap_or_default();
+            let first_choice = lines.nth(2).map_or(true, |option| option.trim().ends_with(",0"));
+            if let Some((r, c)) = position.split_once(',') {
+                if let (Ok(r), Ok(c), true) = (r.trim().parse::<usize>(), c.trim().parse::<usize>(), first_choice) {
+                    centers[r][c] = Some((x + w / 2.0, y + h / 2.0));
+                    left = f64::min(left, x);
+                    top = f64::min(top, y);
+                }
+            }
+            x += w;
+            w = 1.0;
+            h = 1.0;
+        }
+        y += 1.0;
+    }
+
+    let literal = |center: &Option<(f64, f64)>| match center {
+        Some((x, y)) => format!(
+            "Some(({}, {}))",
+            ((x - left) * KEY_UNIT_MM).round() as u16,
+            ((y - top) * KEY_UNIT_MM).round() as u16
+        ),
+        None => "None".to_string(),
+    };
+    let row_literals: Vec<String> = centers
+        .iter()
+        .map(|row| format!("[{}]", row.iter().map(literal).collect::<Vec<_>>().join(", ")))
+        .collect();
+    format!("[{}]", row_literals.join(", "))
+}
+
+/// Output of the git command, `None` outside a git checkout
+fn git(args: &[&str]) -> Option<String> {
+    Command::new("git")
+        .args(args)
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|output| output.trim().to_string())
+}
+
+/// Rerun when HEAD moves. `HEAD` itself only changes on a checkout, a commit rewrites the branch's ref,
+/// or `packed-refs` once git packed it
+fn rerun_on_new_commit() {
+    let mut files = vec!["HEAD".to_string(), "packed-refs".to_string()];
+    files.extend(git(&["symbolic-ref", "-q", "HEAD"]));
+    for file in files {
+        // A missing file would rerun the script on every build
+        if let Some(path) = git(&["rev-parse", "--git-path", &file]).filter(|path| fs::metadata(path).is_ok()) {
+            println!("cargo:rerun-if-changed={}", path);
+        }
+    }
+}
+
+fn generate_build_info() {
+    let git_hash = git(&["rev-parse", "--short", "HEAD"]).unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=DFLIPDAISY_GIT_HASH={}", git_hash);
+
+    // Respect SOURCE_DATE_EPOCH for reproducible builds
+    let epoch_secs = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse::<u64>().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0)
+        });
+    let (year, month, day) = civil_from_days((epoch_secs / 86400) as i64);
+    println!(
+        "cargo:rustc-env=DFLIPDAISY_BUILD_DATE={:04}-{:02}-{:02}",
+        year, month, day
+    );
+
+    let mut features: Vec<String> = env::vars()
+        .filter_map(|(key, _)| {
+            key.strip_prefix("CARGO_FEATURE_")
+                .map(|feature| feature.to_lowercase())
+        })
+        .collect();
+    features.sort();
+    println!("cargo:rustc-env=DFLIPDAISY_FEATURES={}", features.join(","));
+}
+
+/// Convert days since unix epoch into (year, month, day), Howard Hinnant's algorithm
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
 }
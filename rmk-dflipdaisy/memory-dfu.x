@@ -0,0 +1,14 @@
+/* Layout for embassy-boot's bootloader, which owns BOOT2 and swaps FLASH and DFU on update */
+MEMORY {
+    BOOT2            : ORIGIN = 0x10000000, LENGTH = 0x100
+    BOOTLOADER_STATE : ORIGIN = 0x10006000, LENGTH = 4K
+    FLASH            : ORIGIN = 0x10007000, LENGTH = 960K
+    DFU              : ORIGIN = 0x100F7000, LENGTH = 964K
+    RAM              : ORIGIN = 0x20000000, LENGTH = 256K
+}
+
+__bootloader_state_start = ORIGIN(BOOTLOADER_STATE) - ORIGIN(BOOT2);
+__bootloader_state_end = ORIGIN(BOOTLOADER_STATE) + LENGTH(BOOTLOADER_STATE) - ORIGIN(BOOT2);
+
+__bootloader_dfu_start = ORIGIN(DFU) - ORIGIN(BOOT2);
+__bootloader_dfu_end = ORIGIN(DFU) + LENGTH(DFU) - ORIGIN(BOOT2);
@@ -9,49 +9,310 @@ mod vial;
 
 mod custom;
 
-use crate::keymap::{COL, NUM_LAYER, ROW};
-use crate::custom::central::run_rmk_split_central;
-use rmk_custom_device::matrix::SequentialMatrixPins;
+use crate::keymap::{COL, CUSTOM_KEYS, HANDS, KEY_ALIASES, NUM_LAYER, PRESENTER_KEYS, ROW, SOCD_PAIRS};
+use crate::custom::builder::KeyboardBuilder;
+use rmk_custom_device::{
+    action::{run_custom_actions, CustomActionHook},
+    bilateral::BilateralHook,
+    brown_out::run_low_voltage_guard,
+    build_info,
+    clock::run_clock,
+    config_reset::{run_config_reset, run_config_reset_indicator},
+    debounce::MatrixRegion,
+    dedup::DedupHook,
+    feature_flags::{load_feature_flags, run_feature_flags_save, save_feature_flags},
+    handoff::{apply_config_handoff, take_config_handoff},
+    heatmap::{load_heatmap, run_heatmap_checkpoint, HeatmapHook},
+    host_sleep::{run_host_sleep, SleepProfile},
+    info::BuildInfo,
+    key_lock::KeyLockHook,
+    key_stream::KeyStreamHook,
+    keymap_check::matrices_tile,
+    keymap_names::register_keymap,
+    layer_state::LayerTrackerHook,
+    lock_led::LockLedDriver,
+    matrix::SequentialMatrixPins,
+    output::{run_output, HeldModifiersDriver, RmkOutput},
+    physical::{PhysicalLayout, SwapHandsHook},
+    presenter::{run_presenter, PresenterHook},
+    quiesce::QuiescentFlash,
+    ram_budget::RamBudget,
+    raw_hid::RawHidDriver,
+    reboot::{rp2040_reset, run_system_reset},
+    recorder::FlightRecorderHook,
+    reserved::erase_sectors,
+    scheduler::run_action_scheduler,
+    split_link::{run_split_link_log, MonitoredLink},
+    split_order::{run_split_order, SplitOrderHook},
+    split_transport::{rp2040_boot_epoch, run_split_transport, CentralSplitHandler},
+    shared_pin::SharedOutput,
+    snippets::{load_snippets, run_snippets_save, save_snippets},
+    socd::SocdHook,
+    stuck::{run_stuck_key_indicator, run_stuck_key_watchdog, StuckKeyHook},
+    telemetry::{run_rp2040_telemetry, Rp2040Telemetry},
+    timer::{run_timer, LedFlashNotifier},
+    training::TrainingHook,
+    usb::{run_vbus_monitor, HeldKeysHook},
+    usb_power::{run_usb_power_monitor, PowerAwareDriver, UsbPowerConfig},
+};
+#[cfg(feature = "demo")]
+use rmk_custom_device::{
+    demo::{DemoAction, DemoStep},
+    lighting::{LightingSettings, MODE_BREATHING, MODE_RAINBOW_MOOD, MODE_RAINBOW_SWIRL, MODE_SOLID},
+};
 
 use defmt::*;
+#[cfg(not(feature = "minimal"))]
 use defmt_rtt as _;
 use embassy_executor::Spawner;
-use embassy_futures::join::join;
+use embassy_futures::join::{join, join3, join4, join5};
 use embassy_rp::{
+    adc::{self, Adc},
     bind_interrupts,
     flash::{Async, Flash},
-    gpio::{AnyPin, Input, Output},
-    peripherals::{self, UART0, USB},
+    gpio::{AnyPin, Input, Level, Output, Pull},
+    peripherals::{UART0, USB},
     uart::{self, BufferedUart},
     usb::{Driver, InterruptHandler},
 };
 // use embassy_rp::flash::Blocking;
+use embassy_time::Duration;
+#[cfg(not(any(feature = "crash_log", feature = "minimal")))]
 use panic_probe as _;
 use rmk::{
-    config::{KeyboardUsbConfig, RmkConfig, VialConfig},
-    split::{
-        central::run_peripheral_monitor,
-        SPLIT_MESSAGE_MAX_SIZE,
-    },
+    action::KeyAction,
+    config::{BehaviorConfig, KeyboardUsbConfig, RmkConfig, TapHoldConfig, VialConfig},
+    split::SPLIT_MESSAGE_MAX_SIZE,
 };
 use static_cell::StaticCell;
-use vial::{VIAL_KEYBOARD_DEF, VIAL_KEYBOARD_ID};
+use vial::{KEY_POSITIONS, VIAL_KEYBOARD_DEF, VIAL_KEYBOARD_ID};
 
 bind_interrupts!(struct Irqs {
     USBCTRL_IRQ => InterruptHandler<USB>;
     UART0_IRQ => uart::BufferedInterruptHandler<UART0>;
+    ADC_IRQ_FIFO => adc::InterruptHandler;
+    PIO0_IRQ_0 => embassy_rp::pio::InterruptHandler<embassy_rp::peripherals::PIO0>;
+    UART1_IRQ => embassy_rp::uart::BufferedInterruptHandler<embassy_rp::peripherals::UART1>;
 });
 
 const FLASH_SIZE: usize = 2 * 1024 * 1024;
+/// Crash log page, right below the 2 sectors of rmk's storage at the end of flash
+#[cfg(feature = "crash_log")]
+const CRASH_LOG_OFFSET: u32 = (FLASH_SIZE - 3 * embassy_rp::flash::ERASE_SIZE) as u32;
+
+/// Last accepted counter of signed config commands, right below the crash log page
+#[cfg(feature = "signed_config")]
+const SIGNED_CONFIG_OFFSET: u32 = (FLASH_SIZE - 4 * embassy_rp::flash::ERASE_SIZE) as u32;
+
+/// Runtime feature toggles, right below the signed config counter
+const FEATURE_FLAGS_OFFSET: u32 = (FLASH_SIZE - 5 * embassy_rp::flash::ERASE_SIZE) as u32;
+
+/// Sealed secrets of the vault, right below the feature flags
+#[cfg(feature = "secret_vault")]
+const VAULT_OFFSET: u32 = (FLASH_SIZE - 6 * embassy_rp::flash::ERASE_SIZE) as u32;
+/// Presses of the vault's unlock combo
+#[cfg(feature = "secret_vault")]
+const VAULT_COMBO_LEN: usize = 12;
+/// The vault locks after this long without a key event
+#[cfg(feature = "secret_vault")]
+const VAULT_AUTO_LOCK: Duration = Duration::from_secs(10 * 60);
+
+/// Text snippets typed by the snippet keys, right below the vault
+const SNIPPETS_OFFSET: u32 = (FLASH_SIZE - 7 * embassy_rp::flash::ERASE_SIZE) as u32;
+
+/// Press counts of the heatmap, right below the lighting settings
+const HEATMAP_OFFSET: u32 = (FLASH_SIZE - 9 * embassy_rp::flash::ERASE_SIZE) as u32;
+/// The press counts are appended to flash this often if they changed, a sector holds 15 before it's erased
+const HEATMAP_CHECKPOINT_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Settings of Vial's lighting tab, right below the snippets
+#[cfg(feature = "rgb")]
+const LIGHTING_OFFSET: u32 = (FLASH_SIZE - 8 * embassy_rp::flash::ERASE_SIZE) as u32;
+/// WS2812 LEDs under the central's keys, the peripheral's are left dark
+#[cfg(feature = "rgb")]
+const LED_COUNT: usize = CENTRAL_ROW * CENTRAL_COL;
+/// Key under each LED of the chain
+#[cfg(feature = "rgb")]
+const LED_MAP: rmk_custom_device::rgb::LedMap<LED_COUNT> = rmk_custom_device::rgb::LedMap::new([
+    Some((0, 0)), Some((0, 1)),
+    Some((1, 0)), Some((1, 1)),
+]);
+/// Color of the keys bound on the held layer
+#[cfg(feature = "rgb")]
+const LAYER_PREVIEW_COLOR: rmk_custom_device::rgb::RGB8 = rmk_custom_device::rgb::RGB8::new(255, 255, 255);
+/// Color flashing on the pressed key in training mode
+#[cfg(feature = "rgb")]
+const TRAINING_COLOR: rmk_custom_device::rgb::RGB8 = rmk_custom_device::rgb::RGB8::new(0, 255, 0);
+/// Length of a talk in presenter mode, the LEDs fill up over it and blink once it's over
+#[cfg(feature = "rgb")]
+const PRESENTER_TALK: Duration = Duration::from_secs(20 * 60);
+/// Colors of the talk time and of the overtime
+#[cfg(feature = "rgb")]
+const PRESENTER_COLOR: rmk_custom_device::rgb::RGB8 = rmk_custom_device::rgb::RGB8::new(0, 0, 255);
+#[cfg(feature = "rgb")]
+const PRESENTER_OVERTIME_COLOR: rmk_custom_device::rgb::RGB8 = rmk_custom_device::rgb::RGB8::new(255, 128, 0);
+/// Show floor demo stepping through the lighting modes, restoring the user's lighting when stopped
+#[cfg(feature = "demo")]
+const DEMO_SCRIPT: [DemoStep; 4] = [
+    DemoStep::new(DemoAction::Lighting(LightingSettings { mode: MODE_RAINBOW_SWIRL, ..LightingSettings::DEFAULT }), DEMO_STEP),
+    DemoStep::new(DemoAction::Lighting(LightingSettings { mode: MODE_BREATHING, hue: 170, ..LightingSettings::DEFAULT }), DEMO_STEP),
+    DemoStep::new(DemoAction::Lighting(LightingSettings { mode: MODE_RAINBOW_MOOD, ..LightingSettings::DEFAULT }), DEMO_STEP),
+    DemoStep::new(DemoAction::Lighting(LightingSettings { mode: MODE_SOLID, hue: 85, ..LightingSettings::DEFAULT }), DEMO_STEP),
+];
+#[cfg(feature = "demo")]
+const DEMO_STEP: Duration = Duration::from_secs(10);
+/// The jiggler moves the pointer by a pixel every minute, for up to 2 hours
+#[cfg(feature = "jiggler")]
+const JIGGLER_INTERVAL: Duration = Duration::from_secs(60);
+#[cfg(feature = "jiggler")]
+const JIGGLER_AUTO_OFF: Duration = Duration::from_secs(2 * 60 * 60);
+/// First LED lit while the jiggler is on
+#[cfg(all(feature = "jiggler", feature = "rgb"))]
+const JIGGLER_COLOR: rmk_custom_device::rgb::RGB8 = rmk_custom_device::rgb::RGB8::new(255, 255, 255);
+/// Color pulsing while the keyboard is locked
+#[cfg(feature = "rgb")]
+const KEY_LOCK_COLOR: rmk_custom_device::rgb::RGB8 = rmk_custom_device::rgb::RGB8::new(255, 0, 0);
+/// Frame rate of the lighting
+#[cfg(feature = "rgb")]
+const RGB_FPS: u64 = 60;
+/// Frames from the renderer to the WS2812 output
+#[cfg(feature = "rgb")]
+static RGB_FRAME: rmk_custom_device::rgb::FrameSignal<LED_COUNT> = rmk_custom_device::rgb::FrameSignal::new();
+
+/// The OLED goes to the screensaver after this long without key events
+#[cfg(feature = "oled")]
+const OLED_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+/// Time each screensaver animation is shown for
+#[cfg(feature = "oled")]
+const OLED_SCREENSAVER_DURATION: Duration = Duration::from_secs(10);
+/// Frame rate of the OLED
+#[cfg(feature = "oled")]
+const OLED_FPS: u64 = 10;
+
+/// rmk's storage, the last 2 sectors of flash
+const RMK_STORAGE_OFFSET: u32 = (FLASH_SIZE - 2 * embassy_rp::flash::ERASE_SIZE) as u32;
+
+#[cfg(feature = "crash_log")]
+rmk_custom_device::crash_log_panic_handler!(flash_size: FLASH_SIZE, offset: CRASH_LOG_OFFSET);
+#[cfg(all(feature = "minimal", not(feature = "crash_log")))]
+rmk_custom_device::minimal_panic_handler!();
+#[cfg(feature = "priority_tasks")]
+rmk_custom_device::priority_interrupt_handler!();
+
+/// Power attributes reported to the host, the board runs from VBUS only
+const USB_POWER: UsbPowerConfig = UsbPowerConfig::bus_powered(100);
+/// VSYS, VBUS less the Schottky diode's drop, sagging below this blanks the lighting and holds flash writes
+/// until it's back above the recovery level
+#[cfg(not(feature = "charger"))]
+const LOW_VOLTAGE_MV: u16 = 4300;
+#[cfg(not(feature = "charger"))]
+const RECOVERED_VOLTAGE_MV: u16 = 4500;
+/// VSYS follows the cell behind the charger, low near its cut-off
+#[cfg(feature = "charger")]
+const LOW_VOLTAGE_MV: u16 = 3300;
+#[cfg(feature = "charger")]
+const RECOVERED_VOLTAGE_MV: u16 = 3500;
+/// The LED blinks while the battery of either half is below this, in percent
+#[cfg(feature = "charger")]
+const LOW_BATTERY_PERCENT: u8 = 10;
+/// VSYS and the temperature are read this often, the low voltage guard checks as often
+const TELEMETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Interval the host sends the lock LED reports at
+const LOCK_LED_POLL_INTERVAL_MS: u8 = 10;
+
+/// Keys held longer are force-released if the matrix reads look broken
+const STUCK_KEY_LIMIT: Duration = Duration::from_secs(60);
+/// The matrices watched for broken reads, the central's and the peripheral's
+static STUCK_KEY_MATRICES: [MatrixRegion; 2] = SPLIT_MATRICES;
+
+/// Lighting and display off and a slow scan while the host sleeps, a key press still wakes it
+const HOST_SLEEP_PROFILE: SleepProfile = SleepProfile::new(Duration::from_secs(5))
+    .with_rgb_off()
+    .with_oled_off()
+    .with_scan_interval(Duration::from_millis(10));
+
+/// Idle time before sleeping, on top of the host sleeping or USB being unpowered
+#[cfg(feature = "async_matrix")]
+const DORMANT_IDLE: Duration = Duration::from_secs(60);
+/// GPIOs waking the sleep, the matrix input and the peripheral's attention line
+#[cfg(feature = "async_matrix")]
+const DORMANT_WAKE_PINS: [usize; 2] = [13, 2];
+
+/// RP2040's 256K of RAM less a margin for the stacks and rmk's buffers, which aren't counted
+const RAM_BUDGET: usize = 256 * 1024 - 96 * 1024;
+/// embassy-executor's task arena, `task-arena-size-32768`
+const TASK_ARENA_SIZE: usize = 32768;
+
+/// Fail the build when the large static buffers outgrow the RAM
+const _: usize = RamBudget::new(RAM_BUDGET)
+    .with_library_statics()
+    .with::<[[[KeyAction; COL]; ROW]; NUM_LAYER]>()
+    .with_bytes(TASK_ARENA_SIZE)
+    .with_bytes(2 * SPLIT_MESSAGE_MAX_SIZE)
+    .check();
+
+/// Central's matrix, scanned by `central_matrix` below, on the left of the keymap
+const CENTRAL_ROW: usize = 2;
+const CENTRAL_COL: usize = 2;
+/// Peripheral's matrix, as scanned by the peripheral firmware, and its place in the keymap right of the central's
+const PERIPHERAL_ROW: usize = 2;
+const PERIPHERAL_COL: usize = 2;
+const PERIPHERAL_ROW_OFFSET: usize = 0;
+const PERIPHERAL_COL_OFFSET: usize = CENTRAL_COL;
+const SPLIT_MATRICES: [MatrixRegion; 2] = [
+    MatrixRegion::new(0..CENTRAL_ROW as u8, 0..CENTRAL_COL as u8),
+    MatrixRegion::new(
+        PERIPHERAL_ROW_OFFSET as u8..(PERIPHERAL_ROW_OFFSET + PERIPHERAL_ROW) as u8,
+        PERIPHERAL_COL_OFFSET as u8..(PERIPHERAL_COL_OFFSET + PERIPHERAL_COL) as u8,
+    ),
+];
+const _: () = assert!(
+    matrices_tile(&SPLIT_MATRICES, ROW, COL),
+    "the matrices of the halves don't cover each key of the keymap once"
+);
+
+/// Longest hold of the central's key events for the peripheral's barrier, a few retransmits of the split link
+const SPLIT_ORDER_TIMEOUT: Duration = Duration::from_millis(30);
+
+/// Key verifying firmware update images, raw 32 bytes ed25519 public key
+#[cfg(feature = "dfu")]
+static DFU_PUBLIC_KEY: &[u8; 32] = include_bytes!(env!("DFLIPDAISY_DFU_PUBLIC_KEY"));
+
+/// Key verifying config commands, raw 32 bytes ed25519 public key
+#[cfg(feature = "signed_config")]
+static CONFIG_PUBLIC_KEY: &[u8; 32] = include_bytes!(env!("DFLIPDAISY_CONFIG_PUBLIC_KEY"));
+
+/// Key positions of the KLE layout in keyboard.toml, both halves, so swap hands mirrors across them
+const PHYSICAL_LAYOUT: PhysicalLayout<ROW, COL> = PhysicalLayout::new(&KEY_POSITIONS);
+
+/// Holding the four corners locks or unlocks the keyboard, like the lock key of the SYS layer
+const KEY_LOCK_COMBO: [(u8, u8); 4] = [(0, 0), (0, COL as u8 - 1), (ROW as u8 - 1, 0), (ROW as u8 - 1, COL as u8 - 1)];
+
+/// Hold time of tap-hold keys, rmk's default
+const TAPPING_TERM: Duration = Duration::from_millis(250);
+/// A mod-tap held shorter is tapped when the next key is pressed by the same hand, below the tapping term
+const BILATERAL_WINDOW: Duration = Duration::from_millis(200);
+
+static BUILD_INFO: BuildInfo = build_info!(rows: ROW, cols: COL, layers: NUM_LAYER, tapping_term: TAPPING_TERM);
 
 #[embassy_executor::main]
 async fn main(spawner: Spawner) {
-    info!("RMK start!");
+    info!("RMK start! {}", BUILD_INFO);
     // Initialize peripherals
     let p = embassy_rp::init(Default::default());
 
     // Create the usb driver, from the HAL
-    let driver = Driver::new(p.USB, Irqs);
+    let driver = LockLedDriver::new(PowerAwareDriver::new(Driver::new(p.USB, Irqs), USB_POWER))
+        .with_poll_interval(LOCK_LED_POLL_INTERVAL_MS);
+    // Vendor raw HID commands are answered before rmk's Vial handler sees the reports
+    let driver = RawHidDriver::new(driver, &BUILD_INFO);
+    // Modifiers held by the custom actions go along with rmk's keyboard reports too
+    let driver = HeldModifiersDriver::new(driver);
+    // VBUS is sensed at GPIO24 like Pico
+    let vbus = Input::new(p.PIN_24, Pull::None);
+    // The LED at GPIO25 like Pico, shared by the indicators
+    let led = SharedOutput::new(Output::new(p.PIN_25, Level::Low));
 
     // Pin config
     let pins = config_sequential_matrix_pins_rp!(
@@ -66,7 +327,17 @@ async fn main(spawner: Spawner) {
     // Use internal flash to emulate eeprom
     // Both blocking and async flash are support, use different API
     // let flash = Flash::<_, Blocking, FLASH_SIZE>::new_blocking(p.FLASH);
-    let flash = Flash::<_, Async, FLASH_SIZE>::new(p.FLASH, p.DMA_CH0);
+    let mut flash = Flash::<_, Async, FLASH_SIZE>::new(p.FLASH, p.DMA_CH0);
+    #[cfg(feature = "crash_log")]
+    rmk_custom_device::crash::load_crash_record(&mut flash, CRASH_LOG_OFFSET);
+    // Settings left by a newer firmware may not parse, start them over rather than boot rmk on them.
+    // The signed config counter isn't a setting, it survives so that old commands can't be replayed
+    apply_config_handoff::<FLASH_SIZE>(take_config_handoff(), &[(RMK_STORAGE_OFFSET, 2), (FEATURE_FLAGS_OFFSET, 1)]);
+    load_feature_flags(&mut flash, FEATURE_FLAGS_OFFSET);
+    load_snippets(&mut flash, SNIPPETS_OFFSET);
+    load_heatmap(&mut flash, HEATMAP_OFFSET);
+    #[cfg(feature = "rgb")]
+    rmk_custom_device::lighting::load_lighting_settings(&mut flash, LIGHTING_OFFSET);
 
     let keyboard_usb_config = KeyboardUsbConfig {
         vid: 0x4c4b,
@@ -81,6 +352,13 @@ async fn main(spawner: Spawner) {
     let keyboard_config = RmkConfig {
         usb_config: keyboard_usb_config,
         vial_config,
+        behavior_config: BehaviorConfig {
+            tap_hold: TapHoldConfig {
+                hold_timeout: TAPPING_TERM,
+                ..Default::default()
+            },
+            ..Default::default()
+        },
         ..Default::default()
     };
 
@@ -88,7 +366,7 @@ async fn main(spawner: Spawner) {
     let tx_buf = &mut TX_BUF.init([0; SPLIT_MESSAGE_MAX_SIZE])[..];
     static RX_BUF: StaticCell<[u8; SPLIT_MESSAGE_MAX_SIZE]> = StaticCell::new();
     let rx_buf = &mut RX_BUF.init([0; SPLIT_MESSAGE_MAX_SIZE])[..];
-    let uart_receiver = BufferedUart::new(
+    let (uart_tx, uart_rx) = BufferedUart::new(
         p.UART0,
         Irqs,
         p.PIN_0,
@@ -96,31 +374,299 @@ async fn main(spawner: Spawner) {
         tx_buf,
         rx_buf,
         uart::Config::default(),
+    )
+    .split();
+    // Raised by the peripheral at GPIO2 before it sends, the UART alone can't wake the idle sleep
+    let _attention = Input::new(p.PIN_2, Pull::Down);
+
+    // Internal sensors, VSYS is sensed through 1/3 divider at GPIO29 like Pico
+    let telemetry = Rp2040Telemetry::new(
+        Adc::new(p.ADC, Irqs, adc::Config::default()),
+        adc::Channel::new_temp_sensor(p.ADC_TEMP_SENSOR),
+        Some(adc::Channel::new_pin(p.PIN_29, Pull::None)),
+    );
+
+    #[cfg(feature = "dfu")]
+    let dfu = rmk_custom_device::dfu::run_dfu::<FLASH_SIZE>(DFU_PUBLIC_KEY);
+    #[cfg(not(feature = "dfu"))]
+    let dfu = core::future::pending::<()>();
+    #[cfg(feature = "signed_config")]
+    let signed_config =
+        rmk_custom_device::signed::run_signed_config::<FLASH_SIZE>(CONFIG_PUBLIC_KEY, SIGNED_CONFIG_OFFSET);
+    #[cfg(not(feature = "signed_config"))]
+    let signed_config = core::future::pending::<()>();
+    #[cfg(feature = "crash_log")]
+    let crash_log = rmk_custom_device::crash::run_crash_log::<FLASH_SIZE>(CRASH_LOG_OFFSET);
+    #[cfg(not(feature = "crash_log"))]
+    let crash_log = core::future::pending::<()>();
+    #[cfg(feature = "secret_vault")]
+    let vault = join(
+        rmk_custom_device::vault::run_secret_vault::<_, FLASH_SIZE>(rmk_custom_device::vault::Rp2040RoscRng, VAULT_OFFSET),
+        rmk_custom_device::vault::run_vault_auto_lock(VAULT_AUTO_LOCK),
+    );
+    #[cfg(not(feature = "secret_vault"))]
+    let vault = core::future::pending::<()>();
+    let feature_flags_save =
+        run_feature_flags_save(|flags| save_feature_flags::<FLASH_SIZE>(FEATURE_FLAGS_OFFSET, flags));
+    let snippets_save = run_snippets_save(|| save_snippets::<FLASH_SIZE>(SNIPPETS_OFFSET));
+    let heatmap_checkpoint = run_heatmap_checkpoint::<FLASH_SIZE>(HEATMAP_OFFSET, HEATMAP_CHECKPOINT_INTERVAL);
+    // The signed config counter survives, so that old commands can't be replayed after a reset.
+    // The vault does too, its secrets stay sealed under their combo, and so do the snippets, text rather than settings
+    let config_reset = run_config_reset(|| {
+        for (offset, count) in [
+            (RMK_STORAGE_OFFSET, 2),
+            (FEATURE_FLAGS_OFFSET, 1),
+            #[cfg(feature = "rgb")]
+            (LIGHTING_OFFSET, 1),
+        ] {
+            if let Err(e) = erase_sectors::<FLASH_SIZE>(offset, count) {
+                warn!("Failed to erase the config at {}: {}", offset, e);
+            }
+        }
+    });
+
+    // The peripheral's keys go through the hooks below like the central's
+    let split_transport = run_split_transport(
+        MonitoredLink::new(uart_rx),
+        MonitoredLink::new(uart_tx),
+        CentralSplitHandler::<PERIPHERAL_ROW, PERIPHERAL_COL, PERIPHERAL_ROW_OFFSET, PERIPHERAL_COL_OFFSET>::new(),
+        rp2040_boot_epoch(),
+    );
+    #[cfg(feature = "priority_tasks")]
+    let split_transport = {
+        rmk_custom_device::priority::spawn_at_priority(async move {
+            split_transport.await;
+        });
+        core::future::pending::<()>()
+    };
+
+    #[cfg(feature = "async_matrix")]
+    let dormant = rmk_custom_device::dormant::run_dormant_sleep(&DORMANT_WAKE_PINS, DORMANT_IDLE);
+    #[cfg(not(feature = "async_matrix"))]
+    let dormant = core::future::pending::<()>();
+
+    // The DS3231 keeps its time over power cycles, the RP2040's RTC has to be set again after each
+    #[cfg(feature = "ds3231")]
+    let clock = run_clock(rmk_custom_device::clock::Ds3231::new(embassy_rp::i2c::I2c::new_blocking(
+        p.I2C1,
+        p.PIN_15,
+        p.PIN_14,
+        embassy_rp::i2c::Config::default(),
+    )));
+    #[cfg(not(feature = "ds3231"))]
+    let clock = run_clock(rmk_custom_device::clock::Rp2040Rtc::new(embassy_rp::rtc::Rtc::new(p.RTC)));
+
+    // Passive buzzer at GPIO22, the factory test's
+    #[cfg(feature = "buzzer")]
+    let alert_buzzer = rmk_custom_device::alert::run_alert_buzzer(Output::new(p.PIN_22, Level::Low));
+    #[cfg(not(feature = "buzzer"))]
+    let alert_buzzer = core::future::pending::<()>();
+
+    #[cfg(feature = "demo")]
+    let demo = rmk_custom_device::demo::run_demo_mode(&DEMO_SCRIPT);
+    #[cfg(not(feature = "demo"))]
+    let demo = core::future::pending::<()>();
+
+    #[cfg(feature = "jiggler")]
+    let jiggler = rmk_custom_device::jiggler::run_jiggler(
+        rmk_custom_device::jiggler::JigglerMode::Mouse,
+        JIGGLER_INTERVAL,
+        JIGGLER_AUTO_OFF,
+    );
+    #[cfg(not(feature = "jiggler"))]
+    let jiggler = core::future::pending::<()>();
+
+    // Woken by a button from GPIO3 to GND, the matrix is parked while off
+    #[cfg(feature = "soft_off")]
+    let soft_off = rmk_custom_device::soft_off::run_soft_off(rmk_custom_device::soft_off::Rp2040SoftOff::new(
+        Input::new(p.PIN_3, Pull::Up),
+        3,
+    ));
+    #[cfg(not(feature = "soft_off"))]
+    let soft_off = core::future::pending::<()>();
+
+    // TP4056 status outputs, CHRG at GPIO6 and STDBY at GPIO7, shown by the LED
+    #[cfg(feature = "charger")]
+    let charger = join(
+        rmk_custom_device::charger::run_charger_monitor(
+            rmk_custom_device::charger::ChargerStatusPins::new(
+                Input::new(p.PIN_6, Pull::Up),
+                Some(Input::new(p.PIN_7, Pull::Up)),
+            ),
+            Duration::from_secs(1),
+        ),
+        join(
+            rmk_custom_device::charger::run_charge_indicator(led.handle()),
+            rmk_custom_device::battery::run_low_battery_indicator(led.handle(), LOW_BATTERY_PERCENT),
+        ),
     );
+    #[cfg(not(feature = "charger"))]
+    let charger = core::future::pending::<()>();
+
+    // Before the recorder, the heatmap, the key stream and the custom actions, so that the combo's keys are neither recorded, counted, streamed nor acted on
+    #[cfg(feature = "secret_vault")]
+    let vault_hook = rmk_custom_device::vault::VaultHook::new(VAULT_COMBO_LEN);
+    #[cfg(not(feature = "secret_vault"))]
+    let vault_hook = ();
+
+    // After the custom actions, their keys quit the game or aren't prompted
+    #[cfg(feature = "oled")]
+    let typing_game =
+        rmk_custom_device::typing_game::TypingGameHook::<ROW, COL>::new(&CUSTOM_KEYS, &keymap::TYPING_GAME_LAYER_KEYS);
+    #[cfg(not(feature = "oled"))]
+    let typing_game = ();
 
     // Start serving
+    let mut default_keymap = keymap::get_default_keymap();
+    // Looked up by the training mode and raw HID
+    register_keymap(&default_keymap);
+    // Last but the held keys, it has to see what reaches rmk
+    let layer_tracker = LayerTrackerHook::new(&default_keymap, TAPPING_TERM);
+    let bilateral = BilateralHook::new(&default_keymap, HANDS, BILATERAL_WINDOW);
+    // Keys bound on the held layer light up while its layer key is held
+    #[cfg(feature = "rgb")]
+    let layer_summary = rmk_custom_device::layer_preview::KeymapSummary::from_keymap(&default_keymap);
+    #[cfg(feature = "rgb")]
+    let layer_preview = rmk_custom_device::layer_preview::LayerPreviewHook::new(layer_summary);
+    #[cfg(not(feature = "rgb"))]
+    let layer_preview = ();
+    #[cfg(all(feature = "jiggler", feature = "rgb"))]
+    let jiggler_effect = rmk_custom_device::jiggler::JigglerEffect::new(0, JIGGLER_COLOR);
+    #[cfg(not(all(feature = "jiggler", feature = "rgb")))]
+    let jiggler_effect = ();
+    // WS2812 chain at GPIO16, streamed by PIO0 and DMA
+    #[cfg(feature = "rgb")]
+    let rgb = {
+        let embassy_rp::pio::Pio { mut common, sm0, .. } = embassy_rp::pio::Pio::new(p.PIO0, Irqs);
+        let ws2812 = rmk_custom_device::ws2812::Ws2812::<_, 0, LED_COUNT>::new(&mut common, sm0, p.DMA_CH1, p.PIN_16);
+        join3(
+            rmk_custom_device::rgb::run_rgb_renderer(
+                (
+                    (
+                        rmk_custom_device::lighting::LightingEffect,
+                        rmk_custom_device::heatmap::HeatmapEffect::<LED_COUNT, COL>::new(LED_MAP),
+                    ),
+                    (
+                        (
+                            rmk_custom_device::presenter::PresenterEffect::new(
+                                PRESENTER_TALK,
+                                PRESENTER_COLOR,
+                                PRESENTER_OVERTIME_COLOR,
+                            ),
+                            rmk_custom_device::layer_preview::LayerPreviewEffect::new(layer_summary, LED_MAP, LAYER_PREVIEW_COLOR),
+                        ),
+                        (
+                            rmk_custom_device::training::TrainingEffect::new(LED_MAP, TRAINING_COLOR),
+                            (
+                                rmk_custom_device::key_lock::KeyLockEffect::new(KEY_LOCK_COLOR),
+                                (jiggler_effect, rmk_custom_device::alert::AlertEffect),
+                            ),
+                        ),
+                    ),
+                ),
+                &RGB_FRAME,
+                RGB_FPS,
+            ),
+            rmk_custom_device::ws2812::run_ws2812(ws2812, &RGB_FRAME),
+            rmk_custom_device::lighting::run_lighting_save(|settings| {
+                rmk_custom_device::lighting::save_lighting_settings::<FLASH_SIZE>(LIGHTING_OFFSET, settings)
+            }),
+        )
+    };
+    #[cfg(not(feature = "rgb"))]
+    let rgb = core::future::pending::<()>();
+
+    // SSD1306 on I2C0, GPIO20 SDA and GPIO21 SCL, blank while idle as no screensaver animation is registered
+    #[cfg(feature = "oled")]
+    let display = rmk_custom_device::oled::run_oled(
+        rmk_custom_device::oled::Ssd1306::new(embassy_rp::i2c::I2c::new_blocking(
+            p.I2C0,
+            p.PIN_21,
+            p.PIN_20,
+            embassy_rp::i2c::Config::default(),
+        )),
+        rmk_custom_device::oled::LayerPage,
+        rmk_custom_device::oled::Screensaver::<1>::new(OLED_SCREENSAVER_DURATION),
+        OLED_IDLE_TIMEOUT,
+        OLED_FPS,
+    );
+    #[cfg(not(feature = "oled"))]
+    let display = core::future::pending::<()>();
+
+    // Command shell on UART1, GPIO4 TX and GPIO5 RX
+    #[cfg(feature = "shell")]
+    let shell_commands: &[&dyn rmk_custom_device::shell::ShellCommand] = &[
+        &rmk_custom_device::layer_preview::LayerShell,
+        &rmk_custom_device::debounce::DebounceShell,
+        #[cfg(feature = "rgb")]
+        &rmk_custom_device::lighting::LightingShell,
+        #[cfg(feature = "bench")]
+        &rmk_custom_device::bench::BenchShell::<crate::custom::builder::MatrixDebouncer<CENTRAL_ROW, CENTRAL_COL>, CENTRAL_ROW, CENTRAL_COL>::new(),
+    ];
+    #[cfg(feature = "shell")]
+    let (mut shell_tx_buf, mut shell_rx_buf) = ([0u8; 256], [0u8; 64]);
+    #[cfg(feature = "shell")]
+    let shell = rmk_custom_device::shell::run_shell(
+        embassy_rp::uart::BufferedUart::new(
+            p.UART1,
+            Irqs,
+            p.PIN_4,
+            p.PIN_5,
+            &mut shell_tx_buf,
+            &mut shell_rx_buf,
+            embassy_rp::uart::Config::default(),
+        ),
+        shell_commands,
+    );
+    #[cfg(not(feature = "shell"))]
+    let shell = core::future::pending::<()>();
+
+    let keyboard = KeyboardBuilder::new(pins, &mut default_keymap, keyboard_config)
+        .central_matrix::<CENTRAL_ROW, CENTRAL_COL, 0, 0>()
+        // The second scan sources merged first, the key lock right after the split order, nothing else sees the keys it swallows
+        .hook((
+            DedupHook::new(KEY_ALIASES),
+            (
+                SplitOrderHook::<PERIPHERAL_ROW, PERIPHERAL_COL, PERIPHERAL_ROW_OFFSET, PERIPHERAL_COL_OFFSET>,
+                (KeyLockHook::new(KEY_LOCK_COMBO), (vault_hook, (FlightRecorderHook, (HeatmapHook::<ROW, COL>::new(), (KeyStreamHook, (StuckKeyHook, (PresenterHook::new(&PRESENTER_KEYS), (CustomActionHook::new(CUSTOM_KEYS), (typing_game, (bilateral, (SwapHandsHook::new(PHYSICAL_LAYOUT), (SocdHook::new(SOCD_PAIRS), (TrainingHook, (layer_preview, (layer_tracker, HeldKeysHook))))))))))))))),
+            ),
+        ))
+        .usb(driver)
+        .rgb(rgb)
+        .display(display)
+        .storage(QuiescentFlash::new(flash));
+    #[cfg(feature = "core1_matrix")]
+    let keyboard = keyboard.core1(p.CORE1);
     join(
-        run_rmk_split_central::<
-            Input<'_>,
-            Output<'_>,
-            Driver<'_, USB>,
-            Flash<peripherals::FLASH, Async, FLASH_SIZE>,
-            ROW,
-            COL,
-            2,
-            2,
-            0,
-            0,
-            NUM_LAYER,
-        >(
-            pins,
-            driver,
-            flash,
-            &mut keymap::get_default_keymap(),
-            keyboard_config,
-            spawner,
+        keyboard.build().run(spawner),
+        join3(
+            split_transport,
+            join4(
+                run_custom_actions(&BUILD_INFO),
+                join4(run_output(RmkOutput), run_presenter(), demo, jiggler),
+                run_split_order(SPLIT_ORDER_TIMEOUT),
+                join4(join3(dfu, vault, crash_log), signed_config, join3(feature_flags_save, snippets_save, heatmap_checkpoint), join5(run_action_scheduler(), run_system_reset(rp2040_reset), config_reset, run_split_link_log(Duration::from_secs(10)), shell)),
+            ),
+            join4(
+                join(
+                    run_rp2040_telemetry(telemetry, TELEMETRY_INTERVAL),
+                    run_low_voltage_guard(LOW_VOLTAGE_MV, RECOVERED_VOLTAGE_MV, TELEMETRY_INTERVAL),
+                ),
+                join(run_timer(LedFlashNotifier::new(led.handle())), alert_buzzer),
+                clock,
+                join4(
+                    join4(
+                    run_stuck_key_watchdog(STUCK_KEY_LIMIT, &STUCK_KEY_MATRICES),
+                    run_stuck_key_indicator(led.handle()),
+                    run_config_reset_indicator(led.handle()),
+                    charger,
+                ),
+                    run_vbus_monitor(vbus, Duration::from_millis(50)),
+                    run_usb_power_monitor(Duration::from_millis(100)),
+                    join3(run_host_sleep(HOST_SLEEP_PROFILE, Duration::from_millis(100)), dormant, soft_off),
+                ),
+            ),
         ),
-        run_peripheral_monitor::<2, 1, 2, 2, _>(0, uart_receiver),
     )
     .await;
 }
\ No newline at end of file
@@ -0,0 +1,388 @@
+use core::future::{pending, Future, Pending};
+
+use embassy_executor::Spawner;
+use embassy_futures::join::join3;
+#[cfg(feature = "core1_matrix")]
+use embassy_rp::peripherals::CORE1;
+use embassy_usb::driver::Driver;
+use embedded_hal::digital::{InputPin, OutputPin};
+#[cfg(feature = "async_matrix")]
+use embedded_hal_async::digital::Wait;
+#[cfg(not(feature = "_no_external_storage"))]
+use embedded_storage_async::nor_flash::NorFlash;
+
+use rmk::action::KeyAction;
+#[cfg(feature = "_nrf_ble")]
+use rmk::ble::nrf::initialize_nrf_ble_keyboard_and_run;
+use rmk::config::RmkConfig;
+#[cfg(not(feature = "rapid_debouncer"))]
+use rmk::debounce::default_bouncer::DefaultDebouncer;
+#[cfg(feature = "rapid_debouncer")]
+use rmk::debounce::fast_debouncer::RapidDebouncer;
+use rmk::debounce::DebouncerTrait;
+#[cfg(feature = "priority_tasks")]
+use rmk::matrix::MatrixTrait;
+use rmk::split::central::initialize_usb_split_central_and_run;
+
+use rmk_custom_device::debounce::RapidTrigger;
+use rmk_custom_device::event::KeyEventHook;
+use rmk_custom_device::matrix::{SequentialMatrix, SequentialMatrixPins, OffsettedMatrix};
+#[cfg(feature = "core1_matrix")]
+use rmk_custom_device::multicore::spawn_matrix_on_core1;
+#[cfg(feature = "priority_tasks")]
+use rmk_custom_device::{matrix::MatrixProxy, priority::spawn_at_priority};
+
+/// Re-press window of rapid trigger emulation, which is off until toggled
+const RAPID_TRIGGER_WINDOW_MS: u64 = 50;
+
+#[cfg(feature = "rapid_debouncer")]
+type KeyDebouncer<const ROW: usize, const COL: usize> = RapidDebouncer<COL, ROW>;
+#[cfg(not(feature = "rapid_debouncer"))]
+type KeyDebouncer<const ROW: usize, const COL: usize> = DefaultDebouncer<COL, ROW>;
+/// Debouncer of the matrix, also timed by the `bench` shell command
+pub(crate) type MatrixDebouncer<const ROW: usize, const COL: usize> =
+    RapidTrigger<KeyDebouncer<ROW, COL>, ROW, COL, RAPID_TRIGGER_WINDOW_MS>;
+
+#[cfg(all(feature = "core1_matrix", feature = "priority_tasks"))]
+compile_error!("`core1_matrix` and `priority_tasks` both take the matrix scan, enable one of them");
+
+/// Matrix size of the whole keyboard and the central's part of it
+pub struct SplitLayout<
+    const TOTAL_ROW: usize,
+    const TOTAL_COL: usize,
+    const CENTRAL_ROW: usize,
+    const CENTRAL_COL: usize,
+    const CENTRAL_ROW_OFFSET: usize,
+    const CENTRAL_COL_OFFSET: usize,
+>;
+
+/// Builder of the split central keyboard service, each optional subsystem is added by its own method.
+///
+/// ```ignore
+/// KeyboardBuilder::new(pins, &mut keymap, keyboard_config)
+///     .central_matrix::<2, 2, 0, 0>()
+///     .hook(hook)
+///     .usb(driver)
+///     .storage(flash)
+///     .build()
+///     .run(spawner)
+///     .await
+/// ```
+///
+/// * `central_matrix` - rows and columns of the central and their offsets in the whole matrix, the whole matrix by default
+/// * `hook` - hook applied to the central's key events before they reach rmk, none by default
+/// * `usb` - embassy usb driver instance
+/// * `storage` - flash storage for the keymap and keyboard configs, required unless the `_no_external_storage` feature is enabled implicitly by the chip
+/// * `rgb` - RGB renderer run alongside the keyboard, e.g. [`run_rgb_renderer`](rmk_custom_device::rgb::run_rgb_renderer)
+/// * `display` - display task run alongside the keyboard, e.g. [`run_oled`](rmk_custom_device::oled::run_oled)
+/// * `central_addr` - central's BLE static address. Required for nRF BLE split central
+/// * `core1` - RP2040's second core, which runs the matrix scan. Required with `core1_matrix`
+///
+/// With `priority_tasks`, the matrix is scanned by a task of [`spawn_at_priority`](rmk_custom_device::priority::spawn_at_priority) instead of rmk.
+pub struct KeyboardBuilder<'a, In, Out: OutputPin, H, D, F, R, O, L, const ROW: usize, const COL: usize, const NUM_LAYER: usize> {
+    pins: SequentialMatrixPins<In, Out>,
+    default_keymap: &'a mut [[[KeyAction; COL]; ROW]; NUM_LAYER],
+    keyboard_config: RmkConfig<'static, Out>,
+    layout: L,
+    hook: H,
+    usb_driver: D,
+    flash: F,
+    rgb: R,
+    display: O,
+    #[cfg(feature = "_nrf_ble")]
+    central_addr: Option<[u8; 6]>,
+    #[cfg(feature = "core1_matrix")]
+    core1: Option<CORE1>,
+}
+
+impl<'a, In, Out: OutputPin, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
+    KeyboardBuilder<'a, In, Out, (), (), (), Pending<()>, Pending<()>, SplitLayout<ROW, COL, ROW, COL, 0, 0>, ROW, COL, NUM_LAYER>
+{
+    /// * `pins` - matrix pins of the central, if `async_matrix` is enabled, the input pins should implement `embedded_hal_async::digital::Wait` trait
+    /// * `default_keymap` - default keymap definition of the whole keyboard
+    /// * `keyboard_config` - other configurations of the keyboard, check [RmkConfig] struct for details
+    pub fn new(
+        pins: SequentialMatrixPins<In, Out>,
+        default_keymap: &'a mut [[[KeyAction; COL]; ROW]; NUM_LAYER],
+        keyboard_config: RmkConfig<'static, Out>,
+    ) -> Self {
+        Self {
+            pins,
+            default_keymap,
+            keyboard_config,
+            layout: SplitLayout,
+            hook: (),
+            usb_driver: (),
+            flash: (),
+            rgb: pending(),
+            display: pending(),
+            #[cfg(feature = "_nrf_ble")]
+            central_addr: None,
+            #[cfg(feature = "core1_matrix")]
+            core1: None,
+        }
+    }
+}
+
+impl<'a, In, Out: OutputPin, H, D, F, R, O, L, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
+    KeyboardBuilder<'a, In, Out, H, D, F, R, O, L, ROW, COL, NUM_LAYER>
+{
+    pub fn central_matrix<
+        const CENTRAL_ROW: usize,
+        const CENTRAL_COL: usize,
+        const CENTRAL_ROW_OFFSET: usize,
+        const CENTRAL_COL_OFFSET: usize,
+    >(
+        self,
+    ) -> KeyboardBuilder<'a, In, Out, H, D, F, R, O, SplitLayout<ROW, COL, CENTRAL_ROW, CENTRAL_COL, CENTRAL_ROW_OFFSET, CENTRAL_COL_OFFSET>, ROW, COL, NUM_LAYER> {
+        KeyboardBuilder {
+            pins: self.pins,
+            default_keymap: self.default_keymap,
+            keyboard_config: self.keyboard_config,
+            layout: SplitLayout,
+            hook: self.hook,
+            usb_driver: self.usb_driver,
+            flash: self.flash,
+            rgb: self.rgb,
+            display: self.display,
+            #[cfg(feature = "_nrf_ble")]
+            central_addr: self.central_addr,
+            #[cfg(feature = "core1_matrix")]
+            core1: self.core1,
+        }
+    }
+
+    pub fn hook<H2: KeyEventHook>(self, hook: H2) -> KeyboardBuilder<'a, In, Out, H2, D, F, R, O, L, ROW, COL, NUM_LAYER> {
+        KeyboardBuilder {
+            pins: self.pins,
+            default_keymap: self.default_keymap,
+            keyboard_config: self.keyboard_config,
+            layout: self.layout,
+            hook,
+            usb_driver: self.usb_driver,
+            flash: self.flash,
+            rgb: self.rgb,
+            display: self.display,
+            #[cfg(feature = "_nrf_ble")]
+            central_addr: self.central_addr,
+            #[cfg(feature = "core1_matrix")]
+            core1: self.core1,
+        }
+    }
+
+    pub fn usb<D2: Driver<'static>>(self, usb_driver: D2) -> KeyboardBuilder<'a, In, Out, H, D2, F, R, O, L, ROW, COL, NUM_LAYER> {
+        KeyboardBuilder {
+            pins: self.pins,
+            default_keymap: self.default_keymap,
+            keyboard_config: self.keyboard_config,
+            layout: self.layout,
+            hook: self.hook,
+            usb_driver,
+            flash: self.flash,
+            rgb: self.rgb,
+            display: self.display,
+            #[cfg(feature = "_nrf_ble")]
+            central_addr: self.central_addr,
+            #[cfg(feature = "core1_matrix")]
+            core1: self.core1,
+        }
+    }
+
+    #[cfg(not(feature = "_no_external_storage"))]
+    pub fn storage<F2: NorFlash>(self, flash: F2) -> KeyboardBuilder<'a, In, Out, H, D, F2, R, O, L, ROW, COL, NUM_LAYER> {
+        KeyboardBuilder {
+            pins: self.pins,
+            default_keymap: self.default_keymap,
+            keyboard_config: self.keyboard_config,
+            layout: self.layout,
+            hook: self.hook,
+            usb_driver: self.usb_driver,
+            flash,
+            rgb: self.rgb,
+            display: self.display,
+            #[cfg(feature = "_nrf_ble")]
+            central_addr: self.central_addr,
+            #[cfg(feature = "core1_matrix")]
+            core1: self.core1,
+        }
+    }
+
+    pub fn rgb<R2: Future>(self, rgb: R2) -> KeyboardBuilder<'a, In, Out, H, D, F, R2, O, L, ROW, COL, NUM_LAYER> {
+        KeyboardBuilder {
+            pins: self.pins,
+            default_keymap: self.default_keymap,
+            keyboard_config: self.keyboard_config,
+            layout: self.layout,
+            hook: self.hook,
+            usb_driver: self.usb_driver,
+            flash: self.flash,
+            rgb,
+            display: self.display,
+            #[cfg(feature = "_nrf_ble")]
+            central_addr: self.central_addr,
+            #[cfg(feature = "core1_matrix")]
+            core1: self.core1,
+        }
+    }
+
+    pub fn display<O2: Future>(self, display: O2) -> KeyboardBuilder<'a, In, Out, H, D, F, R, O2, L, ROW, COL, NUM_LAYER> {
+        KeyboardBuilder {
+            pins: self.pins,
+            default_keymap: self.default_keymap,
+            keyboard_config: self.keyboard_config,
+            layout: self.layout,
+            hook: self.hook,
+            usb_driver: self.usb_driver,
+            flash: self.flash,
+            rgb: self.rgb,
+            display,
+            #[cfg(feature = "_nrf_ble")]
+            central_addr: self.central_addr,
+            #[cfg(feature = "core1_matrix")]
+            core1: self.core1,
+        }
+    }
+
+    #[cfg(feature = "_nrf_ble")]
+    pub fn central_addr(mut self, central_addr: [u8; 6]) -> Self {
+        self.central_addr = Some(central_addr);
+        self
+    }
+
+    #[cfg(feature = "core1_matrix")]
+    pub fn core1(mut self, core1: CORE1) -> Self {
+        self.core1 = Some(core1);
+        self
+    }
+
+    /// Finish the configuration
+    pub fn build(self) -> Keyboard<'a, In, Out, H, D, F, R, O, L, ROW, COL, NUM_LAYER> {
+        Keyboard { builder: self }
+    }
+}
+
+
+/// Split central keyboard service configured by [`KeyboardBuilder`]
+pub struct Keyboard<'a, In, Out: OutputPin, H, D, F, R, O, L, const ROW: usize, const COL: usize, const NUM_LAYER: usize> {
+    builder: KeyboardBuilder<'a, In, Out, H, D, F, R, O, L, ROW, COL, NUM_LAYER>,
+}
+
+impl<
+        'a,
+        #[cfg(feature = "async_matrix")] In: Wait + InputPin + Send + 'static,
+        #[cfg(not(feature = "async_matrix"))] In: InputPin + Send + 'static,
+        Out: OutputPin + Send + 'static,
+        H: KeyEventHook + Send + 'static,
+        D: Driver<'static>,
+        #[cfg(not(feature = "_no_external_storage"))] F: NorFlash,
+        #[cfg(feature = "_no_external_storage")] F,
+        R: Future,
+        O: Future,
+        const TOTAL_ROW: usize,
+        const TOTAL_COL: usize,
+        const CENTRAL_ROW: usize,
+        const CENTRAL_COL: usize,
+        const CENTRAL_ROW_OFFSET: usize,
+        const CENTRAL_COL_OFFSET: usize,
+        const NUM_LAYER: usize,
+    >
+    Keyboard<
+        'a,
+        In,
+        Out,
+        H,
+        D,
+        F,
+        R,
+        O,
+        SplitLayout<TOTAL_ROW, TOTAL_COL, CENTRAL_ROW, CENTRAL_COL, CENTRAL_ROW_OFFSET, CENTRAL_COL_OFFSET>,
+        TOTAL_ROW,
+        TOTAL_COL,
+        NUM_LAYER,
+    >
+{
+    /// Run RMK split central keyboard service with the subsystems. This function should never return.
+    ///
+    /// * `spawner`: embassy spawner used to spawn async tasks
+    #[allow(unused_variables)]
+    #[allow(unreachable_code)]
+    pub async fn run(self, spawner: Spawner) -> ! {
+        let KeyboardBuilder {
+            pins,
+            default_keymap,
+            keyboard_config,
+            layout: _,
+            hook,
+            usb_driver,
+            flash,
+            rgb,
+            display,
+            #[cfg(feature = "_nrf_ble")]
+            central_addr,
+            #[cfg(feature = "core1_matrix")]
+            core1,
+        } = self.builder;
+
+        let debouncer = MatrixDebouncer::<CENTRAL_ROW, CENTRAL_COL>::new();
+
+        let inner_matrix = SequentialMatrix::<
+            In,
+            Out,
+            _,
+            H,
+            CENTRAL_ROW,
+            CENTRAL_COL,
+        >::new(pins, debouncer, hook);
+        let matrix = OffsettedMatrix::<
+            _,
+            CENTRAL_ROW_OFFSET,
+            CENTRAL_COL_OFFSET,
+            CENTRAL_ROW,
+            CENTRAL_COL,
+        >::new(inner_matrix);
+        #[cfg(feature = "core1_matrix")]
+        let Some(core1) = core1 else {
+            defmt::panic!("core1_matrix needs the second core, see KeyboardBuilder::core1");
+        };
+        #[cfg(feature = "core1_matrix")]
+        let matrix = spawn_matrix_on_core1::<_, CENTRAL_ROW, CENTRAL_COL>(core1, matrix);
+        // Scanned from the software interrupt, rmk gets the proxy
+        #[cfg(feature = "priority_tasks")]
+        let matrix = {
+            let mut matrix = matrix;
+            spawn_at_priority(async move { matrix.scan().await });
+            MatrixProxy::<CENTRAL_ROW, CENTRAL_COL>
+        };
+
+        let keyboard = async move {
+            #[cfg(feature = "_nrf_ble")]
+            {
+                let Some(central_addr) = central_addr else {
+                    defmt::panic!("nRF BLE split central needs its address, see KeyboardBuilder::central_addr");
+                };
+                initialize_nrf_ble_keyboard_and_run::<_, _, D, TOTAL_ROW, TOTAL_COL, NUM_LAYER>(
+                    matrix,
+                    usb_driver,
+                    default_keymap,
+                    keyboard_config,
+                    Some(central_addr),
+                    spawner,
+                )
+                .await;
+            }
+
+            #[cfg(not(any(feature = "_nrf_ble", feature = "_esp_ble")))]
+            initialize_usb_split_central_and_run::<_, _, D, F, TOTAL_ROW, TOTAL_COL, NUM_LAYER>(
+                matrix,
+                usb_driver,
+                flash,
+                default_keymap,
+                keyboard_config,
+            )
+            .await;
+        };
+        join3(keyboard, rgb, display).await;
+
+        defmt::panic!("The run_rmk should never return");
+    }
+}
@@ -1,3 +1,5 @@
 
-pub(crate) mod central;
+pub(crate) mod builder;
 pub(crate) mod peripheral;
+#[cfg(feature = "standalone")]
+pub(crate) mod standalone;
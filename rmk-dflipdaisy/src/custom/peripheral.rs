@@ -9,9 +9,18 @@ use embedded_hal::digital::{InputPin, OutputPin};
 #[cfg(feature = "async_matrix")]
 use embedded_hal_async::digital::Wait;
 #[cfg(not(feature = "_nrf_ble"))]
+use embassy_futures::join::join;
+#[cfg(not(feature = "_nrf_ble"))]
 use embedded_io_async::{Read, Write};
+#[cfg(not(feature = "_nrf_ble"))]
+use rmk::matrix::MatrixTrait;
 
 use rmk_custom_device::matrix::{SequentialMatrix, SequentialMatrixPins};
+#[cfg(not(feature = "_nrf_ble"))]
+use rmk_custom_device::{
+    split_link::MonitoredLink,
+    split_transport::{rp2040_boot_epoch, run_split_transport, PeripheralSplitHandler, SplitSink},
+};
 
 
 /// Run the split peripheral service.
@@ -22,20 +31,22 @@ use rmk_custom_device::matrix::{SequentialMatrix, SequentialMatrixPins};
 /// * `output_pins` - output gpio pins
 /// * `central_addr` - (optional) central's BLE static address. This argument is enabled only for nRF BLE split now
 /// * `peripheral_addr` - (optional) peripheral's BLE static address. This argument is enabled only for nRF BLE split now
-/// * `serial` - (optional) serial port used to send peripheral split message. This argument is enabled only for serial split now
+/// * `rx`, `tx` - (optional) halves of the serial port carrying the [split transport](rmk_custom_device::split_transport). This argument is enabled only for serial split now
 /// * `spawner`: (optional) embassy spawner used to spawn async tasks. This argument is enabled for non-esp microcontrollers
 pub async fn run_rmk_split_peripheral<
     #[cfg(feature = "async_matrix")] In: Wait + InputPin,
     #[cfg(not(feature = "async_matrix"))] In: InputPin,
     Out: OutputPin,
-    #[cfg(not(feature = "_nrf_ble"))] S: Write + Read,
+    #[cfg(not(feature = "_nrf_ble"))] R: Read,
+    #[cfg(not(feature = "_nrf_ble"))] W: Write,
     const ROW: usize,
     const COL: usize,
 >(
     pins: SequentialMatrixPins<In, Out>,
     #[cfg(feature = "_nrf_ble")] central_addr: [u8; 6],
     #[cfg(feature = "_nrf_ble")] peripheral_addr: [u8; 6],
-    #[cfg(not(feature = "_nrf_ble"))] serial: S,
+    #[cfg(not(feature = "_nrf_ble"))] rx: R,
+    #[cfg(not(feature = "_nrf_ble"))] tx: W,
     #[cfg(feature = "_nrf_ble")] spawner: Spawner,
 ) {
     #[cfg(feature = "rapid_debouncer")]
@@ -47,15 +58,26 @@ pub async fn run_rmk_split_peripheral<
         In,
         Out,
         _,
+        _,
         ROW,
         COL,
-    >::new(pins, debouncer);
+    >::new(pins, debouncer, ());
 
+    // The events go to the central, whose hooks see them like its own
     #[cfg(not(feature = "_nrf_ble"))]
-    rmk::split::serial::initialize_serial_split_peripheral_and_run::<_, S, ROW, COL>(
-        matrix, serial,
-    )
-    .await;
+    {
+        let mut matrix = matrix.with_sink(SplitSink);
+        join(
+            matrix.scan(),
+            run_split_transport(
+                MonitoredLink::new(rx),
+                MonitoredLink::new(tx),
+                PeripheralSplitHandler,
+                rp2040_boot_epoch(),
+            ),
+        )
+        .await;
+    }
 
     #[cfg(feature = "_nrf_ble")]
     rmk::split::nrf::peripheral::initialize_nrf_ble_split_peripheral_and_run::<_, ROW, COL>(
@@ -0,0 +1,73 @@
+use embassy_executor::Spawner;
+use embassy_usb::driver::Driver;
+use embedded_hal::digital::{InputPin, OutputPin};
+#[cfg(feature = "async_matrix")]
+use embedded_hal_async::digital::Wait;
+use embedded_storage_async::nor_flash::NorFlash;
+
+use rmk::action::KeyAction;
+use rmk::config::RmkConfig;
+#[cfg(not(feature = "rapid_debouncer"))]
+use rmk::debounce::default_bouncer::DefaultDebouncer;
+#[cfg(feature = "rapid_debouncer")]
+use rmk::debounce::fast_debouncer::RapidDebouncer;
+use rmk::debounce::DebouncerTrait;
+use rmk::initialize_usb_keyboard_and_run;
+
+use rmk_custom_device::matrix::{SequentialMatrix, SequentialMatrixPins};
+
+
+/// Run the peripheral half as a standalone USB keyboard. This function should never return.
+///
+/// # Arguments
+///
+/// * `input_pins` - input gpio pins, if `async_matrix` is enabled, the input pins should implement `embedded_hal_async::digital::Wait` trait
+/// * `output_pins` - output gpio pins
+/// * `usb_driver` - embassy usb driver instance
+/// * `flash` - flash storage, which is used for storing keymap and keyboard configs
+/// * `default_keymap` - keymap of the half
+/// * `keyboard_config` - other configurations of the keyboard, check [RmkConfig] struct for details
+/// * `spawner`: embassy spawner used to spawn async tasks
+#[allow(unused_variables)]
+pub async fn run_rmk_standalone<
+    #[cfg(feature = "async_matrix")] In: Wait + InputPin,
+    #[cfg(not(feature = "async_matrix"))] In: InputPin,
+    Out: OutputPin,
+    D: Driver<'static>,
+    F: NorFlash,
+    const ROW: usize,
+    const COL: usize,
+    const NUM_LAYER: usize,
+>(
+    pins: SequentialMatrixPins<In, Out>,
+    usb_driver: D,
+    flash: F,
+    default_keymap: &mut [[[KeyAction; COL]; ROW]; NUM_LAYER],
+    keyboard_config: RmkConfig<'static, Out>,
+    spawner: Spawner,
+) -> ! {
+    #[cfg(feature = "rapid_debouncer")]
+    let debouncer: RapidDebouncer<COL, ROW> = RapidDebouncer::new();
+    #[cfg(not(feature = "rapid_debouncer"))]
+    let debouncer: DefaultDebouncer<COL, ROW> = DefaultDebouncer::new();
+
+    let matrix = SequentialMatrix::<
+        In,
+        Out,
+        _,
+        _,
+        ROW,
+        COL,
+    >::new(pins, debouncer, ());
+
+    initialize_usb_keyboard_and_run(
+        matrix,
+        usb_driver,
+        flash,
+        default_keymap,
+        keyboard_config,
+    )
+    .await;
+
+    defmt::panic!("The run_rmk should never return");
+}
@@ -0,0 +1,206 @@
+#![no_main]
+#![no_std]
+
+//! Factory test image for DflipDaisy boards.
+//! Verifies the flash, cycles the LED and the buzzer for the operator to check, then prints every matrix
+//! position over USB CDC as it's pressed, and reports PASS once all positions are seen.
+
+#[macro_use]
+mod macros;
+
+use core::fmt::Write;
+use rmk_custom_device::{
+    event::ChannelSink,
+    matrix::{SequentialMatrix, SequentialMatrixPins},
+    timer::{BuzzerNotifier, TimerNotifier},
+};
+
+use defmt::*;
+use defmt_rtt as _;
+use embassy_executor::Spawner;
+use embassy_futures::join::join3;
+use embassy_rp::{
+    bind_interrupts,
+    flash::{Blocking, Flash, ERASE_SIZE},
+    gpio::{AnyPin, Input, Level, Output},
+    peripherals::{FLASH, USB},
+    usb::{Driver, InterruptHandler},
+};
+use embassy_usb::{
+    class::cdc_acm::{CdcAcmClass, State},
+    driver::EndpointError,
+    Builder,
+};
+use heapless::String;
+use panic_probe as _;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+use embassy_time::{Duration, Timer};
+use rmk::{
+    debounce::{default_bouncer::DefaultDebouncer, DebouncerTrait},
+    event::KeyEvent,
+    matrix::MatrixTrait,
+};
+
+bind_interrupts!(struct Irqs {
+    USBCTRL_IRQ => InterruptHandler<USB>;
+});
+
+const FLASH_SIZE: usize = 2 * 1024 * 1024;
+/// Last sector, where the keyboard storage lives, so the test leaves no trace after config reset
+const FLASH_TEST_OFFSET: u32 = (FLASH_SIZE - ERASE_SIZE) as u32;
+
+const ROW: usize = 2;
+const COL: usize = 2;
+
+const MAX_PACKET_SIZE: u16 = 64;
+
+/// Blinks of the LED in the output test
+const LED_BLINKS: usize = 3;
+const LED_BLINK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Events of the matrix, read by the test instead of rmk
+static TEST_EVENT_CHANNEL: Channel<CriticalSectionRawMutex, KeyEvent, 8> = Channel::new();
+
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    info!("Factory test start!");
+    // Initialize peripherals
+    let p = embassy_rp::init(Default::default());
+
+    // Create the usb driver, from the HAL
+    let driver = Driver::new(p.USB, Irqs);
+
+    // Pin config
+    let pins = config_sequential_matrix_pins_rp!(
+        peripherals: p,
+        row_clock: PIN_9,
+        col_clock: PIN_10,
+        any_not: PIN_11,
+        reset_not: PIN_12,
+        input: PIN_13,
+    );
+    let mut matrix = SequentialMatrix::<_, _, _, _, ROW, COL>::new(
+        pins,
+        DefaultDebouncer::<COL, ROW>::new(),
+        (),
+    )
+    .with_sink(ChannelSink::new(&TEST_EVENT_CHANNEL));
+
+    let mut flash = Flash::<_, Blocking, FLASH_SIZE>::new_blocking(p.FLASH);
+    let mut led = Output::new(p.PIN_25, Level::Low);
+    // Passive buzzer, driven like the timer's
+    let mut buzzer = Output::new(p.PIN_22, Level::Low);
+
+    let mut config = embassy_usb::Config::new(0x4c4b, 0x4643);
+    config.manufacturer = Some("Haobo");
+    config.product = Some("DflipDaisy factory test");
+    config.max_packet_size_0 = MAX_PACKET_SIZE as u8;
+
+    let mut config_descriptor = [0; 256];
+    let mut bos_descriptor = [0; 256];
+    let mut msos_descriptor = [0; 256];
+    let mut control_buf = [0; 64];
+    let mut state = State::new();
+    let mut builder = Builder::new(
+        driver,
+        config,
+        &mut config_descriptor,
+        &mut bos_descriptor,
+        &mut msos_descriptor,
+        &mut control_buf,
+    );
+    let mut class = CdcAcmClass::new(&mut builder, &mut state, MAX_PACKET_SIZE);
+    let mut usb = builder.build();
+
+    join3(usb.run(), matrix.scan(), async {
+        loop {
+            class.wait_connection().await;
+            info!("CDC connected");
+            let _ = run_factory_test(&mut class, &mut flash, &mut led, &mut buzzer).await;
+            info!("CDC disconnected");
+        }
+    })
+    .await;
+}
+
+async fn run_factory_test<'d>(
+    class: &mut CdcAcmClass<'d, Driver<'d, USB>>,
+    flash: &mut Flash<'_, FLASH, Blocking, FLASH_SIZE>,
+    led: &mut Output<'_>,
+    buzzer: &mut Output<'_>,
+) -> Result<(), EndpointError> {
+    let mut line: String<64> = String::new();
+    write_line(class, "DflipDaisy factory test").await?;
+
+    let flash_ok = verify_flash(flash);
+    write_line(class, if flash_ok { "flash: ok" } else { "flash: FAIL" }).await?;
+
+    write_line(class, "outputs: check the LED blinking, then the buzzer playing").await?;
+    cycle_outputs(led, buzzer).await;
+    write_line(class, "outputs: done").await?;
+
+    line.clear();
+    let _ = write!(line, "press all {} keys", ROW * COL);
+    write_line(class, &line).await?;
+
+    let mut seen = [[false; COL]; ROW];
+    let mut remaining = ROW * COL;
+    loop {
+        let event = TEST_EVENT_CHANNEL.receive().await;
+        let (row, col) = (event.row as usize, event.col as usize);
+        line.clear();
+        let _ = write!(
+            line,
+            "{} {},{}",
+            if event.pressed { "press" } else { "release" },
+            row,
+            col
+        );
+        write_line(class, &line).await?;
+
+        if event.pressed && row < ROW && col < COL && !seen[row][col] {
+            seen[row][col] = true;
+            remaining -= 1;
+            if remaining == 0 {
+                write_line(class, if flash_ok { "result: PASS" } else { "result: FAIL" }).await?;
+            }
+        }
+    }
+}
+
+/// Blink the LED, then play the timer's melody on the buzzer
+async fn cycle_outputs(led: &mut Output<'_>, buzzer: &mut Output<'_>) {
+    for _ in 0..LED_BLINKS {
+        led.set_high();
+        Timer::after(LED_BLINK_INTERVAL).await;
+        led.set_low();
+        Timer::after(LED_BLINK_INTERVAL).await;
+    }
+    BuzzerNotifier::new(buzzer, BuzzerNotifier::<Output<'_>>::DEFAULT_MELODY).notify().await;
+}
+
+/// Erase, write and read back a pattern on the last sector
+fn verify_flash(flash: &mut Flash<'_, FLASH, Blocking, FLASH_SIZE>) -> bool {
+    let mut pattern = [0u8; 256];
+    for (i, b) in pattern.iter_mut().enumerate() {
+        *b = (i as u8) ^ 0x5A;
+    }
+    let mut read_back = [0u8; 256];
+    let end = FLASH_TEST_OFFSET + ERASE_SIZE as u32;
+    let ok = flash.blocking_erase(FLASH_TEST_OFFSET, end).is_ok()
+        && flash.blocking_write(FLASH_TEST_OFFSET, &pattern).is_ok()
+        && flash.blocking_read(FLASH_TEST_OFFSET, &mut read_back).is_ok()
+        && read_back == pattern;
+    // Leave the sector erased, as a blank storage
+    flash.blocking_erase(FLASH_TEST_OFFSET, end).is_ok() && ok
+}
+
+async fn write_line<'d>(
+    class: &mut CdcAcmClass<'d, Driver<'d, USB>>,
+    text: &str,
+) -> Result<(), EndpointError> {
+    for chunk in text.as_bytes().chunks(MAX_PACKET_SIZE as usize) {
+        class.write_packet(chunk).await?;
+    }
+    class.write_packet(b"\r\n").await
+}
@@ -1,26 +1,177 @@
 use rmk::action::KeyAction;
-use rmk::{a, k, layer, mo};
+use rmk_custom_device::action::{join_custom_keys, CustomAction, CustomKey};
+use rmk_custom_device::bilateral::{Hand, HandMap};
+use rmk_custom_device::dedup::KeyAlias;
+use rmk_custom_device::keymap_check::{custom_keys_in_range, layers_in_range};
+use rmk_custom_device::presenter::PresenterKey;
+use rmk_custom_device::scheduler::ActionStep;
+use rmk_custom_device::socd::{SocdMode, SocdPair};
+use rmk_custom_device::{keymap, layer_names};
 
 // TODO: customize later
 
-pub(crate) const COL: usize = 3;
-pub(crate) const ROW: usize = 4;
-pub(crate) const NUM_LAYER: usize = 2;
+pub(crate) const COL: usize = 4;
+pub(crate) const ROW: usize = 2;
+pub(crate) const NUM_LAYER: usize = 7;
 
+layer_names!(pub(crate) BASE, FN, SYS, TOOL, MODE, TEXT, LIGHT);
+
+/// The central's 2x2 on the left, the peripheral's on the right. FN holds the function
+/// layers of the firmware's own keys, positions bound in [`CUSTOM_KEYS`] are `XX` there
 #[rustfmt::skip]
+const KEYMAP: [[[KeyAction; COL]; ROW]; NUM_LAYER] = keymap! {
+    BASE: [
+        [AudioVolUp  MT(B, LCtrl)  Kp3        XX]
+        [Kp4         LShift        Kp6        MO(FN)]
+    ],
+    FN: [
+        [MO(TEXT)    Kp8           MO(LIGHT)  XX]
+        [MO(SYS)     MO(TOOL)      MO(MODE)   MO(FN)]
+    ],
+    SYS: [
+        [XX          XX            XX         XX]
+        [_           XX            XX         _]
+    ],
+    TOOL: [
+        [XX          XX            XX         XX]
+        [XX          _             XX         _]
+    ],
+    MODE: [
+        [XX          XX            XX         XX]
+        [XX          XX            _          _]
+    ],
+    TEXT: [
+        [_           XX            XX         XX]
+        [XX          XX            XX         _]
+    ],
+    LIGHT: [
+        [XX          XX            _          XX]
+        [XX          XX            XX         _]
+    ],
+};
+
 pub fn get_default_keymap() -> [[[KeyAction; COL]; ROW]; NUM_LAYER] {
-    [
-        layer!([
-            [k!(AudioVolUp), k!(B), k!(AudioVolDown)],
-            [k!(Kp4), k!(LShift), k!(Kp6)],
-            [mo!(1), k!(Kp2), k!(Kp3)],
-            [mo!(1), a!(No), k!(Kp0)]
-        ]),
-        layer!([
-            [k!(Kp7), k!(Kp8), k!(Kp9)],
-            [k!(Kp4), k!(LCtrl), k!(Kp6)],
-            [mo!(1), k!(Kp2), k!(Kp3)],
-            [mo!(1), a!(No), k!(Kp0)]
-        ]),
-    ]
-}
\ No newline at end of file
+    KEYMAP
+}
+
+/// Typed by the TEXT layer's macro key
+const SIGNATURE: [ActionStep; 2] = [ActionStep::Text("Best regards,"), ActionStep::Tap('\n')];
+
+/// Keys handled by the firmware instead of rmk, the version key on every layer, the peripheral's (0,1),
+/// and the others on the function layers
+const FIRMWARE_KEYS: [CustomKey; 24] = [
+    CustomKey::new(0, 3, CustomAction::Version),
+    // Held for `CONFIG_RESET_HOLD`
+    CustomKey::on_layer(SYS, 0, 0, CustomAction::ResetConfig),
+    CustomKey::on_layer(SYS, 0, 1, CustomAction::SystemReset),
+    CustomKey::on_layer(SYS, 0, 2, CustomAction::Bootloader),
+    CustomKey::on_layer(SYS, 1, 1, CustomAction::LockKeyboard),
+    CustomKey::on_layer(TOOL, 0, 0, CustomAction::Settings),
+    CustomKey::on_layer(TOOL, 0, 1, CustomAction::Timestamp),
+    CustomKey::on_layer(TOOL, 0, 2, CustomAction::StartPomodoro),
+    CustomKey::on_layer(TOOL, 0, 3, CustomAction::ToggleMatrixLog),
+    CustomKey::on_layer(TOOL, 1, 0, CustomAction::ToggleKeyStream),
+    CustomKey::on_layer(TOOL, 1, 2, CustomAction::ToggleTraining),
+    CustomKey::on_layer(MODE, 0, 0, CustomAction::ToggleSocd),
+    CustomKey::on_layer(MODE, 0, 1, CustomAction::ToggleRapidTrigger),
+    CustomKey::on_layer(MODE, 1, 0, CustomAction::SwapHands),
+    CustomKey::on_layer(MODE, 1, 1, CustomAction::TogglePresenter),
+    CustomKey::on_layer(TEXT, 0, 1, CustomAction::Char('é')),
+    CustomKey::on_layer(TEXT, 0, 2, CustomAction::Symbol('@')),
+    CustomKey::on_layer(TEXT, 0, 3, CustomAction::Snippet(0)),
+    CustomKey::on_layer(TEXT, 1, 0, CustomAction::CycleHostLayout),
+    CustomKey::on_layer(TEXT, 1, 1, CustomAction::Macro(&SIGNATURE)),
+    // Taps while held
+    CustomKey::on_layer(TEXT, 1, 2, CustomAction::Turbo('z')),
+    CustomKey::on_layer(LIGHT, 0, 0, CustomAction::MuteAlerts),
+    CustomKey::on_layer(LIGHT, 0, 1, CustomAction::ToggleHeatmap),
+    CustomKey::on_layer(LIGHT, 1, 2, CustomAction::CycleBrightness),
+];
+#[cfg(not(feature = "secret_vault"))]
+const VAULT_KEYS: [CustomKey; 0] = [];
+/// With the vault, the base layer's Kp3 unlocks it and Kp6 types its first secret
+#[cfg(all(feature = "secret_vault", not(feature = "totp")))]
+const VAULT_KEYS: [CustomKey; 2] = [
+    CustomKey::on_layer(BASE, 0, 2, CustomAction::UnlockVault),
+    CustomKey::on_layer(BASE, 1, 2, CustomAction::Secret(0)),
+];
+/// With TOTP, Kp4 also types the code of the vault's second secret
+#[cfg(feature = "totp")]
+const VAULT_KEYS: [CustomKey; 3] = [
+    CustomKey::on_layer(BASE, 0, 2, CustomAction::UnlockVault),
+    CustomKey::on_layer(BASE, 1, 2, CustomAction::Secret(0)),
+    CustomKey::on_layer(BASE, 1, 0, CustomAction::Totp(1)),
+];
+#[cfg(not(feature = "oled"))]
+const OLED_KEYS: [CustomKey; 0] = [];
+/// With the OLED, the LIGHT layer's (1,0) starts or quits the typing game
+#[cfg(feature = "oled")]
+const OLED_KEYS: [CustomKey; 1] = [CustomKey::on_layer(LIGHT, 1, 0, CustomAction::ToggleTypingGame)];
+#[cfg(not(feature = "demo"))]
+const DEMO_KEYS: [CustomKey; 0] = [];
+/// With the demo mode, the LIGHT layer's (1,1) starts or stops it
+#[cfg(feature = "demo")]
+const DEMO_KEYS: [CustomKey; 1] = [CustomKey::on_layer(LIGHT, 1, 1, CustomAction::ToggleDemo)];
+#[cfg(not(feature = "jiggler"))]
+const JIGGLER_KEYS: [CustomKey; 0] = [];
+/// With the jiggler, the MODE layer's (0,2) turns it on or off
+#[cfg(feature = "jiggler")]
+const JIGGLER_KEYS: [CustomKey; 1] = [CustomKey::on_layer(MODE, 0, 2, CustomAction::ToggleJiggler)];
+#[cfg(not(feature = "soft_off"))]
+const SOFT_OFF_KEYS: [CustomKey; 0] = [];
+/// With the soft off, the SYS layer's (1,2) turns the keyboard off
+#[cfg(feature = "soft_off")]
+const SOFT_OFF_KEYS: [CustomKey; 1] = [CustomKey::on_layer(SYS, 1, 2, CustomAction::SoftOff)];
+
+pub(crate) const CUSTOM_KEYS: [CustomKey; FIRMWARE_KEYS.len() + VAULT_KEYS.len() + OLED_KEYS.len() + DEMO_KEYS.len() + JIGGLER_KEYS.len() + SOFT_OFF_KEYS.len()] =
+    join_custom_keys(&[&FIRMWARE_KEYS, &VAULT_KEYS, &OLED_KEYS, &DEMO_KEYS, &JIGGLER_KEYS, &SOFT_OFF_KEYS]);
+
+/// Keys passed on through the typing game, MO(FN) and MO(LIGHT) on the way to its toggle key,
+/// as `(layer, row, col)`
+#[cfg(feature = "oled")]
+pub(crate) const TYPING_GAME_LAYER_KEYS: [(u8, u8, u8); 2] = [(BASE, 1, 3), (FN, 0, 2)];
+
+const _: () = assert!(layers_in_range(&KEYMAP), "a layer key switches to a layer out of the keymap");
+const _: () = assert!(custom_keys_in_range(&CUSTOM_KEYS, ROW, COL, NUM_LAYER), "a custom key is out of the keymap or shadowed");
+
+/// Second scan sources of keys, reported at the key's position, for a revision moving keys between sources.
+/// The current revisions scan each key once
+pub(crate) const KEY_ALIASES: [KeyAlias; 0] = [];
+
+/// Hand of each key for the bilateral mod-taps, the central's half is the left hand
+pub(crate) const HANDS: HandMap<ROW, COL> = HandMap::new([[Hand::Left, Hand::Left, Hand::Right, Hand::Right]; ROW]);
+
+/// Opposite keys cleaned by SOCD, Kp4 and Kp6 of the base layer, one on each half
+pub(crate) const SOCD_PAIRS: [SocdPair; 1] = [
+    SocdPair::new((1, 0), (1, 2), SocdMode::LastInputPriority).on_layer(BASE),
+];
+
+/// Slide controls while presenting, whatever the layer: Kp4 goes back, Kp3 forward and B blanks the screen.
+/// None of them is on the way to the MODE layer, whose key stops presenting
+pub(crate) const PRESENTER_KEYS: [(u8, u8, PresenterKey); 3] = [
+    (1, 0, PresenterKey::Previous),
+    (0, 2, PresenterKey::Next),
+    (0, 1, PresenterKey::Blank),
+];
+
+/// Keymap of the peripheral half run alone, only used by the peripheral
+#[cfg(feature = "standalone")]
+#[allow(dead_code)]
+pub(crate) mod standalone {
+    use rmk::action::KeyAction;
+    use rmk::{k, layer};
+
+    pub(crate) const COL: usize = 1;
+    pub(crate) const ROW: usize = 2;
+    pub(crate) const NUM_LAYER: usize = 1;
+
+    #[rustfmt::skip]
+    pub fn get_default_keymap() -> [[[KeyAction; COL]; ROW]; NUM_LAYER] {
+        [
+            layer!([
+                [k!(Kp3)],
+                [k!(Kp0)]
+            ]),
+        ]
+    }
+}
@@ -4,6 +4,13 @@ macro_rules! config_output_pin_rp {
             Output::new(AnyPin::from($p.$out_pin), embassy_rp::gpio::Level::Low)
         }
     };
+    ($p:ident, $out_pin:ident, $pad:expr) => {
+        {
+            let mut pin = config_output_pin_rp!($p, $out_pin);
+            $pad.apply_output(&mut pin);
+            pin
+        }
+    };
 }
 
 macro_rules! config_input_pin_rp {
@@ -12,6 +19,13 @@ macro_rules! config_input_pin_rp {
             Input::new(AnyPin::from($p.$in_pin), embassy_rp::gpio::Pull::Down)
         }
     };
+    ($p:ident, $in_pin:ident, $pad:expr) => {
+        {
+            let mut pin = config_input_pin_rp!($p, $in_pin);
+            $pad.apply_input(&mut pin);
+            pin
+        }
+    };
 }
 
 macro_rules! config_sequential_matrix_pins_rp {
@@ -32,5 +46,25 @@ macro_rules! config_sequential_matrix_pins_rp {
                 config_input_pin_rp!($p, $input),
             )
         }
-    }
+    };
+    (
+        peripherals: $p:ident,
+        row_clock: $row:ident,
+        col_clock: $col:ident,
+        any_not: $any_not:ident,
+        reset_not: $reset_not:ident,
+        input: $input:ident,
+        pad: $pad:expr,
+    ) => {
+        {
+            let pad: rmk_custom_device::matrix::MatrixPadConfig = $pad;
+            SequentialMatrixPins::new(
+                config_output_pin_rp!($p, $row, pad),
+                config_output_pin_rp!($p, $col, pad),
+                config_output_pin_rp!($p, $any_not, pad),
+                config_output_pin_rp!($p, $reset_not, pad),
+                config_input_pin_rp!($p, $input, pad),
+            )
+        }
+    };
 }
\ No newline at end of file
@@ -1,37 +1,113 @@
 #![no_main]
 #![no_std]
 
+#[cfg(feature = "standalone")]
+#[allow(dead_code)]
+mod keymap;
 #[macro_use]
 mod macros;
 
 mod custom;
 use crate::custom::peripheral::run_rmk_split_peripheral;
+#[cfg(feature = "standalone")]
+use crate::custom::standalone::run_rmk_standalone;
+#[cfg(feature = "standalone")]
+use crate::keymap::standalone;
 use rmk_custom_device::matrix::SequentialMatrixPins;
+use rmk_custom_device::split_link::AttentionLink;
+#[cfg(feature = "standalone")]
+use rmk_custom_device::split_link::wait_for_split_peer;
 
 use defmt::*;
+#[cfg(not(feature = "minimal"))]
 use defmt_rtt as _;
 use embassy_executor::Spawner;
+use embassy_futures::join::join;
+#[cfg(feature = "charger")]
+use embassy_rp::adc::{self, Adc};
+#[cfg(feature = "standalone")]
+use embassy_rp::{
+    flash::{Async, Flash},
+    peripherals::USB,
+    usb::{Driver, InterruptHandler},
+};
+#[cfg(any(feature = "standalone", feature = "charger"))]
+use embassy_rp::gpio::Pull;
 use embassy_rp::{
     bind_interrupts,
-    gpio::{AnyPin, Input, Output},
-    peripherals::{UART0, USB},
+    gpio::{AnyPin, Input, Level, Output},
+    peripherals::UART0,
     uart::{self, BufferedUart},
-    usb::InterruptHandler,
 };
+#[cfg(any(feature = "standalone", feature = "charger"))]
+use embassy_time::Duration;
+#[cfg(not(feature = "minimal"))]
 use panic_probe as _;
+#[cfg(feature = "standalone")]
+use rmk::config::{KeyboardUsbConfig, RmkConfig};
 use rmk::split::SPLIT_MESSAGE_MAX_SIZE;
 use static_cell::StaticCell;
 
+#[cfg(feature = "minimal")]
+rmk_custom_device::minimal_panic_handler!();
+
+#[cfg(all(feature = "standalone", feature = "lean_peripheral"))]
+compile_error!("`standalone` runs the USB stack which `lean_peripheral` leaves out");
+
+#[cfg(all(feature = "standalone", not(feature = "charger")))]
+bind_interrupts!(struct Irqs {
+    USBCTRL_IRQ => InterruptHandler<USB>;
+    UART0_IRQ => uart::BufferedInterruptHandler<UART0>;
+});
+
+#[cfg(all(feature = "standalone", feature = "charger"))]
 bind_interrupts!(struct Irqs {
     USBCTRL_IRQ => InterruptHandler<USB>;
     UART0_IRQ => uart::BufferedInterruptHandler<UART0>;
+    ADC_IRQ_FIFO => adc::InterruptHandler;
+});
+
+/// Only the split link's UART
+#[cfg(all(not(feature = "standalone"), not(feature = "charger")))]
+bind_interrupts!(struct Irqs {
+    UART0_IRQ => uart::BufferedInterruptHandler<UART0>;
+});
+
+/// The split link's UART and the ADC sensing the battery
+#[cfg(all(not(feature = "standalone"), feature = "charger"))]
+bind_interrupts!(struct Irqs {
+    UART0_IRQ => uart::BufferedInterruptHandler<UART0>;
+    ADC_IRQ_FIFO => adc::InterruptHandler;
 });
 
+#[cfg(feature = "standalone")]
+const FLASH_SIZE: usize = 2 * 1024 * 1024;
+
+/// Time for the central to boot and drive the UART line, run standalone after it
+#[cfg(feature = "standalone")]
+const CENTRAL_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// VSYS is read this often, the level is relayed to the central as often
+#[cfg(feature = "charger")]
+const BATTERY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Without the USB stack, leave the USB clock off to save current
+fn rp_config() -> embassy_rp::config::Config {
+    #[allow(unused_mut)]
+    let mut config = embassy_rp::config::Config::default();
+    #[cfg(feature = "lean_peripheral")]
+    {
+        config.clocks.usb_clk = None;
+    }
+    config
+}
+
 #[embassy_executor::main]
 async fn main(_spawner: Spawner) {
     info!("RMK start!");
     // Initialize peripherals
-    let p = embassy_rp::init(Default::default());
+    #[cfg_attr(not(feature = "standalone"), allow(unused_mut))]
+    let mut p = embassy_rp::init(rp_config());
 
     // Pin config
     let pins = config_sequential_matrix_pins_rp!(
@@ -43,11 +119,46 @@ async fn main(_spawner: Spawner) {
         input: PIN_13,
     );
 
+    // Powered by its own USB without the other half, act as a keyboard by itself
+    #[cfg(feature = "standalone")]
+    if !wait_for_split_peer(Input::new(&mut p.PIN_1, Pull::Down), CENTRAL_TIMEOUT).await {
+        info!("No central found, running standalone");
+        let driver = Driver::new(p.USB, Irqs);
+        let flash = Flash::<_, Async, FLASH_SIZE>::new(p.FLASH, p.DMA_CH0);
+        let keyboard_config = RmkConfig {
+            usb_config: KeyboardUsbConfig {
+                vid: 0x4c4b,
+                pid: 0x4643,
+                manufacturer: "Haobo",
+                product_name: "RMK Keyboard (half)",
+                serial_number: "standalone:000001",
+            },
+            ..Default::default()
+        };
+        run_rmk_standalone::<
+            Input<'_>,
+            Output<'_>,
+            _,
+            _,
+            { standalone::ROW },
+            { standalone::COL },
+            { standalone::NUM_LAYER },
+        >(
+            pins,
+            driver,
+            flash,
+            &mut standalone::get_default_keymap(),
+            keyboard_config,
+            _spawner,
+        )
+        .await;
+    }
+
     static TX_BUF: StaticCell<[u8; SPLIT_MESSAGE_MAX_SIZE]> = StaticCell::new();
     let tx_buf = &mut TX_BUF.init([0; SPLIT_MESSAGE_MAX_SIZE])[..];
     static RX_BUF: StaticCell<[u8; SPLIT_MESSAGE_MAX_SIZE]> = StaticCell::new();
     let rx_buf = &mut RX_BUF.init([0; SPLIT_MESSAGE_MAX_SIZE])[..];
-    let uart_instance = BufferedUart::new(
+    let (uart_tx, uart_rx) = BufferedUart::new(
         p.UART0,
         Irqs,
         p.PIN_0,
@@ -55,12 +166,35 @@ async fn main(_spawner: Spawner) {
         tx_buf,
         rx_buf,
         uart::Config::default(),
+    )
+    .split();
+    // GPIO2 wired to the central's GPIO2, waking it from the idle sleep before the UART sends
+    let uart_tx = AttentionLink::new(uart_tx, Output::new(p.PIN_2, Level::Low));
+
+    // VSYS follows the cell behind the charger, sensed through 1/3 divider at GPIO29 like Pico
+    #[cfg(feature = "charger")]
+    let battery = join(
+        rmk_custom_device::telemetry::run_rp2040_telemetry(
+            rmk_custom_device::telemetry::Rp2040Telemetry::new(
+                Adc::new(p.ADC, Irqs, adc::Config::default()),
+                adc::Channel::new_temp_sensor(p.ADC_TEMP_SENSOR),
+                Some(adc::Channel::new_pin(p.PIN_29, Pull::None)),
+            ),
+            BATTERY_INTERVAL,
+        ),
+        rmk_custom_device::battery::run_battery_relay(BATTERY_INTERVAL),
     );
+    #[cfg(not(feature = "charger"))]
+    let battery = core::future::pending::<()>();
 
     // Start serving
-    run_rmk_split_peripheral::<Input<'_>, Output<'_>, _, 2, 2>(
-        pins,
-        uart_instance,
+    join(
+        run_rmk_split_peripheral::<Input<'_>, Output<'_>, _, _, 2, 2>(
+            pins,
+            uart_rx,
+            uart_tx,
+        ),
+        battery,
     )
     .await;
 }
\ No newline at end of file
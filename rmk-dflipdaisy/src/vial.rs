@@ -1,3 +1,8 @@
-// Vial config is automatically generated by `build.rs`, according to `vial.json`
-// Please put `vial.json` at your project's root
+// Vial config is automatically generated by `build.rs`, according to `keyboard.toml`
+// The generated `vial.json` is put in OUT_DIR, for sideloading into the Vial GUI
 include!(concat!(env!("OUT_DIR"), "/config_generated.rs"));
+
+const _: () = assert!(
+    VIAL_ROWS == crate::keymap::ROW && VIAL_COLS == crate::keymap::COL,
+    "the keymap doesn't match the matrix of vial.json"
+);